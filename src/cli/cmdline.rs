@@ -1,17 +1,265 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+
 use clap::{App, AppSettings, Arg};
+use http::header::{HeaderName, HeaderValue};
+use http::{Method, StatusCode};
+use regex::Regex;
+
+use crate::proxy::{self, Cidr, PathPattern, ResolveOverride, Route, TlsVersion};
+
+fn validate_path_prefix(s: String) -> Result<(), String> {
+    if s.starts_with('/') {
+        Ok(())
+    } else {
+        Err(String::from("Path prefix must start with /"))
+    }
+}
+
+// Arguments given through the environment are only read after parsing,
+// so the ones required otherwise aren't required on the command line then
+fn required_unless_env(
+    arg: Arg<'static, 'static>,
+    names: &'static [&'static str],
+) -> Arg<'static, 'static> {
+    if names.iter().any(|name| super::env_value(name).is_some()) {
+        arg
+    } else {
+        arg.required_unless_one(&names[1..])
+    }
+}
+
+fn validate_method(s: String) -> Result<(), String> {
+    Method::from_bytes(s.to_uppercase().as_bytes())
+        .map(|_| ())
+        .map_err(|_| String::from("Invalid method"))
+}
+
+// The help has to outlive the app, so it's only made once for every argument
+fn env_help(name: &'static str, help: &str) -> &'static str {
+    static HELPS: OnceLock<Mutex<HashMap<&str, &str>>> = OnceLock::new();
+    let mut helps = HELPS.get_or_init(Default::default).lock().unwrap();
+    helps.entry(name).or_insert_with(|| {
+        let help = format!("{} [env: {}{}]", help, super::ENV_PREFIX, name);
+        Box::leak(help.into_boxed_str())
+    })
+}
+
+// The environment is read along with the config file rather than by clap, which takes
+// env values for defaults and makes flags take values, so the variables are only added
+// to the help the way clap would show them
+trait EnvArg {
+    fn env_arg(self, arg: Arg<'static, 'static>) -> Self;
+}
+
+impl EnvArg for App<'static, 'static> {
+    fn env_arg(self, arg: Arg<'static, 'static>) -> Self {
+        let help = env_help(arg.b.name, arg.b.help.unwrap_or_default());
+        self.arg(arg.help(help))
+    }
+}
+
+// Flags set in the config file or the environment are turned off with --no- before their name
+const NEGATABLE_FLAGS: &[(&str, &str)] = &[
+    ("STRIP_ROUTE_PREFIX", "no-strip-route-prefix"),
+    ("REQUIRE_STRIP_PREFIX", "no-require-strip-prefix"),
+    ("SYSTEMD_SOCKET", "no-systemd-socket"),
+    ("REQUIRE_CLIENT_CERT", "no-require-client-cert"),
+    ("INSECURE_HTTPS", "no-insecure-https"),
+    ("USE_SYSTEM_PROXY", "no-use-system-proxy"),
+    ("UPSTREAM_HTTP2", "no-upstream-http2"),
+    ("TCP_NODELAY", "no-tcp-nodelay"),
+    ("PREFER_IPV6", "no-prefer-ipv6"),
+    ("IPV4_ONLY", "no-ipv4-only"),
+    ("IPV6_ONLY", "no-ipv6-only"),
+    ("NO_CACHE", "no-no-cache"),
+    ("CACHE_RESPONSES", "no-cache-responses"),
+    ("WARM_CACHE", "no-warm-cache"),
+    ("WARM_FAIL_FAST", "no-warm-fail-fast"),
+    ("BACKGROUND_REFRESH", "no-background-refresh"),
+    ("WATCH_TOKEN_FILE", "no-watch-token-file"),
+    ("TTL_FROM_JWT", "no-ttl-from-jwt"),
+    ("APPEND_USER_AGENT", "no-append-user-agent"),
+    ("ADD_VIA", "no-add-via"),
+    ("TRUST_FORWARDED", "no-trust-forwarded"),
+    ("SKIP_COMMAND_CHECK", "no-skip-command-check"),
+    ("COMMAND_CLEAR_ENV", "no-command-clear-env"),
+    ("COMMAND_ENV_REQUEST", "no-command-env-request"),
+    ("LOG_COMMAND_STDERR", "no-log-command-stderr"),
+    ("LOG_TOKENS_UNSAFE", "no-log-tokens-unsafe"),
+    ("SHELL", "no-shell"),
+    ("RETRY_ALL_METHODS", "no-retry-all-methods"),
+    ("FOLLOW_REDIRECTS", "no-follow-redirects"),
+    ("FOLLOW_CROSS_ORIGIN", "no-follow-cross-origin"),
+    ("COMPRESS", "no-compress"),
+    ("MAINTENANCE", "no-maintenance"),
+    ("ACCESS_LOG", "no-access-log"),
+    ("ERROR_DETAIL", "no-error-detail"),
+];
+
+pub fn negation(name: &str) -> Option<&'static str> {
+    NEGATABLE_FLAGS
+        .iter()
+        .find(|(flag, _)| *flag == name)
+        .map(|&(_, negation)| negation)
+}
 
+// The port and cache ttl validators are kept as they were written
+#[allow(clippy::bind_instead_of_map)]
 pub fn build_clap_app() -> App<'static, 'static> {
+    let negations: Vec<Arg> = NEGATABLE_FLAGS
+        .iter()
+        .map(|&(name, negation)| {
+            Arg::with_name(negation)
+                .long(negation)
+                .hidden(true)
+                .overrides_with(name)
+        })
+        .collect();
+
     App::new("authproxy")
         .version(crate::VERSION)
         .author("Author: Anton Barkovsky")
         .about("A Proxy that injects the Authorization header")
         .setting(AppSettings::TrailingVarArg)
-        .arg(
-            Arg::with_name("TARGET_URL")
-                .required(true)
-                .help("Target URL"),
+        .after_help(concat!(
+            "Every argument can also be given through the environment variable shown with it,",
+            " which is checked like the argument.",
+            " Arguments given on the command line take precedence over the environment,",
+            " which takes precedence over the config file and then the defaults.",
+            " Arguments that take several values take one per line, so that with --shell",
+            " AUTHPROXY_COMMAND is a single shell command line,",
+            " and flags are set unless they are empty, 0 or false.",
+            " A flag set in the config file or the environment is turned off on the command line",
+            " by prefixing its name with no-, e.g. --no-shell.",
+        ))
+        .env_arg(
+            Arg::with_name("CONFIG")
+                .long("config")
+                .takes_value(true)
+                .value_name("CONFIG")
+                .help(concat!(
+                    "TOML file with the values of any other arguments keyed by their long names,",
+                    " arguments given on the command line or in the environment take precedence",
+                )),
+        )
+        .env_arg(required_unless_env(
+            Arg::with_name("TARGET_URL").help("Target URL"),
+            &["TARGET_URL", "CONFIG"],
+        ))
+        .env_arg(
+            Arg::with_name("ROUTE")
+                .long("route")
+                .takes_value(true)
+                .value_name("PATH_PREFIX=TARGET_URL")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| s.parse::<Route>().map(|_| ()).map_err(|e| e.to_string()))
+                .help(concat!(
+                    "Send requests with paths under PATH_PREFIX to TARGET_URL instead,",
+                    " the longest matching prefix wins",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ROUTE_COMMAND")
+                .long("route-command")
+                .takes_value(true)
+                .value_name("PATH_PREFIX=COMMAND")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| {
+                    match s.split_once('=').map(|(_, command)| shell_words::split(command)) {
+                        Some(Ok(ref words)) if !words.is_empty() => Ok(()),
+                        _ => Err(String::from("Route command must look like PATH_PREFIX=COMMAND")),
+                    }
+                })
+                .help(concat!(
+                    "Obtain tokens for the route with PATH_PREFIX by running COMMAND,",
+                    " they are cached separately from the tokens of COMMAND",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ROUTE_TIMEOUT")
+                .long("route-timeout")
+                .takes_value(true)
+                .value_name("PATH_PREFIX=SECONDS")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| match s.split_once('=').map(|(_, secs)| secs.parse::<u64>()) {
+                    Some(Ok(_)) => Ok(()),
+                    _ => Err(String::from("Route timeout must look like PATH_PREFIX=SECONDS")),
+                })
+                .help(concat!(
+                    "For how many seconds to wait for the target of the route with PATH_PREFIX",
+                    " to respond, instead of UPSTREAM_TIMEOUT",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ROUTE_AUDIENCE")
+                .long("route-audience")
+                .takes_value(true)
+                .value_name("PATH_PREFIX=AUDIENCE")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| match s.split_once('=') {
+                    Some((_, audience)) if audience.starts_with('-') => {
+                        Err(String::from("Audiences can't start with -"))
+                    }
+                    Some((_, audience)) if !audience.is_empty() => Ok(()),
+                    _ => Err(String::from("Route audience must look like PATH_PREFIX=AUDIENCE")),
+                })
+                .help(concat!(
+                    "Obtain tokens for the route with PATH_PREFIX by passing AUDIENCE to the command",
+                    " as its last argument and in AUTHPROXY_AUDIENCE, tokens are cached per audience",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("STRIP_ROUTE_PREFIX")
+                .long("strip-route-prefix")
+                .takes_value(false)
+                .help("Whether to remove the matched route prefix from the forwarded path"),
+        )
+        .env_arg(
+            Arg::with_name("STRIP_PREFIX")
+                .long("strip-prefix")
+                .takes_value(true)
+                .value_name("STRIP_PREFIX")
+                .validator(validate_path_prefix)
+                .help("Path prefix to remove before forwarding requests"),
+        )
+        .env_arg(
+            Arg::with_name("ADD_PREFIX")
+                .long("add-prefix")
+                .takes_value(true)
+                .value_name("ADD_PREFIX")
+                .validator(validate_path_prefix)
+                .help("Path prefix to add before forwarding requests"),
+        )
+        .env_arg(
+            Arg::with_name("REQUIRE_STRIP_PREFIX")
+                .long("require-strip-prefix")
+                .takes_value(false)
+                .requires("STRIP_PREFIX")
+                .help(concat!(
+                    "Whether to respond with 404 to requests without the prefix to strip",
+                    " instead of forwarding them unchanged",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("HOST_HEADER")
+                .long("host-header")
+                .takes_value(true)
+                .value_name("HOST_HEADER")
+                .possible_values(&["remove", "target", "preserve"])
+                .default_value("remove")
+                .help(concat!(
+                    "What to do with the Host header: remove it, set it to the target authority,",
+                    " or preserve the client's one. Note that preserving it can make the target",
+                    " reject requests that don't match its TLS certificate (SNI) name",
+                )),
         )
-        .arg(
+        .env_arg(
             Arg::with_name("LISTEN_HOST")
                 .short("h")
                 .long("listen-host")
@@ -20,13 +268,355 @@ pub fn build_clap_app() -> App<'static, 'static> {
                 .default_value("127.0.0.1")
                 .help("Which host to listen on"),
         )
-        .arg(
+        .env_arg(
+            Arg::with_name("LISTEN_UNIX")
+                .long("listen-unix")
+                .takes_value(true)
+                .value_name("LISTEN_UNIX")
+                .conflicts_with_all(&["LISTEN_HOST", "LISTEN_PORT"])
+                .help("Listen on a Unix socket at this path instead of TCP"),
+        )
+        .env_arg(
+            Arg::with_name("SYSTEMD_SOCKET")
+                .long("systemd-socket")
+                .takes_value(false)
+                .conflicts_with_all(&["LISTEN_HOST", "LISTEN_PORT", "LISTEN_UNIX"])
+                .help(concat!(
+                    "Listen on the socket passed by systemd with socket activation",
+                    " instead of binding one, a single socket has to be passed",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("LISTEN_BACKLOG")
+                .long("listen-backlog")
+                .takes_value(true)
+                .value_name("LISTEN_BACKLOG")
+                .conflicts_with_all(&["LISTEN_UNIX", "SYSTEMD_SOCKET"])
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(backlog) if backlog > 0 && backlog <= 65535 => Ok(()),
+                    _ => Err(String::from("Invalid listen backlog, it must be 1 to 65535")),
+                })
+                .help(concat!(
+                    "How many connections not yet accepted to queue before refusing more,",
+                    " instead of 128. Linux and macOS silently cap it at the somaxconn",
+                    " sysctl, Windows at a limit of its own",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TLS_CERT")
+                .long("tls-cert")
+                .takes_value(true)
+                .value_name("TLS_CERT")
+                .requires("TLS_KEY")
+                .help("PEM file with the certificate chain to serve HTTPS with"),
+        )
+        .env_arg(
+            Arg::with_name("TLS_KEY")
+                .long("tls-key")
+                .takes_value(true)
+                .value_name("TLS_KEY")
+                .requires("TLS_CERT")
+                .help("PEM file with the PKCS#8 private key to serve HTTPS with"),
+        )
+        .env_arg(
+            Arg::with_name("CLIENT_CA")
+                .long("client-ca")
+                .takes_value(true)
+                .value_name("CLIENT_CA")
+                .requires("TLS_CERT")
+                .help("PEM file with CA certificates to verify client certificates against"),
+        )
+        .env_arg(
+            Arg::with_name("REQUIRE_CLIENT_CERT")
+                .long("require-client-cert")
+                .requires("CLIENT_CA")
+                .help("Refuse clients that don't present a certificate signed by CLIENT_CA"),
+        )
+        .env_arg(
+            Arg::with_name("SHUTDOWN_TIMEOUT")
+                .long("shutdown-timeout")
+                .takes_value(true)
+                .value_name("SHUTDOWN_TIMEOUT")
+                .default_value("30")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid shutdown timeout"))
+                })
+                .help(concat!(
+                    "For how many seconds to wait for active requests to finish",
+                    " after receiving SIGINT or SIGTERM",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("PID_FILE")
+                .long("pid-file")
+                .takes_value(true)
+                .value_name("PID_FILE")
+                .help(concat!(
+                    "Write the process ID to this file once listening,",
+                    " and remove it on shutdown",
+                )),
+        )
+        .env_arg(
             Arg::with_name("INSECURE_HTTPS")
                 .long("insecure-https")
                 .takes_value(false)
                 .help("Whether to ignore errors in HTTPS certificate validation"),
         )
-        .arg(
+        .env_arg(
+            Arg::with_name("MIN_TLS_VERSION")
+                .long("min-tls-version")
+                .takes_value(true)
+                .value_name("MIN_TLS_VERSION")
+                .validator(|s| {
+                    s.parse::<TlsVersion>()
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                })
+                .help(concat!(
+                    "Oldest TLS version to connect to the target with, one of 1.0, 1.1, 1.2 or 1.3,",
+                    " the versions that actually work depend on the system TLS library",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAX_TLS_VERSION")
+                .long("max-tls-version")
+                .takes_value(true)
+                .value_name("MAX_TLS_VERSION")
+                .validator(|s| {
+                    s.parse::<TlsVersion>()
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                })
+                .help(concat!(
+                    "Newest TLS version to connect to the target with, one of 1.0, 1.1, 1.2 or 1.3,",
+                    " by default the newest one the system TLS library supports",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TLS_SNI")
+                .long("tls-sni")
+                .takes_value(true)
+                .value_name("TLS_SNI")
+                .help(concat!(
+                    "Host name to send in SNI and verify the certificate of https targets for,",
+                    " instead of the target host, useful with RESOLVE or targets given by address",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("CA_FILE")
+                .long("ca-file")
+                .takes_value(true)
+                .value_name("CA_FILE")
+                .help(concat!(
+                    "PEM file with additional root certificates",
+                    " to trust when connecting to the target",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("CLIENT_CERT")
+                .long("client-cert")
+                .takes_value(true)
+                .value_name("CLIENT_CERT")
+                .help("PKCS#12 file with the client certificate to present to the target"),
+        )
+        .env_arg(
+            Arg::with_name("CLIENT_CERT_PASSWORD")
+                .long("client-cert-password")
+                .takes_value(true)
+                .value_name("CLIENT_CERT_PASSWORD")
+                .requires("CLIENT_CERT")
+                .help("Password to decrypt the client certificate file with"),
+        )
+        .env_arg(
+            Arg::with_name("SOCKS_PROXY")
+                .long("socks-proxy")
+                .takes_value(true)
+                .value_name("SOCKS_PROXY")
+                .help("SOCKS5 proxy to connect to targets through, as host:port"),
+        )
+        .env_arg(
+            Arg::with_name("SOCKS_USER")
+                .long("socks-user")
+                .takes_value(true)
+                .value_name("SOCKS_USER")
+                .requires_all(&["SOCKS_PROXY", "SOCKS_PASS"])
+                .help("Username to authenticate to the SOCKS5 proxy with"),
+        )
+        .env_arg(
+            Arg::with_name("SOCKS_PASS")
+                .long("socks-pass")
+                .takes_value(true)
+                .value_name("SOCKS_PASS")
+                .requires_all(&["SOCKS_PROXY", "SOCKS_USER"])
+                .help("Password to authenticate to the SOCKS5 proxy with"),
+        )
+        .env_arg(
+            Arg::with_name("USE_SYSTEM_PROXY")
+                .long("use-system-proxy")
+                .conflicts_with("SOCKS_PROXY")
+                .help(concat!(
+                    "Connect to targets through the HTTP proxies from the HTTP_PROXY, HTTPS_PROXY",
+                    " and NO_PROXY environment variables",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RESOLVE")
+                .long("resolve")
+                .takes_value(true)
+                .value_name("HOST:PORT:ADDR")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| {
+                    s.parse::<ResolveOverride>()
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                })
+                .help(concat!(
+                    "Connect to ADDR for targets at HOST:PORT instead of resolving HOST,",
+                    " HOST is still used for TLS and the Host header",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("POOL_MAX_IDLE_PER_HOST")
+                .long("pool-max-idle-per-host")
+                .takes_value(true)
+                .value_name("POOL_MAX_IDLE_PER_HOST")
+                .validator(|s| {
+                    s.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid number of connections"))
+                })
+                .help(concat!(
+                    "How many idle connections to the target to keep open for reuse,",
+                    " unlimited by default",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("POOL_IDLE_TIMEOUT")
+                .long("pool-idle-timeout")
+                .takes_value(true)
+                .value_name("POOL_IDLE_TIMEOUT")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid idle timeout"))
+                })
+                .help(concat!(
+                    "For how many seconds to keep idle connections to the target open,",
+                    " 90 by default",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("UPSTREAM_HTTP2")
+                .long("upstream-http2")
+                .takes_value(false)
+                .help(concat!(
+                    "Whether to talk HTTP/2 to the targets, negotiated with ALPN for https",
+                    " and with prior knowledge for http targets, which all have to support it.",
+                    " Trailers are passed on between HTTP/2 clients and the targets,",
+                    " the upstream timeout then only covers the response headers. Their request",
+                    " bodies aren't sent more than once, and are only held to the max body size",
+                    " by the length they give",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TCP_NODELAY")
+                .long("tcp-nodelay")
+                .takes_value(false)
+                .help(concat!(
+                    "Whether to disable Nagle's algorithm on connections from clients",
+                    " and to the target, sending small writes right away",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TCP_KEEPALIVE")
+                .long("tcp-keepalive")
+                .takes_value(true)
+                .value_name("TCP_KEEPALIVE")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(secs) if secs > 0 => Ok(()),
+                    _ => Err(String::from("Invalid TCP keepalive")),
+                })
+                .help(concat!(
+                    "After how many idle seconds to send keepalive probes on connections",
+                    " from clients and to the target, off by default. The interval between",
+                    " probes and how many are sent before giving up are left to the OS,",
+                    " and some platforms don't support setting the idle time either",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("CONNECT_TIMEOUT")
+                .long("connect-timeout")
+                .takes_value(true)
+                .value_name("CONNECT_TIMEOUT")
+                .default_value("10")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid connect timeout"))
+                })
+                .help(concat!(
+                    "For how many seconds to wait for a connection to the target, including",
+                    " the TLS handshake and connecting through a proxy, 0 means waiting indefinitely",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("HAPPY_EYEBALLS_DELAY")
+                .long("happy-eyeballs-delay")
+                .takes_value(true)
+                .value_name("HAPPY_EYEBALLS_DELAY")
+                .default_value("300")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid happy eyeballs delay"))
+                })
+                .help(concat!(
+                    "For how many milliseconds to wait for a connection to the preferred address",
+                    " family of a target before also trying the other one in parallel,",
+                    " 0 means trying the addresses one after another",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("SOURCE_ADDRESS")
+                .long("source-address")
+                .takes_value(true)
+                .value_name("SOURCE_ADDRESS")
+                .validator(|s| {
+                    s.parse::<IpAddr>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid source address"))
+                })
+                .conflicts_with("SOCKS_PROXY")
+                .help(concat!(
+                    "Local IP address to connect to targets from,",
+                    " only targets of the same address family are reachable then,",
+                    " can't be used with SOCKS_PROXY",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("PREFER_IPV6")
+                .long("prefer-ipv6")
+                .takes_value(false)
+                .conflicts_with_all(&["IPV4_ONLY", "IPV6_ONLY"])
+                .help("Try the IPv6 addresses of targets first, whatever the system prefers"),
+        )
+        .env_arg(
+            Arg::with_name("IPV4_ONLY")
+                .long("ipv4-only")
+                .takes_value(false)
+                .conflicts_with("IPV6_ONLY")
+                .help("Only connect to the IPv4 addresses of targets"),
+        )
+        .env_arg(
+            Arg::with_name("IPV6_ONLY")
+                .long("ipv6-only")
+                .takes_value(false)
+                .help("Only connect to the IPv6 addresses of targets"),
+        )
+        .env_arg(
             Arg::with_name("LISTEN_PORT")
                 .short("p")
                 .long("listen-port")
@@ -38,9 +628,12 @@ pub fn build_clap_app() -> App<'static, 'static> {
                         .and(Ok(()))
                         .or_else(|_| Err(String::from("Invalid port")))
                 })
-                .help("Which port to listen on"),
+                .help(concat!(
+                    "Which port to listen on, with 0 a free port is picked",
+                    " and printed to stdout",
+                )),
         )
-        .arg(
+        .env_arg(
             Arg::with_name("CACHE_TTL")
                 .long("cache-ttl")
                 .takes_value(true)
@@ -53,13 +646,1247 @@ pub fn build_clap_app() -> App<'static, 'static> {
                 })
                 .help("For how many seconds to keep last token in cache"),
         )
-        .arg(
-            Arg::with_name("COMMAND")
-                .multiple(true)
-                .required(true)
+        .env_arg(
+            Arg::with_name("NO_CACHE")
+                .long("no-cache")
+                .conflicts_with_all(&["REFRESH_AHEAD", "WARM_CACHE"])
+                .help(concat!(
+                    "Don't cache tokens and run the command for every request,",
+                    " for commands that return a single-use token",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("CACHE_RESPONSES")
+                .long("cache-responses")
+                .help(concat!(
+                    "Keep the responses to GET requests in memory and serve them again",
+                    " while they are fresh, for as long as the max-age of successful ones says.",
+                    " Requests carrying cookies or an authorization of their own always go",
+                    " to the target",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RESPONSE_CACHE_TTL")
+                .long("response-cache-ttl")
+                .takes_value(true)
+                .value_name("RESPONSE_CACHE_TTL")
+                .requires("CACHE_RESPONSES")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid response cache ttl"))
+                })
+                .help(concat!(
+                    "For how many seconds to keep successful responses instead of their max-age,",
+                    " ones the target forbids caching are still not kept",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RESPONSE_CACHE_ERROR_TTL")
+                .long("response-cache-error-ttl")
+                .takes_value(true)
+                .value_name("RESPONSE_CACHE_ERROR_TTL")
+                .default_value("0")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid response cache error ttl"))
+                })
+                .help(concat!(
+                    "For how many seconds to keep 5xx responses, to spare a failing target",
+                    " the requests in the meantime",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RESPONSE_CACHE_MAX_ENTRIES")
+                .long("response-cache-max-entries")
+                .takes_value(true)
+                .value_name("RESPONSE_CACHE_MAX_ENTRIES")
+                .default_value("1000")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(max_entries) if max_entries > 0 => Ok(()),
+                    _ => Err(String::from("Invalid maximum number of cached responses")),
+                })
+                .help(concat!(
+                    "How many responses to keep, the least recently used ones are dropped first,",
+                    " expired ones before the others",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RESPONSE_CACHE_MAX_BODY_SIZE")
+                .long("response-cache-max-body-size")
+                .takes_value(true)
+                .value_name("RESPONSE_CACHE_MAX_BODY_SIZE")
+                .default_value("1048576")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid maximum cached body size"))
+                })
+                .help("Largest response body in bytes to cache, larger responses aren't kept"),
+        )
+        .env_arg(
+            Arg::with_name("TTL_JITTER")
+                .long("ttl-jitter")
+                .takes_value(true)
+                .value_name("TTL_JITTER")
+                .default_value("0")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(fraction) if (0.0..1.0).contains(&fraction) => Ok(()),
+                    _ => Err(String::from("Invalid ttl jitter fraction")),
+                })
+                .help(concat!(
+                    "Up to which fraction of the ttl to randomly shorten it by for each token,",
+                    " so that proxies sharing a token source don't all refresh at once",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("CACHE_MAX_ENTRIES")
+                .long("cache-max-entries")
+                .takes_value(true)
+                .value_name("CACHE_MAX_ENTRIES")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(max_entries) if max_entries > 0 => Ok(()),
+                    _ => Err(String::from("Invalid maximum number of cache entries")),
+                })
+                .help(concat!(
+                    "How many tokens to keep when they are cached per request metadata or audience,",
+                    " 10000 by default, the least recently used ones are dropped first,",
+                    " expired ones before the others",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("REFRESH_AHEAD")
+                .long("refresh-ahead")
+                .takes_value(true)
+                .value_name("REFRESH_AHEAD")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(fraction) if fraction > 0.0 && fraction < 1.0 => Ok(()),
+                    _ => Err(String::from("Invalid refresh ahead fraction")),
+                })
+                .help(concat!(
+                    "Fraction of the cache ttl after which the token is refreshed in the background,",
+                    " while the cached one is still served",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("SERVE_STALE_FOR")
+                .long("serve-stale-for")
+                .takes_value(true)
+                .value_name("SERVE_STALE_FOR")
+                .default_value("0")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid stale token window"))
+                })
+                .help(concat!(
+                    "For how many seconds after expiry to keep using the last token",
+                    " if the command fails to obtain a new one",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("FAILURE_CACHE_TTL")
+                .long("failure-cache-ttl")
+                .takes_value(true)
+                .value_name("FAILURE_CACHE_TTL")
+                .default_value("0")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid failure cache ttl"))
+                })
                 .help(concat!(
-                    "Command that will be ran for every request and will output",
-                    " Authorization header value",
+                    "For how many seconds to fail requests with the last error",
+                    " instead of rerunning a failed command",
                 )),
         )
+        .env_arg(
+            Arg::with_name("WARM_CACHE")
+                .long("warm-cache")
+                .conflicts_with("COMMAND_ENV_REQUEST")
+                .help("Obtain a token before starting to listen, so the first request doesn't wait"),
+        )
+        .env_arg(
+            Arg::with_name("WARM_FAIL_FAST")
+                .long("warm-fail-fast")
+                .requires("WARM_CACHE")
+                .help("Exit instead of starting anyway if the token can't be obtained at startup"),
+        )
+        .env_arg(
+            Arg::with_name("BACKGROUND_REFRESH")
+                .long("background-refresh")
+                .requires("REFRESH_AHEAD")
+                .conflicts_with("COMMAND_ENV_REQUEST")
+                .help(concat!(
+                    "Keep refreshing the token in the background as REFRESH_AHEAD says,",
+                    " even when there are no requests",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TOKEN_URL")
+                .long("token-url")
+                .takes_value(true)
+                .value_name("TOKEN_URL")
+                .requires_all(&["CLIENT_ID", "CLIENT_SECRET"])
+                .conflicts_with("COMMAND")
+                .help(concat!(
+                    "OAuth2 token endpoint to obtain the token from with the client credentials",
+                    " grant, instead of running a command",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("CLIENT_ID")
+                .long("client-id")
+                .takes_value(true)
+                .value_name("CLIENT_ID")
+                .requires("TOKEN_URL")
+                .help("OAuth2 client id to request tokens with"),
+        )
+        .env_arg(
+            Arg::with_name("CLIENT_SECRET")
+                .long("client-secret")
+                .takes_value(true)
+                .value_name("CLIENT_SECRET")
+                .requires("TOKEN_URL")
+                .help("OAuth2 client secret to request tokens with"),
+        )
+        .env_arg(
+            Arg::with_name("SCOPE")
+                .long("scope")
+                .takes_value(true)
+                .value_name("SCOPE")
+                .requires("TOKEN_URL")
+                .help("Space separated OAuth2 scopes to request"),
+        )
+        .env_arg(
+            Arg::with_name("TOKEN_FILE")
+                .long("token-file")
+                .takes_value(true)
+                .value_name("TOKEN_FILE")
+                .conflicts_with_all(&["COMMAND", "TOKEN_URL"])
+                .help(concat!(
+                    "File to read the token from instead of running a command,",
+                    " it's read again whenever the cached token expires",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("WATCH_TOKEN_FILE")
+                .long("watch-token-file")
+                .requires("TOKEN_FILE")
+                .help(concat!(
+                    "Read the token file again as soon as it changes or is replaced,",
+                    " instead of waiting for the cached token to expire",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("AUTH_MODE")
+                .long("auth-mode")
+                .takes_value(true)
+                .value_name("AUTH_MODE")
+                .possible_values(&["bearer", "sigv4"])
+                .help(concat!(
+                    "How to authenticate requests, bearer injects the token from the command",
+                    " and is the default, sigv4 signs them with AWS credentials",
+                    " from the environment or the instance metadata, signing reads request bodies",
+                    " whole up to MAX_BODY_SIZE or 16 MiB, except for s3, which gets larger ones unsigned",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("AWS_REGION")
+                .long("aws-region")
+                .takes_value(true)
+                .value_name("AWS_REGION")
+                .help("AWS region to sign requests for"),
+        )
+        .env_arg(
+            Arg::with_name("AWS_SERVICE")
+                .long("aws-service")
+                .takes_value(true)
+                .value_name("AWS_SERVICE")
+                .help("AWS service to sign requests for, such as execute-api or s3"),
+        )
+        .env_arg(
+            Arg::with_name("TOKEN_FORMAT")
+                .long("token-format")
+                .takes_value(true)
+                .value_name("TOKEN_FORMAT")
+                .possible_values(&["raw", "json"])
+                .default_value("raw")
+                .help(concat!(
+                    "How to interpret the command output: raw uses it as the token as is,",
+                    " json reads the token and its expiry from the fields of a JSON object",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TOKEN_ENCODING")
+                .long("token-encoding")
+                .takes_value(true)
+                .value_name("TOKEN_ENCODING")
+                .possible_values(&["none", "base64-decode", "base64-encode", "url-encode"])
+                .default_value("none")
+                .help(concat!(
+                    "How to encode the obtained token before using it, or decode it",
+                    " with base64-decode, url-encode percent-encodes it. Tokens put into the",
+                    " query with --auth-location query are encoded anyway, url-encoded ones",
+                    " are put there as they are",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TOKEN_FIELD")
+                .long("token-field")
+                .takes_value(true)
+                .value_name("TOKEN_FIELD")
+                .default_value("access_token")
+                .help("Field holding the token in json token format"),
+        )
+        .env_arg(
+            Arg::with_name("TOKEN_JSONPATH")
+                .long("token-jsonpath")
+                .takes_value(true)
+                .value_name("TOKEN_JSONPATH")
+                .validator(|s| {
+                    jsonpath_lib::Compiled::compile(&s)
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid token JSONPath"))
+                })
+                .help(concat!(
+                    "JSONPath expression selecting the token in json token format,",
+                    " for tokens nested deeper than TOKEN_FIELD can reach",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TOKEN_REGEX")
+                .long("token-regex")
+                .takes_value(true)
+                .value_name("TOKEN_REGEX")
+                .validator(|s| match Regex::new(&s) {
+                    Ok(ref regex) if regex.captures_len() > 1 => Ok(()),
+                    _ => Err(String::from("Invalid token regex, it needs a capture group")),
+                })
+                .help(concat!(
+                    "Regex matched against the output in raw token format,",
+                    " whose first capture group becomes the token",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("EXPIRY_FIELD")
+                .long("expiry-field")
+                .takes_value(true)
+                .value_name("EXPIRY_FIELD")
+                .default_value("expires_in")
+                .help(concat!(
+                    "Field holding in how many seconds the token expires in json token format,",
+                    " used instead of the cache ttl",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TTL_FROM_JWT")
+                .long("ttl-from-jwt")
+                .help(concat!(
+                    "Cache the token until the exp claim of the JWT it contains,",
+                    " unless its expiry is already known",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("AUTH_SCHEME")
+                .long("auth-scheme")
+                .takes_value(true)
+                .value_name("AUTH_SCHEME")
+                .default_value("Bearer")
+                .help(concat!(
+                    "Scheme to prefix the header value with,",
+                    " an empty string inserts the command output verbatim",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("HEADER_NAME")
+                .long("header-name")
+                .takes_value(true)
+                .value_name("HEADER_NAME")
+                .default_value("Authorization")
+                .validator(|s| {
+                    HeaderName::from_bytes(s.as_bytes())
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid header name"))
+                })
+                .help("Which header to put the command output into"),
+        )
+        .env_arg(
+            Arg::with_name("AUTH_LOCATION")
+                .long("auth-location")
+                .takes_value(true)
+                .value_name("AUTH_LOCATION")
+                .possible_values(&["header", "query", "cookie"])
+                .default_value("header")
+                .help(concat!(
+                    "Where to put the token: into the HEADER_NAME header, or without the auth scheme",
+                    " into the QUERY_PARAM_NAME query parameter or the COOKIE_NAME cookie",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("QUERY_PARAM_NAME")
+                .long("query-param-name")
+                .takes_value(true)
+                .value_name("QUERY_PARAM_NAME")
+                .default_value("access_token")
+                .validator(|s| {
+                    if s.is_empty() {
+                        Err(String::from("Query parameter name must not be empty"))
+                    } else {
+                        Ok(())
+                    }
+                })
+                .help(concat!(
+                    "Which query parameter to put the token into with --auth-location query,",
+                    " replacing the ones the client sent",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("COOKIE_NAME")
+                .long("cookie-name")
+                .takes_value(true)
+                .value_name("COOKIE_NAME")
+                .default_value("session")
+                .validator(|s| {
+                    // Cookie names are tokens, just like header names
+                    HeaderName::from_bytes(s.as_bytes())
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid cookie name"))
+                })
+                .help(concat!(
+                    "Which cookie to put the token into with --auth-location cookie,",
+                    " alongside the other cookies the client sent",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ADD_HEADER")
+                .long("add-header")
+                .takes_value(true)
+                .value_name("ADD_HEADER")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| {
+                    proxy::parse_header(&s)
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid header"))
+                })
+                .help("Static header to add to forwarded requests, in the Name: Value form"),
+        )
+        .env_arg(
+            Arg::with_name("STRIP_HEADER")
+                .long("strip-header")
+                .takes_value(true)
+                .value_name("STRIP_HEADER")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| {
+                    HeaderName::from_bytes(s.as_bytes())
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid header name"))
+                })
+                .help("Header to remove from forwarded requests"),
+        )
+        .env_arg(
+            Arg::with_name("USER_AGENT")
+                .long("user-agent")
+                .takes_value(true)
+                .value_name("USER_AGENT")
+                .conflicts_with("APPEND_USER_AGENT")
+                .validator(|s| {
+                    HeaderValue::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid user agent"))
+                })
+                .help("User-Agent to send to the target instead of the client's"),
+        )
+        .env_arg(
+            Arg::with_name("APPEND_USER_AGENT")
+                .long("append-user-agent")
+                .takes_value(false)
+                .help("Whether to add authproxy and its version to the User-Agent of the client"),
+        )
+        .env_arg(
+            Arg::with_name("ADD_VIA")
+                .long("add-via")
+                .takes_value(false)
+                .help(concat!(
+                    "Whether to add authproxy and its version to the Via header",
+                    " of forwarded requests and of responses",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TRUST_FORWARDED")
+                .long("trust-forwarded")
+                .help(concat!(
+                    "Append the client address to the X-Forwarded-For header sent by the client",
+                    " instead of replacing it",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ADD_RESPONSE_HEADER")
+                .long("add-response-header")
+                .takes_value(true)
+                .value_name("ADD_RESPONSE_HEADER")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| {
+                    proxy::parse_header(&s)
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid header"))
+                })
+                .help("Static header to add to responses, in the Name: Value form"),
+        )
+        .env_arg(
+            Arg::with_name("RESPONSE_HEADER_MODE")
+                .long("response-header-mode")
+                .takes_value(true)
+                .value_name("RESPONSE_HEADER_MODE")
+                .possible_values(&["append", "override"])
+                .default_value("append")
+                .help(concat!(
+                    "Whether response headers added with --add-response-header are appended to",
+                    " the ones with the same name from the target, or replace them",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("SHELL")
+                .long("shell")
+                .help(concat!(
+                    "Run COMMAND and the route commands as a single string with sh -c",
+                    " (cmd /C on Windows) to allow pipelines, the first word of COMMAND is",
+                    " the script and the words after it are quoted as its arguments,",
+                    " the script is interpreted by the shell, so it must not contain untrusted input",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TRANSFORM_COMMAND")
+                .long("transform-command")
+                .takes_value(true)
+                .value_name("TRANSFORM_COMMAND")
+                .validator(|s| match shell_words::split(&s) {
+                    Ok(ref words) if !words.is_empty() => Ok(()),
+                    _ => Err(String::from("Invalid transform command")),
+                })
+                .help(concat!(
+                    "Command that will receive the output of COMMAND on stdin",
+                    " and will output the header value instead",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("COMMAND_TIMEOUT")
+                .long("command-timeout")
+                .takes_value(true)
+                .value_name("COMMAND_TIMEOUT")
+                .default_value("30")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid command timeout"))
+                })
+                .help("For how many seconds to wait for the command to finish"),
+        )
+        .env_arg(
+            Arg::with_name("COMMAND_RETRIES")
+                .long("command-retries")
+                .takes_value(true)
+                .value_name("COMMAND_RETRIES")
+                .default_value("0")
+                .validator(|s| {
+                    s.parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid number of command retries"))
+                })
+                .help("How many more times to run the command if it exits unsuccessfully"),
+        )
+        .env_arg(
+            Arg::with_name("COMMAND_RETRY_DELAY")
+                .long("command-retry-delay")
+                .takes_value(true)
+                .value_name("COMMAND_RETRY_DELAY")
+                .default_value("500")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid command retry delay"))
+                })
+                .help("For how many milliseconds to wait before running the command again"),
+        )
+        .env_arg(
+            Arg::with_name("COMMAND_CWD")
+                .long("command-cwd")
+                .takes_value(true)
+                .value_name("COMMAND_CWD")
+                .help("Directory to run the commands in instead of the current one"),
+        )
+        .env_arg(
+            Arg::with_name("SKIP_COMMAND_CHECK")
+                .long("skip-command-check")
+                .takes_value(false)
+                .help(concat!(
+                    "Whether to skip checking at startup that the commands exist and are executable,",
+                    " for setups where they only appear later",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("COMMAND_ENV")
+                .long("command-env")
+                .takes_value(true)
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| match s.split_once('=') {
+                    Some((key, _)) if !key.is_empty() => Ok(()),
+                    _ => Err(String::from("Environment variable must look like KEY=VALUE")),
+                })
+                .help("Environment variable to set for the commands"),
+        )
+        .env_arg(
+            Arg::with_name("COMMAND_CLEAR_ENV")
+                .long("command-clear-env")
+                .help(concat!(
+                    "Run the commands with only the variables from COMMAND_ENV",
+                    " instead of inheriting the environment of the proxy",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("COMMAND_ENV_REQUEST")
+                .long("command-env-request")
+                .help(concat!(
+                    "Pass the request method and path to the command in AUTHPROXY_REQUEST_METHOD",
+                    " and AUTHPROXY_REQUEST_PATH, tokens are then cached per method and path",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("COMMAND_ENV_HEADER")
+                .long("command-env-header")
+                .takes_value(true)
+                .value_name("COMMAND_ENV_HEADER")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| {
+                    HeaderName::from_bytes(s.as_bytes())
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid header name"))
+                })
+                .help(concat!(
+                    "Request header to pass to the command in AUTHPROXY_HEADER_<NAME>,",
+                    " tokens are then cached per header value",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("AUDIENCE_FROM_HEADER")
+                .long("audience-from-header")
+                .takes_value(true)
+                .value_name("AUDIENCE_FROM_HEADER")
+                .validator(|s| {
+                    HeaderName::from_bytes(s.as_bytes())
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid header name"))
+                })
+                .help(concat!(
+                    "Request header with the audience to obtain the token for, like ROUTE_AUDIENCE",
+                    " but taking precedence over it, only AUTHPROXY_AUDIENCE is set with --shell,",
+                    " requires --allowed-audience",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ALLOWED_AUDIENCE")
+                .long("allowed-audience")
+                .takes_value(true)
+                .value_name("AUDIENCE")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| {
+                    if s.starts_with('-') {
+                        Err(String::from("Audiences can't start with -"))
+                    } else {
+                        Ok(())
+                    }
+                })
+                .help(concat!(
+                    "Audience that clients may ask for with AUDIENCE_FROM_HEADER, requests for",
+                    " any other audience are rejected with 403",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("LOG_COMMAND_STDERR")
+                .long("log-command-stderr")
+                .help(concat!(
+                    "Log what the command writes to stderr at debug level even when it succeeds,",
+                    " it is always logged when the command fails",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("LOG_TOKENS_UNSAFE")
+                .long("log-tokens-unsafe")
+                .help(concat!(
+                    "Log whole tokens at debug level instead of just their beginning and length,",
+                    " which leaks them to anyone who can read the logs",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("AUTH_FAILURE_STATUS")
+                .long("auth-failure-status")
+                .takes_value(true)
+                .value_name("AUTH_FAILURE_STATUS")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .default_value("401,403")
+                .validator(|s| {
+                    StatusCode::from_bytes(s.as_bytes())
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid status code"))
+                })
+                .help(concat!(
+                    "Target response statuses after which the token is refreshed",
+                    " and the request is retried once",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAX_RETRY_BODY_SIZE")
+                .long("max-retry-body-size")
+                .takes_value(true)
+                .value_name("MAX_RETRY_BODY_SIZE")
+                .default_value("1048576")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid body size"))
+                })
+                .help(concat!(
+                    "Requests with bodies larger than this many bytes or of unknown size",
+                    " are never retried",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAX_BODY_SIZE")
+                .long("max-body-size")
+                .takes_value(true)
+                .value_name("MAX_BODY_SIZE")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid max body size"))
+                })
+                .help("Requests with bodies larger than this many bytes are rejected with 413"),
+        )
+        .env_arg(
+            Arg::with_name("MAX_HEADER_SIZE")
+                .long("max-header-size")
+                .takes_value(true)
+                .value_name("MAX_HEADER_SIZE")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(size) if size >= 8192 => Ok(()),
+                    _ => Err(String::from(
+                        "Invalid maximum header size, it must be at least 8192 bytes",
+                    )),
+                })
+                .help(concat!(
+                    "Largest request line and headers in bytes to accept from HTTP/1 clients,",
+                    " larger requests are rejected with 431. Responses from the target",
+                    " aren't affected and can have headers of up to about 400KB",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAX_RETRIES")
+                .long("max-retries")
+                .takes_value(true)
+                .value_name("MAX_RETRIES")
+                .default_value("0")
+                .validator(|s| {
+                    s.parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid number of retries"))
+                })
+                .help(concat!(
+                    "How many times to retry requests that failed to reach the target",
+                    " or that it responded to with 429 or 503",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RETRY_BASE_DELAY")
+                .long("retry-base-delay")
+                .takes_value(true)
+                .value_name("RETRY_BASE_DELAY")
+                .default_value("100")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid retry delay"))
+                })
+                .help(concat!(
+                    "For how many milliseconds to wait before the first retry,",
+                    " the delay doubles with every further attempt",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAX_RETRY_WAIT")
+                .long("max-retry-wait")
+                .takes_value(true)
+                .value_name("MAX_RETRY_WAIT")
+                .default_value("30")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid maximum retry wait"))
+                })
+                .help(concat!(
+                    "For how many seconds at most to wait before a retry when the target",
+                    " asks for longer with Retry-After, which is otherwise waited for",
+                    " instead of the retry delay",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RETRY_ALL_METHODS")
+                .long("retry-all-methods")
+                .takes_value(false)
+                .help("Whether to also retry requests with non-idempotent methods like POST"),
+        )
+        .env_arg(
+            Arg::with_name("FOLLOW_REDIRECTS")
+                .long("follow-redirects")
+                .takes_value(false)
+                .help(concat!(
+                    "Whether to follow redirects from the target with the token",
+                    " instead of passing them back to the client",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAX_REDIRECTS")
+                .long("max-redirects")
+                .takes_value(true)
+                .value_name("MAX_REDIRECTS")
+                .default_value("5")
+                .validator(|s| {
+                    s.parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid number of redirects"))
+                })
+                .help(concat!(
+                    "How many redirects to follow for a request,",
+                    " the last redirect is passed back to the client",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("FOLLOW_CROSS_ORIGIN")
+                .long("follow-cross-origin")
+                .takes_value(false)
+                .requires("FOLLOW_REDIRECTS")
+                .help(concat!(
+                    "Whether to also follow redirects to other schemes, hosts or ports,",
+                    " which get sent the token as well",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("UPSTREAM_TIMEOUT")
+                .long("upstream-timeout")
+                .takes_value(true)
+                .value_name("UPSTREAM_TIMEOUT")
+                .default_value("600")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid upstream timeout"))
+                })
+                .help(concat!(
+                    "For how many seconds to wait for the target to send the whole response,",
+                    " 0 means waiting indefinitely",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("TIMEOUT_METHOD")
+                .long("timeout-method")
+                .takes_value(true)
+                .value_name("METHOD=SECONDS")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .validator(|s| match s.split_once('=') {
+                    Some((method, secs))
+                        if Method::from_bytes(method.to_uppercase().as_bytes()).is_ok()
+                            && secs.parse::<u64>().is_ok() =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(String::from("Method timeout must look like METHOD=SECONDS")),
+                })
+                .help(concat!(
+                    "For how many seconds to wait for the target to respond to requests",
+                    " with METHOD, instead of UPSTREAM_TIMEOUT, route timeouts take precedence",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ALLOW_METHOD")
+                .long("allow-method")
+                .takes_value(true)
+                .value_name("METHOD")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .validator(validate_method)
+                .help(concat!(
+                    "Method to forward requests with, requests with other methods",
+                    " are refused with 405, all methods are forwarded when not given",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("BLOCK_METHOD")
+                .long("block-method")
+                .takes_value(true)
+                .value_name("METHOD")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .validator(validate_method)
+                .help("Method to refuse requests with 405 for instead of forwarding them"),
+        )
+        .env_arg(
+            Arg::with_name("ALLOW_CIDR")
+                .long("allow-cidr")
+                .takes_value(true)
+                .value_name("CIDR")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .validator(|s| s.parse::<Cidr>().map(|_| ()).map_err(|e| e.to_string()))
+                .help(concat!(
+                    "Network to accept requests from, like 10.0.0.0/8, requests from anywhere else",
+                    " are refused with 403 once this is given",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("DENY_CIDR")
+                .long("deny-cidr")
+                .takes_value(true)
+                .value_name("CIDR")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .validator(|s| s.parse::<Cidr>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Network to refuse requests from with 403, even if ALLOW_CIDR includes it"),
+        )
+        .env_arg(
+            Arg::with_name("ALLOW_PATH")
+                .long("allow-path")
+                .takes_value(true)
+                .value_name("PATTERN")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| s.parse::<PathPattern>().map(|_| ()).map_err(|e| e.to_string()))
+                .help(concat!(
+                    "Path prefix, or regex if it starts with ~, to forward requests for,",
+                    " requests for any other path are answered with 404 once this is given",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("BLOCK_PATH")
+                .long("block-path")
+                .takes_value(true)
+                .value_name("PATTERN")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| s.parse::<PathPattern>().map(|_| ()).map_err(|e| e.to_string()))
+                .help(concat!(
+                    "Path prefix, or regex if it starts with ~, to answer requests for with 404",
+                    " even if ALLOW_PATH includes it. Paths are matched percent-decoded",
+                    " and with dot segments resolved",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("STREAM_PATHS")
+                .long("stream-paths")
+                .takes_value(true)
+                .value_name("PATH_PREFIX")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .help(concat!(
+                    "Paths under which responses are streamed, like server-sent events,",
+                    " the upstream timeout only covers waiting for their headers",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("COMPRESS")
+                .long("compress")
+                .takes_value(false)
+                .help(concat!(
+                    "Whether to gzip responses for clients that accept it,",
+                    " unless the target already encoded them",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("COMPRESS_MIN_SIZE")
+                .long("compress-min-size")
+                .takes_value(true)
+                .value_name("COMPRESS_MIN_SIZE")
+                .default_value("1024")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid minimum size to compress"))
+                })
+                .help(concat!(
+                    "Responses smaller than this many bytes aren't compressed,",
+                    " responses of an unknown size always are",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("REQUEST_ID_HEADER")
+                .long("request-id-header")
+                .takes_value(true)
+                .value_name("REQUEST_ID_HEADER")
+                .default_value("X-Request-Id")
+                .validator(|s| {
+                    if s.is_empty() {
+                        return Ok(());
+                    }
+                    HeaderName::from_bytes(s.as_bytes())
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid header name"))
+                })
+                .help(concat!(
+                    "Header carrying the request id, which is generated for requests without one,",
+                    " forwarded, returned in the response and logged, an empty string disables it",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("CACHE_STATUS_HEADER")
+                .long("cache-status-header")
+                .takes_value(true)
+                .value_name("CACHE_STATUS_HEADER")
+                .validator(|s| {
+                    HeaderName::from_bytes(s.as_bytes())
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid header name"))
+                })
+                .help(concat!(
+                    "Response header telling whether the token came from the cache,",
+                    " set to hit, miss or stale, like X-Authproxy-Cache",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("HEALTH_PATH")
+                .long("health-path")
+                .takes_value(true)
+                .value_name("HEALTH_PATH")
+                .default_value("/healthz")
+                .help(concat!(
+                    "Path on which the proxy answers GET requests itself for health checks,",
+                    " an empty string disables it",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("METRICS_PATH")
+                .long("metrics-path")
+                .takes_value(true)
+                .value_name("METRICS_PATH")
+                .default_value("/metrics")
+                .help(concat!(
+                    "Path on which the proxy serves its Prometheus metrics,",
+                    " an empty string disables it",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("DEBUG_ECHO_PATH")
+                .long("debug-echo-path")
+                .takes_value(true)
+                .value_name("DEBUG_ECHO_PATH")
+                .validator(|s| {
+                    if s.starts_with('/') {
+                        Ok(())
+                    } else {
+                        Err(String::from("Debug echo path must start with /"))
+                    }
+                })
+                .requires("ADMIN_TOKEN")
+                .help(concat!(
+                    "Answer requests under this path with what would be sent to the target",
+                    " for the rest of their path, as JSON with the token and the added headers",
+                    " redacted, the requests have to carry ADMIN_TOKEN like the admin ones",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ADMIN_TOKEN")
+                .long("admin-token")
+                .takes_value(true)
+                .value_name("ADMIN_TOKEN")
+                .help(concat!(
+                    "Enables POST /admin/flush-cache to clear the token cache",
+                    " and POST or DELETE /admin/maintenance to turn maintenance mode on or off,",
+                    " and GET /admin/status to see how long the cached tokens are good for,",
+                    " requests to them must have an Authorization: Bearer ADMIN_TOKEN header",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAINTENANCE")
+                .long("maintenance")
+                .help(concat!(
+                    "Start in maintenance mode, answering proxied requests with 503",
+                    " without obtaining tokens or contacting the target",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("ACCESS_LOG")
+                .long("access-log")
+                .takes_value(false)
+                .help("Whether to log a line for every proxied request"),
+        )
+        .env_arg(
+            Arg::with_name("ACCESS_LOG_FORMAT")
+                .long("access-log-format")
+                .takes_value(true)
+                .value_name("ACCESS_LOG_FORMAT")
+                .possible_values(&["combined", "json"])
+                .default_value("combined")
+                .help("Format of the access log lines"),
+        )
+        .env_arg(
+            Arg::with_name("ERROR_FORMAT")
+                .long("error-format")
+                .takes_value(true)
+                .value_name("ERROR_FORMAT")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Format of the responses sent to clients when a request fails"),
+        )
+        .env_arg(
+            Arg::with_name("ERROR_DETAIL")
+                .long("error-detail")
+                .help("Include the error and its causes in the responses to failed requests"),
+        )
+        .env_arg(
+            Arg::with_name("RATE_LIMIT")
+                .long("rate-limit")
+                .takes_value(true)
+                .value_name("RATE_LIMIT")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(()),
+                    _ => Err(String::from("Invalid rate limit")),
+                })
+                .help(concat!(
+                    "How many requests per second to allow from each client address,",
+                    " unix socket clients aren't limited",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RATE_BURST")
+                .long("rate-burst")
+                .takes_value(true)
+                .value_name("RATE_BURST")
+                .requires("RATE_LIMIT")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(burst) if burst > 0 => Ok(()),
+                    _ => Err(String::from("Invalid rate burst")),
+                })
+                .help(concat!(
+                    "How many requests a client can make at once before being limited,",
+                    " defaults to the rate limit",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAX_CONCURRENT_REQUESTS")
+                .long("max-concurrent-requests")
+                .takes_value(true)
+                .value_name("MAX_CONCURRENT_REQUESTS")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(max) if max > 0 => Ok(()),
+                    _ => Err(String::from("Invalid maximum number of concurrent requests")),
+                })
+                .help("How many requests to proxy at once, unlimited by default"),
+        )
+        .env_arg(
+            Arg::with_name("OVERFLOW")
+                .long("overflow")
+                .takes_value(true)
+                .value_name("OVERFLOW")
+                .possible_values(&["queue", "reject"])
+                .default_value("queue")
+                .help(concat!(
+                    "Whether requests past the concurrency limit wait for their turn",
+                    " or are rejected with 503",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("MAX_QUEUED_REQUESTS")
+                .long("max-queued-requests")
+                .takes_value(true)
+                .value_name("MAX_QUEUED_REQUESTS")
+                .requires("MAX_CONCURRENT_REQUESTS")
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|_| {
+                    String::from("Invalid maximum number of queued requests")
+                }))
+                .help(concat!(
+                    "How many requests can wait for their turn in the queue overflow mode",
+                    " before the rest are rejected with 503, unlimited by default",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("BREAKER_THRESHOLD")
+                .long("breaker-threshold")
+                .takes_value(true)
+                .value_name("BREAKER_THRESHOLD")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(threshold) if threshold > 0 => Ok(()),
+                    _ => Err(String::from("Invalid circuit breaker threshold")),
+                })
+                .help(concat!(
+                    "After how many failed requests to a target in a row to reject requests to it",
+                    " with 503 for BREAKER_COOLDOWN seconds, before letting a trial request through",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("BREAKER_COOLDOWN")
+                .long("breaker-cooldown")
+                .takes_value(true)
+                .value_name("BREAKER_COOLDOWN")
+                .default_value("30")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid circuit breaker cooldown"))
+                })
+                .help("For how many seconds to reject requests to a target once it keeps failing"),
+        )
+        .env_arg(
+            Arg::with_name("OTLP_ENDPOINT")
+                .long("otlp-endpoint")
+                .takes_value(true)
+                .value_name("OTLP_ENDPOINT")
+                .help(concat!(
+                    "Base URL of an OpenTelemetry collector to export request spans to",
+                    " over OTLP/HTTP, such as http://localhost:4318",
+                )),
+        )
+        .env_arg(
+            Arg::with_name("RUNTIME")
+                .long("runtime")
+                .takes_value(true)
+                .value_name("RUNTIME")
+                .possible_values(&["current-thread", "multi-thread"])
+                .default_value("multi-thread")
+                .help("Whether to handle everything on a single thread or on a pool of them"),
+        )
+        .env_arg(
+            Arg::with_name("WORKER_THREADS")
+                .long("worker-threads")
+                .takes_value(true)
+                .value_name("WORKER_THREADS")
+                .default_value("0")
+                .validator(|s| {
+                    s.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Invalid number of worker threads"))
+                })
+                .help("How many worker threads the multi-thread runtime uses, 0 for one per CPU"),
+        )
+        .env_arg(required_unless_env(
+            Arg::with_name("COMMAND").multiple(true).help(concat!(
+                "Command that will be ran for every request and will output",
+                " Authorization header value",
+            )),
+            &["COMMAND", "CONFIG", "TOKEN_URL", "TOKEN_FILE", "AUTH_MODE"],
+        ))
+        .args(&negations)
 }
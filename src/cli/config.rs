@@ -0,0 +1,208 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use failure::{err_msg, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+
+// Mirrors the command line arguments, keys are named after their long flags
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub target_url: Option<String>,
+    pub route: Option<Vec<String>>,
+    pub route_command: Option<Vec<String>>,
+    pub route_timeout: Option<Vec<String>>,
+    pub route_audience: Option<Vec<String>>,
+    pub strip_route_prefix: Option<bool>,
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+    pub require_strip_prefix: Option<bool>,
+    pub host_header: Option<String>,
+    pub listen_host: Option<String>,
+    pub listen_port: Option<u16>,
+    pub listen_unix: Option<PathBuf>,
+    pub systemd_socket: Option<bool>,
+    pub listen_backlog: Option<u32>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub require_client_cert: Option<bool>,
+    pub shutdown_timeout: Option<u64>,
+    pub pid_file: Option<PathBuf>,
+    pub insecure_https: Option<bool>,
+    pub min_tls_version: Option<String>,
+    pub max_tls_version: Option<String>,
+    pub tls_sni: Option<String>,
+    pub ca_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_cert_password: Option<String>,
+    pub socks_proxy: Option<String>,
+    pub socks_user: Option<String>,
+    pub socks_pass: Option<String>,
+    pub use_system_proxy: Option<bool>,
+    pub resolve: Option<Vec<String>>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout: Option<u64>,
+    pub upstream_http2: Option<bool>,
+    pub tcp_nodelay: Option<bool>,
+    pub tcp_keepalive: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub happy_eyeballs_delay: Option<u64>,
+    pub source_address: Option<IpAddr>,
+    pub prefer_ipv6: Option<bool>,
+    pub ipv4_only: Option<bool>,
+    pub ipv6_only: Option<bool>,
+    pub cache_ttl: Option<u64>,
+    pub no_cache: Option<bool>,
+    pub cache_responses: Option<bool>,
+    pub response_cache_ttl: Option<u64>,
+    pub response_cache_error_ttl: Option<u64>,
+    pub response_cache_max_entries: Option<usize>,
+    pub response_cache_max_body_size: Option<u64>,
+    pub cache_max_entries: Option<usize>,
+    pub ttl_jitter: Option<f64>,
+    pub refresh_ahead: Option<f64>,
+    pub serve_stale_for: Option<u64>,
+    pub failure_cache_ttl: Option<u64>,
+    pub warm_cache: Option<bool>,
+    pub warm_fail_fast: Option<bool>,
+    pub background_refresh: Option<bool>,
+    pub token_url: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub watch_token_file: Option<bool>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub scope: Option<String>,
+    pub auth_mode: Option<String>,
+    pub aws_region: Option<String>,
+    pub aws_service: Option<String>,
+    pub token_format: Option<String>,
+    pub token_encoding: Option<String>,
+    pub token_field: Option<String>,
+    pub token_jsonpath: Option<String>,
+    pub token_regex: Option<String>,
+    pub expiry_field: Option<String>,
+    pub ttl_from_jwt: Option<bool>,
+    pub auth_scheme: Option<String>,
+    pub header_name: Option<String>,
+    pub auth_location: Option<String>,
+    pub query_param_name: Option<String>,
+    pub cookie_name: Option<String>,
+    pub add_header: Option<Vec<String>>,
+    pub strip_header: Option<Vec<String>>,
+    pub user_agent: Option<String>,
+    pub append_user_agent: Option<bool>,
+    pub add_via: Option<bool>,
+    pub trust_forwarded: Option<bool>,
+    pub add_response_header: Option<Vec<String>>,
+    pub response_header_mode: Option<String>,
+    pub transform_command: Option<String>,
+    pub command_timeout: Option<u64>,
+    pub command_retries: Option<u32>,
+    pub command_retry_delay: Option<u64>,
+    pub command_cwd: Option<PathBuf>,
+    pub skip_command_check: Option<bool>,
+    pub command_env: Option<Vec<String>>,
+    pub command_clear_env: Option<bool>,
+    pub command_env_request: Option<bool>,
+    pub command_env_header: Option<Vec<String>>,
+    pub audience_from_header: Option<String>,
+    pub allowed_audience: Option<Vec<String>>,
+    pub log_command_stderr: Option<bool>,
+    pub log_tokens_unsafe: Option<bool>,
+    pub shell: Option<bool>,
+    pub auth_failure_status: Option<Vec<u16>>,
+    pub max_retry_body_size: Option<u64>,
+    pub max_body_size: Option<u64>,
+    pub max_header_size: Option<usize>,
+    pub max_retries: Option<u32>,
+    pub retry_base_delay: Option<u64>,
+    pub max_retry_wait: Option<u64>,
+    pub retry_all_methods: Option<bool>,
+    pub follow_redirects: Option<bool>,
+    pub max_redirects: Option<u32>,
+    pub follow_cross_origin: Option<bool>,
+    pub upstream_timeout: Option<u64>,
+    pub timeout_method: Option<Vec<String>>,
+    pub stream_paths: Option<Vec<String>>,
+    pub allow_path: Option<Vec<String>>,
+    pub block_path: Option<Vec<String>>,
+    pub compress: Option<bool>,
+    pub compress_min_size: Option<u64>,
+    pub allow_method: Option<Vec<String>>,
+    pub block_method: Option<Vec<String>>,
+    pub allow_cidr: Option<Vec<String>>,
+    pub deny_cidr: Option<Vec<String>>,
+    pub request_id_header: Option<String>,
+    pub cache_status_header: Option<String>,
+    pub health_path: Option<String>,
+    pub metrics_path: Option<String>,
+    pub debug_echo_path: Option<String>,
+    pub admin_token: Option<String>,
+    pub maintenance: Option<bool>,
+    pub access_log: Option<bool>,
+    pub access_log_format: Option<String>,
+    pub error_format: Option<String>,
+    pub error_detail: Option<bool>,
+    pub rate_limit: Option<f64>,
+    pub rate_burst: Option<u32>,
+    pub max_concurrent_requests: Option<usize>,
+    pub overflow: Option<String>,
+    pub max_queued_requests: Option<usize>,
+    pub breaker_threshold: Option<u32>,
+    pub breaker_cooldown: Option<u64>,
+    pub otlp_endpoint: Option<String>,
+    pub runtime: Option<String>,
+    pub worker_threads: Option<usize>,
+    pub command: Option<Vec<String>>,
+}
+
+impl ConfigFile {
+    // The long names of the arguments, all but --config can be given in the file
+    pub fn keys() -> Vec<String> {
+        match serde_json::to_value(ConfigFile::default()) {
+            Ok(serde_json::Value::Object(fields)) => fields.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)
+            .with_context(|_| format!("Failed to read config file {}", path.display()))?;
+        let config: ConfigFile = toml::from_str(&contents)
+            .with_context(|_| format!("Failed to parse config file {}", path.display()))?;
+
+        if config.listen_unix.is_some()
+            && (config.listen_host.is_some() || config.listen_port.is_some())
+        {
+            return Err(err_msg(
+                "listen-unix can't be used with listen-host or listen-port",
+            ));
+        }
+        if config.systemd_socket == Some(true)
+            && (config.listen_host.is_some()
+                || config.listen_port.is_some()
+                || config.listen_unix.is_some())
+        {
+            return Err(err_msg(
+                "systemd-socket can't be used with listen-host, listen-port or listen-unix",
+            ));
+        }
+        if [config.prefer_ipv6, config.ipv4_only, config.ipv6_only]
+            .iter()
+            .filter(|&&flag| flag == Some(true))
+            .count()
+            > 1
+        {
+            return Err(err_msg(
+                "Only one of prefer-ipv6, ipv4-only and ipv6-only can be used",
+            ));
+        }
+        if config.user_agent.is_some() && config.append_user_agent == Some(true) {
+            return Err(err_msg("user-agent can't be used with append-user-agent"));
+        }
+
+        Ok(config)
+    }
+}
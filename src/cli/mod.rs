@@ -1,9 +1,21 @@
 mod cmdline;
+mod config;
+mod runtime;
 
-use clap::ArgMatches;
-use failure::{err_msg, Error};
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::{AppSettings, ArgMatches};
+use failure::{err_msg, Error, ResultExt};
+use http::header::{HeaderName, HeaderValue};
+use http::Method;
+use regex::Regex;
 use tokio::runtime::Runtime;
 
+use config::ConfigFile;
+
 use crate::proxy;
 
 fn cmdline_parse_error(argname: &'static str) -> Error {
@@ -13,50 +25,956 @@ fn cmdline_parse_error(argname: &'static str) -> Error {
     ))
 }
 
-fn get_proxy_params(matches: ArgMatches) -> Result<proxy::ProxyParams, Error> {
+// Any argument can also be given through an environment variable named after it,
+// e.g. AUTHPROXY_LISTEN_PORT for --listen-port
+const ENV_PREFIX: &str = "AUTHPROXY_";
+
+#[cfg(not(test))]
+fn env_var_os(name: &str) -> Option<OsString> {
+    env::var_os(format!("{}{}", ENV_PREFIX, name))
+}
+
+#[cfg(test)]
+thread_local! {
+    // Tests run in parallel, so each of them sets the environment for its own thread instead
+    static TEST_ENV: std::cell::RefCell<Vec<(&'static str, &'static str)>> = Default::default();
+}
+
+#[cfg(test)]
+fn env_var_os(name: &str) -> Option<OsString> {
+    TEST_ENV.with(|vars| {
+        vars.borrow()
+            .iter()
+            .find(|(var, _)| var.strip_prefix(ENV_PREFIX) == Some(name))
+            .map(|(_, value)| OsString::from(value))
+    })
+}
+
+fn env_value(name: &str) -> Option<String> {
+    env_var_os(name)?.into_string().ok()
+}
+
+fn env_parse_error(argname: &'static str) -> Error {
+    err_msg(format!(
+        "Failed to parse environment variable: {}{}",
+        ENV_PREFIX, argname
+    ))
+}
+
+// Values given on the command line take precedence over the ones from the environment,
+// then the config file and then the defaults
+fn explicit_value_of<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    if matches.occurrences_of(name) > 0 {
+        matches.value_of(name)
+    } else {
+        None
+    }
+}
+
+fn is_given(matches: &ArgMatches, name: &str) -> bool {
+    matches.occurrences_of(name) > 0 || env_value(name).is_some()
+}
+
+fn arg_value<T: FromStr>(
+    matches: &ArgMatches,
+    name: &'static str,
+    config_value: Option<T>,
+) -> Result<T, Error> {
+    optional_arg_value(matches, name, config_value)?.ok_or_else(|| cmdline_parse_error(name))
+}
+
+fn optional_arg_value<T: FromStr>(
+    matches: &ArgMatches,
+    name: &'static str,
+    config_value: Option<T>,
+) -> Result<Option<T>, Error> {
+    if let Some(value) = env_value(name).filter(|_| explicit_value_of(matches, name).is_none()) {
+        return value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|_| env_parse_error(name));
+    }
+    let value = match (explicit_value_of(matches, name), config_value) {
+        (None, Some(value)) => return Ok(Some(value)),
+        (Some(value), _) => Some(value),
+        (None, None) => matches.value_of(name),
+    };
+
+    value
+        .map(|s| s.parse::<T>().map_err(|_| cmdline_parse_error(name)))
+        .transpose()
+}
+
+// Values from the environment are given one per line
+fn arg_values<T: FromStr>(
+    matches: &ArgMatches,
+    name: &'static str,
+    config_values: Option<Vec<T>>,
+) -> Result<Vec<T>, Error> {
+    if let Some(values) = env_value(name).filter(|_| matches.occurrences_of(name) == 0) {
+        return values
+            .lines()
+            .map(|s| s.parse::<T>().map_err(|_| env_parse_error(name)))
+            .collect();
+    }
+    match (matches.occurrences_of(name), config_values) {
+        (0, Some(values)) => Ok(values),
+        _ => matches.values_of(name).map_or_else(
+            || Ok(Vec::new()),
+            |values| {
+                values
+                    .map(|s| s.parse::<T>().map_err(|_| cmdline_parse_error(name)))
+                    .collect()
+            },
+        ),
+    }
+}
+
+fn arg_path(matches: &ArgMatches, name: &str, config_value: Option<PathBuf>) -> Option<PathBuf> {
+    matches
+        .value_of_os(name)
+        .map(PathBuf::from)
+        .or_else(|| env_var_os(name).map(PathBuf::from))
+        .or(config_value)
+}
+
+// Flags in the environment are set unless they are empty, 0 or false
+fn flag_value(matches: &ArgMatches, name: &str) -> Option<bool> {
+    if matches.is_present(name) {
+        return Some(true);
+    }
+    if cmdline::negation(name).is_some_and(|negation| matches.is_present(negation)) {
+        return Some(false);
+    }
+    env_value(name)
+        .map(|value| !(value.is_empty() || value == "0" || value.eq_ignore_ascii_case("false")))
+}
+
+fn arg_flag(matches: &ArgMatches, name: &str, config_value: Option<bool>) -> bool {
+    flag_value(matches, name).or(config_value).unwrap_or(false)
+}
+
+fn parse_headers(headers: Vec<String>) -> Result<Vec<(HeaderName, HeaderValue)>, Error> {
+    headers.iter().map(|s| proxy::parse_header(s)).collect()
+}
+
+fn parse_env_vars(vars: Vec<String>) -> Result<Vec<(String, String)>, Error> {
+    vars.iter()
+        .map(|s| match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(err_msg(format!(
+                "Environment variable `{}` is not in the KEY=VALUE form",
+                s
+            ))),
+        })
+        .collect()
+}
+
+// Parsed here rather than with optional_arg_value to keep the error explaining what's wrong
+fn tls_version(
+    matches: &ArgMatches,
+    name: &'static str,
+    config_value: Option<String>,
+) -> Result<Option<proxy::TlsVersion>, Error> {
+    optional_arg_value::<String>(matches, name, config_value)?
+        .map(|version| version.parse())
+        .transpose()
+}
+
+// Config values that the ones of other arguments given on the command line
+// or in the environment replace
+fn is_superseded(matches: &ArgMatches, name: &str) -> bool {
+    let listen_tcp = is_given(matches, "LISTEN_HOST") || is_given(matches, "LISTEN_PORT");
+    let family_given = ["PREFER_IPV6", "IPV4_ONLY", "IPV6_ONLY"]
+        .iter()
+        .any(|family| flag_value(matches, family) == Some(true));
+    match name {
+        "LISTEN_UNIX" => listen_tcp,
+        "SYSTEMD_SOCKET" => listen_tcp || is_given(matches, "LISTEN_UNIX"),
+        "PREFER_IPV6" | "IPV4_ONLY" | "IPV6_ONLY" => family_given,
+        _ => false,
+    }
+}
+
+fn given_on_command_line(matches: &ArgMatches, name: &str) -> bool {
+    matches.occurrences_of(name) > 0
+        || cmdline::negation(name).is_some_and(|negation| matches.is_present(negation))
+}
+
+// The values from the environment and the config file are validated like the arguments, by parsing
+// the ones that are used as arguments along with the command line. The target URL and the command
+// need no validation.
+fn env_options(matches: &ArgMatches) -> Vec<String> {
+    let mut options = Vec::new();
+    for key in ConfigFile::keys() {
+        let name = key.to_uppercase().replace('-', "_");
+        let value = match env_value(&name) {
+            Some(value)
+                if name != "TARGET_URL"
+                    && name != "COMMAND"
+                    && !given_on_command_line(matches, &name)
+                    && !is_superseded(matches, &name) =>
+            {
+                value
+            }
+            _ => continue,
+        };
+        if cmdline::negation(&name).is_some() {
+            if flag_value(matches, &name) == Some(true) {
+                options.push(format!("--{}", key));
+            }
+        } else {
+            options.extend(value.lines().map(|value| format!("--{}={}", key, value)));
+        }
+    }
+
+    options
+}
+
+fn config_options(matches: &ArgMatches, config: &ConfigFile) -> Result<Vec<String>, Error> {
+    let values = match toml::Value::try_from(config)? {
+        toml::Value::Table(values) => values,
+        _ => return Ok(Vec::new()),
+    };
+    let mut options = Vec::new();
+    for (key, value) in values {
+        let name = key.to_uppercase().replace('-', "_");
+        if name == "TARGET_URL"
+            || name == "COMMAND"
+            || is_given(matches, &name)
+            || flag_value(matches, &name).is_some()
+            || is_superseded(matches, &name)
+        {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => options.push(format!("--{}", key)),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(value) => options.push(format!("--{}={}", key, value)),
+                value => options.push(format!("--{}={}", key, value)),
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+fn check_options(args: &[OsString], options: &[String]) -> Result<(), Error> {
+    let args = args
+        .iter()
+        .take(1)
+        .cloned()
+        .chain(options.iter().map(OsString::from))
+        .chain(args.iter().skip(1).cloned());
+    cmdline::build_clap_app()
+        .setting(AppSettings::ColorNever)
+        .get_matches_from_safe(args)
+        .map_err(|e| {
+            let message = e.message.lines().next().unwrap_or_default();
+            err_msg(message.trim_start_matches("error: ").to_string())
+        })?;
+
+    Ok(())
+}
+
+fn load_config(args: &[OsString], matches: &ArgMatches) -> Result<ConfigFile, Error> {
+    let env_options = env_options(matches);
+    if !env_options.is_empty() {
+        check_options(args, &env_options)
+            .with_context(|_| format!("Invalid {}* environment variables", ENV_PREFIX))?;
+    }
+    let config = match arg_path(matches, "CONFIG", None) {
+        Some(path) => {
+            let config = ConfigFile::load(&path)?;
+            let options = [env_options, config_options(matches, &config)?].concat();
+            check_options(args, &options)
+                .with_context(|_| format!("Invalid config file {}", path.display()))?;
+            config
+        }
+        None => ConfigFile::default(),
+    };
+    log::trace!("Config file: {:?}", config);
+
+    Ok(config)
+}
+
+fn build_runtime(matches: &ArgMatches, config: &ConfigFile) -> Result<Runtime, Error> {
+    let flavor = arg_value(
+        matches,
+        "RUNTIME",
+        config.runtime.as_deref().map(str::parse).transpose()?,
+    )?;
+    let worker_threads = arg_value(matches, "WORKER_THREADS", config.worker_threads)?;
+
+    runtime::build_runtime(flavor, worker_threads)
+}
+
+fn parse_header_names(names: Vec<String>) -> Result<Vec<HeaderName>, Error> {
+    Ok(names
+        .iter()
+        .map(|name| HeaderName::from_bytes(name.as_bytes()))
+        .collect::<Result<_, _>>()?)
+}
+
+// Route commands are given separately and attached to the route with the same prefix,
+// they are kept whole when they are run with a shell so that their quoting is preserved
+fn attach_route_commands(
+    routes: &mut [proxy::Route],
+    route_commands: Vec<String>,
+    shell: bool,
+) -> Result<(), Error> {
+    for route_command in route_commands {
+        let (path_prefix, command) = route_command
+            .split_once('=')
+            .ok_or_else(|| err_msg("Route command must look like PATH_PREFIX=COMMAND"))?;
+        let command = if shell {
+            Some(command.to_string())
+                .filter(|command| !command.trim().is_empty())
+                .into_iter()
+                .collect()
+        } else {
+            shell_words::split(command)?
+        };
+        if command.is_empty() {
+            return Err(err_msg(format!(
+                "The command for route {} must not be empty",
+                path_prefix
+            )));
+        }
+
+        let route = routes
+            .iter_mut()
+            .find(|route| route.path_prefix == path_prefix)
+            .ok_or_else(|| {
+                err_msg(format!(
+                    "There is no route {} to run a command for",
+                    path_prefix
+                ))
+            })?;
+        route.command = Some(command);
+    }
+
+    Ok(())
+}
+
+fn attach_route_timeouts(
+    routes: &mut [proxy::Route],
+    route_timeouts: Vec<String>,
+) -> Result<(), Error> {
+    for route_timeout in route_timeouts {
+        let (path_prefix, secs) = match route_timeout.split_once('=') {
+            Some((path_prefix, secs)) => (path_prefix, secs.parse::<u64>().ok()),
+            None => (route_timeout.as_str(), None),
+        };
+        let secs =
+            secs.ok_or_else(|| err_msg("Route timeout must look like PATH_PREFIX=SECONDS"))?;
+
+        let route = routes
+            .iter_mut()
+            .find(|route| route.path_prefix == path_prefix)
+            .ok_or_else(|| {
+                err_msg(format!(
+                    "There is no route {} to set a timeout for",
+                    path_prefix
+                ))
+            })?;
+        route.timeout_secs = Some(secs);
+    }
+
+    Ok(())
+}
+
+fn attach_route_audiences(
+    routes: &mut [proxy::Route],
+    route_audiences: Vec<String>,
+) -> Result<(), Error> {
+    for route_audience in route_audiences {
+        let (path_prefix, audience) = route_audience
+            .split_once('=')
+            .filter(|(_, audience)| !audience.is_empty())
+            .ok_or_else(|| err_msg("Route audience must look like PATH_PREFIX=AUDIENCE"))?;
+
+        let route = routes
+            .iter_mut()
+            .find(|route| route.path_prefix == path_prefix)
+            .ok_or_else(|| {
+                err_msg(format!(
+                    "There is no route {} to set an audience for",
+                    path_prefix
+                ))
+            })?;
+        route.audience = Some(audience.to_string());
+    }
+
+    Ok(())
+}
+
+fn parse_methods(methods: Vec<String>) -> Result<Vec<Method>, Error> {
+    methods
+        .iter()
+        .map(|s| Ok(Method::from_bytes(s.to_uppercase().as_bytes())?))
+        .collect()
+}
+
+fn parse_cidrs(cidrs: Vec<String>) -> Result<Vec<proxy::Cidr>, Error> {
+    cidrs.iter().map(|s| s.parse()).collect()
+}
+
+fn parse_path_patterns(patterns: Vec<String>) -> Result<Vec<proxy::PathPattern>, Error> {
+    patterns.iter().map(|s| s.parse()).collect()
+}
+
+fn parse_method_timeouts(timeouts: Vec<String>) -> Result<Vec<(Method, u64)>, Error> {
+    timeouts
+        .iter()
+        .map(|s| {
+            let (method, secs) = s.split_once('=').ok_or_else(|| {
+                err_msg(format!(
+                    "Method timeout `{}` is not in the METHOD=SECONDS form",
+                    s
+                ))
+            })?;
+            let method = Method::from_bytes(method.to_uppercase().as_bytes())?;
+            let secs = secs
+                .parse::<u64>()
+                .map_err(|_| err_msg(format!("Invalid timeout for method {}: {}", method, secs)))?;
+            Ok((method, secs))
+        })
+        .collect()
+}
+
+fn get_proxy_params(matches: ArgMatches, config: ConfigFile) -> Result<proxy::ProxyParams, Error> {
     log::trace!("Matches: {:?}", matches);
+
+    let listen_tcp = is_given(&matches, "LISTEN_HOST") || is_given(&matches, "LISTEN_PORT");
+    let listen_unix = if listen_tcp {
+        None
+    } else {
+        arg_path(&matches, "LISTEN_UNIX", config.listen_unix)
+    };
+    let systemd_socket = !listen_tcp
+        && !is_given(&matches, "LISTEN_UNIX")
+        && arg_flag(&matches, "SYSTEMD_SOCKET", config.systemd_socket);
+    // A family given on the command line or in the environment replaces the one from the config file
+    let family_flags = [
+        (
+            "PREFER_IPV6",
+            config.prefer_ipv6,
+            proxy::AddressFamily::PreferIpv6,
+        ),
+        (
+            "IPV4_ONLY",
+            config.ipv4_only,
+            proxy::AddressFamily::Ipv4Only,
+        ),
+        (
+            "IPV6_ONLY",
+            config.ipv6_only,
+            proxy::AddressFamily::Ipv6Only,
+        ),
+    ];
+    let address_family = family_flags
+        .iter()
+        .find(|(name, _, _)| flag_value(&matches, name) == Some(true))
+        .or_else(|| {
+            family_flags.iter().find(|(name, config_value, _)| {
+                *config_value == Some(true) && flag_value(&matches, name).is_none()
+            })
+        })
+        .map_or(proxy::AddressFamily::Any, |&(_, _, family)| family);
+
+    let header_name = arg_value(&matches, "HEADER_NAME", config.header_name)?;
+    HeaderName::from_bytes(header_name.as_bytes())
+        .map_err(|_| err_msg(format!("Invalid header name: {}", header_name)))?;
+
+    let oauth = match optional_arg_value(&matches, "TOKEN_URL", config.token_url)? {
+        Some(token_url) => Some(proxy::OAuthParams {
+            token_url,
+            client_id: optional_arg_value(&matches, "CLIENT_ID", config.client_id)?
+                .ok_or_else(|| err_msg("A client id is required with a token URL"))?,
+            client_secret: optional_arg_value(&matches, "CLIENT_SECRET", config.client_secret)?
+                .ok_or_else(|| err_msg("A client secret is required with a token URL"))?,
+            scope: optional_arg_value(&matches, "SCOPE", config.scope)?,
+        }),
+        None => None,
+    };
+
+    let sigv4 =
+        match optional_arg_value::<String>(&matches, "AUTH_MODE", config.auth_mode)?.as_deref() {
+            Some("sigv4") => Some(proxy::SigV4Params {
+                region: optional_arg_value(&matches, "AWS_REGION", config.aws_region)?
+                    .ok_or_else(|| err_msg("An AWS region is required to sign requests"))?,
+                service: optional_arg_value(&matches, "AWS_SERVICE", config.aws_service)?
+                    .ok_or_else(|| err_msg("An AWS service is required to sign requests"))?,
+            }),
+            Some("bearer") | None => None,
+            Some(mode) => return Err(err_msg(format!("Unknown auth mode: {}", mode))),
+        };
+
+    let token_file = arg_path(&matches, "TOKEN_FILE", config.token_file);
+    if oauth.is_some() && token_file.is_some() {
+        return Err(err_msg("A token file can't be used with a token URL"));
+    }
+
+    let command: Vec<String> = arg_values(&matches, "COMMAND", config.command)?;
+    if sigv4.is_some() && (!command.is_empty() || oauth.is_some() || token_file.is_some()) {
+        return Err(err_msg(
+            "Signed requests don't need a command, a token URL or a token file",
+        ));
+    }
+    if command.is_empty() && oauth.is_none() && token_file.is_none() && sigv4.is_none() {
+        return Err(err_msg("The command to run must not be empty"));
+    }
+
+    let refresh_ahead = optional_arg_value(&matches, "REFRESH_AHEAD", config.refresh_ahead)?;
+    if refresh_ahead.is_some_and(|fraction| fraction <= 0.0 || fraction >= 1.0) {
+        return Err(err_msg(
+            "The refresh ahead fraction must be between 0 and 1",
+        ));
+    }
+    let ttl_jitter = arg_value(&matches, "TTL_JITTER", config.ttl_jitter)?;
+    if !(0.0..1.0).contains(&ttl_jitter) {
+        return Err(err_msg("The ttl jitter must be at least 0 and less than 1"));
+    }
+    let background_refresh = arg_flag(&matches, "BACKGROUND_REFRESH", config.background_refresh);
+    if background_refresh && refresh_ahead.is_none() {
+        return Err(err_msg(
+            "Background refresh requires a refresh ahead fraction",
+        ));
+    }
+    let warm_cache = arg_flag(&matches, "WARM_CACHE", config.warm_cache);
+    if sigv4.is_some() && (warm_cache || background_refresh) {
+        return Err(err_msg(
+            "There are no tokens to obtain ahead of time when signing requests",
+        ));
+    }
+    let no_cache = arg_flag(&matches, "NO_CACHE", config.no_cache);
+    if no_cache && (warm_cache || refresh_ahead.is_some()) {
+        return Err(err_msg(
+            "Tokens can't be obtained ahead of time when they aren't cached",
+        ));
+    }
+
+    let listen_backlog = optional_arg_value(&matches, "LISTEN_BACKLOG", config.listen_backlog)?;
+    if listen_backlog.is_some_and(|backlog| backlog == 0 || backlog > 65535) {
+        return Err(err_msg("The listen backlog must be between 1 and 65535"));
+    }
+
+    let response_cache_max_entries = arg_value(
+        &matches,
+        "RESPONSE_CACHE_MAX_ENTRIES",
+        config.response_cache_max_entries,
+    )?;
+    if response_cache_max_entries == 0 {
+        return Err(err_msg(
+            "The maximum number of cached responses must be positive",
+        ));
+    }
+    let rate_limit = optional_arg_value(&matches, "RATE_LIMIT", config.rate_limit)?;
+    if rate_limit.is_some_and(|rate| rate <= 0.0 || !rate.is_finite()) {
+        return Err(err_msg("The rate limit must be a positive number"));
+    }
+    let rate_burst = optional_arg_value(&matches, "RATE_BURST", config.rate_burst)?;
+    if rate_burst == Some(0) {
+        return Err(err_msg("The rate burst must be positive"));
+    }
+    let max_concurrent_requests = optional_arg_value(
+        &matches,
+        "MAX_CONCURRENT_REQUESTS",
+        config.max_concurrent_requests,
+    )?;
+    if max_concurrent_requests == Some(0) {
+        return Err(err_msg(
+            "The maximum number of concurrent requests must be positive",
+        ));
+    }
+
+    let token_jsonpath = optional_arg_value(&matches, "TOKEN_JSONPATH", config.token_jsonpath)?;
+    if let Some(ref path) = token_jsonpath {
+        jsonpath_lib::Compiled::compile(path)
+            .map_err(|e| err_msg(format!("Invalid token JSONPath `{}`: {}", path, e)))?;
+    }
+
+    let token_regex = optional_arg_value(&matches, "TOKEN_REGEX", config.token_regex)?
+        .map(|s| Regex::new(&s))
+        .transpose()?;
+    if token_regex
+        .as_ref()
+        .is_some_and(|regex| regex.captures_len() < 2)
+    {
+        return Err(err_msg("The token regex must have a capture group"));
+    }
+
+    let transform_command =
+        optional_arg_value(&matches, "TRANSFORM_COMMAND", config.transform_command)?
+            .map(|s| shell_words::split(&s))
+            .transpose()?;
+    if transform_command.as_ref().is_some_and(Vec::is_empty) {
+        return Err(err_msg("The transform command must not be empty"));
+    }
+
+    let mut routes = arg_values::<String>(&matches, "ROUTE", config.route)?
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<_>, _>>()?;
+    let min_tls_version = tls_version(&matches, "MIN_TLS_VERSION", config.min_tls_version)?;
+    let max_tls_version = tls_version(&matches, "MAX_TLS_VERSION", config.max_tls_version)?;
+    if let (Some(min), Some(max)) = (min_tls_version, max_tls_version) {
+        if min > max {
+            return Err(err_msg(
+                "The minimum TLS version must not be newer than the maximum one",
+            ));
+        }
+    }
+
+    let resolve = arg_values::<String>(&matches, "RESOLVE", config.resolve)?
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<_, _>>()?;
+    let shell = arg_flag(&matches, "SHELL", config.shell);
+    attach_route_commands(
+        &mut routes,
+        arg_values(&matches, "ROUTE_COMMAND", config.route_command)?,
+        shell,
+    )?;
+    attach_route_timeouts(
+        &mut routes,
+        arg_values(&matches, "ROUTE_TIMEOUT", config.route_timeout)?,
+    )?;
+    attach_route_audiences(
+        &mut routes,
+        arg_values(&matches, "ROUTE_AUDIENCE", config.route_audience)?,
+    )?;
+
     Ok(proxy::ProxyParams {
-        target_url: matches
-            .value_of("TARGET_URL")
-            .ok_or_else(|| cmdline_parse_error("TARGET_URL"))?
-            .to_string(),
-        insecure_https: matches.is_present("INSECURE_HTTPS"),
-        local_host: matches
-            .value_of("LISTEN_HOST")
-            .ok_or_else(|| cmdline_parse_error("LISTEN_HOST"))?
-            .to_string(),
-        local_port: matches
-            .value_of("LISTEN_PORT")
-            .and_then(|s| s.parse::<u16>().ok())
-            .ok_or_else(|| cmdline_parse_error("LISTEN_PORT"))?,
-        cache_ttl_secs: matches
-            .value_of("CACHE_TTL")
-            .and_then(|s| s.parse::<u64>().ok())
-            .ok_or_else(|| cmdline_parse_error("CACHE_TTL"))?,
-        command: matches
-            .values_of("COMMAND")
-            .ok_or_else(|| cmdline_parse_error("COMMAND"))?
-            .map(String::from)
-            .collect(),
+        target_url: arg_value(&matches, "TARGET_URL", config.target_url)?,
+        routes,
+        strip_route_prefix: arg_flag(&matches, "STRIP_ROUTE_PREFIX", config.strip_route_prefix),
+        strip_prefix: optional_arg_value(&matches, "STRIP_PREFIX", config.strip_prefix)?,
+        add_prefix: optional_arg_value(&matches, "ADD_PREFIX", config.add_prefix)?,
+        require_strip_prefix: arg_flag(
+            &matches,
+            "REQUIRE_STRIP_PREFIX",
+            config.require_strip_prefix,
+        ),
+        host_header: arg_value(
+            &matches,
+            "HOST_HEADER",
+            config.host_header.as_deref().map(str::parse).transpose()?,
+        )?,
+        tls_cert: arg_path(&matches, "TLS_CERT", config.tls_cert),
+        tls_key: arg_path(&matches, "TLS_KEY", config.tls_key),
+        client_ca: arg_path(&matches, "CLIENT_CA", config.client_ca),
+        require_client_cert: arg_flag(&matches, "REQUIRE_CLIENT_CERT", config.require_client_cert),
+        shutdown_timeout_secs: arg_value(&matches, "SHUTDOWN_TIMEOUT", config.shutdown_timeout)?,
+        pid_file: arg_path(&matches, "PID_FILE", config.pid_file),
+        listen_backlog,
+        insecure_https: arg_flag(&matches, "INSECURE_HTTPS", config.insecure_https),
+        min_tls_version,
+        max_tls_version,
+        tls_sni: optional_arg_value(&matches, "TLS_SNI", config.tls_sni)?,
+        ca_file: arg_path(&matches, "CA_FILE", config.ca_file),
+        client_cert: arg_path(&matches, "CLIENT_CERT", config.client_cert),
+        client_cert_password: optional_arg_value::<String>(
+            &matches,
+            "CLIENT_CERT_PASSWORD",
+            config.client_cert_password,
+        )?
+        .map(proxy::Secret::from),
+        socks_proxy: match optional_arg_value(&matches, "SOCKS_PROXY", config.socks_proxy)? {
+            Some(addr) => Some(proxy::SocksProxy {
+                addr,
+                credentials: match (
+                    optional_arg_value(&matches, "SOCKS_USER", config.socks_user)?,
+                    optional_arg_value(&matches, "SOCKS_PASS", config.socks_pass)?,
+                ) {
+                    (Some(user), Some(pass)) => Some((user, pass)),
+                    (None, None) => None,
+                    _ => return Err(err_msg("Both a SOCKS user and password are required")),
+                },
+            }),
+            None => None,
+        },
+        use_system_proxy: arg_flag(&matches, "USE_SYSTEM_PROXY", config.use_system_proxy),
+        resolve,
+        pool_max_idle_per_host: optional_arg_value(
+            &matches,
+            "POOL_MAX_IDLE_PER_HOST",
+            config.pool_max_idle_per_host,
+        )?,
+        pool_idle_timeout_secs: optional_arg_value(
+            &matches,
+            "POOL_IDLE_TIMEOUT",
+            config.pool_idle_timeout,
+        )?,
+        upstream_http2: arg_flag(&matches, "UPSTREAM_HTTP2", config.upstream_http2),
+        tcp_nodelay: arg_flag(&matches, "TCP_NODELAY", config.tcp_nodelay),
+        tcp_keepalive_secs: optional_arg_value(&matches, "TCP_KEEPALIVE", config.tcp_keepalive)?,
+        connect_timeout_secs: arg_value(&matches, "CONNECT_TIMEOUT", config.connect_timeout)?,
+        happy_eyeballs_delay_ms: arg_value(
+            &matches,
+            "HAPPY_EYEBALLS_DELAY",
+            config.happy_eyeballs_delay,
+        )?,
+        source_address: optional_arg_value(&matches, "SOURCE_ADDRESS", config.source_address)?,
+        address_family,
+        listen_addr: match listen_unix {
+            _ if systemd_socket => proxy::ListenAddr::Systemd,
+            Some(path) => proxy::ListenAddr::Unix(path),
+            None => proxy::ListenAddr::Tcp {
+                host: arg_value(&matches, "LISTEN_HOST", config.listen_host)?,
+                port: arg_value(&matches, "LISTEN_PORT", config.listen_port)?,
+            },
+        },
+        cache_ttl_secs: arg_value(&matches, "CACHE_TTL", config.cache_ttl)?,
+        no_cache,
+        cache_responses: arg_flag(&matches, "CACHE_RESPONSES", config.cache_responses),
+        response_cache_ttl_secs: optional_arg_value(
+            &matches,
+            "RESPONSE_CACHE_TTL",
+            config.response_cache_ttl,
+        )?,
+        response_cache_error_ttl_secs: arg_value(
+            &matches,
+            "RESPONSE_CACHE_ERROR_TTL",
+            config.response_cache_error_ttl,
+        )?,
+        response_cache_max_entries,
+        response_cache_max_body_size: arg_value(
+            &matches,
+            "RESPONSE_CACHE_MAX_BODY_SIZE",
+            config.response_cache_max_body_size,
+        )?,
+        cache_max_entries: optional_arg_value(
+            &matches,
+            "CACHE_MAX_ENTRIES",
+            config.cache_max_entries,
+        )?,
+        ttl_jitter,
+        refresh_ahead,
+        serve_stale_for_secs: arg_value(&matches, "SERVE_STALE_FOR", config.serve_stale_for)?,
+        failure_cache_ttl_secs: arg_value(&matches, "FAILURE_CACHE_TTL", config.failure_cache_ttl)?,
+        warm_cache,
+        warm_fail_fast: arg_flag(&matches, "WARM_FAIL_FAST", config.warm_fail_fast),
+        background_refresh,
+        token_format: arg_value(
+            &matches,
+            "TOKEN_FORMAT",
+            config.token_format.as_deref().map(str::parse).transpose()?,
+        )?,
+        token_encoding: arg_value(
+            &matches,
+            "TOKEN_ENCODING",
+            config
+                .token_encoding
+                .as_deref()
+                .map(str::parse)
+                .transpose()?,
+        )?,
+        token_field: arg_value(&matches, "TOKEN_FIELD", config.token_field)?,
+        token_jsonpath,
+        token_regex,
+        expiry_field: arg_value(&matches, "EXPIRY_FIELD", config.expiry_field)?,
+        ttl_from_jwt: arg_flag(&matches, "TTL_FROM_JWT", config.ttl_from_jwt),
+        auth_scheme: arg_value(&matches, "AUTH_SCHEME", config.auth_scheme)?,
+        header_name,
+        auth_location: arg_value(
+            &matches,
+            "AUTH_LOCATION",
+            config
+                .auth_location
+                .as_deref()
+                .map(str::parse)
+                .transpose()?,
+        )?,
+        query_param_name: arg_value(&matches, "QUERY_PARAM_NAME", config.query_param_name)?,
+        cookie_name: arg_value(&matches, "COOKIE_NAME", config.cookie_name)?,
+        add_headers: parse_headers(arg_values(&matches, "ADD_HEADER", config.add_header)?)?,
+        strip_headers: parse_header_names(arg_values(
+            &matches,
+            "STRIP_HEADER",
+            config.strip_header,
+        )?)?,
+        user_agent: optional_arg_value::<String>(&matches, "USER_AGENT", config.user_agent)?
+            .map(|s| HeaderValue::from_str(&s))
+            .transpose()?,
+        append_user_agent: arg_flag(&matches, "APPEND_USER_AGENT", config.append_user_agent),
+        add_via: arg_flag(&matches, "ADD_VIA", config.add_via),
+        trust_forwarded: arg_flag(&matches, "TRUST_FORWARDED", config.trust_forwarded),
+        add_response_headers: parse_headers(arg_values(
+            &matches,
+            "ADD_RESPONSE_HEADER",
+            config.add_response_header,
+        )?)?,
+        response_header_mode: arg_value(
+            &matches,
+            "RESPONSE_HEADER_MODE",
+            config
+                .response_header_mode
+                .as_deref()
+                .map(str::parse)
+                .transpose()?,
+        )?,
+        command,
+        command_cwd: arg_path(&matches, "COMMAND_CWD", config.command_cwd),
+        skip_command_check: arg_flag(&matches, "SKIP_COMMAND_CHECK", config.skip_command_check),
+        command_env: parse_env_vars(arg_values(&matches, "COMMAND_ENV", config.command_env)?)?,
+        command_clear_env: arg_flag(&matches, "COMMAND_CLEAR_ENV", config.command_clear_env),
+        command_env_request: arg_flag(&matches, "COMMAND_ENV_REQUEST", config.command_env_request),
+        command_env_headers: parse_header_names(arg_values(
+            &matches,
+            "COMMAND_ENV_HEADER",
+            config.command_env_header,
+        )?)?,
+        audience_header: optional_arg_value::<String>(
+            &matches,
+            "AUDIENCE_FROM_HEADER",
+            config.audience_from_header,
+        )?
+        .map(|name| HeaderName::from_bytes(name.as_bytes()))
+        .transpose()?,
+        allowed_audiences: arg_values(&matches, "ALLOWED_AUDIENCE", config.allowed_audience)?,
+        log_command_stderr: arg_flag(&matches, "LOG_COMMAND_STDERR", config.log_command_stderr),
+        log_tokens_unsafe: arg_flag(&matches, "LOG_TOKENS_UNSAFE", config.log_tokens_unsafe),
+        shell,
+        oauth,
+        token_file,
+        watch_token_file: arg_flag(&matches, "WATCH_TOKEN_FILE", config.watch_token_file),
+        sigv4,
+        transform_command,
+        command_timeout_secs: arg_value(&matches, "COMMAND_TIMEOUT", config.command_timeout)?,
+        command_retries: arg_value(&matches, "COMMAND_RETRIES", config.command_retries)?,
+        command_retry_delay_ms: arg_value(
+            &matches,
+            "COMMAND_RETRY_DELAY",
+            config.command_retry_delay,
+        )?,
+        auth_failure_statuses: arg_values(
+            &matches,
+            "AUTH_FAILURE_STATUS",
+            config.auth_failure_status,
+        )?,
+        max_retry_body_size: arg_value(
+            &matches,
+            "MAX_RETRY_BODY_SIZE",
+            config.max_retry_body_size,
+        )?,
+        max_body_size: optional_arg_value(&matches, "MAX_BODY_SIZE", config.max_body_size)?,
+        max_header_size: optional_arg_value(&matches, "MAX_HEADER_SIZE", config.max_header_size)?,
+        max_retries: arg_value(&matches, "MAX_RETRIES", config.max_retries)?,
+        retry_base_delay_ms: arg_value(&matches, "RETRY_BASE_DELAY", config.retry_base_delay)?,
+        max_retry_wait_secs: arg_value(&matches, "MAX_RETRY_WAIT", config.max_retry_wait)?,
+        retry_all_methods: arg_flag(&matches, "RETRY_ALL_METHODS", config.retry_all_methods),
+        follow_redirects: arg_flag(&matches, "FOLLOW_REDIRECTS", config.follow_redirects),
+        max_redirects: arg_value(&matches, "MAX_REDIRECTS", config.max_redirects)?,
+        follow_cross_origin: arg_flag(&matches, "FOLLOW_CROSS_ORIGIN", config.follow_cross_origin),
+        upstream_timeout_secs: arg_value(&matches, "UPSTREAM_TIMEOUT", config.upstream_timeout)?,
+        method_timeouts: parse_method_timeouts(arg_values(
+            &matches,
+            "TIMEOUT_METHOD",
+            config.timeout_method,
+        )?)?,
+        allow_methods: parse_methods(arg_values(&matches, "ALLOW_METHOD", config.allow_method)?)?,
+        block_methods: parse_methods(arg_values(&matches, "BLOCK_METHOD", config.block_method)?)?,
+        allow_cidrs: parse_cidrs(arg_values(&matches, "ALLOW_CIDR", config.allow_cidr)?)?,
+        deny_cidrs: parse_cidrs(arg_values(&matches, "DENY_CIDR", config.deny_cidr)?)?,
+        stream_paths: arg_values(&matches, "STREAM_PATHS", config.stream_paths)?,
+        allow_paths: parse_path_patterns(arg_values(&matches, "ALLOW_PATH", config.allow_path)?)?,
+        block_paths: parse_path_patterns(arg_values(&matches, "BLOCK_PATH", config.block_path)?)?,
+        compress: arg_flag(&matches, "COMPRESS", config.compress),
+        compress_min_size: arg_value(&matches, "COMPRESS_MIN_SIZE", config.compress_min_size)?,
+        request_id_header: Some(arg_value::<String>(
+            &matches,
+            "REQUEST_ID_HEADER",
+            config.request_id_header,
+        )?)
+        .filter(|name| !name.is_empty())
+        .map(|name| HeaderName::from_bytes(name.as_bytes()))
+        .transpose()?,
+        cache_status_header: optional_arg_value::<String>(
+            &matches,
+            "CACHE_STATUS_HEADER",
+            config.cache_status_header,
+        )?
+        .map(|name| HeaderName::from_bytes(name.as_bytes()))
+        .transpose()?,
+        health_path: arg_value(&matches, "HEALTH_PATH", config.health_path)?,
+        metrics_path: arg_value(&matches, "METRICS_PATH", config.metrics_path)?,
+        debug_echo_path: optional_arg_value(&matches, "DEBUG_ECHO_PATH", config.debug_echo_path)?,
+        admin_token: optional_arg_value::<String>(&matches, "ADMIN_TOKEN", config.admin_token)?
+            .map(proxy::Secret::from),
+        maintenance: arg_flag(&matches, "MAINTENANCE", config.maintenance),
+        access_log: arg_flag(&matches, "ACCESS_LOG", config.access_log),
+        access_log_format: arg_value(
+            &matches,
+            "ACCESS_LOG_FORMAT",
+            config
+                .access_log_format
+                .as_deref()
+                .map(str::parse)
+                .transpose()?,
+        )?,
+        error_format: arg_value(
+            &matches,
+            "ERROR_FORMAT",
+            config.error_format.as_deref().map(str::parse).transpose()?,
+        )?,
+        error_detail: arg_flag(&matches, "ERROR_DETAIL", config.error_detail),
+        rate_limit,
+        rate_burst,
+        max_concurrent_requests,
+        overflow: arg_value(
+            &matches,
+            "OVERFLOW",
+            config.overflow.as_deref().map(str::parse).transpose()?,
+        )?,
+        max_queued_requests: optional_arg_value(
+            &matches,
+            "MAX_QUEUED_REQUESTS",
+            config.max_queued_requests,
+        )?,
+        breaker_threshold: optional_arg_value(
+            &matches,
+            "BREAKER_THRESHOLD",
+            config.breaker_threshold,
+        )?,
+        breaker_cooldown_secs: arg_value(&matches, "BREAKER_COOLDOWN", config.breaker_cooldown)?,
+        otlp_endpoint: optional_arg_value(&matches, "OTLP_ENDPOINT", config.otlp_endpoint)?,
     })
 }
 
-pub async fn cli_future() -> i32 {
-    let app = cmdline::build_clap_app();
-    let matches = app.get_matches();
+fn log_error(error: &Error) {
+    log::error!("{}", error);
+    for underlying_error in error.iter_causes() {
+        log::error!("Caused by: {}", underlying_error);
+    }
+}
 
-    let result = match get_proxy_params(matches) {
-        Ok(params) => proxy::run_proxy(proxy::ProxyContext::new(params)).await,
+async fn serve(params: proxy::ProxyParams) -> Result<(), proxy::ProxyError> {
+    let ephemeral_port = matches!(params.listen_addr, proxy::ListenAddr::Tcp { port: 0, .. });
+    let proxy = proxy::Proxy::bind(params).await?;
+    // The port picked by the OS is printed so that scripts starting the proxy can find it
+    if let (true, Some(addr)) = (ephemeral_port, proxy.local_addr()) {
+        println!("{}", addr);
+    }
+    proxy.run().await
+}
+
+async fn cli_future(matches: ArgMatches<'_>, config: ConfigFile) -> i32 {
+    let result = match get_proxy_params(matches, config) {
+        Ok(params) => serve(params).await.map_err(Error::from),
         Err(e) => Err(e),
     };
 
     match result {
         Ok(()) => 0,
         Err(error) => {
-            log::error!("{}", error);
-            for underlying_error in error.iter_causes() {
-                log::error!("Caused by: {}", underlying_error);
-            }
+            log_error(&error);
             1
         }
     }
@@ -65,5 +983,439 @@ pub async fn cli_future() -> i32 {
 pub fn run() -> i32 {
     env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    Runtime::new().unwrap().block_on(cli_future())
+    let args: Vec<OsString> = env::args_os().collect();
+    let app = cmdline::build_clap_app();
+    let matches = app.get_matches_from(&args);
+
+    // The runtime is set up before anything else, so its arguments are read separately
+    let runtime = load_config(&args, &matches)
+        .and_then(|config| build_runtime(&matches, &config).map(|runtime| (runtime, config)));
+    match runtime {
+        Ok((mut runtime, config)) => runtime.block_on(cli_future(matches, config)),
+        Err(error) => {
+            log_error(&error);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use super::*;
+
+    fn parse(args: Vec<OsString>) -> Result<(Vec<OsString>, ArgMatches<'static>), Error> {
+        let args: Vec<OsString> = iter::once(OsString::from("authproxy"))
+            .chain(args)
+            .collect();
+        let matches = cmdline::build_clap_app()
+            .get_matches_from_safe(&args)
+            .map_err(|e| err_msg(e.message))?;
+        Ok((args, matches))
+    }
+
+    fn proxy_params_with_config(args: &[&str], config: &str) -> Result<proxy::ProxyParams, Error> {
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), config)?;
+        let args = vec![OsString::from("--config"), file.path().into()]
+            .into_iter()
+            .chain(args.iter().map(OsString::from))
+            .collect();
+        let (args, matches) = parse(args)?;
+        let config = load_config(&args, &matches)?;
+        get_proxy_params(matches, config)
+    }
+
+    fn proxy_params(args: &[&str]) -> Result<proxy::ProxyParams, Error> {
+        let (args, matches) = parse(args.iter().map(OsString::from).collect())?;
+        let config = load_config(&args, &matches)?;
+        get_proxy_params(matches, config)
+    }
+
+    fn set_env(vars: &[(&'static str, &'static str)]) {
+        TEST_ENV.with(|env| *env.borrow_mut() = vars.to_vec());
+    }
+
+    fn listen_port(params: &proxy::ProxyParams) -> u16 {
+        match params.listen_addr {
+            proxy::ListenAddr::Tcp { port, .. } => port,
+            ref listen_addr => panic!("Listening on {:?}", listen_addr),
+        }
+    }
+
+    #[test]
+    fn parses_the_header_name() {
+        let params = proxy_params(&["--header-name", "X-API-Key", "http://target", "cmd"]).unwrap();
+        assert_eq!(params.header_name, "X-API-Key");
+    }
+
+    #[test]
+    fn rejects_an_invalid_header_name() {
+        let err =
+            proxy_params(&["--header-name", "X API Key", "http://target", "cmd"]).unwrap_err();
+        assert!(err.to_string().contains("Invalid header name"));
+    }
+
+    #[test]
+    fn listens_on_a_unix_socket() {
+        let params =
+            proxy_params(&["--listen-unix", "/tmp/proxy.sock", "http://target", "cmd"]).unwrap();
+        match params.listen_addr {
+            proxy::ListenAddr::Unix(path) => assert_eq!(path, PathBuf::from("/tmp/proxy.sock")),
+            listen_addr => panic!("Listening on {:?}", listen_addr),
+        }
+    }
+
+    #[test]
+    fn rejects_a_unix_socket_with_a_tcp_port() {
+        let args = [
+            "--listen-unix",
+            "/tmp/proxy.sock",
+            "--listen-port",
+            "8080",
+            "http://target",
+            "cmd",
+        ];
+        assert!(proxy_params(&args).is_err());
+    }
+
+    const SAMPLE_CONFIG: &str = r#"
+        target-url = "http://target"
+        command = ["get-token", "--scope", "api"]
+        route = ["/billing=http://billing"]
+        strip-route-prefix = true
+        listen-port = 9000
+        header-name = "X-API-Key"
+        add-header = ["X-Tenant-Id: 1", "X-Tenant-Id: 2"]
+        cache-ttl = 30
+        shell = true
+    "#;
+
+    #[test]
+    fn loads_a_sample_config() {
+        let params = proxy_params_with_config(&[], SAMPLE_CONFIG).unwrap();
+        assert_eq!(params.target_url, "http://target");
+        assert_eq!(params.command, vec!["get-token", "--scope", "api"]);
+        assert_eq!(params.routes.len(), 1);
+        assert_eq!(params.routes[0].path_prefix, "/billing");
+        assert_eq!(params.routes[0].target_url, "http://billing");
+        assert!(params.strip_route_prefix);
+        assert_eq!(listen_port(&params), 9000);
+        assert_eq!(params.header_name, "X-API-Key");
+        let tenants: Vec<_> = params.add_headers.iter().map(|(_, value)| value).collect();
+        assert_eq!(tenants, vec!["1", "2"]);
+        assert_eq!(params.cache_ttl_secs, 30);
+        assert!(params.shell);
+        // Not in the config
+        assert!(!params.no_cache);
+        assert_eq!(params.auth_scheme, "Bearer");
+    }
+
+    #[test]
+    fn command_line_takes_precedence_over_the_config() {
+        let args = ["--listen-port", "9001", "--cache-ttl", "60", "http://other"];
+        let params = proxy_params_with_config(&args, SAMPLE_CONFIG).unwrap();
+        assert_eq!(listen_port(&params), 9001);
+        assert_eq!(params.cache_ttl_secs, 60);
+        assert_eq!(params.target_url, "http://other");
+        // Not on the command line
+        assert_eq!(params.header_name, "X-API-Key");
+        assert_eq!(params.command, vec!["get-token", "--scope", "api"]);
+    }
+
+    #[test]
+    fn turns_off_a_config_flag() {
+        let params = proxy_params_with_config(&["--no-shell"], SAMPLE_CONFIG).unwrap();
+        assert!(!params.shell);
+        assert!(params.strip_route_prefix);
+
+        let params = proxy_params_with_config(&["--no-shell", "--shell"], SAMPLE_CONFIG).unwrap();
+        assert!(params.shell);
+        let params = proxy_params_with_config(&["--shell", "--no-shell"], SAMPLE_CONFIG).unwrap();
+        assert!(!params.shell);
+    }
+
+    #[test]
+    fn validates_config_values() {
+        let config = format!("{}\nstrip-prefix = \"api\"", SAMPLE_CONFIG);
+        let err = proxy_params_with_config(&[], &config).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid config file"));
+        let cause = err.iter_causes().next().unwrap().to_string();
+        assert_eq!(
+            cause,
+            "Invalid value for '--strip-prefix <STRIP_PREFIX>': Path prefix must start with /",
+        );
+
+        // Replaced on the command line
+        let params = proxy_params_with_config(&["--strip-prefix", "/api"], &config).unwrap();
+        assert_eq!(params.strip_prefix.as_deref(), Some("/api"));
+    }
+
+    #[test]
+    fn listens_on_the_command_line_port_instead_of_the_config_socket() {
+        let config = r#"
+            target-url = "http://target"
+            command = ["cmd"]
+            listen-unix = "/tmp/proxy.sock"
+        "#;
+        let params = proxy_params_with_config(&["--listen-port", "9002"], config).unwrap();
+        assert_eq!(listen_port(&params), 9002);
+    }
+
+    #[test]
+    fn rejects_an_invalid_static_header() {
+        for header in &["X-Tenant-Id", "X Tenant: 1"] {
+            assert!(proxy_params(&["--add-header", header, "http://target", "cmd"]).is_err());
+        }
+    }
+
+    #[test]
+    fn parses_the_allowed_audiences() {
+        let args = [
+            "--audience-from-header",
+            "X-Audience",
+            "--allowed-audience",
+            "api://billing",
+            "--allowed-audience",
+            "api://orders",
+            "http://target",
+            "cmd",
+        ];
+        let params = proxy_params(&args).unwrap();
+        assert_eq!(
+            params.allowed_audiences,
+            vec!["api://billing", "api://orders"]
+        );
+    }
+
+    #[test]
+    fn rejects_audiences_starting_with_a_dash() {
+        for args in &[
+            ["--allowed-audience=-o", "http://target", "cmd"],
+            ["--route-audience=/api=-o", "http://target", "cmd"],
+        ] {
+            let err = proxy_params(args).unwrap_err();
+            assert!(
+                err.to_string().contains("Audiences can't start with -"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_source_address_with_a_socks_proxy() {
+        let args = [
+            "--source-address",
+            "127.0.0.2",
+            "--socks-proxy",
+            "127.0.0.1:1080",
+            "http://target",
+            "cmd",
+        ];
+        let err = proxy_params(&args).unwrap_err();
+        assert!(err.to_string().contains("cannot be used with"), "{}", err);
+    }
+
+    #[test]
+    fn turns_the_token_cache_off() {
+        let params = proxy_params(&["--no-cache", "http://target", "cmd"]).unwrap();
+        assert!(params.no_cache);
+        for flag in &["--warm-cache", "--refresh-ahead=0.5"] {
+            let err = proxy_params(&["--no-cache", flag, "http://target", "cmd"]).unwrap_err();
+            assert!(err.to_string().contains("cannot be used with"), "{}", err);
+        }
+        // Nor can they be combined in the config file
+        let config = "no-cache = true\nwarm-cache = true\n";
+        let err = proxy_params_with_config(&["http://target", "cmd"], config).unwrap_err();
+        let cause = err.iter_causes().next().unwrap().to_string();
+        assert!(cause.contains("cannot be used with"), "{}", cause);
+    }
+
+    #[test]
+    fn runs_the_proxy_on_the_chosen_runtime() {
+        for runtime in &["current-thread", "multi-thread"] {
+            let args = [
+                "--runtime",
+                runtime,
+                "--worker-threads",
+                "2",
+                "--listen-unix",
+                "/nonexistent/proxy.sock",
+                "http://target",
+                "true",
+            ];
+            let (_, matches) = parse(args.iter().map(OsString::from).collect()).unwrap();
+            let mut runtime = build_runtime(&matches, &ConfigFile::default()).unwrap();
+            // The socket can't be bound, so the proxy stops right away
+            let exit_code = runtime.block_on(cli_future(matches, ConfigFile::default()));
+            assert_eq!(exit_code, 1);
+        }
+    }
+
+    #[test]
+    fn reads_the_arguments_from_the_environment() {
+        set_env(&[
+            ("AUTHPROXY_TARGET_URL", "http://target"),
+            ("AUTHPROXY_COMMAND", "get-token\n--scope\napi"),
+            ("AUTHPROXY_LISTEN_PORT", "9000"),
+            ("AUTHPROXY_ADD_HEADER", "X-Tenant-Id: 1\nX-Tenant-Id: 2"),
+            ("AUTHPROXY_NO_CACHE", "1"),
+            ("AUTHPROXY_WARM_CACHE", "false"),
+        ]);
+        let params = proxy_params(&[]).unwrap();
+        assert_eq!(params.target_url, "http://target");
+        assert_eq!(params.command, vec!["get-token", "--scope", "api"]);
+        assert_eq!(listen_port(&params), 9000);
+        let tenants: Vec<_> = params.add_headers.iter().map(|(_, value)| value).collect();
+        assert_eq!(tenants, vec!["1", "2"]);
+        assert!(params.no_cache);
+        assert!(!params.warm_cache);
+    }
+
+    #[test]
+    fn reads_a_shell_command_from_the_environment() {
+        set_env(&[
+            ("AUTHPROXY_SHELL", "true"),
+            ("AUTHPROXY_COMMAND", "get-token --scope api | jq -r .token"),
+        ]);
+        let params = proxy_params(&["http://target"]).unwrap();
+        assert!(params.shell);
+        assert_eq!(params.command, vec!["get-token --scope api | jq -r .token"]);
+    }
+
+    #[test]
+    fn command_line_takes_precedence_over_the_environment() {
+        set_env(&[
+            ("AUTHPROXY_LISTEN_PORT", "oops"),
+            ("AUTHPROXY_HEADER_NAME", "X-API-Key"),
+            ("AUTHPROXY_NO_CACHE", "1"),
+        ]);
+        let args = [
+            "--listen-port",
+            "9001",
+            "--no-no-cache",
+            "--warm-cache",
+            "http://target",
+            "cmd",
+        ];
+        let params = proxy_params(&args).unwrap();
+        assert_eq!(listen_port(&params), 9001);
+        assert_eq!(params.header_name, "X-API-Key");
+        assert!(!params.no_cache);
+    }
+
+    #[test]
+    fn environment_takes_precedence_over_the_config() {
+        set_env(&[
+            ("AUTHPROXY_LISTEN_PORT", "9000"),
+            ("AUTHPROXY_LISTEN_UNIX", "/tmp/env.sock"),
+        ]);
+        let config = "listen-port = 7000\ncache-ttl = 30\n";
+        let params = proxy_params_with_config(&["http://target", "cmd"], config).unwrap();
+        assert_eq!(listen_port(&params), 9000);
+        assert_eq!(params.cache_ttl_secs, 30);
+    }
+
+    #[test]
+    fn validates_environment_values() {
+        let cases = [
+            (("AUTHPROXY_LISTEN_PORT", "oops"), "Invalid port"),
+            (
+                ("AUTHPROXY_ALLOWED_AUDIENCE", "-o"),
+                "Audiences can't start with -",
+            ),
+            (("AUTHPROXY_WARM_CACHE", "1"), "cannot be used with"),
+        ];
+        for &(var, message) in &cases {
+            set_env(&[var, ("AUTHPROXY_NO_CACHE", "1")]);
+            let err = proxy_params(&["http://target", "cmd"]).unwrap_err();
+            assert_eq!(err.to_string(), "Invalid AUTHPROXY_* environment variables");
+            let cause = err.iter_causes().next().unwrap().to_string();
+            assert!(cause.contains(message), "{}", cause);
+        }
+    }
+
+    #[test]
+    fn shows_the_environment_variables_in_the_help() {
+        let mut help = Vec::new();
+        cmdline::build_clap_app().write_help(&mut help).unwrap();
+        let help = String::from_utf8(help).unwrap();
+        for var in &[
+            "AUTHPROXY_TARGET_URL",
+            "AUTHPROXY_LISTEN_PORT",
+            "AUTHPROXY_NO_CACHE",
+        ] {
+            assert!(help.contains(&format!("[env: {}]", var)), "{}", var);
+        }
+    }
+
+    #[test]
+    fn parses_the_path_patterns() {
+        let params = proxy_params(&[
+            "--allow-path",
+            "/api",
+            "--allow-path=~^/v[0-9]+/",
+            "--block-path",
+            "/api/internal",
+            "http://target",
+            "cmd",
+        ])
+        .unwrap();
+        assert_eq!(params.allow_paths.len(), 2);
+        assert!(matches!(
+            params.allow_paths[1],
+            proxy::PathPattern::Regex(_)
+        ));
+        assert_eq!(params.block_paths.len(), 1);
+
+        let config = "allow-path = [\"/api\"]\nblock-path = [\"~\\\\.git\"]\n";
+        let params = proxy_params_with_config(&["http://target", "cmd"], config).unwrap();
+        assert_eq!(params.allow_paths.len(), 1);
+        assert!(matches!(
+            params.block_paths[0],
+            proxy::PathPattern::Regex(_)
+        ));
+
+        for pattern in &["api", "~("] {
+            let err = proxy_params(&["--block-path", pattern, "http://target", "cmd"]).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("Path pattern must start with / or ~")
+                    || err.to_string().contains("Invalid path regex"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn parses_the_listen_backlog() {
+        let params = proxy_params(&["--listen-backlog", "1024", "http://target", "cmd"]).unwrap();
+        assert_eq!(params.listen_backlog, Some(1024));
+        assert_eq!(
+            proxy_params(&["http://target", "cmd"])
+                .unwrap()
+                .listen_backlog,
+            None
+        );
+        for backlog in &["0", "65536", "many"] {
+            let err =
+                proxy_params(&["--listen-backlog", backlog, "http://target", "cmd"]).unwrap_err();
+            assert!(
+                err.to_string().contains("Invalid listen backlog"),
+                "{}",
+                err
+            );
+        }
+        let args = [
+            "--listen-backlog",
+            "1024",
+            "--listen-unix",
+            "/tmp/proxy.sock",
+        ];
+        let err = proxy_params(&[&args[..], &["http://target", "cmd"]].concat()).unwrap_err();
+        assert!(err.to_string().contains("cannot be used with"), "{}", err);
+    }
 }
@@ -0,0 +1,53 @@
+use std::str::FromStr;
+
+use failure::{err_msg, Error, ResultExt};
+use tokio::runtime::{Builder, Runtime};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuntimeFlavor {
+    CurrentThread,
+    MultiThread,
+}
+
+impl FromStr for RuntimeFlavor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "current-thread" => Ok(RuntimeFlavor::CurrentThread),
+            "multi-thread" => Ok(RuntimeFlavor::MultiThread),
+            _ => Err(err_msg(format!("Unknown runtime: {}", s))),
+        }
+    }
+}
+
+// A worker_threads of 0 leaves it up to tokio, which uses one per CPU
+pub fn build_runtime(flavor: RuntimeFlavor, worker_threads: usize) -> Result<Runtime, Error> {
+    let mut builder = Builder::new();
+    match flavor {
+        RuntimeFlavor::CurrentThread => {
+            if worker_threads > 0 {
+                log::warn!("The number of worker threads is ignored by the current-thread runtime");
+            }
+            log::info!("Using a current-thread runtime");
+            builder.basic_scheduler();
+        }
+        RuntimeFlavor::MultiThread => {
+            builder.threaded_scheduler();
+            if worker_threads > 0 {
+                log::info!(
+                    "Using a multi-thread runtime with {} worker threads",
+                    worker_threads
+                );
+                builder.core_threads(worker_threads);
+            } else {
+                log::info!("Using a multi-thread runtime with a worker thread per CPU");
+            }
+        }
+    }
+
+    Ok(builder
+        .enable_all()
+        .build()
+        .context("Failed to start the runtime")?)
+}
@@ -1,4 +1,4 @@
 pub mod cli;
-mod proxy;
+pub mod proxy;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
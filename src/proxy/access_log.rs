@@ -0,0 +1,246 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use failure::{err_msg, Error};
+use http::header::{CONTENT_LENGTH, REFERER, USER_AGENT};
+use http::{HeaderMap, Method, Request, Response, StatusCode, Version};
+use serde_json::json;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    Combined,
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(err_msg(format!("Unknown access log format: {}", s))),
+        }
+    }
+}
+
+fn header_string(headers: &HeaderMap, name: impl http::header::AsHeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// In the common log format, like [10/Oct/2000:13:55:36 +0000], always in UTC
+fn clf_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Converts the days since the epoch to a civil date, counting eras of 400 years from March
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "[{:02}/{}/{}:{:02}:{:02}:{:02} +0000]",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
+
+pub struct AccessLogEntry {
+    started_at: Instant,
+    received_at: SystemTime,
+    // None for clients connected over a unix socket
+    peer_addr: Option<SocketAddr>,
+    method: Method,
+    path: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+impl AccessLogEntry {
+    pub fn new<B>(
+        req: &Request<B>,
+        peer_addr: Option<SocketAddr>,
+        request_id: Option<String>,
+    ) -> Self {
+        AccessLogEntry {
+            started_at: Instant::now(),
+            received_at: SystemTime::now(),
+            peer_addr,
+            method: req.method().clone(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.uri().to_string(), ToString::to_string),
+            version: req.version(),
+            referer: header_string(req.headers(), REFERER),
+            user_agent: header_string(req.headers(), USER_AGENT),
+            request_id,
+        }
+    }
+
+    pub fn log<B>(self, format: AccessLogFormat, response: Option<&Response<B>>) {
+        log::info!(target: "authproxy::access", "{}", self.line(format, response));
+    }
+
+    fn line<B>(&self, format: AccessLogFormat, response: Option<&Response<B>>) -> String {
+        let duration = self.started_at.elapsed().as_secs_f64();
+        let status = response.map(Response::status);
+        let size = response
+            .and_then(|response| header_string(response.headers(), CONTENT_LENGTH))
+            .and_then(|size| size.parse::<u64>().ok());
+
+        let client = self
+            .peer_addr
+            .map_or_else(|| String::from("-"), |addr| addr.ip().to_string());
+        match format {
+            // The remote logname and user are never known
+            AccessLogFormat::Combined => format!(
+                "{} - - {} \"{} {} {:?}\" {} {} \"{}\" \"{}\" {:.3} {}",
+                client,
+                clf_timestamp(self.received_at),
+                self.method,
+                self.path,
+                self.version,
+                status
+                    .as_ref()
+                    .map_or_else(|| String::from("-"), |status| status.as_str().to_string()),
+                size.map_or_else(|| String::from("-"), |size| size.to_string()),
+                self.referer.as_deref().unwrap_or("-"),
+                self.user_agent.as_deref().unwrap_or("-"),
+                duration,
+                self.request_id.as_deref().unwrap_or("-"),
+            ),
+            AccessLogFormat::Json => json!({
+                "client": self.peer_addr.map(|addr| addr.ip().to_string()),
+                "method": self.method.as_str(),
+                "path": self.path,
+                "version": format!("{:?}", self.version),
+                "status": status.as_ref().map(StatusCode::as_u16),
+                "size": size,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "duration_secs": duration,
+                "request_id": self.request_id,
+            })
+            .to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::Body;
+
+    use super::*;
+
+    fn entry() -> AccessLogEntry {
+        let req = Request::get("/a?b=1")
+            .header(USER_AGENT, "curl/7.68.0")
+            .body(())
+            .unwrap();
+        let mut entry = AccessLogEntry::new(
+            &req,
+            "127.0.0.1:5000".parse().ok(),
+            Some(String::from("req-1")),
+        );
+        entry.received_at = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        entry
+    }
+
+    fn response() -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_LENGTH, "2")
+            .body(Body::from("ok"))
+            .unwrap()
+    }
+
+    #[test]
+    fn formats_timestamps_like_the_common_log_format() {
+        assert_eq!(
+            clf_timestamp(UNIX_EPOCH + Duration::from_secs(971_186_136)),
+            "[10/Oct/2000:13:55:36 +0000]"
+        );
+        assert_eq!(
+            clf_timestamp(UNIX_EPOCH + Duration::from_secs(1_709_164_800)),
+            "[29/Feb/2024:00:00:00 +0000]"
+        );
+        assert_eq!(clf_timestamp(UNIX_EPOCH), "[01/Jan/1970:00:00:00 +0000]");
+    }
+
+    #[test]
+    fn logs_combined_lines() {
+        let line = entry().line(AccessLogFormat::Combined, Some(&response()));
+        let expected = concat!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a?b=1 HTTP/1.1\" 200 2",
+            " \"-\" \"curl/7.68.0\" ",
+        );
+        assert!(line.starts_with(expected), "{}", line);
+        assert!(line.ends_with(" req-1"), "{}", line);
+    }
+
+    #[test]
+    fn logs_dashes_for_what_is_unknown() {
+        let req = Request::get("/").body(()).unwrap();
+        let line =
+            AccessLogEntry::new(&req, None, None).line::<Body>(AccessLogFormat::Combined, None);
+        assert!(line.starts_with("- - - ["), "{}", line);
+        assert!(
+            line.contains("\"GET / HTTP/1.1\" - - \"-\" \"-\" "),
+            "{}",
+            line
+        );
+        assert!(line.ends_with(" -"), "{}", line);
+    }
+
+    #[test]
+    fn logs_json_objects() {
+        let line = entry().line(AccessLogFormat::Json, Some(&response()));
+        let mut logged: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(logged["duration_secs"].is_f64());
+        logged.as_object_mut().unwrap().remove("duration_secs");
+        assert_eq!(
+            logged,
+            json!({
+                "client": "127.0.0.1",
+                "method": "GET",
+                "path": "/a?b=1",
+                "version": "HTTP/1.1",
+                "status": 200,
+                "size": 2,
+                "referer": null,
+                "user_agent": "curl/7.68.0",
+                "request_id": "req-1",
+            })
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+use failure::Error;
+use futures::stream::{self, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Response, StatusCode};
+use tokio::time::{timeout_at, Instant};
+
+#[derive(Debug)]
+pub struct BodyTooLarge;
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request body is too large")
+    }
+}
+
+impl StdError for BodyTooLarge {}
+
+#[derive(Debug)]
+pub struct BodyTimedOut;
+
+impl fmt::Display for BodyTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Response body didn't arrive in time")
+    }
+}
+
+impl StdError for BodyTimedOut {}
+
+// Makes the body fail once more than max_size bytes have been streamed through it
+pub fn limit_body(body: Body, max_size: u64) -> Body {
+    let mut size = 0;
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            Err(Box::new(BodyTooLarge) as Box<dyn StdError + Send + Sync>)
+        } else {
+            Ok(chunk)
+        }
+    }))
+}
+
+// Reads the body whole, failing once more than max_size bytes have arrived. A buffered body
+// can't be sent with trailers, so they're dropped, which is logged.
+pub async fn read_body(mut body: Body, max_size: u64) -> Result<Bytes, Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() as u64 > max_size {
+            return Err(BodyTooLarge.into());
+        }
+    }
+    if body.trailers().await?.is_some() {
+        log::warn!("Dropping the trailers of a request body that had to be buffered");
+    }
+
+    Ok(Bytes::from(bytes))
+}
+
+// Makes the body fail if it hasn't been streamed through entirely by the deadline
+pub fn deadline_body(body: Body, deadline: Instant) -> Body {
+    Body::wrap_stream(stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match timeout_at(deadline, body.next()).await {
+            Ok(Some(chunk)) => Some((
+                chunk.map_err(|e| Box::new(e) as Box<dyn StdError + Send + Sync>),
+                Some(body),
+            )),
+            Ok(None) => None,
+            Err(_) => {
+                log::warn!("Target didn't finish sending the response body in time");
+                Some((Err(Box::new(BodyTimedOut) as _), None))
+            }
+        }
+    }))
+}
+
+// Whether the error or one of the causes of the hyper error in its chain is a T
+fn is_caused_by<T: StdError + Send + Sync + 'static>(err: &Error) -> bool {
+    if err.downcast_ref::<T>().is_some() {
+        return true;
+    }
+    let mut source = err
+        .iter_chain()
+        .find_map(|cause| cause.downcast_ref::<hyper::Error>())
+        .map(|err| err as &(dyn StdError + 'static));
+    while let Some(err) = source {
+        if err.is::<T>() {
+            return true;
+        }
+        source = err.source();
+    }
+
+    false
+}
+
+// Whether reading or sending the request failed because its body went over the limit
+pub fn is_body_too_large(err: &Error) -> bool {
+    is_caused_by::<BodyTooLarge>(err)
+}
+
+// Whether reading the response failed because its body didn't arrive before the deadline
+pub fn is_body_timed_out(err: &Error) -> bool {
+    is_caused_by::<BodyTimedOut>(err)
+}
+
+pub fn too_large_response() -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(Body::from("Payload too large"))?)
+}
@@ -0,0 +1,199 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // A trial request is on its way, the others are still turned away
+    HalfOpen,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transition {
+    Opened,
+    Closed,
+}
+
+impl Transition {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Transition::Opened => "open",
+            Transition::Closed => "closed",
+        }
+    }
+}
+
+// Stops sending requests to a target after it fails threshold times in a row,
+// until a trial request sent after the cooldown succeeds
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+// Lets a request through to the target, what came of it has to be recorded
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+    recorded: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    // Returns for how much longer the breaker stays open if the request is turned away
+    pub fn acquire(&self) -> Result<Permit<'_>, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let trial = match *state {
+            State::Closed { .. } => false,
+            State::Open { until } => {
+                let now = Instant::now();
+                if now < until {
+                    return Err(until - now);
+                }
+                log::info!("Letting a trial request through to the failing target");
+                *state = State::HalfOpen;
+                true
+            }
+            State::HalfOpen => return Err(Duration::from_secs(0)),
+        };
+
+        Ok(Permit {
+            breaker: self,
+            trial,
+            recorded: false,
+        })
+    }
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, success: bool) -> Option<Transition> {
+        self.recorded = true;
+        let breaker = self.breaker;
+        let mut state = breaker.state.lock().unwrap();
+
+        match (*state, success) {
+            // Only the trial request decides whether the breaker closes again, the others
+            // were let through before it opened
+            (State::HalfOpen, _) if !self.trial => None,
+            (State::HalfOpen, true) => {
+                log::info!("The target recovered, closing the circuit breaker");
+                *state = State::Closed { failures: 0 };
+                Some(Transition::Closed)
+            }
+            (State::HalfOpen, false) => {
+                log::warn!(
+                    "The trial request to the target failed, keeping the circuit breaker open"
+                );
+                *state = State::Open {
+                    until: Instant::now() + breaker.cooldown,
+                };
+                Some(Transition::Opened)
+            }
+            (State::Closed { .. }, true) => {
+                *state = State::Closed { failures: 0 };
+                None
+            }
+            (State::Closed { failures }, false) if failures + 1 >= breaker.threshold => {
+                log::warn!(
+                    "The target failed {} times in a row, opening the circuit breaker for {} seconds",
+                    failures + 1,
+                    breaker.cooldown.as_secs()
+                );
+                *state = State::Open {
+                    until: Instant::now() + breaker.cooldown,
+                };
+                Some(Transition::Opened)
+            }
+            (State::Closed { failures }, false) => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+                None
+            }
+            // Requests let through before the breaker opened don't change anything anymore
+            (State::Open { .. }, _) => None,
+        }
+    }
+}
+
+// A trial request that didn't get to the target lets the next request try instead
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial && !self.recorded {
+            let mut state = self.breaker.state.lock().unwrap();
+            if *state == State::HalfOpen {
+                *state = State::Open {
+                    until: Instant::now(),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(0));
+        let transition = breaker.acquire().ok().unwrap().record(false);
+        assert_eq!(transition, Some(Transition::Opened));
+        breaker
+    }
+
+    #[test]
+    fn lets_one_trial_request_through_after_the_cooldown() {
+        let breaker = open_breaker();
+        let trial = breaker.acquire().ok().unwrap();
+        assert!(breaker.acquire().is_err());
+        assert_eq!(trial.record(true), Some(Transition::Closed));
+        assert!(breaker.acquire().is_ok());
+    }
+
+    #[test]
+    fn lets_another_trial_through_when_one_is_dropped() {
+        let breaker = open_breaker();
+        drop(breaker.acquire().ok().unwrap());
+        let trial = breaker.acquire().ok().unwrap();
+        assert_eq!(trial.record(true), Some(Transition::Closed));
+    }
+
+    #[test]
+    fn reopens_when_the_trial_fails() {
+        let breaker = open_breaker();
+        let trial = breaker.acquire().ok().unwrap();
+        assert_eq!(trial.record(false), Some(Transition::Opened));
+    }
+
+    #[test]
+    fn only_the_trial_closes_the_breaker() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(0));
+        let earlier = breaker.acquire().ok().unwrap();
+        assert_eq!(
+            breaker.acquire().ok().unwrap().record(false),
+            Some(Transition::Opened)
+        );
+        let trial = breaker.acquire().ok().unwrap();
+        assert_eq!(earlier.record(true), None);
+        assert!(breaker.acquire().is_err());
+        assert_eq!(trial.record(true), Some(Transition::Closed));
+    }
+
+    #[test]
+    fn resets_the_failures_on_success() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        for success in &[false, true, false] {
+            assert_eq!(breaker.acquire().ok().unwrap().record(*success), None);
+        }
+        assert!(breaker.acquire().is_ok());
+    }
+}
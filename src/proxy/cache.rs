@@ -0,0 +1,824 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use failure::{err_msg, Error};
+use prometheus::IntCounter;
+use rand::Rng;
+use tokio::sync::{Mutex, RwLock};
+
+use super::token::Token;
+
+// Keeps the requests that each get a token for different metadata from growing the cache unbounded
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+// How many of the least recently used slots are checked for an expired one to evict first,
+// so that evicting doesn't go through the whole cache
+const EVICTION_CANDIDATES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Stale,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Stale => "stale",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TokenCacheEntry {
+    token: String,
+    inserted_at: Instant,
+    ttl: Duration,
+}
+
+impl TokenCacheEntry {
+    // Shortening the ttl by a random fraction of up to ttl_jitter keeps
+    // the proxies sharing a token source from all refreshing at once
+    fn new(token: Token, default_ttl: Duration, ttl_jitter: f64) -> Self {
+        let ttl = token.ttl.unwrap_or(default_ttl);
+        let jitter = if ttl_jitter > 0.0 {
+            rand::thread_rng().gen_range(0.0, ttl_jitter)
+        } else {
+            0.0
+        };
+        TokenCacheEntry {
+            token: token.value,
+            inserted_at: Instant::now(),
+            ttl: ttl.mul_f64(1.0 - jitter),
+        }
+    }
+
+    fn expires_at(&self) -> Instant {
+        self.inserted_at + self.ttl
+    }
+
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.expires_at() > now
+    }
+}
+
+// Tokens obtained for different requests are cached separately when the command gets request
+// metadata, this is the metadata they were obtained with
+pub type CacheKey = Vec<(String, String)>;
+
+#[derive(Debug, Default)]
+struct CacheSlot {
+    // Expired entries are kept around to be served if refreshing them fails
+    entry: RwLock<Option<TokenCacheEntry>>,
+    // Held while obtaining a new token, so that only one callback runs at a time
+    // and the requests waiting for it share its result
+    refresh_lock: Mutex<()>,
+    refreshing_in_background: AtomicBool,
+    // When and why the last callback failed, to avoid rerunning it for every request
+    last_failure: StdMutex<Option<(Instant, String)>>,
+    // Mirrors the entry, so that it can be checked while holding the lock on all the slots
+    expires_at: StdMutex<Option<Instant>>,
+}
+
+impl CacheSlot {
+    async fn store(&self, entry: Option<TokenCacheEntry>) {
+        let mut entry_guard = self.entry.write().await;
+        *self.expires_at.lock().unwrap() = entry.as_ref().map(TokenCacheEntry::expires_at);
+        *entry_guard = entry;
+    }
+
+    // Slots being refreshed are kept, so that the token being obtained doesn't go to waste
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at
+            .lock()
+            .unwrap()
+            .is_some_and(|expires_at| expires_at > now)
+            || self.refresh_lock.try_lock().is_err()
+    }
+
+    async fn fresh_token(&self, refresh_ahead: Option<f64>) -> Option<(String, bool)> {
+        let now = Instant::now();
+        let entry_guard = self.entry.read().await;
+        let entry = entry_guard.as_ref().filter(|entry| entry.is_fresh(now))?;
+
+        let refresh_due = refresh_ahead
+            .is_some_and(|fraction| entry.inserted_at + entry.ttl.mul_f64(fraction) <= now);
+        Some((entry.token.clone(), refresh_due))
+    }
+
+    fn recent_failure(&self, failure_ttl: Duration) -> Option<String> {
+        let last_failure = self.last_failure.lock().unwrap();
+        let (failed_at, message) = last_failure.as_ref()?;
+        if failed_at.elapsed() < failure_ttl {
+            Some(message.clone())
+        } else {
+            None
+        }
+    }
+
+    async fn refresh_in_background<F>(
+        self: Arc<Self>,
+        refresh: F,
+        default_ttl: Duration,
+        ttl_jitter: f64,
+    ) where
+        F: Future<Output = Result<Token, Error>>,
+    {
+        log::debug!("Refreshing the cached token ahead of expiry");
+        let refresh_guard = self.refresh_lock.lock().await;
+        match refresh.await {
+            Ok(token) => {
+                self.store(Some(TokenCacheEntry::new(token, default_ttl, ttl_jitter)))
+                    .await;
+                *self.last_failure.lock().unwrap() = None;
+            }
+            Err(e) => {
+                log::warn!("Failed to refresh the token ahead of expiry: {}", e);
+                *self.last_failure.lock().unwrap() = Some((Instant::now(), e.to_string()));
+            }
+        }
+        drop(refresh_guard);
+
+        self.refreshing_in_background.store(false, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct UsedSlot {
+    slot: Arc<CacheSlot>,
+    last_use: u64,
+}
+
+#[derive(Debug, Default)]
+struct Slots {
+    by_key: HashMap<CacheKey, UsedSlot>,
+    // The keys from the least recently used one, by when they were last used
+    by_use: BTreeMap<u64, CacheKey>,
+    uses: u64,
+}
+
+impl Slots {
+    fn next_use(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+
+    fn touch(&mut self, key: &CacheKey) -> Option<Arc<CacheSlot>> {
+        let last_use = self.by_key.get(key)?.last_use;
+        let next_use = self.next_use();
+        let used_slot = self.by_key.get_mut(key)?;
+        used_slot.last_use = next_use;
+        let key = self.by_use.remove(&last_use)?;
+        self.by_use.insert(next_use, key);
+        Some(used_slot.slot.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, slot: Arc<CacheSlot>) {
+        let last_use = self.next_use();
+        self.by_use.insert(last_use, key.clone());
+        self.by_key.insert(key, UsedSlot { slot, last_use });
+    }
+
+    // The least recently used of the expired slots goes first, then of the live ones
+    fn evict(&mut self, now: Instant) -> Option<CacheKey> {
+        let by_key = &self.by_key;
+        let evicted_use = self
+            .by_use
+            .iter()
+            .take(EVICTION_CANDIDATES)
+            .find(|(_, key)| !by_key[*key].slot.is_live(now))
+            .or_else(|| self.by_use.iter().next())
+            .map(|(last_use, _)| *last_use)?;
+        let key = self.by_use.remove(&evicted_use)?;
+        self.by_key.remove(&key);
+        Some(key)
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenCache {
+    ttl: Duration,
+    ttl_jitter: f64,
+    refresh_ahead: Option<f64>,
+    serve_stale_for: Duration,
+    failure_ttl: Duration,
+    // DEFAULT_MAX_ENTRIES when not set
+    max_entries: Option<usize>,
+    evictions: IntCounter,
+    slots: StdMutex<Slots>,
+}
+
+impl TokenCache {
+    pub fn new(
+        ttl: Duration,
+        ttl_jitter: f64,
+        refresh_ahead: Option<f64>,
+        serve_stale_for: Duration,
+        failure_ttl: Duration,
+        max_entries: Option<usize>,
+        evictions: IntCounter,
+    ) -> Self {
+        TokenCache {
+            ttl,
+            ttl_jitter,
+            refresh_ahead,
+            serve_stale_for,
+            failure_ttl,
+            max_entries,
+            evictions,
+            slots: StdMutex::new(Slots::default()),
+        }
+    }
+
+    fn slot(&self, key: CacheKey) -> Arc<CacheSlot> {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.touch(&key) {
+            return slot;
+        }
+
+        let max_entries = self.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES);
+        if slots.by_key.len() >= max_entries && slots.evict(Instant::now()).is_some() {
+            log::info!(
+                "Evicted the least recently used cached token, the cache holds at most {}",
+                max_entries
+            );
+            self.evictions.inc();
+        }
+
+        let slot = Arc::new(CacheSlot::default());
+        slots.insert(key, slot.clone());
+        slot
+    }
+
+    pub async fn get_or_refresh<C, F>(
+        &self,
+        key: CacheKey,
+        callback: C,
+    ) -> Result<(String, CacheStatus), Error>
+    where
+        C: FnOnce() -> F,
+        F: Future<Output = Result<Token, Error>> + Send + 'static,
+    {
+        let slot = self.slot(key);
+
+        if let Some((token, refresh_due)) = slot.fresh_token(self.refresh_ahead).await {
+            // A failed refresh isn't retried before the failure ttl has passed either
+            if refresh_due
+                && slot.recent_failure(self.failure_ttl).is_none()
+                && !slot.refreshing_in_background.swap(true, Ordering::SeqCst)
+            {
+                tokio::spawn(slot.clone().refresh_in_background(
+                    callback(),
+                    self.ttl,
+                    self.ttl_jitter,
+                ));
+            }
+            return Ok((token, CacheStatus::Hit));
+        }
+
+        let _refresh_guard = slot.refresh_lock.lock().await;
+        // Another request could have obtained a new token while this one was waiting
+        if let Some((token, _)) = slot.fresh_token(self.refresh_ahead).await {
+            return Ok((token, CacheStatus::Hit));
+        }
+
+        let result = match slot.recent_failure(self.failure_ttl) {
+            Some(message) => Err(err_msg(message)),
+            None => {
+                let result = callback().await;
+                *slot.last_failure.lock().unwrap() = match &result {
+                    Ok(_) => None,
+                    Err(e) => Some((Instant::now(), e.to_string())),
+                };
+                result
+            }
+        };
+
+        match result {
+            Ok(token) => {
+                let entry = TokenCacheEntry::new(token, self.ttl, self.ttl_jitter);
+                let token = entry.token.clone();
+                slot.store(Some(entry)).await;
+                Ok((token, CacheStatus::Miss))
+            }
+            Err(e) => {
+                let now = Instant::now();
+                let entry_guard = slot.entry.read().await;
+                let stale_entry = entry_guard
+                    .as_ref()
+                    .filter(|entry| entry.inserted_at + entry.ttl + self.serve_stale_for > now);
+
+                match stale_entry {
+                    Some(entry) => {
+                        log::warn!("Failed to refresh the token, serving the stale one: {}", e);
+                        Ok((entry.token.clone(), CacheStatus::Stale))
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    // Tokens are refreshed ahead of expiry when that's enabled, otherwise when they expire
+    fn refresh_at(&self, entry: &TokenCacheEntry) -> Instant {
+        entry.inserted_at + entry.ttl.mul_f64(self.refresh_ahead.unwrap_or(1.0))
+    }
+
+    // How long until the token for the key is due for a refresh, None if there's no token yet
+    pub async fn next_refresh_in(&self, key: &CacheKey) -> Option<Duration> {
+        let slot = self.slots.lock().unwrap().by_key.get(key)?.slot.clone();
+        let entry_guard = slot.entry.read().await;
+        let entry = entry_guard.as_ref()?;
+        Some(
+            self.refresh_at(entry)
+                .saturating_duration_since(Instant::now()),
+        )
+    }
+
+    // Obtains a new token unless the cached one isn't due for a refresh yet,
+    // failures leave the cached token in place so that requests can still use it
+    pub async fn refresh_if_due<C, F>(&self, key: CacheKey, callback: C) -> Result<(), Error>
+    where
+        C: FnOnce() -> F,
+        F: Future<Output = Result<Token, Error>>,
+    {
+        let slot = self.slot(key);
+        let _refresh_guard = slot.refresh_lock.lock().await;
+        let refresh_due = match *slot.entry.read().await {
+            Some(ref entry) => self.refresh_at(entry) <= Instant::now(),
+            None => true,
+        };
+        if !refresh_due {
+            return Ok(());
+        }
+
+        let token = callback().await?;
+        slot.store(Some(TokenCacheEntry::new(token, self.ttl, self.ttl_jitter)))
+            .await;
+        *slot.last_failure.lock().unwrap() = None;
+        Ok(())
+    }
+
+    // Drops the token the target rejected, unless another request already replaced it,
+    // so that a burst of rejected requests only obtains one new token
+    pub async fn invalidate(&self, key: &CacheKey, rejected_token: &str) {
+        let slot = match self.slots.lock().unwrap().by_key.get(key) {
+            Some(used_slot) => used_slot.slot.clone(),
+            None => return,
+        };
+        let mut entry_guard = slot.entry.write().await;
+        if entry_guard
+            .as_ref()
+            .is_some_and(|entry| entry.token == rejected_token)
+        {
+            *slot.expires_at.lock().unwrap() = None;
+            *entry_guard = None;
+        }
+    }
+
+    // How old the cached token for each key is and for how long it was to be used,
+    // None for the keys that no token has been obtained for yet
+    pub async fn entries(&self) -> Vec<(CacheKey, Option<(Duration, Duration)>)> {
+        let mut slots = self
+            .slots
+            .lock()
+            .unwrap()
+            .by_key
+            .iter()
+            .map(|(key, used_slot)| (key.clone(), used_slot.slot.clone()))
+            .collect::<Vec<_>>();
+        slots.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let now = Instant::now();
+        let mut entries = Vec::with_capacity(slots.len());
+        for (key, slot) in slots {
+            let age_and_ttl = slot
+                .entry
+                .read()
+                .await
+                .as_ref()
+                .map(|entry| (now.saturating_duration_since(entry.inserted_at), entry.ttl));
+            entries.push((key, age_and_ttl));
+        }
+        entries
+    }
+
+    pub fn clear(&self) {
+        *self.slots.lock().unwrap() = Slots::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    fn cache_with(
+        ttl: Duration,
+        refresh_ahead: Option<f64>,
+        serve_stale_for: Duration,
+    ) -> TokenCache {
+        TokenCache::new(
+            ttl,
+            0.0,
+            refresh_ahead,
+            serve_stale_for,
+            Duration::from_secs(0),
+            None,
+            IntCounter::new("evictions", "Evictions").unwrap(),
+        )
+    }
+
+    fn cache() -> TokenCache {
+        cache_with(Duration::from_secs(60), None, Duration::from_secs(0))
+    }
+
+    async fn token(value: &str) -> Result<Token, Error> {
+        Ok(Token {
+            value: value.to_string(),
+            ttl: None,
+        })
+    }
+
+    async fn failure() -> Result<Token, Error> {
+        Err(err_msg("The command failed"))
+    }
+
+    // Takes a while to obtain the token and counts how many times it was asked for
+    fn slow_token(
+        value: &'static str,
+        calls: &Arc<AtomicUsize>,
+    ) -> impl Future<Output = Result<Token, Error>> + Send + 'static {
+        let calls = calls.clone();
+        async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+            token(value).await
+        }
+    }
+
+    #[tokio::test]
+    async fn invalidate_only_drops_the_rejected_token() {
+        let cache = cache();
+        cache
+            .get_or_refresh(CacheKey::new(), || token("first"))
+            .await
+            .unwrap();
+
+        cache.invalidate(&CacheKey::new(), "other").await;
+        let (value, status) = cache
+            .get_or_refresh(CacheKey::new(), || token("second"))
+            .await
+            .unwrap();
+        assert_eq!((value.as_str(), status), ("first", CacheStatus::Hit));
+
+        cache.invalidate(&CacheKey::new(), "first").await;
+        let (value, status) = cache
+            .get_or_refresh(CacheKey::new(), || token("second"))
+            .await
+            .unwrap();
+        assert_eq!((value.as_str(), status), ("second", CacheStatus::Miss));
+    }
+
+    #[tokio::test]
+    async fn invalidating_an_unknown_key_caches_nothing() {
+        let cache = cache();
+        cache.invalidate(&CacheKey::new(), "first").await;
+        assert!(cache.entries().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refreshes_ahead_of_expiry_in_the_background() {
+        let cache = cache_with(Duration::from_secs(1), Some(0.5), Duration::from_secs(0));
+        let calls = Arc::new(AtomicUsize::new(0));
+        cache
+            .get_or_refresh(CacheKey::new(), || token("first"))
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(600)).await;
+
+        // Only one of the requests past the refresh point starts a refresh,
+        // and they get the current token without waiting for it
+        let started_at = Instant::now();
+        for _ in 0..3 {
+            let (value, status) = cache
+                .get_or_refresh(CacheKey::new(), || slow_token("second", &calls))
+                .await
+                .unwrap();
+            assert_eq!((value.as_str(), status), ("first", CacheStatus::Hit));
+        }
+        assert!(started_at.elapsed() < Duration::from_millis(100));
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        let (value, status) = cache
+            .get_or_refresh(CacheKey::new(), || slow_token("third", &calls))
+            .await
+            .unwrap();
+        assert_eq!((value.as_str(), status), ("second", CacheStatus::Hit));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn expires_tokens_after_their_own_ttl() {
+        let cache = cache();
+        let short_lived = || async {
+            Ok(Token {
+                value: String::from("first"),
+                ttl: Some(Duration::from_millis(200)),
+            })
+        };
+        cache
+            .get_or_refresh(CacheKey::new(), short_lived)
+            .await
+            .unwrap();
+        let (value, status) = cache
+            .get_or_refresh(CacheKey::new(), || token("second"))
+            .await
+            .unwrap();
+        assert_eq!((value.as_str(), status), ("first", CacheStatus::Hit));
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        let (value, status) = cache
+            .get_or_refresh(CacheKey::new(), || token("second"))
+            .await
+            .unwrap();
+        assert_eq!((value.as_str(), status), ("second", CacheStatus::Miss));
+    }
+
+    #[tokio::test]
+    async fn serves_the_stale_token_within_the_window() {
+        let cache = cache_with(Duration::from_millis(200), None, Duration::from_millis(300));
+        cache
+            .get_or_refresh(CacheKey::new(), || token("first"))
+            .await
+            .unwrap();
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        let (value, status) = cache
+            .get_or_refresh(CacheKey::new(), failure)
+            .await
+            .unwrap();
+        assert_eq!((value.as_str(), status), ("first", CacheStatus::Stale));
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        let err = cache
+            .get_or_refresh(CacheKey::new(), failure)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "The command failed");
+    }
+
+    #[tokio::test]
+    async fn obtains_one_token_for_concurrent_requests() {
+        let cache = Arc::new(cache());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let requests = (0..20).map(|_| {
+            let (cache, calls) = (cache.clone(), calls.clone());
+            tokio::spawn(async move {
+                cache
+                    .get_or_refresh(CacheKey::new(), || slow_token("first", &calls))
+                    .await
+                    .unwrap()
+                    .0
+            })
+        });
+
+        for token in futures::future::join_all(requests).await {
+            assert_eq!(token.unwrap(), "first");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_the_token_while_it_is_being_refreshed() {
+        let cache = Arc::new(cache_with(
+            Duration::from_secs(10),
+            Some(0.001),
+            Duration::from_secs(0),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        cache
+            .get_or_refresh(CacheKey::new(), || token("first"))
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+
+        let started_at = Instant::now();
+        let requests = (0..20).map(|_| {
+            let (cache, calls) = (cache.clone(), calls.clone());
+            tokio::spawn(async move {
+                cache
+                    .get_or_refresh(CacheKey::new(), || slow_token("second", &calls))
+                    .await
+                    .unwrap()
+                    .0
+            })
+        });
+        for token in futures::future::join_all(requests).await {
+            assert_eq!(token.unwrap(), "first");
+        }
+        assert!(started_at.elapsed() < Duration::from_millis(100));
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn remembers_the_failure_for_the_failure_ttl() {
+        let cache = TokenCache::new(
+            Duration::from_secs(60),
+            0.0,
+            None,
+            Duration::from_secs(0),
+            Duration::from_millis(200),
+            None,
+            IntCounter::new("evictions", "Evictions").unwrap(),
+        );
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted_failure = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            failure()
+        };
+
+        for _ in 0..3 {
+            let err = cache
+                .get_or_refresh(CacheKey::new(), counted_failure)
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_string(), "The command failed");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        cache
+            .get_or_refresh(CacheKey::new(), counted_failure)
+            .await
+            .unwrap_err();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_failure_ttl_before_refreshing_ahead_again() {
+        let cache = TokenCache::new(
+            Duration::from_secs(2),
+            0.0,
+            Some(0.1),
+            Duration::from_secs(0),
+            Duration::from_millis(500),
+            None,
+            IntCounter::new("evictions", "Evictions").unwrap(),
+        );
+        cache
+            .get_or_refresh(CacheKey::new(), || token("first"))
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted_failure = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            failure()
+        };
+        for _ in 0..3 {
+            let (value, status) = cache
+                .get_or_refresh(CacheKey::new(), counted_failure)
+                .await
+                .unwrap();
+            assert_eq!((value.as_str(), status), ("first", CacheStatus::Hit));
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        cache
+            .get_or_refresh(CacheKey::new(), counted_failure)
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    fn bounded_cache(max_entries: usize) -> TokenCache {
+        TokenCache::new(
+            Duration::from_secs(60),
+            0.0,
+            None,
+            Duration::from_secs(0),
+            Duration::from_secs(0),
+            Some(max_entries),
+            IntCounter::new("evictions", "Evictions").unwrap(),
+        )
+    }
+
+    fn key(name: &str) -> CacheKey {
+        vec![(String::from("AUTHPROXY_AUDIENCE"), name.to_string())]
+    }
+
+    async fn cached_keys(cache: &TokenCache) -> Vec<CacheKey> {
+        cache
+            .entries()
+            .await
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_token() {
+        let cache = bounded_cache(3);
+        for name in &["a", "b", "c", "a", "d"] {
+            cache
+                .get_or_refresh(key(name), || token(name))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            cached_keys(&cache).await,
+            vec![key("a"), key("c"), key("d")]
+        );
+        assert_eq!(cache.evictions.get(), 1);
+        let (value, status) = cache
+            .get_or_refresh(key("b"), || token("b2"))
+            .await
+            .unwrap();
+        assert_eq!((value.as_str(), status), ("b2", CacheStatus::Miss));
+        assert_eq!(
+            cached_keys(&cache).await,
+            vec![key("a"), key("b"), key("d")]
+        );
+    }
+
+    #[tokio::test]
+    async fn evicts_expired_tokens_before_live_ones() {
+        let cache = bounded_cache(2);
+        cache.get_or_refresh(key("a"), || token("a")).await.unwrap();
+        let short_lived = || async {
+            Ok(Token {
+                value: String::from("b"),
+                ttl: Some(Duration::from_millis(100)),
+            })
+        };
+        cache.get_or_refresh(key("b"), short_lived).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        cache.get_or_refresh(key("c"), || token("c")).await.unwrap();
+        assert_eq!(cached_keys(&cache).await, vec![key("a"), key("c")]);
+    }
+
+    #[tokio::test]
+    async fn stays_within_the_bound_under_concurrent_requests() {
+        let cache = Arc::new(bounded_cache(10));
+        let requests = (0..50).map(|i| {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                let name = i.to_string();
+                cache
+                    .get_or_refresh(key(&name), || token("token"))
+                    .await
+                    .unwrap()
+            })
+        });
+        for result in futures::future::join_all(requests).await {
+            result.unwrap();
+        }
+
+        let slots = cache.slots.lock().unwrap();
+        assert_eq!((slots.by_key.len(), slots.by_use.len()), (10, 10));
+        assert_eq!(cache.evictions.get(), 40);
+    }
+
+    #[test]
+    fn jitters_the_ttl_within_the_band() {
+        let ttls: Vec<_> = (0..200)
+            .map(|_| {
+                let token = Token {
+                    value: String::from("token"),
+                    ttl: None,
+                };
+                TokenCacheEntry::new(token, Duration::from_secs(100), 0.2).ttl
+            })
+            .collect();
+
+        assert!(ttls
+            .iter()
+            .all(|ttl| *ttl > Duration::from_secs(80) && *ttl <= Duration::from_secs(100)));
+        assert!(ttls.iter().any(|ttl| *ttl != ttls[0]));
+    }
+
+    #[test]
+    fn keeps_the_ttl_without_jitter() {
+        let token = Token {
+            value: String::from("token"),
+            ttl: Some(Duration::from_secs(30)),
+        };
+        let entry = TokenCacheEntry::new(token, Duration::from_secs(100), 0.0);
+        assert_eq!(entry.ttl, Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::{self, Write};
+use std::mem;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::stream::{self, StreamExt};
+use http::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, VARY,
+};
+use hyper::body::Bytes;
+use hyper::{Body, Response, StatusCode, Version};
+
+// Whether gzip is among the encodings accepted by the client, and not with a weight of 0
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let weight = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|weight| weight.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && weight > 0.0
+        })
+}
+
+// Responses that are already encoded, partial or smaller than min_size are left as they are.
+// Event streams are too, since they are read as they arrive and compressing them gains little.
+fn should_compress(response: &Response<Body>, min_size: u64) -> bool {
+    let headers = response.headers();
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let is_event_stream = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+
+    !matches!(
+        response.status(),
+        StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED | StatusCode::PARTIAL_CONTENT
+    ) && !headers.contains_key(CONTENT_ENCODING)
+        && !headers.contains_key(CONTENT_RANGE)
+        && !is_event_stream
+        && content_length.is_none_or(|length| length >= min_size)
+}
+
+// Every chunk is flushed through the encoder, so that the client gets the data as it arrives
+fn gzip_body(body: Body) -> Body {
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    Body::wrap_stream(stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                let compressed = encoder
+                    .write_all(&chunk)
+                    .and_then(|_| encoder.flush())
+                    .map(|_| Bytes::from(mem::take(encoder.get_mut())));
+                Some((compressed, Some((body, encoder))))
+            }
+            Some(Err(e)) => Some((Err(io::Error::other(e)), None)),
+            None => Some((encoder.finish().map(Bytes::from), None)),
+        }
+    }))
+}
+
+pub fn compress_response(response: Response<Body>, min_size: u64) -> Response<Body> {
+    if !should_compress(&response, min_size) {
+        return response;
+    }
+
+    let mut response = response.map(gzip_body);
+    // Without a length the body has to be sent chunked, which HTTP/1.0 responses can't be
+    *response.version_mut() = Version::HTTP_11;
+    let headers = response.headers_mut();
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    response
+}
@@ -0,0 +1,72 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use failure::{err_msg, Error};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowMode {
+    Queue,
+    Reject,
+}
+
+impl FromStr for OverflowMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(OverflowMode::Queue),
+            "reject" => Ok(OverflowMode::Reject),
+            _ => Err(err_msg(format!("Unknown overflow mode: {}", s))),
+        }
+    }
+}
+
+// Decrements the queue length when the waiting request gets a permit or goes away
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    semaphore: Semaphore,
+    overflow: OverflowMode,
+    // Unbounded when not set
+    max_queued: Option<usize>,
+    queued: AtomicUsize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_concurrent: usize, overflow: OverflowMode, max_queued: Option<usize>) -> Self {
+        ConcurrencyLimiter {
+            semaphore: Semaphore::new(max_concurrent),
+            overflow,
+            max_queued,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    // Returns None if the request should be turned away, the permit is held while it's proxied
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Some(permit);
+        }
+        if self.overflow == OverflowMode::Reject {
+            return None;
+        }
+
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        let _queue_guard = QueueGuard(&self.queued);
+        if self
+            .max_queued
+            .is_some_and(|max_queued| queued >= max_queued)
+        {
+            return None;
+        }
+        Some(self.semaphore.acquire().await)
+    }
+}
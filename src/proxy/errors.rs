@@ -0,0 +1,213 @@
+use std::fmt;
+use std::str::FromStr;
+
+use failure::{err_msg, Context, Error};
+use http::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+
+// Attached as context to the errors that clients should see a specific status for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Upstream,
+    UpstreamTimeout,
+    Token,
+}
+
+impl ErrorKind {
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorKind::Upstream => StatusCode::BAD_GATEWAY,
+            ErrorKind::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorKind::Token => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Upstream => "Failed to reach the target",
+            ErrorKind::UpstreamTimeout => "Target timed out",
+            ErrorKind::Token => "Failed to obtain a token",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(err_msg(format!("Unknown error format: {}", s))),
+        }
+    }
+}
+
+fn error_status(err: &Error) -> StatusCode {
+    err.iter_chain()
+        .find_map(|cause| cause.downcast_ref::<Context<ErrorKind>>())
+        .map_or(StatusCode::INTERNAL_SERVER_ERROR, |context| {
+            context.get_context().status()
+        })
+}
+
+// The error messages may reveal internals, so they are only sent to the client with detail
+pub fn error_response(err: &Error, format: ErrorFormat, detail: bool) -> Response<Body> {
+    let status = error_status(err);
+    let message = if detail {
+        err.to_string()
+    } else {
+        status.canonical_reason().unwrap_or("Error").to_string()
+    };
+    let causes: Vec<String> = if detail {
+        err.iter_causes().map(ToString::to_string).collect()
+    } else {
+        Vec::new()
+    };
+
+    let (content_type, body) = match format {
+        ErrorFormat::Text => {
+            let mut body = message;
+            for cause in causes {
+                body.push_str("\nCaused by: ");
+                body.push_str(&cause);
+            }
+            ("text/plain; charset=utf-8", body)
+        }
+        ErrorFormat::Json => {
+            let mut body = serde_json::json!({ "error": message });
+            if !causes.is_empty() {
+                body["cause"] = serde_json::Value::from(causes.join(": "));
+            }
+            ("application/json", body.to_string())
+        }
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+// Why the proxy failed to start or stopped, for embedding it without going through the CLI
+#[derive(Debug)]
+pub enum ProxyError {
+    Config(Error),
+    Tls(Error),
+    Bind(Error),
+    Upstream(Error),
+    Command(Error),
+    Server(Error),
+}
+
+impl ProxyError {
+    pub fn inner(&self) -> &Error {
+        match self {
+            ProxyError::Config(err)
+            | ProxyError::Tls(err)
+            | ProxyError::Bind(err)
+            | ProxyError::Upstream(err)
+            | ProxyError::Command(err)
+            | ProxyError::Server(err) => err,
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ProxyError::Config(_) => "Invalid proxy configuration",
+            ProxyError::Tls(_) => "Failed to set up TLS",
+            ProxyError::Bind(_) => "Failed to listen",
+            ProxyError::Upstream(_) => "Failed to set up the client for the target",
+            ProxyError::Command(_) => "Failed to obtain a token at startup",
+            ProxyError::Server(_) => "Failed to serve requests",
+        })?;
+        // The underlying failure errors can't be exposed as a source, so their messages are included
+        for cause in self.inner().iter_chain() {
+            write!(f, ": {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+#[cfg(test)]
+mod tests {
+    use failure::ResultExt;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn error(kind: Option<ErrorKind>) -> Error {
+        let err = Err::<(), _>(err_msg("connection refused")).context("Failed to connect");
+        match kind {
+            Some(kind) => err.context(kind).unwrap_err().into(),
+            None => err.unwrap_err().into(),
+        }
+    }
+
+    fn json_response(err: &Error, detail: bool) -> (StatusCode, Value) {
+        let response = error_response(err, ErrorFormat::Json, detail);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let status = response.status();
+        let body = futures::executor::block_on(hyper::body::to_bytes(response.into_body()));
+        (status, serde_json::from_slice(&body.unwrap()).unwrap())
+    }
+
+    #[test]
+    fn answers_with_the_status_for_the_error_kind() {
+        let kinds = [
+            (Some(ErrorKind::Upstream), StatusCode::BAD_GATEWAY),
+            (
+                Some(ErrorKind::UpstreamTimeout),
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (Some(ErrorKind::Token), StatusCode::SERVICE_UNAVAILABLE),
+            (None, StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        for &(kind, expected_status) in &kinds {
+            let (status, body) = json_response(&error(kind), false);
+            assert_eq!(status, expected_status);
+            assert_eq!(body, json!({"error": expected_status.canonical_reason()}));
+        }
+    }
+
+    #[test]
+    fn includes_the_causes_with_detail() {
+        let (status, body) = json_response(&error(Some(ErrorKind::Upstream)), true);
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            body,
+            json!({
+                "error": "Failed to reach the target",
+                "cause": "Failed to connect: connection refused",
+            })
+        );
+    }
+
+    #[test]
+    fn lists_the_causes_in_text() {
+        let response = error_response(&error(None), ErrorFormat::Text, true);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        let body = futures::executor::block_on(hyper::body::to_bytes(response.into_body()));
+        assert_eq!(
+            body.unwrap(),
+            "Failed to connect\nCaused by: connection refused"
+        );
+    }
+}
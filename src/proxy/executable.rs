@@ -0,0 +1,71 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use failure::{err_msg, Error};
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).map_or(false, |metadata| metadata.is_file())
+        || fs::metadata(path.with_extension("exe")).map_or(false, |metadata| metadata.is_file())
+}
+
+// Programs given as a path are taken relative to the directory the command runs in,
+// like the OS does when starting them, bare names are looked up in PATH
+pub fn check(program: &str, cwd: Option<&Path>) -> Result<(), Error> {
+    let path = Path::new(program);
+    let found = if path.components().count() > 1 {
+        is_executable(&cwd.map_or_else(|| path.to_path_buf(), |cwd| cwd.join(path)))
+    } else {
+        env::var_os("PATH").is_some_and(|paths| {
+            env::split_paths(&paths).any(|dir| is_executable(&dir.join(program)))
+        })
+    };
+
+    if found {
+        Ok(())
+    } else {
+        Err(err_msg(format!(
+            "Command `{}` doesn't exist or isn't executable",
+            program
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_programs_in_path() {
+        assert!(check("sh", None).is_ok());
+        assert_eq!(
+            check("no-such-program", None).unwrap_err().to_string(),
+            "Command `no-such-program` doesn't exist or isn't executable"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn takes_paths_relative_to_the_command_directory() {
+        assert!(check("/bin/sh", None).is_ok());
+        assert!(check("./sh", Some(Path::new("/bin"))).is_ok());
+        assert!(check("./sh", Some(Path::new("/"))).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_files_that_are_not_executable() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(check(file.path().to_str().unwrap(), None).is_err());
+    }
+}
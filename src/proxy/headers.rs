@@ -0,0 +1,358 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use failure::{err_msg, Error};
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, CONNECTION, COOKIE, HOST, PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE, USER_AGENT, VIA,
+};
+use http::Version;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+// Not in the http crate, since it's deprecated
+const KEEP_ALIVE: &str = "keep-alive";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostHeaderMode {
+    Remove,
+    Target,
+    Preserve,
+}
+
+impl FromStr for HostHeaderMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "remove" => Ok(HostHeaderMode::Remove),
+            "target" => Ok(HostHeaderMode::Target),
+            "preserve" => Ok(HostHeaderMode::Preserve),
+            _ => Err(err_msg(format!("Unknown Host header mode: {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseHeaderMode {
+    Append,
+    Override,
+}
+
+impl FromStr for ResponseHeaderMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(ResponseHeaderMode::Append),
+            "override" => Ok(ResponseHeaderMode::Override),
+            _ => Err(err_msg(format!("Unknown response header mode: {}", s))),
+        }
+    }
+}
+
+// Parses headers given as "Name: Value"
+pub fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), Error> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| err_msg(format!("Header `{}` is not in the Name: Value form", s)))?;
+
+    Ok((
+        HeaderName::from_bytes(name.trim().as_bytes())?,
+        HeaderValue::from_str(value.trim())?,
+    ))
+}
+
+// Hop-by-hop headers only make sense for a single connection, so they aren't forwarded,
+// as RFC 7230 requires. Hyper sets the ones it needs for its own connections.
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+
+    for name in &[
+        CONNECTION,
+        PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION,
+        TE,
+        TRAILER,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+    headers.remove(KEEP_ALIVE);
+}
+
+// Percent-encodes whatever isn't a cookie-octet as RFC 6265 defines it, along with the percent sign
+fn encode_cookie_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for &byte in value.as_bytes() {
+        match byte {
+            0x21 | 0x23..=0x24 | 0x26..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Merges the cookie into a single Cookie header with the ones the client sent,
+// replacing a client's cookie with the same name. The client's cookies are kept as bytes,
+// since they don't have to be ASCII.
+pub fn set_cookie(headers: &mut HeaderMap, name: &str, value: &str) -> Result<(), Error> {
+    let is_replaced = |cookie: &[u8]| {
+        let cookie_name = cookie
+            .split(|&byte| byte == b'=')
+            .next()
+            .unwrap_or_default();
+        cookie_name.trim_ascii() == name.as_bytes()
+    };
+    let mut cookies: Vec<Vec<u8>> = headers
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|header_value| header_value.as_bytes().split(|&byte| byte == b';'))
+        .map(<[u8]>::trim_ascii)
+        .filter(|cookie| !cookie.is_empty() && !is_replaced(cookie))
+        .map(<[u8]>::to_vec)
+        .collect();
+    cookies.push(format!("{}={}", name, encode_cookie_value(value)).into_bytes());
+
+    headers.insert(COOKIE, HeaderValue::from_bytes(&cookies.join(&b"; "[..]))?);
+    Ok(())
+}
+
+// Whether the client can take trailers in the response, which gRPC relies on
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let coding = coding.split(';').next().unwrap_or_default();
+            coding.trim().eq_ignore_ascii_case("trailers")
+        })
+}
+
+// Built from bytes, since the client's User-Agent doesn't have to be ASCII
+pub fn append_user_agent(headers: &mut HeaderMap) -> Result<(), Error> {
+    let mut user_agent = Vec::new();
+    if let Some(client_user_agent) = headers.get(USER_AGENT) {
+        user_agent.extend_from_slice(client_user_agent.as_bytes());
+        user_agent.push(b' ');
+    }
+    user_agent.extend_from_slice(format!("authproxy/{}", crate::VERSION).as_bytes());
+    headers.insert(USER_AGENT, HeaderValue::from_bytes(&user_agent)?);
+
+    Ok(())
+}
+
+// Adds the proxy to the intermediaries the message passed through,
+// along with the version of HTTP it was received with
+pub fn append_via(headers: &mut HeaderMap, version: Version) -> Result<(), Error> {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    let mut via = Vec::new();
+    for value in headers.get_all(VIA) {
+        via.extend_from_slice(value.as_bytes());
+        via.extend_from_slice(b", ");
+    }
+    via.extend_from_slice(format!("{} authproxy/{}", protocol, crate::VERSION).as_bytes());
+    headers.insert(VIA, HeaderValue::from_bytes(&via)?);
+
+    Ok(())
+}
+
+// Tells the target who the original client is. An incoming X-Forwarded-For chain is only kept
+// if the clients are trusted to send a correct one, otherwise anyone could spoof their address.
+pub fn set_forwarded_headers(
+    headers: &mut HeaderMap,
+    peer_ip: Option<IpAddr>,
+    proto: &'static str,
+    trust_forwarded: bool,
+) -> Result<(), Error> {
+    let mut chain = Vec::new();
+    if trust_forwarded {
+        for value in headers.get_all(X_FORWARDED_FOR) {
+            // Addresses are ASCII, so a value that isn't can only be dropped
+            match value.to_str() {
+                Ok(value) => chain.push(value.to_string()),
+                Err(_) => log::warn!("Dropping an X-Forwarded-For value that isn't ASCII"),
+            }
+        }
+    }
+    if let Some(peer_ip) = peer_ip {
+        chain.push(peer_ip.to_string());
+    }
+
+    if chain.is_empty() {
+        headers.remove(X_FORWARDED_FOR);
+    } else {
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_str(&chain.join(", "))?);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    match headers.get(HOST).cloned() {
+        Some(host) => headers.insert(X_FORWARDED_HOST, host),
+        None => headers.remove(X_FORWARDED_HOST),
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn forwarded_for(incoming: &[&[u8]], trust_forwarded: bool) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        for value in incoming {
+            headers.append(X_FORWARDED_FOR, HeaderValue::from_bytes(value).unwrap());
+        }
+        headers.insert(HOST, HeaderValue::from_static("proxy.example"));
+        set_forwarded_headers(&mut headers, Some(CLIENT_IP), "http", trust_forwarded).unwrap();
+
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[X_FORWARDED_HOST], "proxy.example");
+        headers
+            .get(X_FORWARDED_FOR)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn forwards_the_address_of_a_direct_client() {
+        assert_eq!(forwarded_for(&[], true).as_deref(), Some("192.0.2.1"));
+    }
+
+    #[test]
+    fn appends_to_the_trusted_chain() {
+        let incoming: &[&[u8]] = &[b"203.0.113.1, 198.51.100.1", b"198.51.100.2"];
+        assert_eq!(
+            forwarded_for(incoming, true).as_deref(),
+            Some("203.0.113.1, 198.51.100.1, 198.51.100.2, 192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn replaces_the_untrusted_chain() {
+        let incoming: &[&[u8]] = &[b"203.0.113.1"];
+        assert_eq!(forwarded_for(incoming, false).as_deref(), Some("192.0.2.1"));
+    }
+
+    #[test]
+    fn drops_values_that_are_not_ascii() {
+        let incoming: &[&[u8]] = &[b"203.0.113.1", "caf\u{e9}".as_bytes()];
+        assert_eq!(
+            forwarded_for(incoming, true).as_deref(),
+            Some("203.0.113.1, 192.0.2.1")
+        );
+    }
+
+    fn cookie_after_setting(incoming: &[&[u8]], value: &str) -> Vec<u8> {
+        let mut headers = HeaderMap::new();
+        for cookie in incoming {
+            headers.append(COOKIE, HeaderValue::from_bytes(cookie).unwrap());
+        }
+        set_cookie(&mut headers, "session", value).unwrap();
+        assert_eq!(headers.get_all(COOKIE).iter().count(), 1);
+        headers[COOKIE].as_bytes().to_vec()
+    }
+
+    #[test]
+    fn sets_the_cookie() {
+        assert_eq!(cookie_after_setting(&[], "token"), b"session=token");
+    }
+
+    #[test]
+    fn merges_the_cookie_with_the_clients() {
+        let incoming: &[&[u8]] = &[b"theme=dark; session=client", b"lang=en;"];
+        assert_eq!(
+            cookie_after_setting(incoming, "token"),
+            b"theme=dark; lang=en; session=token"
+        );
+    }
+
+    #[test]
+    fn encodes_what_cookies_cant_hold() {
+        assert_eq!(
+            cookie_after_setting(&[], "a b;c\"d%"),
+            b"session=a%20b%3Bc%22d%25"
+        );
+    }
+
+    #[test]
+    fn keeps_client_cookies_that_are_not_ascii() {
+        let incoming: &[&[u8]] = &["name=caf\u{e9}".as_bytes()];
+        let mut expected = "name=caf\u{e9}".as_bytes().to_vec();
+        expected.extend_from_slice(b"; session=token");
+        assert_eq!(cookie_after_setting(incoming, "token"), expected);
+    }
+
+    fn user_agent_after_appending(incoming: Option<&[u8]>) -> Vec<u8> {
+        let mut headers = HeaderMap::new();
+        if let Some(incoming) = incoming {
+            headers.insert(USER_AGENT, HeaderValue::from_bytes(incoming).unwrap());
+        }
+        append_user_agent(&mut headers).unwrap();
+        headers[USER_AGENT].as_bytes().to_vec()
+    }
+
+    #[test]
+    fn appends_the_proxy_to_the_user_agent() {
+        let product = format!("authproxy/{}", crate::VERSION);
+        assert_eq!(user_agent_after_appending(None), product.as_bytes());
+        assert_eq!(
+            user_agent_after_appending(Some(b"curl/7.68.0")),
+            format!("curl/7.68.0 {}", product).as_bytes()
+        );
+        assert_eq!(
+            user_agent_after_appending(Some("caf\u{e9}/1.0".as_bytes())),
+            format!("caf\u{e9}/1.0 {}", product).as_bytes()
+        );
+    }
+
+    fn via_after_appending(incoming: &[&[u8]], version: Version) -> Vec<u8> {
+        let mut headers = HeaderMap::new();
+        for value in incoming {
+            headers.append(VIA, HeaderValue::from_bytes(value).unwrap());
+        }
+        append_via(&mut headers, version).unwrap();
+        assert_eq!(headers.get_all(VIA).iter().count(), 1);
+        headers[VIA].as_bytes().to_vec()
+    }
+
+    #[test]
+    fn appends_the_proxy_to_via() {
+        let entry = format!("authproxy/{}", crate::VERSION);
+        assert_eq!(
+            via_after_appending(&[], Version::HTTP_11),
+            format!("1.1 {}", entry).as_bytes()
+        );
+        assert_eq!(
+            via_after_appending(&[b"1.0 fred, 1.1 p.example.net", b"2 lb"], Version::HTTP_2),
+            format!("1.0 fred, 1.1 p.example.net, 2 lb, 2 {}", entry).as_bytes()
+        );
+        assert_eq!(
+            via_after_appending(&["1.1 caf\u{e9}".as_bytes()], Version::HTTP_10),
+            format!("1.1 caf\u{e9}, 1.0 {}", entry).as_bytes()
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use failure::{err_msg, Error};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+// A bare address stands for itself alone
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| err_msg(format!("Invalid address in CIDR: {}", s)))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|&prefix_len| prefix_len <= max_prefix_len)
+                .ok_or_else(|| err_msg(format!("Invalid prefix length in CIDR: {}", s)))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+fn prefix_matches(network: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let (whole_bytes, rest_bits) = (prefix_len as usize / 8, prefix_len % 8);
+    if network[..whole_bytes] != addr[..whole_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    network[whole_bytes] & mask == addr[whole_bytes] & mask
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 clients of a server listening on IPv6 show up with mapped addresses
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Deny rules win over allow rules, and once there are allow rules nothing else is allowed
+pub fn is_allowed(allow: &[Cidr], deny: &[Cidr], addr: IpAddr) -> bool {
+    !deny.iter().any(|cidr| cidr.contains(addr))
+        && (allow.is_empty() || allow.iter().any(|cidr| cidr.contains(addr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_addresses_within_the_prefix() {
+        let cidr: Cidr = "10.1.0.0/17".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.127.255")));
+        assert!(!cidr.contains(ip("10.1.128.0")));
+        assert!(cidr.contains(ip("::ffff:10.1.0.1")));
+        assert!(!cidr.contains(ip("fe80::1")));
+
+        let single: Cidr = "fe80::1".parse().unwrap();
+        assert!(single.contains(ip("fe80::1")));
+        assert!(!single.contains(ip("fe80::2")));
+    }
+
+    #[test]
+    fn rejects_invalid_cidrs() {
+        for cidr in &["10.0.0.0/33", "fe80::/129", "10.0.0/8", "10.0.0.0/x"] {
+            assert!(cidr.parse::<Cidr>().is_err(), "{} was accepted", cidr);
+        }
+    }
+
+    #[test]
+    fn denies_before_allowing() {
+        let allow = cidrs(&["10.0.0.0/8"]);
+        let deny = cidrs(&["10.0.0.1"]);
+        assert!(is_allowed(&allow, &deny, ip("10.0.0.2")));
+        assert!(!is_allowed(&allow, &deny, ip("10.0.0.1")));
+        assert!(!is_allowed(&allow, &deny, ip("192.168.0.1")));
+        assert!(is_allowed(&[], &deny, ip("192.168.0.1")));
+        assert!(is_allowed(&[], &[], ip("10.0.0.1")));
+    }
+}
@@ -0,0 +1,268 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::{self, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::{err_msg, Error, ResultExt};
+use futures::future;
+use futures::stream::{Stream, StreamExt};
+use hyper::server::conn::AddrStream;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::{delay_for, timeout};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_PENDING_HANDSHAKES: usize = 64;
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum ListenAddr {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+    // Use the socket passed by systemd with socket activation
+    Systemd,
+}
+
+// Binds the way the standard library does, only with another backlog than its 128
+pub fn bind_tcp(addr: &SocketAddr, backlog: i32) -> io::Result<net::TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into_tcp_listener())
+}
+
+// Removes the socket file once the server stops listening on it
+#[cfg(unix)]
+pub struct SocketFileGuard<'a>(pub &'a Path);
+
+#[cfg(unix)]
+impl Drop for SocketFileGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(self.0) {
+            log::warn!("Failed to remove socket file {}: {}", self.0.display(), err);
+        }
+    }
+}
+
+// Address of the client on the other end of an accepted connection, if there is one
+pub trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl PeerAddr for AddrStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl<S: PeerAddr + AsyncRead + AsyncWrite + Unpin> PeerAddr for TlsStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+}
+
+#[cfg(unix)]
+impl PeerAddr for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+fn open_pem(path: &Path, what: &str) -> Result<BufReader<File>, Error> {
+    let file =
+        File::open(path).with_context(|_| format!("Failed to read {} {}", what, path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+// When a client CA is given, certificates presented by clients have to be signed by it,
+// and with require_client_cert clients without one are refused as well
+pub fn load_tls_acceptor(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+    require_client_cert: bool,
+) -> Result<TlsAcceptor, Error> {
+    let certs = pemfile::certs(&mut open_pem(cert_path, "TLS certificate")?).map_err(|_| {
+        err_msg(format!(
+            "Failed to parse TLS certificate {}",
+            cert_path.display()
+        ))
+    })?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut open_pem(key_path, "TLS key")?)
+        .map_err(|_| err_msg(format!("Failed to parse TLS key {}", key_path.display())))?;
+    if keys.is_empty() {
+        return Err(err_msg(format!(
+            "No PKCS#8 private key found in {}",
+            key_path.display()
+        )));
+    }
+
+    let client_verifier = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots
+                .add_pem_file(&mut open_pem(client_ca_path, "client CA file")?)
+                .map_err(|_| {
+                    err_msg(format!(
+                        "Failed to parse client CA file {}",
+                        client_ca_path.display()
+                    ))
+                })?;
+            if added == 0 {
+                return Err(err_msg(format!(
+                    "No certificates found in client CA file {}",
+                    client_ca_path.display()
+                )));
+            }
+            if require_client_cert {
+                AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(client_verifier);
+    config
+        .set_single_cert(certs, keys.remove(0))
+        .context("Failed to set up TLS")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Whether accepting failed because of the connection rather than the listener
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+// Logs and leaves out the connections that failed to be accepted, since hyper stops serving
+// at the first error. Other errors, such as running out of file descriptors, would most likely
+// happen again right away, so they pause accepting for a moment like AddrIncoming does.
+pub fn skip_accept_errors<S, IO>(incoming: S) -> impl Stream<Item = Result<IO, io::Error>>
+where
+    S: Stream<Item = Result<IO, io::Error>>,
+{
+    incoming.filter_map(|conn| async move {
+        match conn {
+            Ok(conn) => Some(Ok(conn)),
+            Err(err) if is_connection_error(&err) => {
+                log::warn!("Failed to accept a connection: {}", err);
+                None
+            }
+            Err(err) => {
+                log::error!("Failed to accept a connection: {}", err);
+                delay_for(ACCEPT_ERROR_BACKOFF).await;
+                None
+            }
+        }
+    })
+}
+
+// Performs TLS handshakes on the accepted connections, several at a time so that
+// a single slow client can't hold up the others. Failed accepts and handshakes are only
+// logged since they shouldn't bring the whole server down.
+pub fn tls_incoming<S, IO>(
+    incoming: S,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = Result<TlsStream<IO>, io::Error>>
+where
+    S: Stream<Item = Result<IO, io::Error>>,
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    incoming
+        .map(move |conn| {
+            let acceptor = acceptor.clone();
+            async move {
+                let conn = match conn {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        log::warn!("Failed to accept a connection: {}", err);
+                        return None;
+                    }
+                };
+
+                match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                    Ok(Ok(stream)) => Some(Ok(stream)),
+                    Ok(Err(err)) => {
+                        log::warn!("TLS handshake failed: {}", err);
+                        None
+                    }
+                    Err(_) => {
+                        log::warn!("TLS handshake timed out");
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(MAX_PENDING_HANDSHAKES)
+        .filter_map(future::ready)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use futures::stream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn skips_the_connections_that_failed_to_be_accepted() {
+        let incoming = stream::iter(vec![
+            Ok(1),
+            Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            Ok(2),
+            Err(io::Error::from_raw_os_error(libc::EMFILE)),
+            Ok(3),
+        ]);
+        let started_at = Instant::now();
+        let accepted: Vec<_> = skip_accept_errors(incoming)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(accepted, vec![1, 2, 3]);
+        // Only running out of file descriptors waits
+        assert!(started_at.elapsed() >= ACCEPT_ERROR_BACKOFF);
+        assert!(started_at.elapsed() < ACCEPT_ERROR_BACKOFF * 2);
+    }
+
+    #[tokio::test]
+    async fn skips_the_connections_that_failed_to_be_accepted_for_tls() {
+        let incoming = stream::iter(vec![Err::<TcpStream, _>(io::Error::from(
+            io::ErrorKind::ConnectionReset,
+        ))]);
+        let acceptor = TlsAcceptor::from(Arc::new(ServerConfig::new(NoClientAuth::new())));
+        let accepted: Vec<_> = tls_incoming(incoming, acceptor).collect().await;
+        assert!(accepted.is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+
+use failure::Error;
+use hyper::StatusCode;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use super::cache::CacheStatus;
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounter,
+    responses: IntCounterVec,
+    token_cache: IntCounterVec,
+    token_cache_evictions: IntCounter,
+    upstream_duration: Histogram,
+    command_runs: IntCounterVec,
+    command_duration: Histogram,
+    breaker_transitions: IntCounterVec,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Metrics").finish()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, Error> {
+        let registry = Registry::new();
+
+        let requests = IntCounter::new(
+            "authproxy_requests_total",
+            "Number of requests received for proxying",
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+
+        let responses = IntCounterVec::new(
+            Opts::new(
+                "authproxy_responses_total",
+                "Number of proxied responses by status class, or error if there was none",
+            ),
+            &["class"],
+        )?;
+        registry.register(Box::new(responses.clone()))?;
+
+        let token_cache = IntCounterVec::new(
+            Opts::new(
+                "authproxy_token_cache_total",
+                "Number of token lookups served from the cache or by running the command",
+            ),
+            &["result"],
+        )?;
+        registry.register(Box::new(token_cache.clone()))?;
+
+        let token_cache_evictions = IntCounter::new(
+            "authproxy_token_cache_evictions_total",
+            "Number of cached tokens dropped to stay within the maximum number of entries",
+        )?;
+        registry.register(Box::new(token_cache_evictions.clone()))?;
+
+        let upstream_duration = Histogram::with_opts(HistogramOpts::new(
+            "authproxy_upstream_request_duration_seconds",
+            "Time spent waiting for the target to respond",
+        ))?;
+        registry.register(Box::new(upstream_duration.clone()))?;
+
+        let command_runs = IntCounterVec::new(
+            Opts::new(
+                "authproxy_command_runs_total",
+                "Number of token command runs by whether they succeeded, failed or timed out",
+            ),
+            &["result"],
+        )?;
+        registry.register(Box::new(command_runs.clone()))?;
+
+        let command_duration = Histogram::with_opts(HistogramOpts::new(
+            "authproxy_command_duration_seconds",
+            "Time spent running the token command",
+        ))?;
+        registry.register(Box::new(command_duration.clone()))?;
+
+        let breaker_transitions = IntCounterVec::new(
+            Opts::new(
+                "authproxy_circuit_breaker_transitions_total",
+                "Number of times a circuit breaker opened or closed again",
+            ),
+            &["state"],
+        )?;
+        registry.register(Box::new(breaker_transitions.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests,
+            responses,
+            token_cache,
+            token_cache_evictions,
+            upstream_duration,
+            command_runs,
+            command_duration,
+            breaker_transitions,
+        })
+    }
+
+    pub fn observe_request(&self) {
+        self.requests.inc();
+    }
+
+    pub fn observe_response(&self, status: Option<StatusCode>) {
+        let class = match status {
+            Some(status) => format!("{}xx", status.as_u16() / 100),
+            None => String::from("error"),
+        };
+        self.responses.with_label_values(&[&class]).inc();
+    }
+
+    pub fn observe_token_lookup(&self, status: CacheStatus) {
+        self.token_cache.with_label_values(&[status.as_str()]).inc();
+    }
+
+    // Shared by all the token caches, which count their evictions themselves
+    pub fn token_cache_evictions(&self) -> IntCounter {
+        self.token_cache_evictions.clone()
+    }
+
+    pub fn observe_upstream_duration(&self, seconds: f64) {
+        self.upstream_duration.observe(seconds);
+    }
+
+    pub fn observe_command_run(&self, result: &str, seconds: f64) {
+        self.command_runs.with_label_values(&[result]).inc();
+        self.command_duration.observe(seconds);
+    }
+
+    pub fn observe_breaker_transition(&self, state: &str) {
+        self.breaker_transitions.with_label_values(&[state]).inc();
+    }
+
+    pub fn render(&self) -> Result<(String, Vec<u8>), Error> {
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buffer)?;
+        Ok((encoder.format_type().to_string(), buffer))
+    }
+}
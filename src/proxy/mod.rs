@@ -0,0 +1,2495 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{Output, Stdio};
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use failure::{err_msg, Context, Error, ResultExt};
+use futures::future::{self, Either, FutureExt};
+use futures::stream::{self, StreamExt};
+use http::header::{
+    HeaderName, HeaderValue, ALLOW, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST,
+    RETRY_AFTER, TE, USER_AGENT,
+};
+use http::request::Parts;
+use http::uri::{PathAndQuery, Uri};
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::HttpConnector;
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Version};
+use native_tls::{Certificate, Identity, TlsConnector};
+use regex::Regex;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::process::{Child, Command};
+use tokio::time::{delay_for, timeout};
+use tokio_rustls::TlsAcceptor;
+use url::form_urlencoded;
+use uuid::Uuid;
+
+mod access_log;
+mod body_limit;
+mod breaker;
+mod cache;
+mod compression;
+mod concurrency;
+mod connector;
+mod errors;
+mod executable;
+mod headers;
+mod ip_filter;
+mod listener;
+mod metrics;
+mod oauth;
+mod pid_file;
+mod rate_limit;
+mod redirect;
+mod resolver;
+mod response_cache;
+mod retry;
+mod routing;
+mod shutdown;
+mod sigv4;
+#[cfg(target_os = "linux")]
+mod systemd;
+mod token;
+mod token_file;
+mod trace;
+
+use access_log::AccessLogEntry;
+use breaker::CircuitBreaker;
+use cache::{CacheKey, CacheStatus, TokenCache};
+use concurrency::ConcurrencyLimiter;
+use connector::{OverridingConnector, SystemProxy, UpstreamConnector, UpstreamTlsConnector};
+use errors::ErrorKind;
+use listener::PeerAddr;
+use metrics::Metrics;
+use pid_file::PidFile;
+use rate_limit::RateLimiter;
+use resolver::FamilyResolver;
+use response_cache::ResponseCache;
+use retry::ReplayableBody;
+use shutdown::ConnectionCounter;
+use sigv4::CredentialsProvider;
+use token::Token;
+use trace::{Span, SpanKind, Tracer};
+
+pub use access_log::AccessLogFormat;
+pub use concurrency::OverflowMode;
+pub use connector::{ResolveOverride, SocksProxy, TlsVersion};
+pub use errors::{ErrorFormat, ProxyError};
+pub use headers::{parse_header, HostHeaderMode, ResponseHeaderMode};
+pub use ip_filter::Cidr;
+pub use listener::ListenAddr;
+pub use oauth::OAuthParams;
+pub use resolver::AddressFamily;
+pub use routing::{PathPattern, Route};
+pub use sigv4::SigV4Params;
+pub use token::{AuthLocation, Secret, TokenEncoding, TokenFormat};
+
+type HttpsClient = Client<UpstreamTlsConnector, Body>;
+
+const ADMIN_FLUSH_CACHE_PATH: &str = "/admin/flush-cache";
+const ADMIN_MAINTENANCE_PATH: &str = "/admin/maintenance";
+const ADMIN_STATUS_PATH: &str = "/admin/status";
+// Hyper can't read requests with a smaller buffer
+const MIN_MAX_HEADER_SIZE: usize = 8192;
+// Bodies are read whole to sign them, up to this size unless a maximum body size is set
+const MAX_SIGNED_BODY_SIZE: u64 = 16 * 1024 * 1024;
+// How long clients are told to wait before retrying while in maintenance mode
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 60;
+// Also appended to the command line as its last argument
+const AUDIENCE_VAR: &str = "AUTHPROXY_AUDIENCE";
+
+// Bounds for backing off when the token keeps failing to refresh in the background
+const BACKGROUND_RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const BACKGROUND_RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+// Keeps tokens that expire right away from being refreshed in a busy loop
+const BACKGROUND_REFRESH_MIN_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct ProxyParams {
+    pub target_url: String,
+    pub routes: Vec<Route>,
+    pub strip_route_prefix: bool,
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+    pub require_strip_prefix: bool,
+    pub host_header: HostHeaderMode,
+    pub insecure_https: bool,
+    // The native-tls defaults are used when not set
+    pub min_tls_version: Option<TlsVersion>,
+    pub max_tls_version: Option<TlsVersion>,
+    // Sent in SNI and checked against the certificate instead of the target host
+    pub tls_sni: Option<String>,
+    pub ca_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_cert_password: Option<Secret>,
+    pub socks_proxy: Option<SocksProxy>,
+    pub use_system_proxy: bool,
+    pub resolve: Vec<ResolveOverride>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout_secs: Option<u64>,
+    pub upstream_http2: bool,
+    // Both for the connections from clients and the ones to the target
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive_secs: Option<u64>,
+    pub connect_timeout_secs: u64,
+    pub happy_eyeballs_delay_ms: u64,
+    pub source_address: Option<IpAddr>,
+    pub address_family: AddressFamily,
+    pub listen_addr: ListenAddr,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub require_client_cert: bool,
+    pub shutdown_timeout_secs: u64,
+    pub pid_file: Option<PathBuf>,
+    pub listen_backlog: Option<u32>,
+    pub cache_ttl_secs: u64,
+    pub no_cache: bool,
+    pub cache_responses: bool,
+    // Overrides the max-age of successful responses, which aren't cached without either
+    pub response_cache_ttl_secs: Option<u64>,
+    pub response_cache_error_ttl_secs: u64,
+    pub response_cache_max_entries: usize,
+    pub response_cache_max_body_size: u64,
+    pub cache_max_entries: Option<usize>,
+    pub ttl_jitter: f64,
+    pub refresh_ahead: Option<f64>,
+    pub serve_stale_for_secs: u64,
+    pub failure_cache_ttl_secs: u64,
+    pub warm_cache: bool,
+    pub warm_fail_fast: bool,
+    pub background_refresh: bool,
+    pub token_format: TokenFormat,
+    pub token_encoding: TokenEncoding,
+    pub token_field: String,
+    pub token_jsonpath: Option<String>,
+    pub token_regex: Option<Regex>,
+    pub expiry_field: String,
+    pub ttl_from_jwt: bool,
+    pub auth_scheme: String,
+    pub header_name: String,
+    pub auth_location: AuthLocation,
+    pub query_param_name: String,
+    pub cookie_name: String,
+    pub add_headers: Vec<(HeaderName, HeaderValue)>,
+    pub strip_headers: Vec<HeaderName>,
+    pub user_agent: Option<HeaderValue>,
+    // Adds authproxy/VERSION to the client's User-Agent instead
+    pub append_user_agent: bool,
+    pub add_via: bool,
+    pub trust_forwarded: bool,
+    pub add_response_headers: Vec<(HeaderName, HeaderValue)>,
+    pub response_header_mode: ResponseHeaderMode,
+    pub command: Vec<String>,
+    pub command_cwd: Option<PathBuf>,
+    pub skip_command_check: bool,
+    pub command_env: Vec<(String, String)>,
+    pub command_clear_env: bool,
+    pub command_env_request: bool,
+    pub command_env_headers: Vec<HeaderName>,
+    pub audience_header: Option<HeaderName>,
+    // The audiences clients may ask for with the audience header
+    pub allowed_audiences: Vec<String>,
+    pub log_command_stderr: bool,
+    pub log_tokens_unsafe: bool,
+    pub shell: bool,
+    pub oauth: Option<OAuthParams>,
+    // Read instead of running the command when set
+    pub token_file: Option<PathBuf>,
+    pub watch_token_file: bool,
+    // Requests are signed instead of getting a token when set
+    pub sigv4: Option<SigV4Params>,
+    pub transform_command: Option<Vec<String>>,
+    pub command_timeout_secs: u64,
+    pub command_retries: u32,
+    pub command_retry_delay_ms: u64,
+    pub auth_failure_statuses: Vec<u16>,
+    pub max_retry_body_size: u64,
+    pub max_body_size: Option<u64>,
+    pub max_header_size: Option<usize>,
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub max_retry_wait_secs: u64,
+    pub retry_all_methods: bool,
+    pub follow_redirects: bool,
+    pub max_redirects: u32,
+    pub follow_cross_origin: bool,
+    pub upstream_timeout_secs: u64,
+    pub method_timeouts: Vec<(Method, u64)>,
+    pub allow_methods: Vec<Method>,
+    pub block_methods: Vec<Method>,
+    pub allow_cidrs: Vec<Cidr>,
+    pub deny_cidrs: Vec<Cidr>,
+    pub stream_paths: Vec<String>,
+    pub allow_paths: Vec<PathPattern>,
+    pub block_paths: Vec<PathPattern>,
+    pub compress: bool,
+    pub compress_min_size: u64,
+    pub request_id_header: Option<HeaderName>,
+    pub cache_status_header: Option<HeaderName>,
+    pub health_path: String,
+    pub metrics_path: String,
+    pub debug_echo_path: Option<String>,
+    pub admin_token: Option<Secret>,
+    pub maintenance: bool,
+    pub access_log: bool,
+    pub access_log_format: AccessLogFormat,
+    pub error_format: ErrorFormat,
+    pub error_detail: bool,
+    pub rate_limit: Option<f64>,
+    pub rate_burst: Option<u32>,
+    pub max_concurrent_requests: Option<usize>,
+    pub overflow: OverflowMode,
+    pub max_queued_requests: Option<usize>,
+    pub breaker_threshold: Option<u32>,
+    pub breaker_cooldown_secs: u64,
+    pub otlp_endpoint: Option<String>,
+}
+
+impl ProxyParams {
+    // Uses the same defaults as the command line
+    pub fn new(target_url: impl Into<String>, command: Vec<String>) -> Self {
+        ProxyParams {
+            target_url: target_url.into(),
+            routes: Vec::new(),
+            strip_route_prefix: false,
+            strip_prefix: None,
+            add_prefix: None,
+            require_strip_prefix: false,
+            host_header: HostHeaderMode::Remove,
+            insecure_https: false,
+            min_tls_version: None,
+            max_tls_version: None,
+            tls_sni: None,
+            ca_file: None,
+            client_cert: None,
+            client_cert_password: None,
+            socks_proxy: None,
+            use_system_proxy: false,
+            resolve: Vec::new(),
+            pool_max_idle_per_host: None,
+            pool_idle_timeout_secs: None,
+            upstream_http2: false,
+            tcp_nodelay: false,
+            tcp_keepalive_secs: None,
+            connect_timeout_secs: 10,
+            happy_eyeballs_delay_ms: 300,
+            source_address: None,
+            address_family: AddressFamily::Any,
+            listen_addr: ListenAddr::Tcp {
+                host: String::from("127.0.0.1"),
+                port: 4545,
+            },
+            tls_cert: None,
+            tls_key: None,
+            client_ca: None,
+            require_client_cert: false,
+            shutdown_timeout_secs: 30,
+            pid_file: None,
+            listen_backlog: None,
+            cache_ttl_secs: 300,
+            no_cache: false,
+            cache_responses: false,
+            response_cache_ttl_secs: None,
+            response_cache_error_ttl_secs: 0,
+            response_cache_max_entries: 1000,
+            response_cache_max_body_size: 1024 * 1024,
+            cache_max_entries: None,
+            ttl_jitter: 0.0,
+            refresh_ahead: None,
+            serve_stale_for_secs: 0,
+            failure_cache_ttl_secs: 0,
+            warm_cache: false,
+            warm_fail_fast: false,
+            background_refresh: false,
+            token_format: TokenFormat::Raw,
+            token_encoding: TokenEncoding::None,
+            token_field: String::from("access_token"),
+            token_jsonpath: None,
+            token_regex: None,
+            expiry_field: String::from("expires_in"),
+            ttl_from_jwt: false,
+            auth_scheme: String::from("Bearer"),
+            header_name: String::from("Authorization"),
+            auth_location: AuthLocation::Header,
+            query_param_name: String::from("access_token"),
+            cookie_name: String::from("session"),
+            add_headers: Vec::new(),
+            strip_headers: Vec::new(),
+            user_agent: None,
+            append_user_agent: false,
+            add_via: false,
+            trust_forwarded: false,
+            add_response_headers: Vec::new(),
+            response_header_mode: ResponseHeaderMode::Append,
+            command,
+            command_cwd: None,
+            skip_command_check: false,
+            command_env: Vec::new(),
+            command_clear_env: false,
+            command_env_request: false,
+            command_env_headers: Vec::new(),
+            audience_header: None,
+            allowed_audiences: Vec::new(),
+            log_command_stderr: false,
+            log_tokens_unsafe: false,
+            shell: false,
+            oauth: None,
+            token_file: None,
+            watch_token_file: false,
+            sigv4: None,
+            transform_command: None,
+            command_timeout_secs: 30,
+            command_retries: 0,
+            command_retry_delay_ms: 500,
+            auth_failure_statuses: vec![401, 403],
+            max_retry_body_size: 1024 * 1024,
+            max_body_size: None,
+            max_header_size: None,
+            max_retries: 0,
+            retry_base_delay_ms: 100,
+            max_retry_wait_secs: 30,
+            retry_all_methods: false,
+            follow_redirects: false,
+            max_redirects: 5,
+            follow_cross_origin: false,
+            upstream_timeout_secs: 600,
+            stream_paths: Vec::new(),
+            allow_paths: Vec::new(),
+            block_paths: Vec::new(),
+            compress: false,
+            compress_min_size: 1024,
+            allow_methods: Vec::new(),
+            block_methods: Vec::new(),
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            method_timeouts: Vec::new(),
+            request_id_header: Some(HeaderName::from_static("x-request-id")),
+            cache_status_header: None,
+            health_path: String::from("/healthz"),
+            metrics_path: String::from("/metrics"),
+            debug_echo_path: None,
+            admin_token: None,
+            maintenance: false,
+            access_log: false,
+            access_log_format: AccessLogFormat::Combined,
+            error_format: ErrorFormat::Text,
+            error_detail: false,
+            rate_limit: None,
+            rate_burst: None,
+            max_concurrent_requests: None,
+            overflow: OverflowMode::Queue,
+            max_queued_requests: None,
+            breaker_threshold: None,
+            breaker_cooldown_secs: 30,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+fn new_token_cache(params: &ProxyParams, metrics: &Metrics) -> TokenCache {
+    TokenCache::new(
+        Duration::from_secs(params.cache_ttl_secs),
+        params.ttl_jitter,
+        params.refresh_ahead,
+        Duration::from_secs(params.serve_stale_for_secs),
+        Duration::from_secs(params.failure_cache_ttl_secs),
+        params.cache_max_entries,
+        metrics.token_cache_evictions(),
+    )
+}
+
+#[derive(Debug)]
+struct ProxyContext {
+    params: ProxyParams,
+    // The target URL, parsed once at startup
+    target_uri: Uri,
+    cache: TokenCache,
+    // Keyed by the path prefix of routes that have their own command
+    route_caches: HashMap<String, TokenCache>,
+    metrics: Metrics,
+    rate_limiter: Option<RateLimiter>,
+    concurrency_limiter: Option<ConcurrencyLimiter>,
+    // Only set when responses are cached
+    response_cache: Option<ResponseCache>,
+    // Keyed by the target URL, so that a failing route doesn't cut off the others
+    breakers: HashMap<String, CircuitBreaker>,
+    // Only set when spans are exported, requests aren't traced otherwise
+    tracer: Option<Tracer>,
+    // Starts out as given in the params and can be toggled through the admin endpoint
+    maintenance: AtomicBool,
+    aws_credentials: CredentialsProvider,
+}
+
+// A command that can't be run is reported at startup rather than on the first request
+fn check_commands(params: &ProxyParams) -> Result<(), Error> {
+    let token_commands = iter::once(&params.command)
+        .chain(
+            params
+                .routes
+                .iter()
+                .filter_map(|route| route.command.as_ref()),
+        )
+        .filter(|command| !command.is_empty())
+        .map(|command| {
+            if params.shell {
+                shell_command_line(command)
+            } else {
+                command.clone()
+            }
+        });
+    for command in token_commands.chain(params.transform_command.clone()) {
+        executable::check(&command[0], params.command_cwd.as_deref())?;
+    }
+
+    Ok(())
+}
+
+impl ProxyContext {
+    fn new(params: ProxyParams) -> Result<Self, Error> {
+        if params
+            .max_header_size
+            .is_some_and(|size| size < MIN_MAX_HEADER_SIZE)
+        {
+            return Err(err_msg(format!(
+                "The maximum header size must be at least {} bytes",
+                MIN_MAX_HEADER_SIZE
+            )));
+        }
+        if params.debug_echo_path.is_some() && params.admin_token.is_none() {
+            return Err(err_msg("The debug echo path requires an admin token"));
+        }
+        // The connections to the SOCKS proxy are opened by tokio-socks, which can't bind them
+        if params.source_address.is_some() && params.socks_proxy.is_some() {
+            return Err(err_msg(
+                "The source address can't be used with a SOCKS proxy",
+            ));
+        }
+        if params.audience_header.is_some() && params.allowed_audiences.is_empty() {
+            return Err(err_msg("The audience header requires allowed audiences"));
+        }
+        // The command would take them for options, since audiences are passed as arguments
+        let route_audiences = params
+            .routes
+            .iter()
+            .filter_map(|route| route.audience.as_ref());
+        if let Some(audience) = route_audiences
+            .chain(&params.allowed_audiences)
+            .find(|audience| audience.starts_with('-'))
+        {
+            return Err(err_msg(format!("Audience {} can't start with -", audience)));
+        }
+        if !params.skip_command_check {
+            check_commands(&params)?;
+        }
+        let target_uri = params
+            .target_url
+            .parse::<Uri>()
+            .with_context(|_| format!("Invalid target URL {}", params.target_url))?;
+        if target_uri.scheme().is_none() || target_uri.authority().is_none() {
+            return Err(err_msg(format!(
+                "The target URL {} must be absolute",
+                params.target_url
+            )));
+        }
+
+        let metrics = Metrics::new()?;
+        Ok(ProxyContext {
+            target_uri,
+            cache: new_token_cache(&params, &metrics),
+            route_caches: params
+                .routes
+                .iter()
+                .filter(|route| route.command.is_some())
+                .map(|route| {
+                    (
+                        route.path_prefix.clone(),
+                        new_token_cache(&params, &metrics),
+                    )
+                })
+                .collect(),
+            metrics,
+            rate_limiter: params.rate_limit.map(|rate| {
+                let burst = params.rate_burst.unwrap_or_else(|| rate.ceil() as u32);
+                RateLimiter::new(rate, burst)
+            }),
+            concurrency_limiter: params.max_concurrent_requests.map(|max_concurrent| {
+                ConcurrencyLimiter::new(max_concurrent, params.overflow, params.max_queued_requests)
+            }),
+            response_cache: if params.cache_responses {
+                Some(ResponseCache::new(
+                    params.response_cache_ttl_secs.map(Duration::from_secs),
+                    Duration::from_secs(params.response_cache_error_ttl_secs),
+                    params.response_cache_max_entries,
+                    params.response_cache_max_body_size,
+                ))
+            } else {
+                None
+            },
+            breakers: match params.breaker_threshold {
+                Some(threshold) => iter::once(&params.target_url)
+                    .chain(params.routes.iter().map(|route| &route.target_url))
+                    .map(|target_url| {
+                        let cooldown = Duration::from_secs(params.breaker_cooldown_secs);
+                        (target_url.clone(), CircuitBreaker::new(threshold, cooldown))
+                    })
+                    .collect(),
+                None => HashMap::new(),
+            },
+            tracer: params
+                .otlp_endpoint
+                .as_deref()
+                .map(Tracer::new)
+                .transpose()?,
+            maintenance: AtomicBool::new(params.maintenance),
+            aws_credentials: CredentialsProvider::default(),
+            params,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct CommandTimeout {
+    command: String,
+    timeout: Duration,
+}
+
+impl fmt::Display for CommandTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Command `{}` timed out after {} seconds",
+            self.command,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl StdError for CommandTimeout {}
+
+#[cfg(unix)]
+fn kill_command(child: &mut Child) -> io::Result<()> {
+    // The process group has the id of the command, which started it
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_command(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
+
+async fn run_command(
+    ctx: &ProxyContext,
+    command: &[String],
+    input: Option<&[u8]>,
+    env: &[(String, String)],
+    command_timeout: Duration,
+) -> Result<Output, Error> {
+    let mut process = Command::new(&command[0]);
+    if ctx.params.command_clear_env {
+        process.env_clear();
+    }
+    if let Some(ref cwd) = ctx.params.command_cwd {
+        process.current_dir(cwd);
+    }
+    // In its own process group, whatever the command starts can be killed along with it
+    #[cfg(unix)]
+    unsafe {
+        process.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+    // The request metadata comes last so that it can't be overridden
+    let mut child = process
+        .args(&command[1..])
+        .envs(
+            ctx.params
+                .command_env
+                .iter()
+                .chain(env)
+                .map(|(name, value)| (name, value)),
+        )
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Makes sure the subprocess doesn't outlive its future if the request goes away
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let output = async {
+        if let (Some(input), Some(mut stdin)) = (input, stdin) {
+            match stdin.write_all(input).await {
+                // The subprocess exited without reading its input, its exit status will tell more
+                Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+                result => result?,
+            }
+            // Dropping stdin closes the pipe so the subprocess sees EOF
+        }
+
+        let (status, stdout, stderr) =
+            future::try_join3(&mut child, read_pipe(stdout), read_pipe(stderr)).await?;
+        Ok::<_, Error>(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    };
+
+    match timeout(command_timeout, output).await {
+        Ok(output) => output,
+        Err(_) => {
+            let command = command.join(" ");
+            // It could have exited right after timing out, then there's nothing to kill
+            if let Err(e) = kill_command(&mut child) {
+                log::debug!("Failed to kill `{}`: {}", command, e);
+            }
+            // Waiting for it reaps the process, so that timed out commands don't pile up as zombies
+            child.await?;
+            log::warn!("Killed `{}` after it timed out", command);
+            Err(CommandTimeout {
+                command,
+                timeout: command_timeout,
+            }
+            .into())
+        }
+    }
+}
+
+async fn read_pipe<R: AsyncRead + Unpin>(pipe: Option<R>) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut contents).await?;
+    }
+    Ok(contents)
+}
+
+// The first word is the script for the shell, the words after it are quoted so that
+// they reach it as the arguments they were given as
+fn shell_command_line(command: &[String]) -> Vec<String> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut line = command[0].clone();
+    for arg in &command[1..] {
+        line.push(' ');
+        line.push_str(&shell_words::quote(arg));
+    }
+    vec![shell.to_string(), flag.to_string(), line]
+}
+
+// Runs the token command, recording how long it took and how it ended
+async fn run_measured_command(
+    ctx: &ProxyContext,
+    command: &[String],
+    env: &[(String, String)],
+    command_timeout: Duration,
+) -> Result<Output, Error> {
+    let shell_command;
+    let command = if ctx.params.shell {
+        shell_command = shell_command_line(command);
+        &shell_command
+    } else {
+        command
+    };
+
+    let started_at = Instant::now();
+    let result = run_command(ctx, command, None, env, command_timeout).await;
+
+    let outcome = match result {
+        Ok(ref output) if output.status.success() => "success",
+        Err(ref e) if e.downcast_ref::<CommandTimeout>().is_some() => "timeout",
+        _ => "failure",
+    };
+    ctx.metrics
+        .observe_command_run(outcome, started_at.elapsed().as_secs_f64());
+
+    result
+}
+
+// Commands often explain on stderr why they failed, or warn there while succeeding
+fn check_command_output(ctx: &ProxyContext, output: &Output, action: &str) -> Result<(), Error> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim_end();
+
+    if output.status.success() {
+        if ctx.params.log_command_stderr && !stderr.is_empty() {
+            log::debug!("Command stderr: {}", stderr);
+        }
+        return Ok(());
+    }
+
+    if !stderr.is_empty() {
+        log::error!("Command stderr: {}", stderr);
+    }
+    Err(err_msg(format!(
+        "Failed to {}, command exited with {}",
+        action, output.status
+    )))
+}
+
+async fn run_token_command(
+    ctx: &ProxyContext,
+    command: &[String],
+    env: &[(String, String)],
+) -> Result<Token, Error> {
+    log::debug!("Running the command to obtain the authorization header");
+    // A shell would interpret the audience, so it only gets the environment variable
+    let command_with_audience;
+    let command = match env.iter().find(|(name, _)| name == AUDIENCE_VAR) {
+        Some((_, audience)) if !ctx.params.shell => {
+            command_with_audience = [command, slice::from_ref(audience)].concat();
+            &command_with_audience
+        }
+        _ => command,
+    };
+    let command_timeout = Duration::from_secs(ctx.params.command_timeout_secs);
+    let mut attempt = 0;
+    let mut output = loop {
+        let output = run_measured_command(ctx, command, env, command_timeout).await?;
+        match check_command_output(ctx, &output, "obtain the header value") {
+            Ok(()) => break output,
+            Err(e) if attempt >= ctx.params.command_retries => return Err(e),
+            Err(_) => {}
+        }
+        attempt += 1;
+        log::warn!(
+            "Command failed with {}, retrying ({}/{})",
+            output.status,
+            attempt,
+            ctx.params.command_retries
+        );
+        delay_for(Duration::from_millis(ctx.params.command_retry_delay_ms)).await;
+    };
+
+    if let Some(ref transform_command) = ctx.params.transform_command {
+        log::debug!("Running the transform command on the obtained value");
+        output = run_command(
+            ctx,
+            transform_command,
+            Some(&output.stdout),
+            &[],
+            command_timeout,
+        )
+        .await?;
+        check_command_output(ctx, &output, "transform the header value")?;
+    }
+
+    parse_token(ctx, output.stdout)
+}
+
+fn parse_token(ctx: &ProxyContext, output: Vec<u8>) -> Result<Token, Error> {
+    token::parse_token(
+        output,
+        ctx.params.token_format,
+        &ctx.params.token_field,
+        ctx.params.token_jsonpath.as_deref(),
+        ctx.params.token_regex.as_ref(),
+        &ctx.params.expiry_field,
+    )
+}
+
+// Request metadata for the command, which the token is then cached by, or None when the
+// client asks for an audience that isn't allowed
+fn command_env(ctx: &ProxyContext, route: Option<&Route>, req: &Request<Body>) -> Option<CacheKey> {
+    let mut env = Vec::new();
+    if ctx.params.command_env_request {
+        env.push((
+            String::from("AUTHPROXY_REQUEST_METHOD"),
+            req.method().to_string(),
+        ));
+        env.push((
+            String::from("AUTHPROXY_REQUEST_PATH"),
+            req.uri().path().to_string(),
+        ));
+    }
+    for name in &ctx.params.command_env_headers {
+        if let Some(value) = req.headers().get(name).and_then(|v| v.to_str().ok()) {
+            let var_name = format!(
+                "AUTHPROXY_HEADER_{}",
+                name.as_str().to_uppercase().replace('-', "_")
+            );
+            env.push((var_name, value.to_string()));
+        }
+    }
+    // The header lets clients ask for a different audience than the route's
+    let requested_audience = ctx
+        .params
+        .audience_header
+        .as_ref()
+        .and_then(|name| req.headers().get(name));
+    let audience = match requested_audience {
+        Some(value) => {
+            let allowed = value.to_str().ok().filter(|audience| {
+                ctx.params
+                    .allowed_audiences
+                    .iter()
+                    .any(|allowed| allowed == audience)
+            });
+            if allowed.is_none() {
+                log::warn!("Rejecting the request for audience {:?}", value);
+                return None;
+            }
+            allowed
+        }
+        None => route.and_then(|route| route.audience.as_deref()),
+    };
+    if let Some(audience) = audience {
+        env.push((String::from(AUDIENCE_VAR), audience.to_string()));
+    }
+
+    Some(env)
+}
+
+// Routes with their own command get their own tokens, the others share the global ones
+fn token_cache(ctx: &'static ProxyContext, route: Option<&Route>) -> &'static TokenCache {
+    route
+        .and_then(|route| ctx.route_caches.get(&route.path_prefix))
+        .unwrap_or(&ctx.cache)
+}
+
+async fn fetch_token(
+    ctx: &'static ProxyContext,
+    client: Arc<HttpsClient>,
+    route: Option<&'static Route>,
+    env: CacheKey,
+) -> Result<Token, Error> {
+    let route_command = route.and_then(|route| route.command.as_deref());
+    let mut token = match (route_command, &ctx.params.oauth, &ctx.params.token_file) {
+        (Some(command), _, _) => run_token_command(ctx, command, &env).await?,
+        (None, Some(oauth_params), _) => {
+            log::debug!("Requesting a token from {}", oauth_params.token_url);
+            let request_timeout = Duration::from_secs(ctx.params.command_timeout_secs);
+            oauth::fetch_token(&client, oauth_params, request_timeout).await?
+        }
+        (None, None, Some(path)) => {
+            log::debug!("Reading the token from {}", path.display());
+            parse_token(ctx, token_file::read(path).await?)?
+        }
+        (None, None, None) => run_token_command(ctx, &ctx.params.command, &env).await?,
+    };
+
+    // Read before the value is encoded, which would make it unreadable as a JWT
+    if ctx.params.ttl_from_jwt && token.ttl.is_none() {
+        token.ttl = token::jwt_ttl(&token.value);
+        if token.ttl.is_none() {
+            log::warn!("Failed to read the expiry of the token as a JWT, using the cache ttl");
+        }
+    }
+    token.value = token::encode_token(token.value, ctx.params.token_encoding)?;
+
+    Ok(token)
+}
+
+// Keeps the token fresh for requests without metadata for the command, so that they
+// never wait for it even when they are too rare to trigger refreshing it ahead of time
+async fn refresh_in_background(ctx: &'static ProxyContext, client: Arc<HttpsClient>) {
+    let mut retry_delay = BACKGROUND_RETRY_MIN_DELAY;
+    loop {
+        if let Some(refresh_in) = ctx.cache.next_refresh_in(&CacheKey::new()).await {
+            delay_for(refresh_in.max(BACKGROUND_REFRESH_MIN_DELAY)).await;
+        }
+
+        let result = ctx
+            .cache
+            .refresh_if_due(CacheKey::new(), || {
+                fetch_token(ctx, client.clone(), None, CacheKey::new())
+            })
+            .await;
+        match result {
+            Ok(()) => retry_delay = BACKGROUND_RETRY_MIN_DELAY,
+            Err(e) => {
+                log::warn!(
+                    "Failed to refresh the token in the background, retrying in {} seconds: {}",
+                    retry_delay.as_secs(),
+                    e
+                );
+                delay_for(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(BACKGROUND_RETRY_MAX_DELAY);
+            }
+        }
+    }
+}
+
+fn finish_span(ctx: &ProxyContext, span: Option<Span>) {
+    if let (Some(tracer), Some(span)) = (&ctx.tracer, span) {
+        tracer.finish(span);
+    }
+}
+
+async fn obtain_token(
+    ctx: &'static ProxyContext,
+    client: &Arc<HttpsClient>,
+    route: Option<&'static Route>,
+    env: &CacheKey,
+    parent_span: Option<&Span>,
+) -> Result<(String, CacheStatus), Error> {
+    let mut span = parent_span.map(|span| span.child("obtain token", SpanKind::Internal));
+    let result = if ctx.params.no_cache {
+        fetch_token(ctx, client.clone(), route, env.clone())
+            .await
+            .map(|token| (token.value, CacheStatus::Miss))
+    } else {
+        token_cache(ctx, route)
+            .get_or_refresh(env.clone(), || {
+                fetch_token(ctx, client.clone(), route, env.clone())
+            })
+            .await
+    };
+    if let Some(ref mut span) = span {
+        match result {
+            Ok((_, cache_status)) => {
+                span.set_attribute("authproxy.token_cache", cache_status.as_str())
+            }
+            Err(_) => span.set_failed(),
+        }
+    }
+    finish_span(ctx, span);
+
+    let (token, cache_status) = result.context(ErrorKind::Token)?;
+    ctx.metrics.observe_token_lookup(cache_status);
+
+    Ok((token, cache_status))
+}
+
+fn insert_token(
+    ctx: &ProxyContext,
+    request_parts: &mut Parts,
+    token_value: String,
+) -> Result<(), Error> {
+    let logged_token = if ctx.params.log_tokens_unsafe {
+        token_value.clone()
+    } else {
+        token::redact(&token_value)
+    };
+    if ctx.params.auth_location == AuthLocation::Cookie {
+        log::debug!(
+            "Will use token: `{}` in cookie {}",
+            logged_token,
+            ctx.params.cookie_name
+        );
+        return headers::set_cookie(
+            &mut request_parts.headers,
+            &ctx.params.cookie_name,
+            &token_value,
+        );
+    }
+    if ctx.params.auth_location == AuthLocation::Query {
+        log::debug!(
+            "Will use token: `{}` in query parameter {}",
+            logged_token,
+            ctx.params.query_param_name
+        );
+        // A url-encoded token is fit for the query already, encoding it again would change it
+        let value: String = match ctx.params.token_encoding {
+            TokenEncoding::UrlEncode => token_value,
+            _ => form_urlencoded::byte_serialize(token_value.as_bytes()).collect(),
+        };
+        let mut uri_parts = request_parts.uri.clone().into_parts();
+        let path_and_query = uri_parts
+            .path_and_query
+            .unwrap_or_else(|| PathAndQuery::from_static("/"));
+        uri_parts.path_and_query = Some(routing::set_query_param(
+            &path_and_query,
+            &ctx.params.query_param_name,
+            &value,
+        )?);
+        request_parts.uri = Uri::from_parts(uri_parts)?;
+        return Ok(());
+    }
+
+    let token_header = if ctx.params.auth_scheme.is_empty() {
+        log::debug!("Will use token: `{}`", logged_token);
+        token_value
+    } else {
+        log::debug!(
+            "Will use token: `{} {}`",
+            ctx.params.auth_scheme,
+            logged_token
+        );
+        format!("{} {}", ctx.params.auth_scheme, token_value)
+    };
+    request_parts.headers.insert(
+        HeaderName::from_bytes(ctx.params.header_name.as_bytes())?,
+        HeaderValue::from_str(&token_header)?,
+    );
+
+    Ok(())
+}
+
+fn copy_request_parts(parts: &Parts) -> Parts {
+    let mut request = Request::new(());
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    request.into_parts().0
+}
+
+// The route's timeout wins over the method's, which wins over the global one
+fn upstream_timeout_secs(ctx: &ProxyContext, route: Option<&Route>, method: &Method) -> u64 {
+    route
+        .and_then(|route| route.timeout_secs)
+        .or_else(|| {
+            ctx.params
+                .method_timeouts
+                .iter()
+                .find(|(timeout_method, _)| timeout_method == method)
+                .map(|&(_, secs)| secs)
+        })
+        .unwrap_or(ctx.params.upstream_timeout_secs)
+}
+
+async fn forward_request(
+    ctx: &ProxyContext,
+    client: &HttpsClient,
+    outgoing_request: Request<Body>,
+    upstream_timeout_secs: u64,
+) -> Result<Response<Body>, Error> {
+    let started_at = Instant::now();
+    let result = match upstream_timeout_secs {
+        0 => Ok(client.request(outgoing_request).await),
+        secs => timeout(Duration::from_secs(secs), client.request(outgoing_request)).await,
+    };
+    ctx.metrics
+        .observe_upstream_duration(started_at.elapsed().as_secs_f64());
+
+    match result {
+        Ok(response) => Ok(response.context(ErrorKind::Upstream)?),
+        Err(_) => {
+            log::warn!(
+                "Target didn't respond within {} seconds",
+                upstream_timeout_secs
+            );
+            // A response rather than an error, since the request did reach the target
+            Ok(errors::error_response(
+                &Error::from(Context::new(ErrorKind::UpstreamTimeout)),
+                ctx.params.error_format,
+                ctx.params.error_detail,
+            ))
+        }
+    }
+}
+
+// Sends the request, retrying connection failures and responses asking to try again later
+// when the body can be sent again
+async fn send_request(
+    ctx: &ProxyContext,
+    client: &HttpsClient,
+    request_parts: &Parts,
+    body: &mut ReplayableBody,
+    upstream_timeout_secs: u64,
+    parent_span: Option<&Span>,
+) -> Result<Response<Body>, Error> {
+    let mut span = parent_span.map(|span| span.child("upstream request", SpanKind::Client));
+    if let Some(ref mut span) = span {
+        span.set_attribute("http.method", &request_parts.method);
+        span.set_attribute("http.url", loggable_uri(ctx, &request_parts.uri));
+    }
+
+    let result =
+        send_request_with_retries(ctx, client, request_parts, body, upstream_timeout_secs).await;
+    if let Some(ref mut span) = span {
+        match result {
+            Ok(ref response) => span.set_attribute("http.status_code", response.status().as_u16()),
+            Err(_) => span.set_failed(),
+        }
+    }
+    finish_span(ctx, span);
+
+    result
+}
+
+async fn send_request_with_retries(
+    ctx: &ProxyContext,
+    client: &HttpsClient,
+    request_parts: &Parts,
+    body: &mut ReplayableBody,
+    upstream_timeout_secs: u64,
+) -> Result<Response<Body>, Error> {
+    let max_retries = if body.is_replayable()
+        && (ctx.params.retry_all_methods || retry::is_idempotent(&request_parts.method))
+    {
+        ctx.params.max_retries
+    } else {
+        0
+    };
+
+    let mut attempt = 0;
+    loop {
+        let body = body
+            .take()
+            .ok_or_else(|| err_msg("The request body has already been sent"))?;
+        let outgoing_request = Request::from_parts(copy_request_parts(request_parts), body);
+
+        match forward_request(ctx, client, outgoing_request, upstream_timeout_secs).await {
+            Err(ref err)
+                if attempt < max_retries
+                    && retry::is_retryable_error(err, &request_parts.method) =>
+            {
+                let delay = retry::backoff_delay(
+                    Duration::from_millis(ctx.params.retry_base_delay_ms),
+                    attempt,
+                );
+                log::warn!(
+                    "Request to the target failed, retrying in {}ms: {}",
+                    delay.as_millis(),
+                    err
+                );
+                delay_for(delay).await;
+                attempt += 1;
+            }
+            Ok(ref response)
+                if attempt < max_retries && retry::is_retryable_status(response.status()) =>
+            {
+                let delay = match retry::retry_after(response.headers()) {
+                    Some(delay) => delay.min(Duration::from_secs(ctx.params.max_retry_wait_secs)),
+                    None => retry::backoff_delay(
+                        Duration::from_millis(ctx.params.retry_base_delay_ms),
+                        attempt,
+                    ),
+                };
+                log::warn!(
+                    "Target responded with {}, retrying in {}ms",
+                    response.status(),
+                    delay.as_millis()
+                );
+                delay_for(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Returns the response to send if the request doesn't have the right admin token
+fn check_admin_token(
+    admin_token: &str,
+    req: &Request<Body>,
+) -> Result<Option<Response<Body>>, Error> {
+    let expected = format!("Bearer {}", admin_token);
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()));
+    if authorized {
+        return Ok(None);
+    }
+
+    log::warn!("Rejected an admin request with a missing or wrong token");
+    Ok(Some(
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from("Forbidden"))?,
+    ))
+}
+
+async fn flush_cache(
+    ctx: &ProxyContext,
+    admin_token: &str,
+    req: &Request<Body>,
+) -> Result<Response<Body>, Error> {
+    if let Some(response) = check_admin_token(admin_token, req)? {
+        return Ok(response);
+    }
+
+    ctx.cache.clear();
+    for cache in ctx.route_caches.values() {
+        cache.clear();
+    }
+    log::info!("Token cache flushed through the admin endpoint");
+    Ok(Response::new(Body::from("ok")))
+}
+
+async fn cache_status_json(cache: &TokenCache, route: Option<&str>) -> Vec<Value> {
+    let mut tokens = Vec::new();
+    for (key, age_and_ttl) in cache.entries().await {
+        let env = key
+            .into_iter()
+            .map(|(name, value)| (name, Value::from(value)))
+            .collect::<serde_json::Map<_, _>>();
+        tokens.push(match age_and_ttl {
+            Some((age, ttl)) => json!({
+                "route": route,
+                "env": env,
+                "cached": age < ttl,
+                "age_secs": age.as_secs(),
+                "ttl_secs": ttl.as_secs(),
+                "expires_in_secs": ttl.saturating_sub(age).as_secs(),
+            }),
+            None => json!({"route": route, "env": env, "cached": false}),
+        });
+    }
+    tokens
+}
+
+// Reports on the cached tokens without revealing them
+async fn admin_status(
+    ctx: &ProxyContext,
+    admin_token: &str,
+    req: &Request<Body>,
+) -> Result<Response<Body>, Error> {
+    if let Some(response) = check_admin_token(admin_token, req)? {
+        return Ok(response);
+    }
+
+    let mut tokens = cache_status_json(&ctx.cache, None).await;
+    let mut routes = ctx.route_caches.iter().collect::<Vec<_>>();
+    routes.sort_by_key(|&(path_prefix, _)| path_prefix);
+    for (path_prefix, cache) in routes {
+        tokens.extend(cache_status_json(cache, Some(path_prefix)).await);
+    }
+    let status = json!({
+        "maintenance": ctx.maintenance.load(Ordering::SeqCst),
+        "tokens": tokens,
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(status.to_string()))?)
+}
+
+// POST turns maintenance mode on and DELETE turns it off
+fn toggle_maintenance(
+    ctx: &ProxyContext,
+    admin_token: &str,
+    req: &Request<Body>,
+) -> Result<Response<Body>, Error> {
+    if let Some(response) = check_admin_token(admin_token, req)? {
+        return Ok(response);
+    }
+
+    let enabled = req.method() == Method::POST;
+    if ctx.maintenance.swap(enabled, Ordering::SeqCst) != enabled {
+        log::info!(
+            "Maintenance mode turned {} through the admin endpoint",
+            if enabled { "on" } else { "off" }
+        );
+    }
+    Ok(Response::new(Body::from("ok")))
+}
+
+fn is_method_allowed(params: &ProxyParams, method: &Method) -> bool {
+    (params.allow_methods.is_empty() || params.allow_methods.contains(method))
+        && !params.block_methods.contains(method)
+}
+
+fn method_not_allowed_response(params: &ProxyParams) -> Result<Response<Body>, Error> {
+    // Without an allowlist, all the standard methods but the blocked ones are allowed
+    let standard_methods = [
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PUT,
+        Method::DELETE,
+        Method::CONNECT,
+        Method::OPTIONS,
+        Method::TRACE,
+        Method::PATCH,
+    ];
+    let candidates = if params.allow_methods.is_empty() {
+        &standard_methods[..]
+    } else {
+        &params.allow_methods[..]
+    };
+    let allowed = candidates
+        .iter()
+        .filter(|method| is_method_allowed(params, method))
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Ok(Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(ALLOW, allowed)
+        .body(Body::from("Method not allowed"))?)
+}
+
+fn forbidden_response() -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::from("Forbidden"))?)
+}
+
+fn maintenance_response() -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(RETRY_AFTER, MAINTENANCE_RETRY_AFTER_SECS.to_string())
+        .body(Body::from("Down for maintenance"))?)
+}
+
+// The URI with the token in the query redacted, for logs and traces
+fn loggable_uri(ctx: &ProxyContext, uri: &Uri) -> String {
+    let path_and_query = match (
+        ctx.params.auth_location,
+        ctx.params.log_tokens_unsafe,
+        uri.path_and_query(),
+    ) {
+        (AuthLocation::Query, false, Some(path_and_query)) => path_and_query,
+        _ => return uri.to_string(),
+    };
+    let value = match routing::query_param(path_and_query, &ctx.params.query_param_name) {
+        Some(value) => value,
+        None => return uri.to_string(),
+    };
+
+    let redacted: String =
+        form_urlencoded::byte_serialize(token::redact(&value).as_bytes()).collect();
+    let mut uri_parts = uri.clone().into_parts();
+    routing::set_query_param(path_and_query, &ctx.params.query_param_name, &redacted)
+        .ok()
+        .and_then(|path_and_query| {
+            uri_parts.path_and_query = Some(path_and_query);
+            Uri::from_parts(uri_parts).ok()
+        })
+        // Without the query rather than with the token, should it fail to be rewritten
+        .map_or_else(|| uri.path().to_string(), |uri| uri.to_string())
+}
+
+fn debug_echo_response(ctx: &ProxyContext, request_parts: &Parts) -> Result<Response<Body>, Error> {
+    let is_secret = |name: &HeaderName| {
+        name.as_str().eq_ignore_ascii_case(&ctx.params.header_name)
+            || (ctx.params.auth_location == AuthLocation::Cookie && name == COOKIE)
+            || (ctx.params.sigv4.is_some()
+                && (name == AUTHORIZATION || name == "x-amz-security-token"))
+            // Static headers often carry credentials as well
+            || ctx
+                .params
+                .add_headers
+                .iter()
+                .any(|(added, _)| added == name)
+    };
+    let headers: Vec<Value> = request_parts
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let value = if !is_secret(name) || ctx.params.log_tokens_unsafe {
+                value.into_owned()
+            } else {
+                // Keeps the scheme visible, since it's not the secret part
+                match value.split_once(' ') {
+                    Some((scheme, token)) if scheme == ctx.params.auth_scheme => {
+                        format!("{} {}", scheme, token::redact(token))
+                    }
+                    _ => token::redact(&value),
+                }
+            };
+            json!({"name": name.as_str(), "value": value})
+        })
+        .collect();
+
+    let echo = json!({
+        "method": request_parts.method.as_str(),
+        "uri": loggable_uri(ctx, &request_parts.uri),
+        "headers": headers,
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(echo.to_string()))?)
+}
+
+// Compares without returning early so the time taken doesn't give away how much of a secret matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Keeps the id the client sent, otherwise generates one and adds it to the request
+fn request_id(name: &HeaderName, req: &mut Request<Body>) -> Result<String, Error> {
+    if let Some(id) = req
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+    {
+        return Ok(id.to_string());
+    }
+
+    let id = Uuid::new_v4().to_string();
+    req.headers_mut()
+        .insert(name.clone(), HeaderValue::from_str(&id)?);
+    Ok(id)
+}
+
+async fn handle_request(
+    ctx: &'static ProxyContext,
+    client: Arc<HttpsClient>,
+    peer_addr: Option<SocketAddr>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, Error> {
+    // Answered before anything else so a failing command doesn't fail the health checks
+    if !ctx.params.health_path.is_empty()
+        && req.method() == Method::GET
+        && req.uri().path() == ctx.params.health_path
+    {
+        return Ok(Response::new(Body::from("ok")));
+    }
+
+    if !ctx.params.metrics_path.is_empty()
+        && req.method() == Method::GET
+        && req.uri().path() == ctx.params.metrics_path
+    {
+        let (content_type, metrics) = ctx.metrics.render()?;
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(metrics))?);
+    }
+
+    if let Some(ref admin_token) = ctx.params.admin_token {
+        if req.uri().path() == ADMIN_FLUSH_CACHE_PATH && req.method() == Method::POST {
+            return flush_cache(ctx, admin_token.expose(), &req).await;
+        }
+        if req.uri().path() == ADMIN_MAINTENANCE_PATH
+            && (req.method() == Method::POST || req.method() == Method::DELETE)
+        {
+            return toggle_maintenance(ctx, admin_token.expose(), &req);
+        }
+        if req.uri().path() == ADMIN_STATUS_PATH && req.method() == Method::GET {
+            return admin_status(ctx, admin_token.expose(), &req).await;
+        }
+    }
+
+    // Requests under the debug echo path are transformed as if they were for the rest of their path,
+    // and then sent back instead of to the target
+    let debug_echo = match (
+        &ctx.params.debug_echo_path,
+        &ctx.params.admin_token,
+        req.uri().path_and_query(),
+    ) {
+        (Some(prefix), Some(admin_token), Some(path_and_query)) => {
+            match routing::rewrite_path(path_and_query, Some(prefix), None)? {
+                Some(rewritten) => {
+                    // The echo shows the configured headers, so it's only for admins
+                    if let Some(response) = check_admin_token(admin_token.expose(), &req)? {
+                        return Ok(response);
+                    }
+                    // The admin token is meant for the proxy, clients don't send it to the target
+                    req.headers_mut().remove(AUTHORIZATION);
+                    let mut uri_parts = req.uri().clone().into_parts();
+                    uri_parts.path_and_query = Some(rewritten);
+                    *req.uri_mut() = Uri::from_parts(uri_parts)?;
+                    true
+                }
+                None => false,
+            }
+        }
+        _ => false,
+    };
+
+    ctx.metrics.observe_request();
+    let request_id = match ctx.params.request_id_header {
+        Some(ref name) => Some(request_id(name, &mut req)?),
+        None => None,
+    };
+    let access_log_entry = if ctx.params.access_log {
+        Some(AccessLogEntry::new(&req, peer_addr, request_id.clone()))
+    } else {
+        None
+    };
+    let mut span = ctx
+        .tracer
+        .as_ref()
+        .map(|_| Span::for_request("proxy request", req.headers()));
+    if let Some(ref mut span) = span {
+        span.set_attribute("http.method", req.method());
+        span.set_attribute("http.target", req.uri());
+    }
+
+    let maintenance = ctx.maintenance.load(Ordering::SeqCst);
+    // Clients without an address, such as the ones connected over a unix socket, aren't limited
+    let rate_limit_result = match (&ctx.rate_limiter, peer_addr) {
+        (Some(rate_limiter), Some(addr)) if !maintenance => rate_limiter.check(addr.ip()),
+        _ => Ok(()),
+    };
+
+    let mut result = match rate_limit_result {
+        // Neither the command nor the target are needed to answer
+        Ok(()) if maintenance => maintenance_response(),
+        Ok(()) if !is_method_allowed(&ctx.params, req.method()) => {
+            log::debug!("Method {} is not allowed", req.method());
+            method_not_allowed_response(&ctx.params)
+        }
+        Ok(())
+            if !routing::is_path_allowed(
+                &ctx.params.allow_paths,
+                &ctx.params.block_paths,
+                req.uri().path(),
+            ) =>
+        {
+            log::debug!("Path {} is blocked", req.uri().path());
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not found"))
+                .map_err(Error::from)
+        }
+        Ok(()) => {
+            limited_proxy_request(ctx, client, peer_addr, req, debug_echo, span.as_ref()).await
+        }
+        Err(retry_after) => {
+            log::debug!("Rate limit exceeded for {:?}", peer_addr);
+            Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())
+                .body(Body::from("Too many requests"))
+                .map_err(Error::from)
+        }
+    };
+
+    if let (Some(name), Some(id), Ok(response)) =
+        (&ctx.params.request_id_header, &request_id, &mut result)
+    {
+        response
+            .headers_mut()
+            .insert(name, HeaderValue::from_str(id)?);
+    }
+
+    ctx.metrics
+        .observe_response(result.as_ref().ok().map(Response::status));
+    if let Some(entry) = access_log_entry {
+        entry.log(ctx.params.access_log_format, result.as_ref().ok());
+    }
+    if let Some(ref mut span) = span {
+        match result {
+            Ok(ref response) => {
+                span.set_attribute("http.status_code", response.status().as_u16());
+                if response.status().is_server_error() {
+                    span.set_failed();
+                }
+            }
+            Err(_) => span.set_failed(),
+        }
+    }
+    finish_span(ctx, span);
+
+    result
+}
+
+async fn limited_proxy_request(
+    ctx: &'static ProxyContext,
+    client: Arc<HttpsClient>,
+    peer_addr: Option<SocketAddr>,
+    req: Request<Body>,
+    debug_echo: bool,
+    span: Option<&Span>,
+) -> Result<Response<Body>, Error> {
+    let _permit = match ctx.concurrency_limiter {
+        Some(ref limiter) => match limiter.acquire().await {
+            Some(permit) => Some(permit),
+            None => {
+                log::debug!(
+                    "Too many concurrent requests, rejecting one from {:?}",
+                    peer_addr
+                );
+                return Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::from("Too many concurrent requests"))
+                    .map_err(Error::from);
+            }
+        },
+        None => None,
+    };
+
+    match proxy_request(ctx, client, peer_addr, req, debug_echo, span).await {
+        Err(ref err) if body_limit::is_body_too_large(err) => body_limit::too_large_response(),
+        result => result,
+    }
+}
+
+async fn sign_request(
+    ctx: &ProxyContext,
+    client: &HttpsClient,
+    request_parts: &mut Parts,
+    body: &ReplayableBody,
+) -> Result<(), Error> {
+    if let Some(ref sigv4_params) = ctx.params.sigv4 {
+        let credentials = ctx.aws_credentials.credentials(client).await?;
+        sigv4::sign(
+            request_parts,
+            body.buffered().map(|bytes| &bytes[..]),
+            sigv4_params,
+            &credentials,
+            SystemTime::now(),
+        )?;
+    }
+
+    Ok(())
+}
+
+// Bad gateway, service unavailable and gateway timeout responses count as failures as well
+fn record_upstream_result(
+    ctx: &ProxyContext,
+    permit: Option<breaker::Permit>,
+    result: &Result<Response<Body>, Error>,
+) {
+    let success = match result {
+        Ok(response) => !matches!(response.status().as_u16(), 502..=504),
+        // The request didn't get to the target, so it tells nothing about it
+        Err(err) if body_limit::is_body_too_large(err) => return,
+        Err(_) => false,
+    };
+    if let Some(transition) = permit.and_then(|permit| permit.record(success)) {
+        ctx.metrics.observe_breaker_transition(transition.as_str());
+    }
+}
+
+async fn proxy_request(
+    ctx: &'static ProxyContext,
+    client: Arc<HttpsClient>,
+    peer_addr: Option<SocketAddr>,
+    req: Request<Body>,
+    debug_echo: bool,
+    span: Option<&Span>,
+) -> Result<Response<Body>, Error> {
+    let route = routing::find_route(&ctx.params.routes, req.uri().path());
+    let target_url = route.map_or(&ctx.params.target_url, |route| &route.target_url);
+    let target_uri = route.map_or(&ctx.target_uri, |route| &route.target_uri);
+    let mut target_uri_parts = req.uri().clone().into_parts();
+    target_uri_parts.scheme = target_uri.scheme().cloned();
+    target_uri_parts.authority = target_uri.authority().cloned();
+    if let (Some(route), Some(path_and_query)) = (route, &target_uri_parts.path_and_query) {
+        log::debug!("Matched route {:?}", route);
+        if ctx.params.strip_route_prefix {
+            target_uri_parts.path_and_query = Some(route.strip_prefix(path_and_query)?);
+        }
+    }
+    if let Some(ref path_and_query) = target_uri_parts.path_and_query {
+        let strip_prefix = ctx.params.strip_prefix.as_deref();
+        let add_prefix = ctx.params.add_prefix.as_deref();
+        if strip_prefix.is_some() || add_prefix.is_some() {
+            match routing::rewrite_path(path_and_query, strip_prefix, add_prefix)? {
+                Some(rewritten) => target_uri_parts.path_and_query = Some(rewritten),
+                None if ctx.params.require_strip_prefix => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("Not found"))?);
+                }
+                None => {}
+            }
+        }
+    }
+
+    // Streaming responses are only bounded until their headers arrive
+    let streaming = routing::matches_any_prefix(&ctx.params.stream_paths, req.uri().path());
+    let command_env = match command_env(ctx, route, &req) {
+        Some(env) => env,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("Audience not allowed"))?);
+        }
+    };
+    let (mut request_parts, mut body) = req.into_parts();
+    request_parts.uri = Uri::from_parts(target_uri_parts)?;
+    let client_version = request_parts.version;
+    let accepts_trailers = headers::accepts_trailers(&request_parts.headers);
+    // Trailers only make it through when both sides talk HTTP/2 and the bodies are left
+    // as they are, hyper drops them on HTTP/1 connections
+    let passes_trailers =
+        ctx.params.upstream_http2 && client_version == Version::HTTP_2 && accepts_trailers;
+    // Any request body can end with trailers, without declaring them first,
+    // so those bodies are only wrapped or buffered where that can't be helped
+    let may_send_trailers = ctx.params.upstream_http2 && client_version == Version::HTTP_2;
+    // Responses to HEAD requests have no body, but still the headers of the uncompressed one
+    let compress = ctx.params.compress
+        && !passes_trailers
+        && request_parts.method != Method::HEAD
+        && compression::accepts_gzip(&request_parts.headers);
+    headers::remove_hop_by_hop_headers(&mut request_parts.headers);
+    if ctx.params.upstream_http2 {
+        request_parts.version = Version::HTTP_2;
+        // The only TE allowed in HTTP/2, which gRPC targets insist on
+        if accepts_trailers {
+            request_parts
+                .headers
+                .insert(TE, HeaderValue::from_static("trailers"));
+        }
+    }
+
+    // Done before the host header is changed, since X-Forwarded-Host is taken from it
+    let proto = if ctx.params.tls_cert.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    headers::set_forwarded_headers(
+        &mut request_parts.headers,
+        peer_addr.map(|addr| addr.ip()),
+        proto,
+        ctx.params.trust_forwarded,
+    )?;
+
+    match ctx.params.host_header {
+        // The incoming host header will very likely be considered incorrect by the target server
+        HostHeaderMode::Remove => {
+            request_parts.headers.remove(HOST);
+        }
+        // HTTP/2 sends the authority of the URI instead
+        HostHeaderMode::Target if ctx.params.upstream_http2 => {
+            request_parts.headers.remove(HOST);
+        }
+        HostHeaderMode::Target => {
+            if let Some(authority) = target_uri.authority() {
+                request_parts
+                    .headers
+                    .insert(HOST, HeaderValue::from_str(authority.as_str())?);
+            }
+        }
+        HostHeaderMode::Preserve => {}
+    }
+    // Done before the token is inserted so that the token header can't be stripped
+    for name in &ctx.params.strip_headers {
+        request_parts.headers.remove(name);
+    }
+    if let Some(ref user_agent) = ctx.params.user_agent {
+        request_parts.headers.insert(USER_AGENT, user_agent.clone());
+    } else if ctx.params.append_user_agent {
+        headers::append_user_agent(&mut request_parts.headers)?;
+    }
+    if ctx.params.add_via {
+        headers::append_via(&mut request_parts.headers, client_version)?;
+    }
+
+    if let Some(max_body_size) = ctx.params.max_body_size {
+        // Bodies of a known size can't turn out larger, so only the others are counted as they
+        // stream, except for those passing trailers on, which would be dropped by counting
+        if body.size_hint().lower() > max_body_size {
+            return body_limit::too_large_response();
+        } else if body.size_hint().exact().is_none() && !may_send_trailers {
+            body = body_limit::limit_body(body, max_body_size);
+        }
+    }
+
+    let has_body = body.size_hint().exact() != Some(0);
+
+    // GETs without a body are answered from the response cache while the response is fresh,
+    // unless the client asks for a response from the target or sends its own credentials along.
+    // The token is only inserted below, so these are the client's.
+    let response_cache_key = match ctx.response_cache {
+        Some(_)
+            if request_parts.method == Method::GET
+                && !has_body
+                && !streaming
+                && !passes_trailers
+                && !debug_echo
+                && !response_cache::bypasses_cache(&request_parts.headers)
+                && !response_cache::has_client_credentials(&request_parts.headers) =>
+        {
+            // The token in the query is the proxy's own by the time the request is sent, so
+            // the redacted URI tells responses apart as well, and is fine to log
+            Some((loggable_uri(ctx, &request_parts.uri), command_env.clone()))
+        }
+        _ => None,
+    };
+    let cached_response = match (&ctx.response_cache, &response_cache_key) {
+        (Some(response_cache), Some(key)) => response_cache.get(key),
+        _ => None,
+    };
+    // Checked before the body is read, so that neither the client, the command nor the target
+    // are waited for while it's open. Cached responses are served even then.
+    let mut breaker_permit = match ctx.breakers.get(target_url) {
+        Some(breaker) if cached_response.is_none() => match breaker.acquire() {
+            Ok(permit) => Some(permit),
+            Err(retry_after) => {
+                log::debug!("The circuit breaker for {} is open", target_url);
+                return Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())
+                    .body(Body::from("Target is unavailable"))
+                    .map_err(Error::from);
+            }
+        },
+        _ => None,
+    };
+
+    // Sending the request more than once needs the body to be buffered,
+    // which is only done for bodies of a known and small enough size
+    let can_replay = !ctx.params.auth_failure_statuses.is_empty()
+        || ctx.params.max_retries > 0
+        || ctx.params.follow_redirects;
+    let signed_body_size = ctx.params.max_body_size.unwrap_or(MAX_SIGNED_BODY_SIZE);
+    let mut body = match (body.size_hint().exact(), &ctx.params.sigv4) {
+        // The signature covers the body, so it has to be read whole first
+        (Some(size), Some(_)) if size <= signed_body_size => {
+            ReplayableBody::Buffered(body_limit::read_body(body, signed_body_size).await?)
+        }
+        // Larger and streamed bodies are sent unsigned as they come, where that's allowed
+        (_, Some(sigv4)) if sigv4.allows_unsigned_payload() => {
+            ReplayableBody::Streaming(Some(body))
+        }
+        // Or read up to the limit
+        (_, Some(_)) => {
+            if body.size_hint().lower() > signed_body_size {
+                return body_limit::too_large_response();
+            }
+            ReplayableBody::Buffered(body_limit::read_body(body, signed_body_size).await?)
+        }
+        (Some(size), None)
+            if can_replay && !may_send_trailers && size <= ctx.params.max_retry_body_size =>
+        {
+            ReplayableBody::Buffered(body_limit::read_body(body, size).await?)
+        }
+        _ => ReplayableBody::Streaming(Some(body)),
+    };
+
+    let upstream_timeout_secs = upstream_timeout_secs(ctx, route, &request_parts.method);
+    let (mut response, cache_status) = if let Some(response) = cached_response {
+        log::debug!(
+            "Serving the cached response for {}",
+            loggable_uri(ctx, &request_parts.uri)
+        );
+        (response, None)
+    } else {
+        // Of the token the request was last sent with, there's none in SigV4 mode
+        let mut cache_status = None;
+        let mut sent_token = None;
+        if ctx.params.sigv4.is_none() {
+            let (token, status) = obtain_token(ctx, &client, route, &command_env, span).await?;
+            cache_status = Some(status);
+            sent_token = Some(token.clone());
+            insert_token(ctx, &mut request_parts, token)?;
+        }
+        for (name, value) in &ctx.params.add_headers {
+            request_parts.headers.append(name, value.clone());
+        }
+        sign_request(ctx, &client, &mut request_parts, &body).await?;
+        if debug_echo {
+            return debug_echo_response(ctx, &request_parts);
+        }
+        let mut sent_at = Instant::now();
+        let result = send_request(
+            ctx,
+            &client,
+            &request_parts,
+            &mut body,
+            upstream_timeout_secs,
+            span,
+        )
+        .await;
+        record_upstream_result(ctx, breaker_permit.take(), &result);
+        let response = result?;
+
+        // Without the cache the token was just obtained, so there's no stale one to replace
+        let rejected_token = sent_token.filter(|_| {
+            !ctx.params.no_cache
+                && body.is_replayable()
+                && ctx
+                    .params
+                    .auth_failure_statuses
+                    .contains(&response.status().as_u16())
+        });
+        let mut response = if let Some(rejected_token) = rejected_token {
+            log::info!(
+                "Target responded with {}, refreshing the token and retrying",
+                response.status()
+            );
+            token_cache(ctx, route)
+                .invalidate(&command_env, &rejected_token)
+                .await;
+            let (token, status) = obtain_token(ctx, &client, route, &command_env, span).await?;
+            cache_status = Some(status);
+            insert_token(ctx, &mut request_parts, token)?;
+            sent_at = Instant::now();
+            send_request(
+                ctx,
+                &client,
+                &request_parts,
+                &mut body,
+                upstream_timeout_secs,
+                span,
+            )
+            .await?
+        } else {
+            response
+        };
+
+        if ctx.params.follow_redirects {
+            let mut redirects = 0;
+            while let Some(location) = redirect::location(
+                &request_parts.uri,
+                &response,
+                ctx.params.follow_cross_origin,
+            ) {
+                if redirects == ctx.params.max_redirects {
+                    log::warn!("Not following more than {} redirects", redirects);
+                    break;
+                }
+                let method = redirect::redirected_method(response.status(), &request_parts.method);
+                if method != request_parts.method {
+                    body = ReplayableBody::Buffered(Bytes::new());
+                    request_parts.headers.remove(CONTENT_LENGTH);
+                    request_parts.headers.remove(CONTENT_TYPE);
+                } else if !body.is_replayable() {
+                    log::warn!("Not following the redirect, the request body can't be sent again");
+                    break;
+                }
+                log::debug!("Following the redirect to {}", loggable_uri(ctx, &location));
+
+                if ctx.params.host_header == HostHeaderMode::Target && !ctx.params.upstream_http2 {
+                    if let Some(authority) = location.authority() {
+                        request_parts
+                            .headers
+                            .insert(HOST, HeaderValue::from_str(authority.as_str())?);
+                    }
+                }
+                request_parts.method = method;
+                request_parts.uri = location;
+                // The token may have to go into the new query string, and the signature has to cover it
+                if ctx.params.sigv4.is_none() {
+                    let (token, status) =
+                        obtain_token(ctx, &client, route, &command_env, span).await?;
+                    cache_status = Some(status);
+                    insert_token(ctx, &mut request_parts, token)?;
+                }
+                sign_request(ctx, &client, &mut request_parts, &body).await?;
+
+                sent_at = Instant::now();
+                response = send_request(
+                    ctx,
+                    &client,
+                    &request_parts,
+                    &mut body,
+                    upstream_timeout_secs,
+                    span,
+                )
+                .await?;
+                redirects += 1;
+            }
+        }
+
+        // Applied before the response cache reads the body, so that reading it is bounded too
+        if !streaming && !passes_trailers && upstream_timeout_secs > 0 {
+            let deadline = sent_at + Duration::from_secs(upstream_timeout_secs);
+            response = response.map(|body| body_limit::deadline_body(body, deadline.into()));
+        }
+        if let (Some(response_cache), Some(key)) = (&ctx.response_cache, response_cache_key) {
+            response = response_cache.store(key, response).await.map_err(|err| {
+                let kind = if body_limit::is_body_timed_out(&err) {
+                    ErrorKind::UpstreamTimeout
+                } else {
+                    ErrorKind::Upstream
+                };
+                Error::from(err.context(kind))
+            })?;
+        }
+        (response, cache_status)
+    };
+
+    // The version is changed below to the one the client talks
+    let upstream_version = response.version();
+    if ctx.params.upstream_http2 {
+        // Otherwise clients talking HTTP/1 would get an HTTP/2 status line
+        *response.version_mut() = match client_version {
+            Version::HTTP_2 => Version::HTTP_2,
+            _ => Version::HTTP_11,
+        };
+    }
+    if compress {
+        response = compression::compress_response(response, ctx.params.compress_min_size);
+    }
+
+    let headers = response.headers_mut();
+    headers::remove_hop_by_hop_headers(headers);
+    if ctx.params.add_via {
+        headers::append_via(headers, upstream_version)?;
+    }
+    if let (Some(name), Some(status)) = (&ctx.params.cache_status_header, cache_status) {
+        headers.insert(name, HeaderValue::from_static(status.as_str()));
+    }
+    if ctx.params.response_header_mode == ResponseHeaderMode::Override {
+        for (name, _) in &ctx.params.add_response_headers {
+            headers.remove(name);
+        }
+    }
+    for (name, value) in &ctx.params.add_response_headers {
+        headers.append(name, value.clone());
+    }
+
+    Ok(response)
+}
+
+fn get_https_client(params: &ProxyParams) -> Result<HttpsClient, Error> {
+    let mut tls_builder = TlsConnector::builder();
+    tls_builder.danger_accept_invalid_certs(params.insecure_https);
+    tls_builder.min_protocol_version(params.min_tls_version.map(TlsVersion::protocol));
+    tls_builder.max_protocol_version(params.max_tls_version.map(TlsVersion::protocol));
+
+    if let Some(ref ca_file) = params.ca_file {
+        if params.insecure_https {
+            log::warn!("A CA file is given, but certificate verification is disabled anyway");
+        }
+
+        let pem = fs::read(ca_file)
+            .with_context(|_| format!("Failed to read CA file {}", ca_file.display()))?;
+        let certs = Certificate::stack_from_pem(&pem).context("Failed to parse CA file")?;
+        if certs.is_empty() {
+            return Err(err_msg(format!(
+                "No certificates found in CA file {}",
+                ca_file.display()
+            )));
+        }
+        for cert in certs {
+            tls_builder.add_root_certificate(cert);
+        }
+    }
+
+    if let Some(ref client_cert) = params.client_cert {
+        let der = fs::read(client_cert).with_context(|_| {
+            format!(
+                "Failed to read client certificate {}",
+                client_cert.display()
+            )
+        })?;
+        let password = params
+            .client_cert_password
+            .as_ref()
+            .map_or("", Secret::expose);
+        let identity = Identity::from_pkcs12(&der, password).with_context(|_| {
+            format!(
+                "Failed to load client certificate {}, is the password correct?",
+                client_cert.display()
+            )
+        })?;
+        tls_builder.identity(identity);
+    }
+
+    if params.upstream_http2 {
+        tls_builder.request_alpns(&["h2"]);
+    }
+    let tls_connector = tokio_tls::TlsConnector::from(tls_builder.build()?);
+
+    let mut http_connector =
+        HttpConnector::new_with_resolver(FamilyResolver::new(params.address_family));
+    http_connector.enforce_http(false);
+    http_connector.set_nodelay(params.tcp_nodelay);
+    http_connector.set_keepalive(params.tcp_keepalive_secs.map(Duration::from_secs));
+    http_connector.set_happy_eyeballs_timeout(match params.happy_eyeballs_delay_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    });
+    if let Some(source_address) = params.source_address {
+        // Fails if the address isn't one of this host's, which would otherwise only show per request
+        UdpSocket::bind((source_address, 0))
+            .with_context(|_| format!("Can't connect to targets from {}", source_address))?;
+        log::info!("Connecting to targets from {}", source_address);
+        http_connector.set_local_address(Some(source_address));
+    }
+    let upstream_connector = match params.socks_proxy {
+        Some(ref socks_proxy) => {
+            log::info!(
+                "Connecting to targets through SOCKS proxy {}",
+                socks_proxy.addr
+            );
+            UpstreamConnector::Socks(Arc::new(socks_proxy.clone()))
+        }
+        None if params.use_system_proxy => {
+            let system_proxy = SystemProxy::from_env()?;
+            log::info!("Using proxies from the environment: {:?}", system_proxy);
+            UpstreamConnector::System(http_connector, Arc::new(system_proxy))
+        }
+        None => UpstreamConnector::Direct(http_connector),
+    };
+    for resolve in &params.resolve {
+        log::info!(
+            "Connecting to {} for {}:{}",
+            resolve.addr,
+            resolve.host,
+            resolve.port
+        );
+    }
+    let upstream_connector = OverridingConnector::new(upstream_connector, params.resolve.clone());
+    if let Some(ref tls_sni) = params.tls_sni {
+        log::info!("Connecting to https targets as {}", tls_sni);
+    }
+    let connect_timeout = Some(params.connect_timeout_secs)
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    let https_connector = UpstreamTlsConnector::new(
+        upstream_connector,
+        tls_connector,
+        params.tls_sni.clone(),
+        connect_timeout,
+    );
+
+    let mut client_builder = Client::builder();
+    if params.upstream_http2 {
+        log::info!("Talking HTTP/2 to the targets");
+        client_builder.http2_only(true);
+    }
+    if let Some(max_idle) = params.pool_max_idle_per_host {
+        client_builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout_secs) = params.pool_idle_timeout_secs {
+        client_builder.pool_idle_timeout(Duration::from_secs(idle_timeout_secs));
+    }
+
+    Ok(client_builder.build::<_, hyper::Body>(https_connector))
+}
+
+async fn serve<I>(
+    ctx: &'static ProxyContext,
+    client_arc: Arc<HttpsClient>,
+    incoming: I,
+) -> Result<(), Error>
+where
+    I: Accept,
+    I::Conn: PeerAddr + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let connections = ConnectionCounter::default();
+    let connections_for_service = connections.clone();
+
+    let make_service = make_service_fn(move |conn: &I::Conn| {
+        let per_target_client_arc = client_arc.clone();
+        let peer_addr = conn.peer_addr();
+        // Clients without an address, such as the ones connected over a unix socket, are let in
+        let peer_allowed = peer_addr.is_none_or(|addr| {
+            ip_filter::is_allowed(&ctx.params.allow_cidrs, &ctx.params.deny_cidrs, addr.ip())
+        });
+        if !peer_allowed {
+            log::info!("Refusing requests from {:?}", peer_addr);
+        }
+        // Dropped along with the service once the connection is closed
+        let connection_guard = connections_for_service.track();
+
+        async move {
+            let service = service_fn(move |req: Request<Body>| {
+                let _ = &connection_guard;
+                let response = if peer_allowed {
+                    Either::Left(handle_request(
+                        ctx,
+                        per_target_client_arc.clone(),
+                        peer_addr,
+                        req,
+                    ))
+                } else {
+                    Either::Right(future::ready(forbidden_response()))
+                };
+                response.map(move |result| {
+                    result.or_else(|err| {
+                        log::error!("{}", err);
+                        for underlying_error in err.iter_causes() {
+                            log::error!("Caused by: {}", underlying_error);
+                        }
+
+                        Ok::<_, Error>(errors::error_response(
+                            &err,
+                            ctx.params.error_format,
+                            ctx.params.error_detail,
+                        ))
+                    })
+                })
+            });
+
+            Ok::<_, hyper::Error>(service)
+        }
+    });
+
+    let shutdown = shutdown::shutdown_signal()
+        .map(|_| {
+            log::info!(
+                "Shutting down, waiting for {} connections to finish...",
+                connections.count()
+            );
+        })
+        .shared();
+
+    let mut server_builder = Server::builder(incoming);
+    // Requests with larger heads are answered by hyper with 431 without reaching handle_request
+    if let Some(max_header_size) = ctx.params.max_header_size {
+        server_builder = server_builder.http1_max_buf_size(max_header_size);
+    }
+    let server = server_builder
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.clone());
+    let shutdown_timeout = Duration::from_secs(ctx.params.shutdown_timeout_secs);
+    let forced_shutdown = shutdown.then(|_| delay_for(shutdown_timeout));
+    futures::pin_mut!(server, forced_shutdown);
+
+    match future::select(server, forced_shutdown).await {
+        Either::Left((result, _)) => {
+            result?;
+            log::info!("All connections are finished");
+        }
+        Either::Right(_) => {
+            log::warn!(
+                "Shutdown timed out, closing {} remaining connections",
+                connections.count()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+enum Listener {
+    Tcp(AddrIncoming),
+    // A socket passed by systemd or bound with another backlog,
+    // for which hyper can't take care of accepting connections
+    CustomTcp(TcpListener),
+    // The socket file is only removed if the proxy created it
+    #[cfg(unix)]
+    Unix(UnixListener, Option<listener::SocketFileGuard<'static>>),
+}
+
+// A proxy that is already listening, so its address is known before it starts serving
+pub struct Proxy {
+    ctx: &'static ProxyContext,
+    client: Arc<HttpsClient>,
+    tls_acceptor: Option<TlsAcceptor>,
+    listener: Listener,
+    token_file_watcher: Option<notify::RecommendedWatcher>,
+    pid_file: Option<PidFile>,
+}
+
+// Tokens are read from the file again on the next request after it changes
+fn watch_token_file(
+    ctx: &'static ProxyContext,
+    path: &Path,
+) -> Result<notify::RecommendedWatcher, Error> {
+    log::info!("Watching {} for changes", path.display());
+    token_file::watch(path, move || {
+        log::info!("The token file changed, dropping the cached token");
+        ctx.cache.clear();
+    })
+}
+
+impl Proxy {
+    pub async fn bind(params: ProxyParams) -> Result<Self, ProxyError> {
+        log::debug!("Running proxy with params: {:?}", params);
+
+        // The params live for the entire duration of the program
+        // and don't have any interesting destructors, so just leak them.
+        let ctx: &'static ProxyContext = Box::leak(Box::new(
+            ProxyContext::new(params).map_err(ProxyError::Config)?,
+        ));
+
+        let client = Arc::new(get_https_client(&ctx.params).map_err(ProxyError::Upstream)?);
+
+        let tls_acceptor = match (&ctx.params.tls_cert, &ctx.params.tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(
+                listener::load_tls_acceptor(
+                    cert_path,
+                    key_path,
+                    ctx.params.client_ca.as_deref(),
+                    ctx.params.require_client_cert,
+                )
+                .map_err(ProxyError::Tls)?,
+            ),
+            _ => None,
+        };
+
+        if ctx.params.warm_cache {
+            log::info!("Obtaining a token before listening");
+            if let Err(e) = obtain_token(ctx, &client, None, &CacheKey::new(), None).await {
+                if ctx.params.warm_fail_fast {
+                    return Err(ProxyError::Command(e));
+                }
+                log::warn!(
+                    "Failed to obtain a token at startup, continuing: {}",
+                    e.find_root_cause()
+                );
+            }
+        }
+
+        let token_file_watcher = match ctx.params.token_file {
+            Some(ref path) if ctx.params.watch_token_file => {
+                Some(watch_token_file(ctx, path).map_err(ProxyError::Config)?)
+            }
+            _ => None,
+        };
+
+        let listener = bind_listener(&ctx.params).map_err(ProxyError::Bind)?;
+
+        let pid_file = match ctx.params.pid_file {
+            Some(ref path) => Some(PidFile::create(path).map_err(ProxyError::Config)?),
+            None => None,
+        };
+
+        Ok(Proxy {
+            ctx,
+            client,
+            tls_acceptor,
+            listener,
+            token_file_watcher,
+            pid_file,
+        })
+    }
+
+    // None when listening on a unix socket
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self.listener {
+            Listener::Tcp(ref incoming) => Some(incoming.local_addr()),
+            Listener::CustomTcp(ref listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+
+    pub async fn run(self) -> Result<(), ProxyError> {
+        let Proxy {
+            ctx,
+            client,
+            tls_acceptor,
+            listener,
+            token_file_watcher: _token_file_watcher,
+            pid_file: _pid_file,
+        } = self;
+
+        if ctx.params.background_refresh {
+            tokio::spawn(refresh_in_background(ctx, client.clone()));
+        }
+        if let Some(ref tracer) = ctx.tracer {
+            tokio::spawn(tracer.export_periodically());
+        }
+
+        #[cfg(target_os = "linux")]
+        systemd::notify_ready();
+        let result = match listener {
+            Listener::Tcp(mut incoming) => match tls_acceptor {
+                Some(acceptor) => {
+                    let incoming =
+                        stream::poll_fn(move |cx| Pin::new(&mut incoming).poll_accept(cx));
+                    let incoming = listener::tls_incoming(incoming, acceptor);
+                    serve(ctx, client, accept::from_stream(incoming)).await
+                }
+                None => serve(ctx, client, incoming).await,
+            },
+            Listener::CustomTcp(mut listener) => {
+                // Hyper only sets these on the connections it accepts itself
+                let keepalive = ctx.params.tcp_keepalive_secs.map(Duration::from_secs);
+                let incoming =
+                    listener::skip_accept_errors(listener.incoming()).filter_map(move |stream| {
+                        let stream = stream.and_then(|stream| {
+                            stream.set_nodelay(ctx.params.tcp_nodelay)?;
+                            stream.set_keepalive(keepalive)?;
+                            Ok(stream)
+                        });
+                        // The client may have reset the connection already
+                        future::ready(match stream {
+                            Ok(stream) => Some(Ok(stream)),
+                            Err(err) => {
+                                log::warn!("Failed to set up an accepted connection: {}", err);
+                                None
+                            }
+                        })
+                    });
+                match tls_acceptor {
+                    Some(acceptor) => {
+                        let incoming = listener::tls_incoming(incoming, acceptor);
+                        serve(ctx, client, accept::from_stream(incoming)).await
+                    }
+                    None => serve(ctx, client, accept::from_stream(incoming)).await,
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(mut listener, _guard) => {
+                let incoming = listener::skip_accept_errors(listener.incoming());
+                match tls_acceptor {
+                    Some(acceptor) => {
+                        let incoming = listener::tls_incoming(incoming, acceptor);
+                        serve(ctx, client, accept::from_stream(incoming)).await
+                    }
+                    None => serve(ctx, client, accept::from_stream(incoming)).await,
+                }
+            }
+        };
+
+        // Spans of the last requests would be lost otherwise
+        if let Some(ref tracer) = ctx.tracer {
+            tracer.export_remaining().await;
+        }
+        result.map_err(ProxyError::Server)
+    }
+}
+
+fn bind_listener(params: &'static ProxyParams) -> Result<Listener, Error> {
+    match &params.listen_addr {
+        ListenAddr::Tcp { host, port } => {
+            let mut addrs = (&**host, *port).to_socket_addrs()?;
+            let addr = addrs
+                .next()
+                .ok_or_else(|| err_msg("Failed to resolve target address"))?;
+            if let Some(backlog) = params.listen_backlog {
+                let listener = listener::bind_tcp(&addr, backlog as i32)
+                    .with_context(|_| format!("Failed to bind to {}", addr))?;
+                listener.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(listener)?;
+                log::info!("Listening on {}...", listener.local_addr()?);
+                return Ok(Listener::CustomTcp(listener));
+            }
+            let mut incoming = AddrIncoming::bind(&addr)?;
+            incoming.set_nodelay(params.tcp_nodelay);
+            incoming.set_keepalive(params.tcp_keepalive_secs.map(Duration::from_secs));
+            log::info!("Listening on {}...", incoming.local_addr());
+            Ok(Listener::Tcp(incoming))
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            let listener = UnixListener::bind(path)
+                .with_context(|_| format!("Failed to bind to {}", path.display()))?;
+            log::info!("Listening on {}...", path.display());
+            Ok(Listener::Unix(
+                listener,
+                Some(listener::SocketFileGuard(path)),
+            ))
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => Err(err_msg("Unix sockets are not supported on this platform")),
+        #[cfg(target_os = "linux")]
+        ListenAddr::Systemd => match systemd::take_listen_socket()? {
+            systemd::InheritedSocket::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(listener)?;
+                log::info!(
+                    "Listening on {} passed by systemd...",
+                    listener.local_addr()?
+                );
+                Ok(Listener::CustomTcp(listener))
+            }
+            systemd::InheritedSocket::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                log::info!("Listening on a Unix socket passed by systemd...");
+                Ok(Listener::Unix(UnixListener::from_std(listener)?, None))
+            }
+        },
+        #[cfg(not(target_os = "linux"))]
+        ListenAddr::Systemd => Err(err_msg(
+            "Systemd socket activation is not supported on this platform",
+        )),
+    }
+}
+
+pub async fn run_proxy(params: ProxyParams) -> Result<(), ProxyError> {
+    Proxy::bind(params).await?.run().await
+}
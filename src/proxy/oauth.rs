@@ -0,0 +1,109 @@
+use std::fmt;
+use std::time::Duration;
+
+use failure::{err_msg, Error, ResultExt};
+use http::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Method, Request};
+use serde_json::Value;
+use tokio::time::timeout;
+use url::form_urlencoded;
+
+use super::token::Token;
+use super::HttpsClient;
+
+pub struct OAuthParams {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: Option<String>,
+}
+
+// Keeps the client secret out of the logs
+impl fmt::Debug for OAuthParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OAuthParams")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
+// Obtains a token with the OAuth2 client credentials grant
+pub async fn fetch_token(
+    client: &HttpsClient,
+    params: &OAuthParams,
+    request_timeout: Duration,
+) -> Result<Token, Error> {
+    let form = {
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "client_credentials")
+            .append_pair("client_id", &params.client_id)
+            .append_pair("client_secret", &params.client_secret);
+        if let Some(ref scope) = params.scope {
+            form.append_pair("scope", scope);
+        }
+        form.finish()
+    };
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&params.token_url)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(ACCEPT, "application/json")
+        .body(Body::from(form))?;
+
+    let response = timeout(request_timeout, client.request(request))
+        .await
+        .map_err(|_| {
+            err_msg(format!(
+                "Token endpoint didn't respond within {} seconds",
+                request_timeout.as_secs()
+            ))
+        })?
+        .context("Failed to reach the token endpoint")?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+
+    if !status.is_success() {
+        return Err(err_msg(format!(
+            "Token endpoint responded with {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        )));
+    }
+
+    let json: Value =
+        serde_json::from_slice(&body).context("Failed to parse the token endpoint response")?;
+    let value = json
+        .get("access_token")
+        .and_then(Value::as_str)
+        .ok_or_else(|| err_msg("Token endpoint response has no access_token"))?;
+
+    // expires_in is optional, the cache ttl is used when it's missing
+    Ok(Token {
+        value: value.to_string(),
+        ttl: json
+            .get("expires_in")
+            .and_then(Value::as_u64)
+            .map(Duration::from_secs),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_the_client_secret_out_of_the_debug_output() {
+        let params = OAuthParams {
+            token_url: String::from("https://auth/token"),
+            client_id: String::from("client"),
+            client_secret: String::from("client-secret"),
+            scope: None,
+        };
+        let debug = format!("{:?}", params);
+        assert!(debug.contains("client"));
+        assert!(!debug.contains("client-secret"), "{}", debug);
+    }
+}
@@ -0,0 +1,24 @@
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use failure::{Error, ResultExt};
+
+// Holds the file with the process ID for as long as the proxy runs, removing it afterwards
+pub struct PidFile(&'static Path);
+
+impl PidFile {
+    pub fn create(path: &'static Path) -> Result<Self, Error> {
+        fs::write(path, format!("{}\n", process::id()))
+            .with_context(|_| format!("Failed to write PID file {}", path.display()))?;
+        Ok(PidFile(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(self.0) {
+            log::warn!("Failed to remove PID file {}: {}", self.0.display(), err);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use http::header::LOCATION;
+use http::uri::Uri;
+use hyper::{Body, Method, Response, StatusCode};
+use url::Url;
+
+// Where the response redirects the request to, resolved against the URI it was sent to.
+// Redirects to another scheme, host or port are only followed when allowed,
+// since the token is sent along to wherever the request is redirected.
+pub fn location(
+    request_uri: &Uri,
+    response: &Response<Body>,
+    follow_cross_origin: bool,
+) -> Option<Uri> {
+    if !response.status().is_redirection() || response.status() == StatusCode::NOT_MODIFIED {
+        return None;
+    }
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    let request_url = Url::parse(&request_uri.to_string()).ok()?;
+    let location_url = match request_url.join(location) {
+        Ok(url) => url,
+        Err(_) => {
+            log::warn!("Not following the redirect to an invalid URL {}", location);
+            return None;
+        }
+    };
+    if location_url.origin() != request_url.origin() && !follow_cross_origin {
+        // Only the origin, the rest could carry the token the target was sent
+        log::warn!(
+            "Not following the redirect to another origin {}",
+            location_url.origin().ascii_serialization()
+        );
+        return None;
+    }
+
+    location_url.as_str().parse::<Uri>().ok()
+}
+
+// Clients change the method of the redirected request to GET, except for 307 and 308
+pub fn redirected_method(status: StatusCode, method: &Method) -> Method {
+    match status {
+        StatusCode::SEE_OTHER if method != Method::HEAD => Method::GET,
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND if method == Method::POST => Method::GET,
+        _ => method.clone(),
+    }
+}
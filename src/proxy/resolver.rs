@@ -0,0 +1,171 @@
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::vec;
+
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressFamily {
+    // In the order the system resolver returns the addresses in
+    Any,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+// Limits or orders the addresses of the targets by their family. The connector tries the family
+// of the first address first, and only tries the other one if that doesn't connect quickly.
+#[derive(Clone)]
+pub struct FamilyResolver<R = GaiResolver> {
+    inner: R,
+    family: AddressFamily,
+}
+
+impl FamilyResolver {
+    pub fn new(family: AddressFamily) -> Self {
+        FamilyResolver {
+            inner: GaiResolver::new(),
+            family,
+        }
+    }
+}
+
+impl<R, A> Service<Name> for FamilyResolver<R>
+where
+    R: Service<Name, Response = A, Error = io::Error>,
+    R::Future: Send + 'static,
+    A: Iterator<Item = IpAddr>,
+{
+    type Response = vec::IntoIter<IpAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let family = self.family;
+        let resolving = self.inner.call(name.clone());
+        Box::pin(async move {
+            let mut addrs = resolving.await?.collect::<Vec<_>>();
+            match family {
+                AddressFamily::Any => {}
+                // Sorting is stable, so the addresses of each family stay in their order
+                AddressFamily::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+                AddressFamily::Ipv4Only => addrs.retain(IpAddr::is_ipv4),
+                AddressFamily::Ipv6Only => addrs.retain(IpAddr::is_ipv6),
+            }
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No address of the allowed family found for {}", name),
+                ));
+            }
+
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use futures::future::{self, Ready};
+    use hyper::client::HttpConnector;
+    use hyper::Uri;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // An address in the discard-only prefix, which nothing ever answers from
+    const BLACKHOLED_IPV6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 1));
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[derive(Clone)]
+    struct StaticResolver(Vec<IpAddr>);
+
+    impl Service<Name> for StaticResolver {
+        type Response = vec::IntoIter<IpAddr>;
+        type Error = io::Error;
+        type Future = Ready<io::Result<Self::Response>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _name: Name) -> Self::Future {
+            future::ready(Ok(self.0.clone().into_iter()))
+        }
+    }
+
+    async fn resolve(family: AddressFamily) -> io::Result<Vec<IpAddr>> {
+        let mut resolver = FamilyResolver {
+            inner: StaticResolver(vec![LOCALHOST, BLACKHOLED_IPV6]),
+            family,
+        };
+        let addrs = resolver
+            .call(Name::from_str("target.test").unwrap())
+            .await?;
+        Ok(addrs.collect())
+    }
+
+    #[tokio::test]
+    async fn orders_and_limits_the_addresses_by_family() {
+        let all = vec![LOCALHOST, BLACKHOLED_IPV6];
+        assert_eq!(resolve(AddressFamily::Any).await.unwrap(), all);
+        let ipv6_first = vec![BLACKHOLED_IPV6, LOCALHOST];
+        assert_eq!(
+            resolve(AddressFamily::PreferIpv6).await.unwrap(),
+            ipv6_first
+        );
+        assert_eq!(
+            resolve(AddressFamily::Ipv4Only).await.unwrap(),
+            vec![LOCALHOST]
+        );
+        let ipv6_only = vec![BLACKHOLED_IPV6];
+        assert_eq!(resolve(AddressFamily::Ipv6Only).await.unwrap(), ipv6_only);
+    }
+
+    #[tokio::test]
+    async fn fails_without_an_address_of_the_family() {
+        let mut resolver = FamilyResolver {
+            inner: StaticResolver(vec![LOCALHOST]),
+            family: AddressFamily::Ipv6Only,
+        };
+        let err = resolver
+            .call(Name::from_str("target.test").unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No address of the allowed family found for target.test"
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_ipv4_when_ipv6_is_blackholed() {
+        let mut listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+        // The blackholed address is tried first
+        let resolver = FamilyResolver {
+            inner: StaticResolver(vec![LOCALHOST, BLACKHOLED_IPV6]),
+            family: AddressFamily::PreferIpv6,
+        };
+        let mut connector = HttpConnector::new_with_resolver(resolver);
+        connector.set_happy_eyeballs_timeout(Some(Duration::from_millis(100)));
+
+        let started_at = Instant::now();
+        let uri = Uri::from_str(&format!("http://target.test:{}/", port)).unwrap();
+        connector.call(uri).await.unwrap();
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use futures::stream::{self, StreamExt};
+use http::header::{
+    HeaderMap, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, COOKIE, SET_COOKIE, VARY,
+};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Response, StatusCode, Version};
+
+use super::cache::CacheKey;
+
+// The target URI, and the metadata the token was obtained with, since that can make the target
+// respond differently to the same URI
+pub type ResponseCacheKey = (String, CacheKey);
+
+#[derive(Debug)]
+struct CachedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    ttl: Duration,
+    last_used: Instant,
+}
+
+impl CachedResponse {
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.stored_at + self.ttl > now
+    }
+}
+
+// Keeps responses to GET requests in memory, so that the target isn't asked again while they are
+// fresh. Successful responses are kept for as long as the target allows unless the ttl is given,
+// server errors for error_ttl to spare a failing target the requests.
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Option<Duration>,
+    error_ttl: Duration,
+    max_entries: usize,
+    max_body_size: u64,
+    entries: Mutex<HashMap<ResponseCacheKey, CachedResponse>>,
+}
+
+fn cache_directives(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect()
+}
+
+fn forbids_caching(directives: &[String]) -> bool {
+    directives
+        .iter()
+        .any(|directive| matches!(directive.as_str(), "no-store" | "no-cache" | "private"))
+}
+
+// How long the target allows shared caches to keep the response for
+fn max_age(directives: &[String]) -> Option<Duration> {
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find_map(|directive| directive.strip_prefix(name)?.strip_prefix('='))
+            .and_then(|secs| secs.trim_matches('"').parse::<u64>().ok())
+    };
+
+    seconds("s-maxage")
+        .or_else(|| seconds("max-age"))
+        .map(Duration::from_secs)
+}
+
+// Whether the client asks for a response from the target rather than a cached one
+pub fn bypasses_cache(headers: &HeaderMap) -> bool {
+    cache_directives(headers)
+        .iter()
+        .any(|directive| matches!(directive.as_str(), "no-cache" | "no-store"))
+}
+
+// Whether the request carries credentials of the client, which the target may personalize the
+// response for, so that it can't be served to other clients
+pub fn has_client_credentials(headers: &HeaderMap) -> bool {
+    headers.contains_key(AUTHORIZATION) || headers.contains_key(COOKIE)
+}
+
+impl ResponseCache {
+    pub fn new(
+        ttl: Option<Duration>,
+        error_ttl: Duration,
+        max_entries: usize,
+        max_body_size: u64,
+    ) -> Self {
+        ResponseCache {
+            ttl,
+            error_ttl,
+            max_entries,
+            max_body_size,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &ResponseCacheKey) -> Option<Response<Body>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key).filter(|entry| entry.is_fresh(now))?;
+        entry.last_used = now;
+
+        let mut response = Response::new(Body::from(entry.body.clone()));
+        *response.status_mut() = entry.status;
+        *response.version_mut() = entry.version;
+        *response.headers_mut() = entry.headers.clone();
+        let age = now.duration_since(entry.stored_at).as_secs();
+        response.headers_mut().insert(AGE, HeaderValue::from(age));
+        Some(response)
+    }
+
+    // Responses that vary by the request headers or set cookies are never kept,
+    // since they can't be served to every client alike
+    fn ttl_for(&self, response: &Response<Body>) -> Option<Duration> {
+        let headers = response.headers();
+        let directives = cache_directives(headers);
+        if headers.contains_key(VARY)
+            || headers.contains_key(SET_COOKIE)
+            || forbids_caching(&directives)
+        {
+            return None;
+        }
+        let ttl = match response.status() {
+            StatusCode::OK => self.ttl.or_else(|| max_age(&directives))?,
+            status if status.is_server_error() => self.error_ttl,
+            _ => return None,
+        };
+
+        Some(ttl).filter(|ttl| *ttl > Duration::from_secs(0))
+    }
+
+    // Reads the body of a response that can be cached to keep it, larger bodies are passed on
+    // with what was read of them so far
+    pub async fn store(
+        &self,
+        key: ResponseCacheKey,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, Error> {
+        let ttl = match self.ttl_for(&response) {
+            Some(ttl) if response.body().size_hint().lower() <= self.max_body_size => ttl,
+            _ => return Ok(response),
+        };
+
+        let (parts, mut body) = response.into_parts();
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            chunks.push(chunk);
+            if size > self.max_body_size {
+                let read = stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+                return Ok(Response::from_parts(
+                    parts,
+                    Body::wrap_stream(read.chain(body)),
+                ));
+            }
+        }
+        let body = Bytes::from(chunks.concat());
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            // The least recently used of the expired responses goes first, then of the fresh ones
+            let evicted_key = entries
+                .iter()
+                .min_by_key(|(_, entry)| (entry.is_fresh(now), entry.last_used))
+                .map(|(key, _)| key.clone());
+            if let Some(evicted_key) = evicted_key {
+                entries.remove(&evicted_key);
+            }
+        }
+        log::debug!(
+            "Caching the response for {} for {} seconds",
+            key.0,
+            ttl.as_secs()
+        );
+        entries.insert(
+            key,
+            CachedResponse {
+                status: parts.status,
+                version: parts.version,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                stored_at: now,
+                ttl,
+                last_used: now,
+            },
+        );
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(cache_control: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_str(cache_control).unwrap());
+        headers
+    }
+
+    #[test]
+    fn bypasses_the_cache_when_the_client_asks_to() {
+        assert!(bypasses_cache(&headers("no-cache")));
+        assert!(bypasses_cache(&headers("max-age=0, No-Store")));
+        assert!(!bypasses_cache(&headers("max-age=0")));
+        assert!(!bypasses_cache(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn tells_requests_with_client_credentials_apart() {
+        let mut headers = HeaderMap::new();
+        assert!(!has_client_credentials(&headers));
+        headers.insert(COOKIE, HeaderValue::from_static("session=a"));
+        assert!(has_client_credentials(&headers));
+        headers.remove(COOKIE);
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic dTpw"));
+        assert!(has_client_credentials(&headers));
+    }
+
+    #[test]
+    fn prefers_the_shared_max_age() {
+        let directives = cache_directives(&headers("max-age=10, s-maxage=\"20\""));
+        assert_eq!(max_age(&directives), Some(Duration::from_secs(20)));
+        let directives = cache_directives(&headers("public, max-age=10"));
+        assert_eq!(max_age(&directives), Some(Duration::from_secs(10)));
+        assert_eq!(max_age(&cache_directives(&headers("public"))), None);
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::{Duration, SystemTime};
+
+use failure::Error;
+use http::header::{HeaderMap, RETRY_AFTER};
+use hyper::body::Bytes;
+use hyper::{Body, Method, StatusCode};
+use rand::Rng;
+
+const MAX_BACKOFF_DELAY: Duration = Duration::from_secs(300);
+
+// Requests are only sent more than once when their body could be buffered
+#[derive(Debug)]
+pub enum ReplayableBody {
+    Streaming(Option<Body>),
+    Buffered(Bytes),
+}
+
+impl ReplayableBody {
+    pub fn is_replayable(&self) -> bool {
+        match self {
+            ReplayableBody::Streaming(_) => false,
+            ReplayableBody::Buffered(_) => true,
+        }
+    }
+
+    pub fn buffered(&self) -> Option<&Bytes> {
+        match self {
+            ReplayableBody::Streaming(_) => None,
+            ReplayableBody::Buffered(bytes) => Some(bytes),
+        }
+    }
+
+    pub fn take(&mut self) -> Option<Body> {
+        match self {
+            ReplayableBody::Streaming(body) => body.take(),
+            ReplayableBody::Buffered(bytes) => Some(Body::from(bytes.clone())),
+        }
+    }
+}
+
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+// Only failures where the target most likely didn't get to process the request are retried.
+// A connection closed before the response could also have been closed after processing it,
+// so that's only retried when processing it again does no harm.
+pub fn is_retryable_error(err: &Error, method: &Method) -> bool {
+    err.iter_chain()
+        .find_map(|cause| cause.downcast_ref::<hyper::Error>())
+        .is_some_and(|err| {
+            err.is_connect() || (err.is_incomplete_message() && is_idempotent(method))
+        })
+}
+
+// Responses telling that the target can't handle the request right now, but may soon
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    )
+}
+
+// How long the response asks to wait, given in seconds or as an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+// Exponential backoff where the upper half of each delay is random
+pub fn backoff_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let delay = base_delay
+        .checked_mul(2u32.saturating_pow(attempt.min(16)))
+        .map_or(MAX_BACKOFF_DELAY, |delay| delay.min(MAX_BACKOFF_DELAY));
+    delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0, 0.5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay_grows_with_the_attempts() {
+        let base_delay = Duration::from_millis(100);
+        for attempt in 0..5 {
+            let delay = backoff_delay(base_delay, attempt);
+            let full_delay = base_delay * 2u32.pow(attempt);
+            assert!(delay >= full_delay / 2 && delay <= full_delay);
+        }
+    }
+
+    #[test]
+    fn backoff_delay_is_capped() {
+        assert!(backoff_delay(Duration::from_secs(u64::MAX), 3) <= MAX_BACKOFF_DELAY);
+        assert!(backoff_delay(Duration::from_secs(1), u32::MAX) <= MAX_BACKOFF_DELAY);
+    }
+
+    #[test]
+    fn post_and_patch_are_not_idempotent() {
+        assert!(is_idempotent(&Method::GET));
+        assert!(is_idempotent(&Method::PUT));
+        assert!(!is_idempotent(&Method::POST));
+        assert!(!is_idempotent(&Method::PATCH));
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let err = failure::err_msg("Failed to get the token");
+        assert!(!is_retryable_error(&err, &Method::GET));
+    }
+
+    #[test]
+    fn only_busy_statuses_are_retried() {
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_retryable_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!is_retryable_status(StatusCode::BAD_GATEWAY));
+    }
+
+    fn retry_after_of(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, value.parse().unwrap());
+        retry_after(&headers)
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(retry_after_of("2"), Some(Duration::from_secs(2)));
+        assert_eq!(retry_after_of(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after_of("-1"), None);
+        assert_eq!(retry_after_of("soon"), None);
+    }
+
+    #[test]
+    fn parses_retry_after_dates() {
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let delay = retry_after_of(&date).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+        // Dates in the past mean the request can be retried right away
+        assert_eq!(
+            retry_after_of("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::from_secs(0))
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use std::str::FromStr;
+
+use failure::{err_msg, Error};
+use http::uri::{PathAndQuery, Uri};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use url::form_urlencoded;
+
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub path_prefix: String,
+    pub target_url: String,
+    // Parsed once, so that requests don't have to
+    pub target_uri: Uri,
+    // Obtains the tokens for this route instead of the global command
+    pub command: Option<Vec<String>>,
+    // Overrides the upstream timeout for requests to this route
+    pub timeout_secs: Option<u64>,
+    // Passed to the command to obtain tokens for, tokens are cached per audience
+    pub audience: Option<String>,
+}
+
+impl FromStr for Route {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let path_prefix = parts.next().unwrap_or_default();
+        let target_url = parts
+            .next()
+            .ok_or_else(|| err_msg("Route must look like PATH_PREFIX=TARGET_URL"))?;
+
+        if !path_prefix.starts_with('/') {
+            return Err(err_msg("Route path prefix must start with /"));
+        }
+
+        let target_uri = target_url.parse::<Uri>()?;
+        if target_uri.scheme().is_none() || target_uri.authority().is_none() {
+            return Err(err_msg("Route target URL must be absolute"));
+        }
+
+        Ok(Route {
+            path_prefix: path_prefix.to_string(),
+            target_url: target_url.to_string(),
+            target_uri,
+            command: None,
+            timeout_secs: None,
+            audience: None,
+        })
+    }
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> bool {
+        strip_path_prefix(path, &self.path_prefix).is_some()
+    }
+
+    pub fn strip_prefix(&self, path_and_query: &PathAndQuery) -> Result<PathAndQuery, Error> {
+        let path = path_and_query.path();
+        with_path(
+            path_and_query,
+            strip_path_prefix(path, &self.path_prefix).unwrap_or(path),
+        )
+    }
+}
+
+// A path prefix, or a regex searched for in the path when it starts with ~
+#[derive(Clone, Debug)]
+pub enum PathPattern {
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl FromStr for PathPattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('~') {
+            Some(regex) => Regex::new(regex)
+                .map(PathPattern::Regex)
+                .map_err(|e| err_msg(format!("Invalid path regex `{}`: {}", regex, e))),
+            None if s.starts_with('/') => Ok(PathPattern::Prefix(s.to_string())),
+            None => Err(err_msg("Path pattern must start with / or ~")),
+        }
+    }
+}
+
+impl PathPattern {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Prefix(prefix) => strip_path_prefix(path, prefix).is_some(),
+            PathPattern::Regex(regex) => regex.is_match(path),
+        }
+    }
+}
+
+// The path as the target most likely sees it, so that encoding it or adding dot segments
+// doesn't get a request past the patterns
+fn normalize_path(path: &str) -> String {
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+    let mut segments = Vec::new();
+    for segment in decoded.split('/').skip(1) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    format!("/{}", segments.join("/"))
+}
+
+// Blocked paths stay blocked even if they are allowed, and once any paths are allowed
+// all the others are blocked
+pub fn is_path_allowed(allowed: &[PathPattern], blocked: &[PathPattern], path: &str) -> bool {
+    let path = normalize_path(path);
+    !blocked.iter().any(|pattern| pattern.matches(&path))
+        && (allowed.is_empty() || allowed.iter().any(|pattern| pattern.matches(&path)))
+}
+
+// Matches whole path segments only, so `/api` doesn't match `/apiary`
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    if !path.starts_with(prefix) {
+        return None;
+    }
+
+    match &path[prefix.len()..] {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+fn with_path(path_and_query: &PathAndQuery, path: &str) -> Result<PathAndQuery, Error> {
+    let mut rewritten = path.to_string();
+    if let Some(query) = path_and_query.query() {
+        rewritten.push('?');
+        rewritten.push_str(query);
+    }
+
+    Ok(rewritten.parse::<PathAndQuery>()?)
+}
+
+fn is_param(param: &str, name: &str) -> bool {
+    let param_name = param
+        .split_once('=')
+        .map_or(param, |(param_name, _)| param_name);
+    form_urlencoded::parse(param_name.as_bytes())
+        .next()
+        .is_some_and(|(param_name, _)| param_name == name)
+}
+
+// The decoded value of the first parameter with the name
+pub fn query_param(path_and_query: &PathAndQuery, name: &str) -> Option<String> {
+    form_urlencoded::parse(path_and_query.query()?.as_bytes())
+        .find(|(param_name, _)| param_name == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+// Replaces all the parameters with the name, leaving the others as they are
+pub fn set_query_param(
+    path_and_query: &PathAndQuery,
+    name: &str,
+    value: &str,
+) -> Result<PathAndQuery, Error> {
+    let mut rewritten = path_and_query.path().to_string();
+    let params = path_and_query
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|param| !param.is_empty() && !is_param(param, name));
+    for (i, param) in params.enumerate() {
+        rewritten.push(if i == 0 { '?' } else { '&' });
+        rewritten.push_str(param);
+    }
+    rewritten.push(if rewritten.contains('?') { '&' } else { '?' });
+    rewritten.extend(form_urlencoded::byte_serialize(name.as_bytes()));
+    rewritten.push('=');
+    rewritten.push_str(value);
+
+    Ok(rewritten.parse::<PathAndQuery>()?)
+}
+
+pub fn matches_any_prefix(prefixes: &[String], path: &str) -> bool {
+    prefixes
+        .iter()
+        .any(|prefix| strip_path_prefix(path, prefix).is_some())
+}
+
+pub fn find_route<'a>(routes: &'a [Route], path: &str) -> Option<&'a Route> {
+    routes
+        .iter()
+        .filter(|route| route.matches(path))
+        .max_by_key(|route| route.path_prefix.trim_end_matches('/').len())
+}
+
+// Returns None when the path doesn't start with the prefix to strip
+pub fn rewrite_path(
+    path_and_query: &PathAndQuery,
+    strip_prefix: Option<&str>,
+    add_prefix: Option<&str>,
+) -> Result<Option<PathAndQuery>, Error> {
+    let path = match strip_prefix {
+        Some(prefix) => match strip_path_prefix(path_and_query.path(), prefix) {
+            Some(path) => path,
+            None => return Ok(None),
+        },
+        None => path_and_query.path(),
+    };
+
+    let path = match add_prefix {
+        Some(prefix) => format!("{}{}", prefix.trim_end_matches('/'), path),
+        None => path.to_string(),
+    };
+
+    Ok(Some(with_path(path_and_query, &path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes() -> Vec<Route> {
+        [
+            "/api=http://api",
+            "/api/v2=http://api-v2",
+            "/web/=http://web",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect()
+    }
+
+    fn target_of(path: &str) -> Option<String> {
+        find_route(&routes(), path).map(|route| route.target_url.clone())
+    }
+
+    #[test]
+    fn picks_the_longest_matching_prefix() {
+        assert_eq!(target_of("/api/v2/users").as_deref(), Some("http://api-v2"));
+        assert_eq!(target_of("/api/v1/users").as_deref(), Some("http://api"));
+        assert_eq!(target_of("/api").as_deref(), Some("http://api"));
+        assert_eq!(target_of("/web").as_deref(), Some("http://web"));
+    }
+
+    #[test]
+    fn matches_whole_segments_only() {
+        assert_eq!(target_of("/apiary"), None);
+        assert_eq!(target_of("/api/v2x").as_deref(), Some("http://api"));
+        assert_eq!(target_of("/"), None);
+    }
+
+    #[test]
+    fn strips_the_route_prefix() {
+        let route = "/api=http://api".parse::<Route>().unwrap();
+        let strip = |s: &'static str| {
+            route
+                .strip_prefix(&PathAndQuery::from_static(s))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(strip("/api/users?page=2"), "/users?page=2");
+        assert_eq!(strip("/api"), "/");
+    }
+
+    #[test]
+    fn parses_the_target_url_once() {
+        let route = "/api=https://api:8443".parse::<Route>().unwrap();
+        assert_eq!(route.target_uri.scheme_str(), Some("https"));
+        assert_eq!(
+            route.target_uri.authority().map(|a| a.as_str()),
+            Some("api:8443")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        assert!("api=http://api".parse::<Route>().is_err());
+        assert!("/api".parse::<Route>().is_err());
+        assert!("/api=api".parse::<Route>().is_err());
+    }
+
+    fn rewrite(path: &'static str, strip: Option<&str>, add: Option<&str>) -> Option<String> {
+        rewrite_path(&PathAndQuery::from_static(path), strip, add)
+            .unwrap()
+            .map(|rewritten| rewritten.to_string())
+    }
+
+    #[test]
+    fn strips_and_adds_path_prefixes() {
+        let strip = Some("/service");
+        let add = Some("/v2/");
+        assert_eq!(
+            rewrite("/service/foo", strip, None).as_deref(),
+            Some("/foo")
+        );
+        assert_eq!(rewrite("/foo", None, add).as_deref(), Some("/v2/foo"));
+        assert_eq!(
+            rewrite("/service/foo", strip, add).as_deref(),
+            Some("/v2/foo")
+        );
+        assert_eq!(rewrite("/service", strip, add).as_deref(), Some("/v2/"));
+    }
+
+    #[test]
+    fn keeps_the_query_when_rewriting() {
+        assert_eq!(
+            rewrite("/service/foo?a=1&b=2", Some("/service"), Some("/v2")).as_deref(),
+            Some("/v2/foo?a=1&b=2")
+        );
+    }
+
+    #[test]
+    fn does_not_rewrite_paths_without_the_prefix_to_strip() {
+        assert_eq!(rewrite("/other/foo", Some("/service"), Some("/v2")), None);
+        assert_eq!(rewrite("/services/foo", Some("/service"), None), None);
+    }
+
+    fn is_allowed(allowed: &[&str], blocked: &[&str], path: &str) -> bool {
+        let parse = |patterns: &[&str]| -> Vec<PathPattern> {
+            patterns.iter().map(|s| s.parse().unwrap()).collect()
+        };
+        is_path_allowed(&parse(allowed), &parse(blocked), path)
+    }
+
+    #[test]
+    fn parses_path_patterns() {
+        assert!(matches!("/api".parse(), Ok(PathPattern::Prefix(_))));
+        assert!(matches!("~^/v[0-9]+/".parse(), Ok(PathPattern::Regex(_))));
+        let err = "api".parse::<PathPattern>().unwrap_err();
+        assert_eq!(err.to_string(), "Path pattern must start with / or ~");
+        let err = "~(".parse::<PathPattern>().unwrap_err();
+        assert!(
+            err.to_string().starts_with("Invalid path regex `(`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn allows_every_path_without_patterns() {
+        assert!(is_allowed(&[], &[], "/"));
+        assert!(is_allowed(&[], &[], "/anything"));
+    }
+
+    #[test]
+    fn blocks_the_paths_not_allowed() {
+        let allowed = ["/api/", "~^/v[0-9]+$"];
+        assert!(is_allowed(&allowed, &[], "/api"));
+        assert!(is_allowed(&allowed, &[], "/api/items"));
+        assert!(is_allowed(&allowed, &[], "/v2"));
+        assert!(!is_allowed(&allowed, &[], "/apiary"));
+        assert!(!is_allowed(&allowed, &[], "/v2/items"));
+    }
+
+    #[test]
+    fn blocked_paths_take_precedence() {
+        let allowed = ["/api"];
+        let blocked = ["/api/admin", "~\\.bak$"];
+        assert!(is_allowed(&allowed, &blocked, "/api/items"));
+        assert!(!is_allowed(&allowed, &blocked, "/api/admin/users"));
+        assert!(!is_allowed(&allowed, &blocked, "/api/items.bak"));
+        assert!(!is_allowed(&[], &blocked, "/api/admin"));
+    }
+
+    #[test]
+    fn matches_the_normalized_path() {
+        assert_eq!(normalize_path("/api/%61dmin"), "/api/admin");
+        assert_eq!(normalize_path("/api/./items/../admin/"), "/api/admin/");
+        assert_eq!(normalize_path("/../../admin"), "/admin");
+        assert!(!is_allowed(&["/api"], &["/api/admin"], "/api/x/../%61dmin"));
+    }
+}
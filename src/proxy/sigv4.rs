@@ -0,0 +1,520 @@
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use failure::{err_msg, Error, ResultExt};
+use http::header::{HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE, HOST};
+use http::request::Parts;
+use hyper::{Body, Method, Request};
+use ring::{digest, hmac};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use super::HttpsClient;
+
+const X_AMZ_DATE: &str = "x-amz-date";
+const X_AMZ_CONTENT_SHA256: &str = "x-amz-content-sha256";
+const X_AMZ_SECURITY_TOKEN: &str = "x-amz-security-token";
+// Sent as the payload hash for bodies that are streamed instead of read whole to hash them
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+const IMDS_URL: &str = "http://169.254.169.254/latest";
+const IMDS_TIMEOUT: Duration = Duration::from_secs(5);
+// New instance credentials are available well before the old ones expire
+const IMDS_EXPIRY_MARGIN: Duration = Duration::from_secs(300);
+// For instance credentials without an expiry
+const IMDS_CREDENTIALS_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub struct SigV4Params {
+    pub region: String,
+    pub service: String,
+}
+
+impl SigV4Params {
+    // Only S3 accepts requests without the hash of their payload
+    pub fn allows_unsigned_payload(&self) -> bool {
+        self.service == "s3"
+    }
+}
+
+#[derive(Clone)]
+pub struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> Option<Self> {
+        let var = |name| env::var(name).ok().filter(|value| !value.is_empty());
+        Some(Credentials {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: var("AWS_SESSION_TOKEN"),
+        })
+    }
+}
+
+// Takes the credentials from the standard environment variables,
+// or from the instance metadata service when they aren't set
+#[derive(Default)]
+pub struct CredentialsProvider {
+    // Along with when to fetch new ones
+    instance_credentials: Mutex<Option<(Credentials, Instant)>>,
+}
+
+// Keeps the credentials out of the logs
+impl fmt::Debug for CredentialsProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CredentialsProvider").finish()
+    }
+}
+
+async fn imds_request(client: &HttpsClient, request: Request<Body>) -> Result<String, Error> {
+    let response = timeout(IMDS_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| err_msg("Instance metadata service didn't respond in time"))?
+        .context("Failed to reach the instance metadata service")?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    if !status.is_success() {
+        return Err(err_msg(format!(
+            "Instance metadata service responded with {}",
+            status
+        )));
+    }
+
+    Ok(String::from_utf8(body.to_vec())?)
+}
+
+// Parses the timestamps the instance metadata service gives, like 2015-08-30T12:36:00Z
+fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let number = |range: std::ops::Range<usize>| s.get(range)?.parse::<i64>().ok();
+    if s.len() != 20 || !s.ends_with('Z') {
+        return None;
+    }
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // The inverse of the conversion in amz_date
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+// Uses IMDSv2, which needs a session token for the metadata requests
+async fn fetch_instance_credentials(
+    client: &HttpsClient,
+) -> Result<(Credentials, Option<SystemTime>), Error> {
+    let session_token = imds_request(
+        client,
+        Request::builder()
+            .method(Method::PUT)
+            .uri(format!("{}/api/token", IMDS_URL))
+            .header("x-aws-ec2-metadata-token-ttl-seconds", "60")
+            .body(Body::empty())?,
+    )
+    .await?;
+    let metadata_request = |path: &str| {
+        Request::builder()
+            .uri(format!(
+                "{}/meta-data/iam/security-credentials/{}",
+                IMDS_URL, path
+            ))
+            .header("x-aws-ec2-metadata-token", session_token.as_str())
+            .body(Body::empty())
+    };
+
+    let roles = imds_request(client, metadata_request("")?).await?;
+    let role = roles
+        .lines()
+        .next()
+        .ok_or_else(|| err_msg("The instance has no IAM role"))?;
+    let json: Value = serde_json::from_str(&imds_request(client, metadata_request(role)?).await?)
+        .context("Failed to parse the instance credentials")?;
+
+    let field = |name| {
+        json.get(name)
+            .and_then(Value::as_str)
+            .map(String::from)
+            .ok_or_else(|| err_msg(format!("Instance credentials have no {}", name)))
+    };
+    let credentials = Credentials {
+        access_key_id: field("AccessKeyId")?,
+        secret_access_key: field("SecretAccessKey")?,
+        session_token: Some(field("Token")?),
+    };
+    let expiration = json
+        .get("Expiration")
+        .and_then(Value::as_str)
+        .and_then(parse_timestamp);
+    Ok((credentials, expiration))
+}
+
+// Fetches new credentials ahead of their expiry
+fn refresh_at(expiration: Option<SystemTime>, now: Instant) -> Instant {
+    let ttl = match expiration {
+        Some(expiration) => expiration
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .checked_sub(IMDS_EXPIRY_MARGIN)
+            .unwrap_or_default(),
+        None => IMDS_CREDENTIALS_TTL,
+    };
+    now + ttl
+}
+
+impl CredentialsProvider {
+    pub async fn credentials(&self, client: &HttpsClient) -> Result<Credentials, Error> {
+        if let Some(credentials) = Credentials::from_env() {
+            return Ok(credentials);
+        }
+
+        let mut instance_credentials = self.instance_credentials.lock().await;
+        if let Some((ref credentials, refresh_at)) = *instance_credentials {
+            if Instant::now() < refresh_at {
+                return Ok(credentials.clone());
+            }
+        }
+
+        log::debug!("Fetching AWS credentials from the instance metadata service");
+        let (credentials, expiration) = fetch_instance_credentials(client)
+            .await
+            .context("No AWS credentials in the environment or the instance metadata")?;
+        *instance_credentials = Some((credentials.clone(), refresh_at(expiration, Instant::now())));
+        Ok(credentials)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> hmac::Tag {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+}
+
+// Percent-encodes everything but the unreserved characters, as SigV4 requires
+fn uri_encode(s: &[u8], encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &byte in s {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = s
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn canonical_query(query: &str) -> String {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (
+                uri_encode(&percent_decode(name), true),
+                uri_encode(&percent_decode(value), true),
+            )
+        })
+        .collect();
+    params.sort();
+
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// Formats the time both as a date and as a timestamp, in UTC
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Converts days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    );
+    (date, timestamp)
+}
+
+// Signed headers are kept to the ones no proxy along the way should change
+fn is_signed_header(name: &HeaderName) -> bool {
+    name == HOST || name == CONTENT_TYPE || name.as_str().starts_with("x-amz-")
+}
+
+// Adds the SigV4 Authorization header, along with the X-Amz headers it covers,
+// to a request whose URI and other headers are final. Without the payload, which
+// only services allowing unsigned payloads take, the signature doesn't cover it.
+pub fn sign(
+    request_parts: &mut Parts,
+    payload: Option<&[u8]>,
+    params: &SigV4Params,
+    credentials: &Credentials,
+    now: SystemTime,
+) -> Result<(), Error> {
+    let (date, timestamp) = amz_date(now);
+    let payload_hash = payload.map_or_else(|| UNSIGNED_PAYLOAD.to_string(), sha256_hex);
+
+    // Set every time the request is signed, since a redirect can send it to another host,
+    // hyper would otherwise add it after signing
+    let authority = request_parts
+        .uri
+        .authority()
+        .ok_or_else(|| err_msg("Target URL has no host"))?;
+    let headers = &mut request_parts.headers;
+    headers.insert(HOST, HeaderValue::from_str(authority.as_str())?);
+    headers.insert(X_AMZ_DATE, HeaderValue::from_str(&timestamp)?);
+    // Only S3 requires the payload hash to be sent as well
+    if params.service == "s3" {
+        headers.insert(X_AMZ_CONTENT_SHA256, HeaderValue::from_str(&payload_hash)?);
+    }
+    match credentials.session_token {
+        Some(ref session_token) => {
+            headers.insert(X_AMZ_SECURITY_TOKEN, HeaderValue::from_str(session_token)?)
+        }
+        None => headers.remove(X_AMZ_SECURITY_TOKEN),
+    };
+
+    let mut signed_headers: Vec<(&str, String)> = Vec::new();
+    for name in headers.keys().filter(|name| is_signed_header(name)) {
+        let values = headers
+            .get_all(name)
+            .iter()
+            .map(|value| {
+                Ok(value
+                    .to_str()?
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" "))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        signed_headers.push((name.as_str(), values.join(",")));
+    }
+    signed_headers.sort();
+    let canonical_headers: String = signed_headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value))
+        .collect();
+    let signed_header_names = signed_headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    // S3 paths are used as they are, other services need them encoded once more
+    let path = match request_parts.uri.path() {
+        "" => "/",
+        path => path,
+    };
+    let canonical_path = if params.service == "s3" {
+        path.to_string()
+    } else {
+        uri_encode(path.as_bytes(), false)
+    };
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request_parts.method,
+        canonical_path,
+        canonical_query(request_parts.uri.query().unwrap_or("")),
+        canonical_headers,
+        signed_header_names,
+        payload_hash
+    );
+    log::trace!("SigV4 canonical request: {:?}", canonical_request);
+
+    let scope = format!("{}/{}/{}/aws4_request", date, params.region, params.service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+
+    let secret = format!("AWS4{}", credentials.secret_access_key);
+    let key = hmac_sha256(secret.as_bytes(), &date);
+    let key = hmac_sha256(key.as_ref(), &params.region);
+    let key = hmac_sha256(key.as_ref(), &params.service);
+    let key = hmac_sha256(key.as_ref(), "aws4_request");
+    let signature = hex(hmac_sha256(key.as_ref(), &string_to_sign).as_ref());
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_header_names, signature
+    );
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&authorization)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The credentials and time used by the AWS SigV4 test suite
+    fn sign_example(uri: &str, service: &str, payload: Option<&[u8]>) -> Parts {
+        let mut parts = Request::get(uri).body(()).unwrap().into_parts().0;
+        let params = SigV4Params {
+            region: String::from("us-east-1"),
+            service: String::from(service),
+        };
+        let credentials = Credentials {
+            access_key_id: String::from("AKIDEXAMPLE"),
+            secret_access_key: String::from("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY"),
+            session_token: None,
+        };
+        let now = parse_timestamp("2015-08-30T12:36:00Z").unwrap();
+        sign(&mut parts, payload, &params, &credentials, now).unwrap();
+        parts
+    }
+
+    #[test]
+    fn signs_the_get_vanilla_example() {
+        let parts = sign_example("https://example.amazonaws.com/", "service", Some(b""));
+        assert_eq!(parts.headers[HOST], "example.amazonaws.com");
+        assert_eq!(parts.headers[X_AMZ_DATE], "20150830T123600Z");
+        assert_eq!(
+            parts.headers[AUTHORIZATION],
+            concat!(
+                "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request,",
+                " SignedHeaders=host;x-amz-date,",
+                " Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            )
+        );
+        assert!(!parts.headers.contains_key(X_AMZ_CONTENT_SHA256));
+    }
+
+    #[test]
+    fn signs_the_get_vanilla_query_order_key_case_example() {
+        let parts = sign_example(
+            "https://example.amazonaws.com/?Param2=value2&Param1=value1",
+            "service",
+            Some(b""),
+        );
+        assert!(parts.headers[AUTHORIZATION].to_str().unwrap().ends_with(
+            "Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        ));
+    }
+
+    #[test]
+    fn sends_the_payload_hash_to_s3() {
+        let parts = sign_example("https://bucket.s3.amazonaws.com/key", "s3", Some(b""));
+        assert_eq!(
+            parts.headers[X_AMZ_CONTENT_SHA256],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let parts = sign_example("https://bucket.s3.amazonaws.com/key", "s3", None);
+        assert_eq!(parts.headers[X_AMZ_CONTENT_SHA256], UNSIGNED_PAYLOAD);
+    }
+
+    #[test]
+    fn signs_for_the_host_the_request_goes_to() {
+        let mut parts = sign_example("https://example.amazonaws.com/", "service", Some(b""));
+        parts.uri = "https://other.amazonaws.com/".parse().unwrap();
+        let params = SigV4Params {
+            region: String::from("us-east-1"),
+            service: String::from("service"),
+        };
+        let credentials = Credentials {
+            access_key_id: String::from("AKIDEXAMPLE"),
+            secret_access_key: String::from("secret"),
+            session_token: None,
+        };
+        sign(
+            &mut parts,
+            Some(b""),
+            &params,
+            &credentials,
+            SystemTime::now(),
+        )
+        .unwrap();
+        assert_eq!(parts.headers[HOST], "other.amazonaws.com");
+    }
+
+    #[test]
+    fn parses_the_instance_credentials_expiration() {
+        assert_eq!(
+            parse_timestamp("2015-08-30T12:36:00Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_440_938_160))
+        );
+        assert_eq!(
+            parse_timestamp("2000-02-29T00:00:00Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(951_782_400))
+        );
+        assert_eq!(parse_timestamp("2015-08-30 12:36:00"), None);
+        assert_eq!(parse_timestamp("2015-13-30T12:36:00Z"), None);
+    }
+
+    #[test]
+    fn refreshes_the_instance_credentials_ahead_of_their_expiration() {
+        let now = Instant::now();
+        let expiration = SystemTime::now() + Duration::from_secs(3600);
+        let refresh_in = refresh_at(Some(expiration), now) - now;
+        assert!(refresh_in <= Duration::from_secs(3300) && refresh_in > Duration::from_secs(3290));
+        let expired = SystemTime::now() - Duration::from_secs(60);
+        assert_eq!(refresh_at(Some(expired), now), now);
+        assert_eq!(refresh_at(None, now), now + IMDS_CREDENTIALS_TTL);
+    }
+}
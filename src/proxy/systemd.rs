@@ -0,0 +1,123 @@
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::process;
+
+use failure::{err_msg, Error, ResultExt};
+
+// The first file descriptor passed with socket activation, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum InheritedSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+fn check_os_result(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn is_listening(fd: RawFd) -> io::Result<bool> {
+    let mut accepting: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    check_os_result(unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut accepting as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    })?;
+    Ok(accepting != 0)
+}
+
+fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    check_os_result(unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    })?;
+    Ok(addr.ss_family as libc::c_int)
+}
+
+// Takes over the listening socket passed by systemd, which has to be the only one.
+// The ownership of file descriptor 3 is assumed, so this must only be called once.
+pub fn take_listen_socket() -> Result<InheritedSocket, Error> {
+    let listen_pid = env::var("LISTEN_PID")
+        .map_err(|_| err_msg("No socket was passed by systemd, LISTEN_PID isn't set"))?;
+    if listen_pid.parse::<u32>().ok() != Some(process::id()) {
+        return Err(err_msg(
+            "The sockets passed by systemd are meant for another process",
+        ));
+    }
+    let listen_fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<u32>().ok())
+        .ok_or_else(|| err_msg("No socket was passed by systemd, LISTEN_FDS isn't set"))?;
+    if listen_fds != 1 {
+        return Err(err_msg(format!(
+            "Expected exactly one socket to be passed by systemd, got {}",
+            listen_fds
+        )));
+    }
+    // Commands run by the proxy shouldn't take the socket for theirs
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    let fd = LISTEN_FDS_START;
+    check_os_result(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })
+        .context("Failed to use the socket passed by systemd")?;
+    if !is_listening(fd).context("The file descriptor passed by systemd isn't a socket")? {
+        return Err(err_msg(
+            "The socket passed by systemd isn't listening, is Accept=yes set?",
+        ));
+    }
+
+    match socket_family(fd).context("Failed to use the socket passed by systemd")? {
+        libc::AF_INET | libc::AF_INET6 => Ok(InheritedSocket::Tcp(unsafe {
+            TcpListener::from_raw_fd(fd)
+        })),
+        libc::AF_UNIX => Ok(InheritedSocket::Unix(unsafe {
+            UnixListener::from_raw_fd(fd)
+        })),
+        family => Err(err_msg(format!(
+            "The socket passed by systemd has an unsupported address family {}",
+            family
+        ))),
+    }
+}
+
+fn send_notification(socket_path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let addr = match socket_path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket_path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+// Tells systemd that the proxy is ready for requests, when it's run as a Type=notify service
+pub fn notify_ready() {
+    if let Some(socket_path) = env::var_os("NOTIFY_SOCKET") {
+        if let Err(e) = send_notification(&socket_path, "READY=1") {
+            log::warn!("Failed to notify systemd of readiness: {}", e);
+        }
+    }
+}
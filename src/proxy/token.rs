@@ -0,0 +1,460 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::{err_msg, Error, ResultExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use serde_json::Value;
+
+// Subtracted from the JWT expiry so the token isn't used right as it expires
+const JWT_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+// How much of a token is logged, only for tokens long enough that it gives little away
+const REDACTED_PREFIX_LEN: usize = 4;
+const MIN_LEN_TO_SHOW_PREFIX: usize = 16;
+
+// Everything but the characters RFC 3986 leaves unreserved is percent-encoded by url-encode
+const URL_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenFormat {
+    Raw,
+    Json,
+}
+
+impl FromStr for TokenFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(TokenFormat::Raw),
+            "json" => Ok(TokenFormat::Json),
+            _ => Err(err_msg(format!("Unknown token format: {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenEncoding {
+    None,
+    Base64Decode,
+    Base64Encode,
+    UrlEncode,
+}
+
+impl FromStr for TokenEncoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TokenEncoding::None),
+            "base64-decode" => Ok(TokenEncoding::Base64Decode),
+            "base64-encode" => Ok(TokenEncoding::Base64Encode),
+            "url-encode" => Ok(TokenEncoding::UrlEncode),
+            _ => Err(err_msg(format!("Unknown token encoding: {}", s))),
+        }
+    }
+}
+
+// Where the token goes in the forwarded request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthLocation {
+    Header,
+    Query,
+    Cookie,
+}
+
+impl FromStr for AuthLocation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "header" => Ok(AuthLocation::Header),
+            "query" => Ok(AuthLocation::Query),
+            "cookie" => Ok(AuthLocation::Cookie),
+            _ => Err(err_msg(format!("Unknown auth location: {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub value: String,
+    // Overrides the cache ttl when the command reports when the token expires
+    pub ttl: Option<Duration>,
+}
+
+pub fn encode_token(value: String, encoding: TokenEncoding) -> Result<String, Error> {
+    match encoding {
+        TokenEncoding::None => Ok(value),
+        TokenEncoding::Base64Decode => {
+            let decoded = base64::decode(&value).context("Failed to decode the token as base64")?;
+            Ok(String::from_utf8(decoded).context("The token decoded from base64 isn't UTF-8")?)
+        }
+        TokenEncoding::Base64Encode => Ok(base64::encode(&value)),
+        TokenEncoding::UrlEncode => Ok(utf8_percent_encode(&value, URL_ENCODE_SET).to_string()),
+    }
+}
+
+pub fn parse_token(
+    output: Vec<u8>,
+    format: TokenFormat,
+    token_field: &str,
+    token_jsonpath: Option<&str>,
+    token_regex: Option<&Regex>,
+    expiry_field: &str,
+) -> Result<Token, Error> {
+    let output = String::from_utf8(output)?;
+
+    match format {
+        TokenFormat::Raw => {
+            let output = output.trim();
+            let value = match token_regex {
+                Some(regex) => regex
+                    .captures(output)
+                    .and_then(|captures| captures.get(1))
+                    .ok_or_else(|| {
+                        err_msg(format!(
+                            "Token regex `{}` doesn't match the command output",
+                            regex
+                        ))
+                    })?
+                    .as_str(),
+                None => output,
+            };
+
+            Ok(Token {
+                value: value.to_string(),
+                ttl: None,
+            })
+        }
+        TokenFormat::Json => {
+            let json: Value = serde_json::from_str(&output)
+                .context("Failed to parse the command output as JSON")?;
+
+            let value = match token_jsonpath {
+                Some(path) => select_token(&json, path)?,
+                None => json
+                    .get(token_field)
+                    .ok_or_else(|| {
+                        err_msg(format!(
+                            "Field `{}` is missing from the command output",
+                            token_field
+                        ))
+                    })?
+                    .as_str()
+                    .ok_or_else(|| {
+                        err_msg(format!(
+                            "Field `{}` in the command output is not a string",
+                            token_field
+                        ))
+                    })?,
+            };
+            let expires_in = json
+                .get(expiry_field)
+                .ok_or_else(|| {
+                    err_msg(format!(
+                        "Field `{}` is missing from the command output",
+                        expiry_field
+                    ))
+                })?
+                .as_u64()
+                .ok_or_else(|| {
+                    err_msg(format!(
+                        "Field `{}` in the command output is not a non-negative integer",
+                        expiry_field
+                    ))
+                })?;
+
+            Ok(Token {
+                value: value.to_string(),
+                ttl: Some(Duration::from_secs(expires_in)),
+            })
+        }
+    }
+}
+
+fn select_token<'a>(json: &'a Value, path: &str) -> Result<&'a str, Error> {
+    let selected = jsonpath_lib::select(json, path)
+        .with_context(|_| format!("Failed to evaluate JSONPath `{}`", path))?;
+
+    match selected.as_slice() {
+        [value] => value.as_str().ok_or_else(|| {
+            err_msg(format!(
+                "Value at `{}` in the command output is not a string",
+                path
+            ))
+        }),
+        [] => Err(err_msg(format!(
+            "JSONPath `{}` matches nothing in the command output",
+            path
+        ))),
+        _ => Err(err_msg(format!(
+            "JSONPath `{}` matches {} values in the command output, expected one",
+            path,
+            selected.len()
+        ))),
+    }
+}
+
+// How long the token is valid for according to its exp claim, if it's a JWT that has one
+pub fn jwt_ttl(token: &str) -> Option<Duration> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let exp = serde_json::from_slice::<Value>(&payload)
+        .ok()?
+        .get("exp")?
+        .as_u64()?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    Some(
+        Duration::from_secs(exp)
+            .checked_sub(now)
+            .and_then(|ttl| ttl.checked_sub(JWT_EXPIRY_MARGIN))
+            .unwrap_or_default(),
+    )
+}
+
+// Enough of the token to tell tokens apart in logs without leaking them
+pub fn redact(token: &str) -> String {
+    let prefix: String = if token.len() >= MIN_LEN_TO_SHOW_PREFIX {
+        token.chars().take(REDACTED_PREFIX_LEN).collect()
+    } else {
+        String::new()
+    };
+    format!("{}… (len {})", prefix, token.len())
+}
+
+// A password or token given to the proxy, which its Debug leaves out so that logging
+// the params doesn't leak it
+#[derive(Clone, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Secret(s)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Secret(s.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(\"{}\")", redact(&self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_json(output: &str) -> Result<Token, Error> {
+        parse_token(
+            output.as_bytes().to_vec(),
+            TokenFormat::Json,
+            "access_token",
+            None,
+            None,
+            "expires_in",
+        )
+    }
+
+    #[test]
+    fn parses_the_token_and_its_expiry_from_json() {
+        let token = parse_json(r#"{"access_token": "secret", "expires_in": 3600}"#).unwrap();
+        assert_eq!(token.value, "secret");
+        assert_eq!(token.ttl, Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn rejects_json_without_the_fields() {
+        let err = parse_json(r#"{"expires_in": 3600}"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Field `access_token` is missing from the command output"
+        );
+        let err = parse_json(r#"{"access_token": "secret"}"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Field `expires_in` is missing from the command output"
+        );
+        let err = parse_json(r#"{"access_token": "secret", "expires_in": -1}"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Field `expires_in` in the command output is not a non-negative integer"
+        );
+        assert!(parse_json("secret").is_err());
+    }
+
+    fn parse_json_at(output: &str, path: &str) -> Result<Token, Error> {
+        parse_token(
+            output.as_bytes().to_vec(),
+            TokenFormat::Json,
+            "access_token",
+            Some(path),
+            None,
+            "expires_in",
+        )
+    }
+
+    #[test]
+    fn selects_a_nested_token_with_a_jsonpath() {
+        let output = r#"{"data": {"credentials": {"token": "secret"}}, "expires_in": 60}"#;
+        let token = parse_json_at(output, "$.data.credentials.token").unwrap();
+        assert_eq!(token.value, "secret");
+        assert_eq!(token.ttl, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn rejects_a_jsonpath_matching_nothing() {
+        let output = r#"{"data": {}, "expires_in": 60}"#;
+        let err = parse_json_at(output, "$.data.credentials.token").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "JSONPath `$.data.credentials.token` matches nothing in the command output"
+        );
+    }
+
+    #[test]
+    fn rejects_a_jsonpath_matching_several_values() {
+        let output = r#"{"tokens": [{"value": "a"}, {"value": "b"}], "expires_in": 60}"#;
+        let err = parse_json_at(output, "$.tokens[*].value").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "JSONPath `$.tokens[*].value` matches 2 values in the command output, expected one"
+        );
+    }
+
+    #[test]
+    fn keeps_raw_output_as_the_token() {
+        let token = parse_token(
+            b"secret\n".to_vec(),
+            TokenFormat::Raw,
+            "access_token",
+            None,
+            None,
+            "expires_in",
+        )
+        .unwrap();
+        assert_eq!(token.value, "secret");
+        assert_eq!(token.ttl, None);
+    }
+
+    fn parse_raw_with(output: &str, regex: &str) -> Result<Token, Error> {
+        parse_token(
+            output.as_bytes().to_vec(),
+            TokenFormat::Raw,
+            "access_token",
+            None,
+            Some(&Regex::new(regex).unwrap()),
+            "expires_in",
+        )
+    }
+
+    #[test]
+    fn extracts_the_token_with_a_regex() {
+        let token = parse_raw_with("token=abc123; expires=60\n", r"token=([^;]+)").unwrap();
+        assert_eq!(token.value, "abc123");
+    }
+
+    #[test]
+    fn rejects_output_not_matching_the_regex() {
+        let err = parse_raw_with("expires=60", r"token=([^;]+)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Token regex `token=([^;]+)` doesn't match the command output"
+        );
+    }
+
+    fn jwt_expiring_in(secs: u64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let payload = format!(r#"{{"sub":"proxy","exp":{}}}"#, now.as_secs() + secs);
+        format!(
+            "eyJhbGciOiJIUzI1NiJ9.{}.signature",
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn reads_the_ttl_from_the_jwt_expiry() {
+        let ttl = jwt_ttl(&jwt_expiring_in(60)).unwrap();
+        // Less the margin, and the part of a second that could have passed since
+        assert!(ttl <= Duration::from_secs(50) && ttl >= Duration::from_secs(48));
+        assert_eq!(jwt_ttl(&jwt_expiring_in(5)), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn reads_no_ttl_from_other_tokens() {
+        assert_eq!(jwt_ttl("not-a-jwt"), None);
+        let payload = base64::encode_config(r#"{"sub":"proxy"}"#, base64::URL_SAFE_NO_PAD);
+        assert_eq!(jwt_ttl(&format!("header.{}.signature", payload)), None);
+    }
+
+    #[test]
+    fn leaves_secrets_out_of_the_debug_output() {
+        let secret = Secret::from("admin-secret");
+        assert_eq!(
+            format!("{:?}", Some(&secret)),
+            "Some(Secret(\"… (len 12)\"))"
+        );
+        assert_eq!(secret.expose(), "admin-secret");
+    }
+
+    #[test]
+    fn redacts_all_but_a_prefix_of_long_tokens() {
+        assert_eq!(redact("abcdefghijklmnopqrstuvwxyz"), "abcd… (len 26)");
+        assert_eq!(redact("short-token"), "… (len 11)");
+    }
+
+    #[test]
+    fn encodes_the_token() {
+        let encode = |value: &str, encoding| encode_token(value.to_string(), encoding).unwrap();
+        assert_eq!(encode("a b/c", TokenEncoding::None), "a b/c");
+        assert_eq!(
+            encode("dXNlcjpwYXNz", TokenEncoding::Base64Decode),
+            "user:pass"
+        );
+        assert_eq!(
+            encode("user:pass", TokenEncoding::Base64Encode),
+            "dXNlcjpwYXNz"
+        );
+        assert_eq!(
+            encode("a b/c=d&e+f~g", TokenEncoding::UrlEncode),
+            "a%20b%2Fc%3Dd%26e%2Bf~g"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_base64() {
+        let err =
+            encode_token(String::from("not base64!"), TokenEncoding::Base64Decode).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to decode the token as base64");
+        let err =
+            encode_token(base64::encode([0xff, 0xfe]), TokenEncoding::Base64Decode).unwrap_err();
+        assert_eq!(err.to_string(), "The token decoded from base64 isn't UTF-8");
+    }
+
+    #[test]
+    fn parses_token_encodings() {
+        assert_eq!(
+            "base64-decode".parse::<TokenEncoding>().unwrap(),
+            TokenEncoding::Base64Decode
+        );
+        assert!("base32".parse::<TokenEncoding>().is_err());
+    }
+}
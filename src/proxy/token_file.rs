@@ -0,0 +1,49 @@
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+// Changes that come in quick succession, such as writing a file and renaming it, are reported once
+const WATCH_DELAY: Duration = Duration::from_millis(500);
+
+// The file is opened anew every time, so replacing it, or the symlink to it
+// as Kubernetes does for projected tokens, takes effect on the next read
+pub async fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    Ok(tokio::fs::read(path)
+        .await
+        .with_context(|_| format!("Failed to read token file {}", path.display()))?)
+}
+
+// Calls on_change whenever something in the directory of the file changes, watching the
+// directory rather than the file itself so that the file being replaced is noticed too.
+// The file is watched for as long as the returned watcher is kept around.
+pub fn watch<F>(path: &Path, on_change: F) -> Result<RecommendedWatcher, Error>
+where
+    F: Fn() + Send + 'static,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::watcher(sender, WATCH_DELAY)?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|_| format!("Failed to watch {}", dir.display()))?;
+
+    thread::spawn(move || {
+        // Ends when the watcher is dropped
+        for event in receiver {
+            match event {
+                DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => {}
+                DebouncedEvent::Error(e, _) => log::warn!("Failed to watch the token file: {}", e),
+                _ => on_change(),
+            }
+        }
+    });
+
+    Ok(watcher)
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::{err_msg, Error};
+use http::header::CONTENT_TYPE;
+use http::uri::Uri;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, HeaderMap, Method, Request};
+use hyper_tls::HttpsConnector;
+use serde_json::{json, Value};
+
+const TRACEPARENT: &str = "traceparent";
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+// An unresponsive collector mustn't keep the proxy from exiting
+const FINAL_EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+// Spans finished while the collector is unreachable are dropped past this many
+const MAX_BUFFERED_SPANS: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+pub enum SpanKind {
+    Internal,
+    Server,
+    Client,
+}
+
+impl SpanKind {
+    fn as_otlp(self) -> u8 {
+        match self {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+            SpanKind::Client => 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Span {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start_time: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    failed: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex<T: AsMut<[u8]> + Default>(s: &str) -> Option<T> {
+    let mut bytes = T::default();
+    if s.len() != bytes.as_mut().len() * 2 {
+        return None;
+    }
+    for (i, byte) in bytes.as_mut().iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// Trace and parent span ids from a W3C traceparent header, all zero ids are invalid
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    if version.len() != 2 || version == "ff" {
+        return None;
+    }
+    let trace_id: [u8; 16] = parse_hex(parts.next()?)?;
+    let parent_id: [u8; 8] = parse_hex(parts.next()?)?;
+    parse_hex::<[u8; 1]>(parts.next()?)?;
+
+    if trace_id == [0; 16] || parent_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, parent_id))
+}
+
+fn random_span_id() -> [u8; 8] {
+    loop {
+        let id = rand::random::<[u8; 8]>();
+        if id != [0; 8] {
+            return id;
+        }
+    }
+}
+
+fn random_trace_id() -> [u8; 16] {
+    loop {
+        let id = rand::random::<[u8; 16]>();
+        if id != [0; 16] {
+            return id;
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+impl Span {
+    // Continues the client's trace when the request carries a valid traceparent
+    pub fn for_request(name: &'static str, headers: &HeaderMap) -> Self {
+        let parent = headers
+            .get(TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent);
+
+        Span {
+            trace_id: parent.map_or_else(random_trace_id, |(trace_id, _)| trace_id),
+            span_id: random_span_id(),
+            parent_span_id: parent.map(|(_, parent_id)| parent_id),
+            name,
+            kind: SpanKind::Server,
+            start_time: SystemTime::now(),
+            attributes: Vec::new(),
+            failed: false,
+        }
+    }
+
+    pub fn child(&self, name: &'static str, kind: SpanKind) -> Self {
+        Span {
+            trace_id: self.trace_id,
+            span_id: random_span_id(),
+            parent_span_id: Some(self.span_id),
+            name,
+            kind,
+            start_time: SystemTime::now(),
+            attributes: Vec::new(),
+            failed: false,
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        self.attributes.push((key, value.to_string()));
+    }
+
+    pub fn set_failed(&mut self) {
+        self.failed = true;
+    }
+
+    fn to_otlp(&self, end_time: SystemTime) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+            .collect();
+
+        let mut span = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            "kind": self.kind.as_otlp(),
+            "startTimeUnixNano": unix_nanos(self.start_time),
+            "endTimeUnixNano": unix_nanos(end_time),
+            "attributes": attributes,
+            "status": {"code": if self.failed { 2 } else { 0 }},
+        });
+        if let Some(ref parent_span_id) = self.parent_span_id {
+            span["parentSpanId"] = json!(hex(parent_span_id));
+        }
+        span
+    }
+}
+
+// Exports finished spans in batches to an OTLP collector over HTTP with JSON encoding
+pub struct Tracer {
+    traces_uri: Uri,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    finished: Mutex<Vec<Value>>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("traces_uri", &self.traces_uri)
+            .finish()
+    }
+}
+
+impl Tracer {
+    pub fn new(endpoint: &str) -> Result<Self, Error> {
+        let traces_uri = format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+            .parse::<Uri>()
+            .map_err(|_| err_msg(format!("Invalid OTLP endpoint: {}", endpoint)))?;
+
+        Ok(Tracer {
+            traces_uri,
+            client: Client::builder().build(HttpsConnector::new()),
+            finished: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn finish(&self, span: Span) {
+        let mut finished = self.finished.lock().unwrap();
+        if finished.len() < MAX_BUFFERED_SPANS {
+            finished.push(span.to_otlp(SystemTime::now()));
+        }
+    }
+
+    pub async fn export(&self) {
+        let spans = mem::take(&mut *self.finished.lock().unwrap());
+        if spans.is_empty() {
+            return;
+        }
+
+        let payload = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{"key": "service.name", "value": {"stringValue": "authproxy"}}],
+                },
+                "scopeSpans": [{"scope": {"name": "authproxy"}, "spans": spans}],
+            }],
+        });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.traces_uri.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()));
+
+        let result = match request {
+            Ok(request) => self.client.request(request).await.map_err(Error::from),
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => log::warn!(
+                "Failed to export spans: collector responded with {}",
+                response.status()
+            ),
+            Err(e) => log::warn!("Failed to export spans: {}", e),
+        }
+    }
+
+    pub async fn export_remaining(&self) {
+        if tokio::time::timeout(FINAL_EXPORT_TIMEOUT, self.export())
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "Failed to export spans: collector didn't respond within {} seconds",
+                FINAL_EXPORT_TIMEOUT.as_secs()
+            );
+        }
+    }
+
+    pub async fn export_periodically(&self) {
+        loop {
+            tokio::time::delay_for(EXPORT_INTERVAL).await;
+            self.export().await;
+        }
+    }
+}
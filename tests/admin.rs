@@ -0,0 +1,207 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use authproxy::proxy::TokenEncoding;
+use hyper::{Body, Method, Request, StatusCode};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+async fn flush(proxy: SocketAddr, authorization: &str) -> StatusCode {
+    let request = Request::post(format!("http://{}/admin/flush-cache", proxy))
+        .header("authorization", authorization)
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await.status()
+}
+
+async fn spawn_admin_proxy(dir: &TempDir) -> SocketAddr {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.admin_token = Some("admin-secret".into());
+    common::spawn_proxy(params).await
+}
+
+#[tokio::test]
+async fn flushes_the_token_cache() {
+    let dir = TempDir::new().unwrap();
+    let proxy = spawn_admin_proxy(&dir).await;
+
+    common::get(proxy, "/").await;
+    common::get(proxy, "/").await;
+    assert_eq!(common::command_runs(dir.path()), 1);
+
+    assert_eq!(flush(proxy, "Bearer admin-secret").await, StatusCode::OK);
+    common::get(proxy, "/").await;
+    assert_eq!(common::command_runs(dir.path()), 2);
+}
+
+#[tokio::test]
+async fn refuses_to_flush_with_the_wrong_token() {
+    let dir = TempDir::new().unwrap();
+    let proxy = spawn_admin_proxy(&dir).await;
+
+    common::get(proxy, "/").await;
+    assert_eq!(flush(proxy, "Bearer wrong").await, StatusCode::FORBIDDEN);
+    common::get(proxy, "/").await;
+    assert_eq!(common::command_runs(dir.path()), 1);
+}
+
+async fn cached_ttl_secs(command: Vec<String>, token_encoding: TokenEncoding) -> u64 {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = command;
+    params.ttl_from_jwt = true;
+    params.token_encoding = token_encoding;
+    params.cache_ttl_secs = 300;
+    params.admin_token = Some("admin-secret".into());
+    let proxy = common::spawn_proxy(params).await;
+
+    common::get(proxy, "/").await;
+    let request = Request::get(format!("http://{}/admin/status", proxy))
+        .header("authorization", "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let status = common::body_string(common::send(request).await).await;
+    let status: Value = serde_json::from_str(&status).unwrap();
+    status["tokens"][0]["ttl_secs"].as_u64().unwrap()
+}
+
+fn jwt_expiring_in(secs: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let payload = format!(r#"{{"exp":{}}}"#, now.as_secs() + secs);
+    format!(
+        "eyJhbGciOiJIUzI1NiJ9.{}.signature",
+        base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+#[tokio::test]
+async fn caches_the_token_until_the_jwt_expires() {
+    let command = vec![String::from("echo"), jwt_expiring_in(60)];
+    let ttl_secs = cached_ttl_secs(command, TokenEncoding::None).await;
+    // Less the safety margin, and the time it took to get here
+    assert!((48..=50).contains(&ttl_secs), "{}", ttl_secs);
+}
+
+#[tokio::test]
+async fn reads_the_jwt_expiry_before_encoding_the_token() {
+    let command = vec![String::from("echo"), jwt_expiring_in(60)];
+    let ttl_secs = cached_ttl_secs(command, TokenEncoding::Base64Encode).await;
+    assert!((48..=50).contains(&ttl_secs), "{}", ttl_secs);
+}
+
+#[tokio::test]
+async fn caches_other_tokens_for_the_cache_ttl() {
+    let command = vec![String::from("echo"), String::from("opaque")];
+    let ttl_secs = cached_ttl_secs(command, TokenEncoding::None).await;
+    assert_eq!(ttl_secs, 300);
+}
+
+async fn toggle_maintenance(proxy: SocketAddr, method: Method) -> StatusCode {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://{}/admin/maintenance", proxy))
+        .header("authorization", "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await.status()
+}
+
+#[tokio::test]
+async fn answers_with_service_unavailable_in_maintenance_mode() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.maintenance = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "60");
+    assert_eq!(
+        common::get(proxy, "/healthz").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(common::command_runs(dir.path()), 0);
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn toggles_maintenance_mode_at_runtime() {
+    let dir = TempDir::new().unwrap();
+    let proxy = spawn_admin_proxy(&dir).await;
+
+    assert_eq!(
+        toggle_maintenance(proxy, Method::POST).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        common::get(proxy, "/").await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(
+        toggle_maintenance(proxy, Method::DELETE).await,
+        StatusCode::OK
+    );
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    assert_eq!(common::command_runs(dir.path()), 1);
+}
+
+async fn admin_status(proxy: SocketAddr, authorization: &str) -> (StatusCode, Value) {
+    let request = Request::get(format!("http://{}/admin/status", proxy))
+        .header("authorization", authorization)
+        .body(Body::empty())
+        .unwrap();
+    let response = common::send(request).await;
+    let status = response.status();
+    let body = common::body_string(response).await;
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn reports_the_cached_token_before_and_after_a_refresh() {
+    let dir = TempDir::new().unwrap();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.cache_ttl_secs = 2;
+    params.admin_token = Some("admin-secret".into());
+    let proxy = common::spawn_proxy(params).await;
+
+    let (_, status) = admin_status(proxy, "Bearer admin-secret").await;
+    assert_eq!(status["tokens"], json!([]));
+
+    common::get(proxy, "/").await;
+    let (_, status) = admin_status(proxy, "Bearer admin-secret").await;
+    let token = &status["tokens"][0];
+    assert_eq!(token["cached"], true);
+    assert_eq!(token["age_secs"], 0);
+    assert_eq!(token["ttl_secs"], 2);
+    assert!(token["expires_in_secs"].as_u64().unwrap() <= 2);
+    assert!(!status.to_string().contains("token1"));
+
+    tokio::time::delay_for(Duration::from_millis(2100)).await;
+    let (_, status) = admin_status(proxy, "Bearer admin-secret").await;
+    assert_eq!(status["tokens"][0]["cached"], false);
+    assert_eq!(status["tokens"][0]["expires_in_secs"], 0);
+
+    common::get(proxy, "/").await;
+    assert_eq!(common::command_runs(dir.path()), 2);
+    let (_, status) = admin_status(proxy, "Bearer admin-secret").await;
+    assert_eq!(status["tokens"][0]["cached"], true);
+    assert_eq!(status["tokens"][0]["age_secs"], 0);
+}
+
+#[tokio::test]
+async fn refuses_to_report_with_the_wrong_token() {
+    let dir = TempDir::new().unwrap();
+    let proxy = spawn_admin_proxy(&dir).await;
+
+    let (status, _) = admin_status(proxy, "Bearer wrong").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use authproxy::proxy::{Proxy, ProxyError, Route};
+use http::header::HeaderName;
+use hyper::{Body, Request, StatusCode};
+use tempfile::TempDir;
+
+// Obtains "token-AUDIENCE" for the audience passed as the last argument, counting the runs per
+// audience
+fn audience_command(dir: &Path) -> Vec<String> {
+    let script = format!(
+        "f={}/runs-$0; n=$(($(cat $f 2>/dev/null || echo 0) + 1)); echo $n > $f; echo token-$0",
+        dir.display()
+    );
+    vec![String::from("sh"), String::from("-c"), script]
+}
+
+fn audience_runs(dir: &Path, audience: &str) -> u32 {
+    fs::read_to_string(dir.join(format!("runs-{}", audience)))
+        .map_or(0, |runs| runs.trim().parse().unwrap())
+}
+
+fn route(path_prefix: &str, target: std::net::SocketAddr, audience: &str) -> Route {
+    let mut route: Route = format!("{}=http://{}", path_prefix, target)
+        .parse()
+        .unwrap();
+    route.audience = Some(String::from(audience));
+    route
+}
+
+#[tokio::test]
+async fn caches_the_tokens_for_each_route_audience_independently() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = audience_command(dir.path());
+    params.routes = vec![route("/a", target, "aud-a"), route("/b", target, "aud-b")];
+    let proxy = common::spawn_proxy(params).await;
+
+    for path in &["/a/1", "/b/1", "/a/2", "/b/2"] {
+        assert_eq!(common::get(proxy, path).await.status(), StatusCode::OK);
+    }
+    assert_eq!(audience_runs(dir.path(), "aud-a"), 1);
+    assert_eq!(audience_runs(dir.path(), "aud-b"), 1);
+    let received = received.lock().unwrap();
+    let headers: Vec<_> = received
+        .iter()
+        .map(|request| request.header("authorization").unwrap())
+        .collect();
+    assert_eq!(
+        headers,
+        vec![
+            "Bearer token-aud-a",
+            "Bearer token-aud-b",
+            "Bearer token-aud-a",
+            "Bearer token-aud-b",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn obtains_tokens_for_allowed_audiences_from_the_header() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = audience_command(dir.path());
+    params.audience_header = Some(HeaderName::from_static("x-audience"));
+    params.allowed_audiences = vec![String::from("aud-a"), String::from("aud-b")];
+    let proxy = common::spawn_proxy(params).await;
+
+    for audience in &["aud-a", "aud-b", "aud-a"] {
+        let request = Request::get(format!("http://{}/", proxy))
+            .header("x-audience", *audience)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(common::send(request).await.status(), StatusCode::OK);
+    }
+    assert_eq!(audience_runs(dir.path(), "aud-a"), 1);
+    assert_eq!(audience_runs(dir.path(), "aud-b"), 1);
+    assert_eq!(
+        received.lock().unwrap()[1].header("authorization"),
+        Some("Bearer token-aud-b")
+    );
+}
+
+#[tokio::test]
+async fn rejects_header_audiences_that_are_not_allowed() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = audience_command(dir.path());
+    params.audience_header = Some(HeaderName::from_static("x-audience"));
+    params.allowed_audiences = vec![String::from("aud-a")];
+    let proxy = common::spawn_proxy(params).await;
+
+    for audience in &["aud-c", "--help"] {
+        let request = Request::get(format!("http://{}/", proxy))
+            .header("x-audience", *audience)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(common::send(request).await.status(), StatusCode::FORBIDDEN);
+    }
+    assert!(received.lock().unwrap().is_empty());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn requires_allowed_audiences_for_the_audience_header() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.audience_header = Some(HeaderName::from_static("x-audience"));
+
+    match Proxy::bind(params).await {
+        Err(ProxyError::Config(err)) => assert_eq!(
+            err.to_string(),
+            "The audience header requires allowed audiences"
+        ),
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started without allowed audiences"),
+    }
+}
+
+#[tokio::test]
+async fn rejects_audiences_starting_with_a_dash() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.routes = vec![route("/a", target, "--output=/tmp/x")];
+
+    match Proxy::bind(params).await {
+        Err(ProxyError::Config(err)) => {
+            assert_eq!(
+                err.to_string(),
+                "Audience --output=/tmp/x can't start with -"
+            )
+        }
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started with an audience starting with -"),
+    }
+}
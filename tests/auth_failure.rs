@@ -0,0 +1,90 @@
+mod common;
+
+use std::time::Duration;
+
+use hyper::{Body, Request, Response, StatusCode};
+use tempfile::TempDir;
+
+// Rejects the first token, slowly so that concurrent requests are all sent with it
+async fn reject_first_token(req: Request<Body>) -> Response<Body> {
+    let authorization = req.headers().get("authorization").unwrap();
+    if authorization == "Bearer token1" {
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        let mut response = Response::new(Body::from("expired"));
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        return response;
+    }
+    Response::new(Body::from(authorization.to_str().unwrap().to_string()))
+}
+
+#[tokio::test]
+async fn refreshes_the_token_and_retries_once_rejected() {
+    let dir = TempDir::new().unwrap();
+    let target = common::spawn_target(reject_first_token).await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "Bearer token2");
+    assert_eq!(common::command_runs(dir.path()), 2);
+}
+
+#[tokio::test]
+async fn refreshes_the_token_once_for_concurrent_rejections() {
+    let dir = TempDir::new().unwrap();
+    let target = common::spawn_target(reject_first_token).await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    let proxy = common::spawn_proxy(params).await;
+
+    let responses = futures::future::join_all((0..5).map(|_| common::get(proxy, "/"))).await;
+    for response in responses {
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    assert_eq!(common::command_runs(dir.path()), 2);
+}
+
+#[tokio::test]
+async fn passes_the_rejection_on_without_auth_failure_statuses() {
+    let dir = TempDir::new().unwrap();
+    let target = common::spawn_target(reject_first_token).await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.auth_failure_statuses = Vec::new();
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(common::command_runs(dir.path()), 1);
+}
+
+#[tokio::test]
+async fn passes_the_rejection_on_without_a_cache() {
+    let dir = TempDir::new().unwrap();
+    let target = common::spawn_target(reject_first_token).await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.no_cache = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(common::command_runs(dir.path()), 1);
+}
+
+#[tokio::test]
+async fn does_not_retry_bodies_above_the_max_retry_body_size() {
+    let dir = TempDir::new().unwrap();
+    let target = common::spawn_target(reject_first_token).await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.max_retry_body_size = 4;
+    let proxy = common::spawn_proxy(params).await;
+
+    let uri = format!("http://{}/", proxy);
+    let request = Request::put(uri).body(Body::from("larger")).unwrap();
+    let response = common::send(request).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use authproxy::proxy::{AuthLocation, TokenEncoding};
+use http::header::HeaderValue;
+use hyper::{Body, Request, StatusCode};
+
+fn sh(script: &str) -> Vec<String> {
+    vec![String::from("sh"), String::from("-c"), String::from(script)]
+}
+
+#[tokio::test]
+async fn puts_the_token_into_the_query() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = sh("echo 'a b&c=d'");
+    params.auth_location = AuthLocation::Query;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        common::get(proxy, "/resource").await.status(),
+        StatusCode::OK
+    );
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].parts.uri, "/resource?access_token=a+b%26c%3Dd");
+    assert_eq!(received[0].header("authorization"), None);
+}
+
+#[tokio::test]
+async fn puts_the_url_encoded_token_into_the_query_as_it_is() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = sh("echo 'a b&c=d+e'");
+    params.auth_location = AuthLocation::Query;
+    params.token_encoding = TokenEncoding::UrlEncode;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        common::get(proxy, "/resource").await.status(),
+        StatusCode::OK
+    );
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].parts.uri,
+        "/resource?access_token=a%20b%26c%3Dd%2Be"
+    );
+}
+
+#[tokio::test]
+async fn keeps_the_other_query_params() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.auth_location = AuthLocation::Query;
+    params.query_param_name = String::from("key");
+    let proxy = common::spawn_proxy(params).await;
+
+    for path in &["/resource?page=2&sort=name", "/resource?key=client&page=2"] {
+        assert_eq!(common::get(proxy, path).await.status(), StatusCode::OK);
+    }
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].parts.uri,
+        "/resource?page=2&sort=name&key=token"
+    );
+    assert_eq!(received[1].parts.uri, "/resource?page=2&key=token");
+}
+
+#[tokio::test]
+async fn sets_the_token_as_a_cookie_merged_with_the_clients() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.auth_location = AuthLocation::Cookie;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    let request = Request::get(format!("http://{}/", proxy))
+        .header("cookie", "theme=dark; session=client")
+        .header(
+            "cookie",
+            HeaderValue::from_bytes("name=caf\u{e9}".as_bytes()).unwrap(),
+        )
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(common::send(request).await.status(), StatusCode::OK);
+
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header_values("cookie"), vec!["session=token"]);
+    assert_eq!(received[0].header("authorization"), None);
+    let cookies: Vec<_> = received[1].parts.headers.get_all("cookie").iter().collect();
+    assert_eq!(cookies.len(), 1);
+    let mut expected = b"theme=dark; name=caf".to_vec();
+    expected.extend_from_slice("\u{e9}; session=token".as_bytes());
+    assert_eq!(cookies[0].as_bytes(), &expected[..]);
+}
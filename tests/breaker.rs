@@ -0,0 +1,96 @@
+mod common;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Response, StatusCode};
+use tokio::time::delay_for;
+
+#[tokio::test]
+async fn opens_after_consecutive_failures_and_closes_once_the_target_recovers() {
+    let healthy = Arc::new(AtomicBool::new(false));
+    let hits = Arc::new(AtomicUsize::new(0));
+    let (target_healthy, target_hits) = (healthy.clone(), hits.clone());
+    let target = common::spawn_target(move |_| {
+        target_hits.fetch_add(1, Ordering::SeqCst);
+        let status = if target_healthy.load(Ordering::SeqCst) {
+            StatusCode::OK
+        } else {
+            StatusCode::BAD_GATEWAY
+        };
+        async move {
+            Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap()
+        }
+    })
+    .await;
+    let mut params = common::params(target);
+    params.breaker_threshold = Some(2);
+    params.breaker_cooldown_secs = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    for _ in 0..2 {
+        let response = common::get(proxy, "/").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+    let started_at = Instant::now();
+    for _ in 0..3 {
+        let response = common::get(proxy, "/").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "1");
+    }
+    assert!(started_at.elapsed() < Duration::from_millis(200));
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    healthy.store(true, Ordering::SeqCst);
+    delay_for(Duration::from_millis(1100)).await;
+    for _ in 0..2 {
+        assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+
+    let metrics = common::body_string(common::get(proxy, "/metrics").await).await;
+    for state in &["open", "closed"] {
+        let line = format!(
+            "authproxy_circuit_breaker_transitions_total{{state=\"{}\"}} 1",
+            state
+        );
+        assert!(metrics.lines().any(|l| l == line), "{}", metrics);
+    }
+}
+
+#[tokio::test]
+async fn reopens_when_the_trial_request_fails() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let target_hits = hits.clone();
+    let target = common::spawn_target(move |_| {
+        target_hits.fetch_add(1, Ordering::SeqCst);
+        async {
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::empty())
+                .unwrap()
+        }
+    })
+    .await;
+    let mut params = common::params(target);
+    params.breaker_threshold = Some(1);
+    params.breaker_cooldown_secs = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    common::get(proxy, "/").await;
+    delay_for(Duration::from_millis(1100)).await;
+    // Only the trial request gets to the target
+    common::get(proxy, "/").await;
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "1");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    let metrics = common::body_string(common::get(proxy, "/metrics").await).await;
+    let line = "authproxy_circuit_breaker_transitions_total{state=\"open\"} 2";
+    assert!(metrics.lines().any(|l| l == line), "{}", metrics);
+}
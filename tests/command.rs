@@ -0,0 +1,302 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use http::header::HeaderName;
+use hyper::{Body, Request, StatusCode};
+use tempfile::TempDir;
+
+fn sh(script: &str) -> Vec<String> {
+    vec![String::from("sh"), String::from("-c"), String::from(script)]
+}
+
+#[tokio::test]
+async fn passes_the_command_output_through_the_transform_command() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.transform_command = Some(sh("tr a-z A-Z; printf '  \\n'"));
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("authorization"), Some("Bearer TOKEN"));
+}
+
+#[tokio::test]
+async fn fails_the_request_when_the_transform_command_fails() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.transform_command = Some(sh("cat; exit 3"));
+    params.error_detail = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = common::body_string(response).await;
+    assert!(
+        body.contains("Failed to transform the header value"),
+        "{}",
+        body
+    );
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn fails_fast_when_the_command_times_out() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("sleep"), String::from("10")];
+    params.command_timeout_secs = 1;
+    params.error_detail = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let started_at = Instant::now();
+    let response = common::get(proxy, "/").await;
+    assert!(started_at.elapsed() < Duration::from_secs(5));
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = common::body_string(response).await;
+    assert!(
+        body.contains("Command `sleep 10` timed out after 1 seconds"),
+        "{}",
+        body
+    );
+}
+
+// Fails the first two runs, counting them in a file in the directory
+fn fails_twice(dir: &TempDir) -> Vec<String> {
+    let counter = dir.path().join("runs");
+    sh(&format!(
+        "n=$(($(cat {0} 2>/dev/null || echo 0) + 1)); echo $n > {0}; [ $n -gt 2 ] && echo token$n",
+        counter.display()
+    ))
+}
+
+#[tokio::test]
+async fn retries_the_command_until_it_succeeds() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = fails_twice(&dir);
+    params.command_retries = 2;
+    params.command_retry_delay_ms = 10;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::command_runs(dir.path()), 3);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("authorization"), Some("Bearer token3"));
+}
+
+#[tokio::test]
+async fn fails_once_the_command_retries_run_out() {
+    let dir = TempDir::new().unwrap();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = fails_twice(&dir);
+    params.command_retries = 1;
+    params.command_retry_delay_ms = 10;
+    params.error_detail = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(common::command_runs(dir.path()), 2);
+    let body = common::body_string(response).await;
+    assert!(
+        body.contains("Failed to obtain the header value, command exited with exit status: 1"),
+        "{}",
+        body
+    );
+}
+
+#[tokio::test]
+async fn passes_the_request_metadata_to_the_command() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command =
+        sh(r#"echo "$AUTHPROXY_REQUEST_METHOD $AUTHPROXY_REQUEST_PATH $AUTHPROXY_HEADER_X_USER""#);
+    params.command_env_request = true;
+    params.command_env_headers = vec![HeaderName::from_static("x-user")];
+    params.auth_scheme = String::new();
+    let proxy = common::spawn_proxy(params).await;
+
+    let request = Request::get(format!("http://{}/orders?id=1", proxy))
+        .header("x-user", "alice")
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await;
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].header("authorization"),
+        Some("GET /orders alice")
+    );
+}
+
+#[tokio::test]
+async fn caches_the_tokens_per_request_metadata() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.command_env_request = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    for path in &["/a", "/b", "/a"] {
+        common::get(proxy, path).await;
+    }
+    assert_eq!(common::command_runs(dir.path()), 2);
+    let received = received.lock().unwrap();
+    let tokens: Vec<_> = received
+        .iter()
+        .map(|request| request.header("authorization").unwrap())
+        .collect();
+    assert_eq!(
+        tokens,
+        vec!["Bearer token1", "Bearer token2", "Bearer token1"]
+    );
+}
+
+#[tokio::test]
+async fn runs_the_command_for_every_request_without_a_cache() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.no_cache = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    for _ in 0..3 {
+        assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    }
+    assert_eq!(common::command_runs(dir.path()), 3);
+    let received = received.lock().unwrap();
+    let tokens: Vec<_> = received
+        .iter()
+        .map(|request| request.header("authorization").unwrap())
+        .collect();
+    assert_eq!(
+        tokens,
+        vec!["Bearer token1", "Bearer token2", "Bearer token3"]
+    );
+}
+
+#[tokio::test]
+async fn runs_a_pipeline_with_the_shell() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from(
+        "printf 'secret\\n' | tr a-z A-Z | tr -d '\\n'",
+    )];
+    params.shell = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("authorization"), Some("Bearer SECRET"));
+}
+
+#[tokio::test]
+async fn passes_the_words_after_the_script_to_the_shell_quoted() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![
+        String::from("printf '%s|%s'"),
+        String::from("a b"),
+        String::from("$HOME; false"),
+    ];
+    params.shell = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].header("authorization"),
+        Some("Bearer a b|$HOME; false")
+    );
+}
+
+#[tokio::test]
+async fn runs_the_command_in_the_given_directory() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("pwd")];
+    params.command_cwd = Some(dir.path().to_path_buf());
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    let expected = format!("Bearer {}", dir.path().canonicalize().unwrap().display());
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("authorization"), Some(&*expected));
+}
+
+#[tokio::test]
+async fn runs_the_command_with_the_given_environment() {
+    std::env::set_var("AUTHPROXY_TEST_INHERITED", "inherited");
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = sh("printf '%s,%s' \"$TOKEN_SCOPE\" \"$AUTHPROXY_TEST_INHERITED\"");
+    params.command_env = vec![(String::from("TOKEN_SCOPE"), String::from("read"))];
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].header("authorization"),
+        Some("Bearer read,inherited")
+    );
+}
+
+#[tokio::test]
+async fn runs_the_command_with_only_the_given_environment_when_cleared() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("/usr/bin/env")];
+    params.command_env = vec![(String::from("TOKEN_SCOPE"), String::from("read"))];
+    params.command_clear_env = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].header("authorization"),
+        Some("Bearer TOKEN_SCOPE=read")
+    );
+}
+
+// Whether the process is gone, zombies count as gone since nothing runs anymore
+#[cfg(unix)]
+fn is_gone(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .trim_start()
+            .starts_with('Z'),
+        Err(_) => true,
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn kills_what_the_command_started_when_it_times_out() {
+    let dir = TempDir::new().unwrap();
+    let pid_file = dir.path().join("pid");
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = sh(&format!(
+        "sleep 30 & echo $! > {}; wait",
+        pid_file.display()
+    ));
+    params.command_timeout_secs = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert!(is_gone(pid.trim()));
+}
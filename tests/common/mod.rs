@@ -0,0 +1,289 @@
+// Helpers shared by the integration tests: a target the proxy forwards to, and the proxy itself
+#![allow(dead_code)]
+
+use std::convert::Infallible;
+use std::fs;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use authproxy::proxy::{Proxy, ProxyParams};
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::http::request::Parts;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Request, Response};
+use hyper_tls::HttpsConnector;
+use native_tls::{Certificate, Identity};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+// Starts a target that answers every request with the handler
+pub async fn spawn_target<F, R>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    spawn_flaky_target(0, handler).await
+}
+
+pub async fn spawn_target_at<F, R>(addr: SocketAddr, handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    serve(TcpListener::bind(addr).await.unwrap(), None, 0, handler).0
+}
+
+// Starts a target that counts the connections it accepts
+pub async fn spawn_counting_target<F, R>(handler: F) -> (SocketAddr, Arc<AtomicUsize>)
+where
+    F: Fn(Request<Body>) -> R + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    serve(
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        None,
+        0,
+        handler,
+    )
+}
+
+// Starts a target serving HTTPS with the test server certificate, which requires clients
+// to present a certificate signed by the test CA when asked to
+pub async fn spawn_https_target<F, R>(require_client_cert: bool, handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    spawn_https_target_at("127.0.0.1:0".parse().unwrap(), require_client_cert, handler).await
+}
+
+pub async fn spawn_https_target_at<F, R>(
+    addr: SocketAddr,
+    require_client_cert: bool,
+    handler: F,
+) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let mut config = if require_client_cert {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add_pem_file(&mut read_fixture("ca.pem").as_slice())
+            .unwrap();
+        ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+    } else {
+        ServerConfig::new(NoClientAuth::new())
+    };
+    let certs = pemfile::certs(&mut read_fixture("server.pem").as_slice()).unwrap();
+    let mut keys = pemfile::pkcs8_private_keys(&mut read_fixture("server.key").as_slice()).unwrap();
+    config.set_single_cert(certs, keys.remove(0)).unwrap();
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+
+    let listener = TcpListener::bind(addr).await.unwrap();
+    serve(
+        listener,
+        Some(TlsAcceptor::from(Arc::new(config))),
+        0,
+        handler,
+    )
+    .0
+}
+
+// Starts a target that closes the first connections it accepts without answering them
+pub async fn spawn_flaky_target<F, R>(failed_connections: usize, handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    serve(
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        None,
+        failed_connections,
+        handler,
+    )
+    .0
+}
+
+fn serve<F, R>(
+    mut listener: TcpListener,
+    tls_acceptor: Option<TlsAcceptor>,
+    failed_connections: usize,
+    handler: F,
+) -> (SocketAddr, Arc<AtomicUsize>)
+where
+    F: Fn(Request<Body>) -> R + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let accepted_by_target = accepted.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            if accepted_by_target.fetch_add(1, Ordering::SeqCst) < failed_connections {
+                drop(stream);
+                continue;
+            }
+
+            let handler = handler.clone();
+            let service = service_fn(move |req| {
+                let response = handler(req);
+                async move { Ok::<_, Infallible>(response.await) }
+            });
+            let tls_acceptor = tls_acceptor.clone();
+            tokio::spawn(async move {
+                match tls_acceptor {
+                    Some(acceptor) => {
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            let _ = Http::new().serve_connection(stream, service).await;
+                        }
+                    }
+                    None => {
+                        let _ = Http::new().serve_connection(stream, service).await;
+                    }
+                }
+            });
+        }
+    });
+
+    (addr, accepted)
+}
+
+// A request as the target received it
+#[derive(Debug)]
+pub struct Received {
+    pub parts: Parts,
+    pub body: Bytes,
+}
+
+impl Received {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.parts
+            .headers
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        self.parts
+            .headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+}
+
+pub type ReceivedRequests = Arc<Mutex<Vec<Received>>>;
+
+// Starts a target that keeps the requests it receives and answers them with ok
+pub async fn spawn_recording_target() -> (SocketAddr, ReceivedRequests) {
+    let received = ReceivedRequests::default();
+    let received_by_target = received.clone();
+    let addr = spawn_target(move |req| {
+        let received = received_by_target.clone();
+        async move {
+            let (parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap();
+            received.lock().unwrap().push(Received { parts, body });
+            Response::new(Body::from("ok"))
+        }
+    })
+    .await;
+    (addr, received)
+}
+
+// The proxy params for forwarding to the target, with the token printed by echo
+pub fn params(target: SocketAddr) -> ProxyParams {
+    let mut params = ProxyParams::new(
+        format!("http://{}", target),
+        vec![String::from("echo"), String::from("token")],
+    );
+    params.listen_addr = authproxy::proxy::ListenAddr::Tcp {
+        host: String::from("127.0.0.1"),
+        port: 0,
+    };
+    params
+}
+
+// A command printing token1, token2 and so on, counting its runs in a file in the directory
+pub fn counting_command(dir: &Path) -> Vec<String> {
+    let counter = dir.join("runs");
+    let script = format!(
+        "n=$(($(cat {0} 2>/dev/null || echo 0) + 1)); echo $n > {0}; echo token$n",
+        counter.display()
+    );
+    vec![String::from("sh"), String::from("-c"), script]
+}
+
+pub fn command_runs(dir: &Path) -> u32 {
+    fs::read_to_string(dir.join("runs")).map_or(0, |runs| runs.trim().parse().unwrap())
+}
+
+pub async fn spawn_proxy(params: ProxyParams) -> SocketAddr {
+    let proxy = Proxy::bind(params).await.unwrap();
+    let addr = proxy.local_addr().unwrap();
+    tokio::spawn(proxy.run());
+    addr
+}
+
+pub async fn send(request: Request<Body>) -> Response<Body> {
+    Client::new().request(request).await.unwrap()
+}
+
+pub async fn get(proxy: SocketAddr, path: &str) -> Response<Body> {
+    let uri = format!("http://{}{}", proxy, path);
+    send(Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+pub async fn body_string(response: Response<Body>) -> String {
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn read_fixture(name: &str) -> Vec<u8> {
+    fs::read(fixture(name)).unwrap()
+}
+
+// A client trusting the test CA
+pub fn https_client() -> Client<HttpsConnector<HttpConnector>> {
+    https_client_with_identity(None)
+}
+
+// A client trusting the test CA and presenting the test client certificate
+pub fn https_client_with_cert() -> Client<HttpsConnector<HttpConnector>> {
+    let identity = Identity::from_pkcs12(&read_fixture("client.p12"), "secret").unwrap();
+    https_client_with_identity(Some(identity))
+}
+
+fn https_client_with_identity(identity: Option<Identity>) -> Client<HttpsConnector<HttpConnector>> {
+    let ca = Certificate::from_pem(&read_fixture("ca.pem")).unwrap();
+    let mut tls_builder = native_tls::TlsConnector::builder();
+    tls_builder.add_root_certificate(ca);
+    if let Some(identity) = identity {
+        tls_builder.identity(identity);
+    }
+    let tls_connector = tls_builder.build().unwrap();
+    let mut http_connector = HttpConnector::new();
+    http_connector.enforce_http(false);
+    let https_connector = HttpsConnector::from((http_connector, tls_connector.into()));
+    Client::builder().build(https_connector)
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use hyper::{Body, Request, Response, StatusCode};
+
+const TEXT: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ";
+
+// Answers /small with a short body and /encoded with one the target already compressed
+async fn compressing_proxy() -> std::net::SocketAddr {
+    let target = common::spawn_target(|req| async move {
+        match req.uri().path() {
+            "/small" => Response::new(Body::from("short")),
+            "/encoded" => Response::builder()
+                .header("content-encoding", "br")
+                .body(Body::from(TEXT.repeat(50)))
+                .unwrap(),
+            _ => Response::new(Body::from(TEXT.repeat(50))),
+        }
+    })
+    .await;
+    let mut params = common::params(target);
+    params.compress = true;
+    common::spawn_proxy(params).await
+}
+
+async fn get_accepting(
+    proxy: std::net::SocketAddr,
+    path: &str,
+    accept_encoding: Option<&str>,
+) -> Response<Body> {
+    let mut request = Request::get(format!("http://{}{}", proxy, path));
+    if let Some(accept_encoding) = accept_encoding {
+        request = request.header("accept-encoding", accept_encoding);
+    }
+    common::send(request.body(Body::empty()).unwrap()).await
+}
+
+#[tokio::test]
+async fn compresses_the_response_for_clients_accepting_gzip() {
+    let proxy = compressing_proxy().await;
+
+    let response = get_accepting(proxy, "/", Some("br;q=0.5, gzip")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert!(!response.headers().contains_key("content-length"));
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(body.len() < TEXT.len() * 50);
+    let mut decompressed = String::new();
+    GzDecoder::new(&body[..])
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, TEXT.repeat(50));
+}
+
+#[tokio::test]
+async fn passes_the_response_through_for_other_clients() {
+    let proxy = compressing_proxy().await;
+
+    for accept_encoding in &[None, Some("br"), Some("gzip;q=0")] {
+        let response = get_accepting(proxy, "/", *accept_encoding).await;
+        assert!(!response.headers().contains_key("content-encoding"));
+        assert_eq!(common::body_string(response).await, TEXT.repeat(50));
+    }
+}
+
+#[tokio::test]
+async fn leaves_small_and_encoded_responses_alone() {
+    let proxy = compressing_proxy().await;
+
+    let response = get_accepting(proxy, "/small", Some("gzip")).await;
+    assert!(!response.headers().contains_key("content-encoding"));
+    assert_eq!(common::body_string(response).await, "short");
+    let response = get_accepting(proxy, "/encoded", Some("gzip")).await;
+    assert_eq!(response.headers()["content-encoding"], "br");
+    assert_eq!(common::body_string(response).await, TEXT.repeat(50));
+}
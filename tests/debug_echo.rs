@@ -0,0 +1,97 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use authproxy::proxy::{parse_header, Proxy, ProxyError};
+use hyper::{Body, Request, StatusCode};
+use serde_json::Value;
+
+async fn spawn_echoing_proxy() -> (SocketAddr, common::ReceivedRequests) {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("echo"), String::from("long-enough-token")];
+    params.strip_prefix = Some(String::from("/service"));
+    params.add_headers = vec![parse_header("X-Api-Key: static-secret").unwrap()];
+    params.debug_echo_path = Some(String::from("/debug"));
+    params.admin_token = Some("admin-secret".into());
+    (common::spawn_proxy(params).await, received)
+}
+
+fn request(uri: String, authorization: Option<&str>) -> Request<Body> {
+    let mut request = Request::get(uri)
+        .header("connection", "x-hop")
+        .header("x-hop", "1")
+        .header("x-client", "a");
+    if let Some(authorization) = authorization {
+        request = request.header("authorization", authorization);
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+fn header<'a>(echo: &'a Value, name: &str) -> Option<&'a str> {
+    echo["headers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|header| header["name"] == name)
+        .map(|header| header["value"].as_str().unwrap())
+}
+
+#[tokio::test]
+async fn echoes_the_request_as_it_would_be_forwarded() {
+    let (proxy, received) = spawn_echoing_proxy().await;
+
+    let uri = format!("http://{}/debug/service/items?a=1", proxy);
+    let response = common::send(request(uri, Some("Bearer admin-secret"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let echo: Value = serde_json::from_str(&common::body_string(response).await).unwrap();
+    assert!(received.lock().unwrap().is_empty());
+
+    let uri = format!("http://{}/service/items?a=1", proxy);
+    common::send(request(uri, None)).await;
+    let received = received.lock().unwrap();
+    let forwarded = &received[0];
+    assert_eq!(echo["method"], "GET");
+    assert!(echo["uri"].as_str().unwrap().ends_with("/items?a=1"));
+    assert_eq!(forwarded.parts.uri, "/items?a=1");
+    assert_eq!(header(&echo, "x-client"), forwarded.header("x-client"));
+    assert_eq!(header(&echo, "x-hop"), None);
+    assert_eq!(forwarded.header("x-hop"), None);
+    assert_eq!(
+        header(&echo, "authorization"),
+        Some("Bearer long… (len 17)")
+    );
+    assert_eq!(
+        forwarded.header("authorization"),
+        Some("Bearer long-enough-token")
+    );
+    assert_eq!(header(&echo, "x-api-key"), Some("… (len 13)"));
+    assert_eq!(forwarded.header("x-api-key"), Some("static-secret"));
+}
+
+#[tokio::test]
+async fn refuses_to_echo_without_the_admin_token() {
+    let (proxy, received) = spawn_echoing_proxy().await;
+
+    let uri = format!("http://{}/debug/service/items", proxy);
+    let response = common::send(request(uri.clone(), None)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = common::send(request(uri, Some("Bearer wrong"))).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn fails_to_start_echoing_without_an_admin_token() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.debug_echo_path = Some(String::from("/debug"));
+    match Proxy::bind(params).await {
+        Err(ProxyError::Config(err)) => assert_eq!(
+            err.to_string(),
+            "The debug echo path requires an admin token"
+        ),
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started echoing to anyone"),
+    }
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use hyper::{Body, Request, Response, StatusCode};
+
+fn failing_command() -> Vec<String> {
+    vec![String::from("false")]
+}
+
+#[tokio::test]
+async fn answers_health_checks_without_the_command_or_the_target() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = failing_command();
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/healthz").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "ok");
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn proxies_other_paths_and_methods() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.health_path = String::from("/ready");
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        common::get(proxy, "/healthz").await.status(),
+        StatusCode::OK
+    );
+    let uri = format!("http://{}/ready", proxy);
+    let response = common::send(Request::post(uri).body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].parts.uri, "/healthz");
+    assert_eq!(received[1].parts.uri, "/ready");
+}
+
+#[tokio::test]
+async fn proxies_the_health_path_when_disabled() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.health_path = String::new();
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        common::get(proxy, "/healthz").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn counts_requests_responses_and_token_lookups() {
+    let target = common::spawn_target(|req: Request<Body>| async move {
+        let mut response = Response::new(Body::empty());
+        if req.uri().path() == "/missing" {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
+        response
+    })
+    .await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    for path in &["/", "/", "/missing"] {
+        common::get(proxy, path).await;
+    }
+    let response = common::get(proxy, "/metrics").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let metrics = common::body_string(response).await;
+    let lines = metrics.lines().collect::<Vec<_>>();
+    for expected in &[
+        "authproxy_requests_total 3",
+        "authproxy_responses_total{class=\"2xx\"} 2",
+        "authproxy_responses_total{class=\"4xx\"} 1",
+        "authproxy_token_cache_total{result=\"hit\"} 2",
+        "authproxy_token_cache_total{result=\"miss\"} 1",
+        "authproxy_command_runs_total{result=\"success\"} 1",
+        "authproxy_upstream_request_duration_seconds_count 3",
+    ] {
+        assert!(lines.contains(expected), "{} not in {}", expected, metrics);
+    }
+}
+
+#[tokio::test]
+async fn counts_the_command_runs_by_result() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = failing_command();
+    let proxy = common::spawn_proxy(params).await;
+
+    for _ in 0..2 {
+        common::get(proxy, "/").await;
+    }
+    let metrics = common::body_string(common::get(proxy, "/metrics").await).await;
+    let lines = metrics.lines().collect::<Vec<_>>();
+    for expected in &[
+        "authproxy_command_runs_total{result=\"failure\"} 2",
+        "authproxy_command_duration_seconds_count 2",
+    ] {
+        assert!(lines.contains(expected), "{} not in {}", expected, metrics);
+    }
+    assert!(!metrics.contains("authproxy_command_runs_total{result=\"success\"}"));
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use std::net::{SocketAddr, TcpListener};
+
+use authproxy::proxy::{ErrorFormat, ProxyParams};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+// An address that nothing listens on
+fn closed_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+async fn status_for(mut params: ProxyParams) -> (StatusCode, Value) {
+    params.error_format = ErrorFormat::Json;
+    let proxy = common::spawn_proxy(params).await;
+    let response = common::get(proxy, "/").await;
+    let status = response.status();
+    let body = common::body_string(response).await;
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+#[tokio::test]
+async fn answers_with_a_bad_gateway_when_the_target_is_unreachable() {
+    let params = common::params(closed_port());
+    assert_eq!(
+        status_for(params).await,
+        (StatusCode::BAD_GATEWAY, json!({"error": "Bad Gateway"}))
+    );
+}
+
+#[tokio::test]
+async fn answers_with_service_unavailable_when_the_command_fails() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("false")];
+    assert_eq!(
+        status_for(params).await,
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({"error": "Service Unavailable"})
+        )
+    );
+}
+
+#[tokio::test]
+async fn answers_with_an_internal_error_otherwise() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    // Not a valid header value
+    params.command = vec![String::from("printf"), String::from("bad\\001token")];
+    assert_eq!(
+        status_for(params).await,
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({"error": "Internal Server Error"})
+        )
+    );
+}
@@ -0,0 +1,31 @@
+#!/bin/sh
+# Generates the certificates the tests use: a CA, a server certificate for localhost
+# and a client certificate, both signed by the CA
+set -e
+cd "$(dirname "$0")"
+
+days=36500
+openssl req -x509 -newkey rsa:2048 -nodes -days $days -subj "/CN=authproxy test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" \
+    -keyout ca.key -out ca.pem
+
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" -keyout server.key -out server.csr
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days $days \
+    -extfile /dev/stdin -out server.pem <<EXT
+basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=serverAuth
+subjectAltName=DNS:localhost,IP:127.0.0.1
+EXT
+
+openssl req -newkey rsa:2048 -nodes -subj "/CN=authproxy test client" \
+    -keyout client.key -out client.csr
+openssl x509 -req -in client.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days $days \
+    -extfile /dev/stdin -out client.pem <<EXT
+basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=clientAuth
+EXT
+openssl pkcs12 -export -in client.pem -inkey client.key -passout pass:secret -out client.p12
+
+rm -f server.csr client.csr ca.srl
@@ -0,0 +1,426 @@
+mod common;
+
+use authproxy::proxy::{parse_header, HostHeaderMode, ResponseHeaderMode, TokenEncoding};
+use http::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use regex::Regex;
+use tempfile::TempDir;
+
+async fn received_authorization(auth_scheme: Option<&str>) -> Option<String> {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    if let Some(auth_scheme) = auth_scheme {
+        params.auth_scheme = String::from(auth_scheme);
+    }
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    received[0].header("authorization").map(String::from)
+}
+
+#[tokio::test]
+async fn uses_the_bearer_scheme_by_default() {
+    assert_eq!(
+        received_authorization(None).await.as_deref(),
+        Some("Bearer token")
+    );
+}
+
+#[tokio::test]
+async fn uses_the_given_scheme() {
+    assert_eq!(
+        received_authorization(Some("Token")).await.as_deref(),
+        Some("Token token")
+    );
+}
+
+#[tokio::test]
+async fn inserts_the_token_verbatim_without_a_scheme() {
+    assert_eq!(
+        received_authorization(Some("")).await.as_deref(),
+        Some("token")
+    );
+}
+
+#[tokio::test]
+async fn puts_the_token_into_the_given_header() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.header_name = String::from("X-API-Key");
+    params.auth_scheme = String::new();
+    let proxy = common::spawn_proxy(params).await;
+
+    common::get(proxy, "/").await;
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("x-api-key"), Some("token"));
+    assert_eq!(received[0].header("authorization"), None);
+}
+
+#[tokio::test]
+async fn prefixes_the_token_extracted_with_a_regex_with_the_scheme() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![
+        String::from("echo"),
+        String::from("token=abc123; expires=60"),
+    ];
+    params.token_regex = Some(Regex::new(r"token=([^;]+)").unwrap());
+    params.auth_scheme = String::from("Token");
+    let proxy = common::spawn_proxy(params).await;
+
+    common::get(proxy, "/").await;
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("authorization"), Some("Token abc123"));
+}
+
+// The host the target received, and the one it listens on
+async fn received_host(host_header: HostHeaderMode) -> (String, String) {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.host_header = host_header;
+    let proxy = common::spawn_proxy(params).await;
+
+    let uri = format!("http://{}/", proxy);
+    let request = Request::get(uri)
+        .header("host", "client.example")
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await;
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header_values("host").len(), 1);
+    (
+        received[0].header("host").unwrap().to_string(),
+        target.to_string(),
+    )
+}
+
+#[tokio::test]
+async fn replaces_the_host_with_the_targets_by_default() {
+    let (host, target_host) = received_host(HostHeaderMode::Remove).await;
+    assert_eq!(host, target_host);
+}
+
+#[tokio::test]
+async fn sets_the_host_of_the_target() {
+    let (host, target_host) = received_host(HostHeaderMode::Target).await;
+    assert_eq!(host, target_host);
+}
+
+#[tokio::test]
+async fn preserves_the_host_of_the_client() {
+    let (host, _) = received_host(HostHeaderMode::Preserve).await;
+    assert_eq!(host, "client.example");
+}
+
+#[tokio::test]
+async fn adds_the_static_headers() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.add_headers = ["X-Tenant-Id: 1", "X-Tenant-Id: 2", "X-Team: core"]
+        .iter()
+        .map(|header| parse_header(header).unwrap())
+        .collect();
+    let proxy = common::spawn_proxy(params).await;
+
+    let uri = format!("http://{}/", proxy);
+    let request = Request::get(uri)
+        .header("x-tenant-id", "0")
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await;
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].header_values("x-tenant-id"),
+        vec!["0", "1", "2"]
+    );
+    assert_eq!(received[0].header("x-team"), Some("core"));
+    assert_eq!(received[0].header("authorization"), Some("Bearer token"));
+}
+
+#[tokio::test]
+async fn strips_the_named_headers_but_not_the_token() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.strip_headers = vec![
+        HeaderName::from_static("x-internal"),
+        HeaderName::from_static("authorization"),
+    ];
+    let proxy = common::spawn_proxy(params).await;
+
+    let uri = format!("http://{}/", proxy);
+    let request = Request::get(uri)
+        .header("X-Internal", "secret")
+        .header("authorization", "Bearer client")
+        .header("x-kept", "yes")
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await;
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("x-internal"), None);
+    assert_eq!(
+        received[0].header_values("authorization"),
+        vec!["Bearer token"]
+    );
+    assert_eq!(received[0].header("x-kept"), Some("yes"));
+}
+
+async fn response_headers(mode: ResponseHeaderMode) -> Vec<String> {
+    let target = common::spawn_target(|_| async {
+        Response::builder()
+            .header("x-frame-options", "SAMEORIGIN")
+            .header("x-upstream", "kept")
+            .body(Body::empty())
+            .unwrap()
+    })
+    .await;
+    let mut params = common::params(target);
+    params.add_response_headers = ["X-Frame-Options: DENY", "Access-Control-Allow-Origin: *"]
+        .iter()
+        .map(|header| parse_header(header).unwrap())
+        .collect();
+    params.response_header_mode = mode;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    let headers = response.headers();
+    assert_eq!(headers["x-upstream"], "kept");
+    assert_eq!(headers["access-control-allow-origin"], "*");
+    headers
+        .get_all("x-frame-options")
+        .iter()
+        .map(|value| value.to_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn appends_the_response_headers() {
+    assert_eq!(
+        response_headers(ResponseHeaderMode::Append).await,
+        vec!["SAMEORIGIN", "DENY"]
+    );
+}
+
+#[tokio::test]
+async fn overrides_the_response_headers() {
+    assert_eq!(
+        response_headers(ResponseHeaderMode::Override).await,
+        vec!["DENY"]
+    );
+}
+
+#[tokio::test]
+async fn drops_hop_by_hop_request_headers() {
+    let (target, received) = common::spawn_recording_target().await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    let request = Request::get(format!("http://{}/", proxy))
+        .header("connection", "keep-alive, x-hop")
+        .header("keep-alive", "timeout=5")
+        .header("x-hop", "1")
+        .header("proxy-authorization", "Basic Zm9vOmJhcg==")
+        .header("upgrade", "h2c")
+        .header("x-end-to-end", "1")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(common::send(request).await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    for name in &["keep-alive", "x-hop", "proxy-authorization", "upgrade"] {
+        assert_eq!(received[0].header(name), None, "{}", name);
+    }
+    assert_eq!(received[0].header("x-end-to-end"), Some("1"));
+}
+
+#[tokio::test]
+async fn drops_hop_by_hop_response_headers() {
+    let target = common::spawn_target(|_req: Request<Body>| async {
+        Response::builder()
+            .header("connection", "x-hop")
+            .header("x-hop", "1")
+            .header("proxy-authenticate", "Basic")
+            .header("x-end-to-end", "1")
+            .body(Body::empty())
+            .unwrap()
+    })
+    .await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    for name in &["x-hop", "proxy-authenticate"] {
+        assert!(!response.headers().contains_key(*name), "{}", name);
+    }
+    assert_eq!(response.headers()["x-end-to-end"], "1");
+}
+
+#[tokio::test]
+async fn reports_whether_the_token_came_from_the_cache() {
+    let dir = TempDir::new().unwrap();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    // Fails once the first token expires, which is then served while stale
+    let counter = dir.path().join("runs");
+    params.command = vec![
+        String::from("sh"),
+        String::from("-c"),
+        format!("[ ! -e {0} ] && touch {0} && echo token", counter.display()),
+    ];
+    params.cache_status_header = Some(HeaderName::from_static("x-authproxy-cache"));
+    params.cache_ttl_secs = 1;
+    params.serve_stale_for_secs = 10;
+    let proxy = common::spawn_proxy(params).await;
+
+    let cache_status = |response: Response<Body>| response.headers()["x-authproxy-cache"].clone();
+    assert_eq!(cache_status(common::get(proxy, "/").await), "miss");
+    assert_eq!(cache_status(common::get(proxy, "/").await), "hit");
+    tokio::time::delay_for(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(cache_status(common::get(proxy, "/").await), "stale");
+}
+
+#[tokio::test]
+async fn adds_no_cache_status_by_default() {
+    let (target, _) = common::spawn_recording_target().await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    let response = common::get(proxy, "/").await;
+    assert!(!response.headers().contains_key("x-authproxy-cache"));
+}
+
+async fn received_with_encoding(
+    output: &str,
+    token_encoding: TokenEncoding,
+) -> (Option<String>, StatusCode) {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("echo"), output.to_string()];
+    params.token_encoding = token_encoding;
+    params.auth_scheme = String::from("Basic");
+    let proxy = common::spawn_proxy(params).await;
+
+    let status = common::get(proxy, "/").await.status();
+    let authorization = received
+        .lock()
+        .unwrap()
+        .first()
+        .and_then(|request| request.header("authorization").map(String::from));
+    (authorization, status)
+}
+
+#[tokio::test]
+async fn decodes_the_token_from_base64() {
+    let (authorization, _) =
+        received_with_encoding("dXNlcjpwYXNz", TokenEncoding::Base64Decode).await;
+    assert_eq!(authorization.as_deref(), Some("Basic user:pass"));
+}
+
+#[tokio::test]
+async fn encodes_the_token_as_base64() {
+    let (authorization, _) = received_with_encoding("user:pass", TokenEncoding::Base64Encode).await;
+    assert_eq!(authorization.as_deref(), Some("Basic dXNlcjpwYXNz"));
+}
+
+#[tokio::test]
+async fn url_encodes_the_token() {
+    let (authorization, _) = received_with_encoding("a b/c=d", TokenEncoding::UrlEncode).await;
+    assert_eq!(authorization.as_deref(), Some("Basic a%20b%2Fc%3Dd"));
+}
+
+#[tokio::test]
+async fn fails_on_a_token_that_isnt_base64() {
+    let (authorization, status) =
+        received_with_encoding("not base64!", TokenEncoding::Base64Decode).await;
+    assert_eq!(authorization, None);
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+async fn received_user_agent(
+    user_agent: Option<&'static str>,
+    append_user_agent: bool,
+    client_user_agent: HeaderValue,
+) -> Vec<u8> {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.user_agent = user_agent.map(HeaderValue::from_static);
+    params.append_user_agent = append_user_agent;
+    let proxy = common::spawn_proxy(params).await;
+
+    let request = Request::get(format!("http://{}/", proxy))
+        .header("user-agent", client_user_agent)
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(common::send(request).await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    received[0].parts.headers["user-agent"].as_bytes().to_vec()
+}
+
+#[tokio::test]
+async fn overrides_the_user_agent() {
+    let user_agent = received_user_agent(
+        Some("mirror/2.0"),
+        false,
+        HeaderValue::from_static("curl/7.68.0"),
+    )
+    .await;
+    assert_eq!(user_agent, b"mirror/2.0");
+}
+
+#[tokio::test]
+async fn appends_to_the_user_agent() {
+    let client_user_agent = HeaderValue::from_bytes("caf\u{e9}/1.0".as_bytes()).unwrap();
+    let user_agent = received_user_agent(None, true, client_user_agent).await;
+    let expected = format!("caf\u{e9}/1.0 authproxy/{}", authproxy::VERSION);
+    assert_eq!(user_agent, expected.as_bytes());
+}
+
+#[tokio::test]
+async fn passes_the_user_agent_through_by_default() {
+    let user_agent =
+        received_user_agent(None, false, HeaderValue::from_static("curl/7.68.0")).await;
+    assert_eq!(user_agent, b"curl/7.68.0");
+}
+
+// Returns the Via the target received and the one the client got back
+async fn via_headers(add_via: bool) -> (Vec<u8>, Vec<u8>) {
+    let target = common::spawn_target(|req: Request<Body>| async move {
+        let received = req.headers()["via"].as_bytes().to_vec();
+        Response::builder()
+            .header("via", "1.1 upstream-cache")
+            .body(Body::from(received))
+            .unwrap()
+    })
+    .await;
+    let mut params = common::params(target);
+    params.add_via = add_via;
+    let proxy = common::spawn_proxy(params).await;
+
+    let request = Request::get(format!("http://{}/", proxy))
+        .header("via", "1.0 fred")
+        .header(
+            "via",
+            HeaderValue::from_bytes("1.1 caf\u{e9}".as_bytes()).unwrap(),
+        )
+        .body(Body::empty())
+        .unwrap();
+    let response = common::send(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let via = response.headers()["via"].as_bytes().to_vec();
+    let received = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (received.to_vec(), via)
+}
+
+#[tokio::test]
+async fn appends_the_proxy_to_via() {
+    let (received, via) = via_headers(true).await;
+    let entry = format!("1.1 authproxy/{}", authproxy::VERSION);
+    let expected = format!("1.0 fred, 1.1 caf\u{e9}, {}", entry);
+    assert_eq!(received, expected.as_bytes());
+    assert_eq!(via, format!("1.1 upstream-cache, {}", entry).as_bytes());
+}
+
+#[tokio::test]
+async fn passes_via_through_by_default() {
+    let (received, via) = via_headers(false).await;
+    assert_eq!(received, b"1.0 fred");
+    assert_eq!(via, b"1.1 upstream-cache");
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Request, Response, StatusCode, Version};
+use tokio::net::TcpListener;
+
+// A body ending with trailers, which hyper's own body can't be made to send
+struct TrailingBody {
+    data: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl TrailingBody {
+    fn new(data: impl Into<Bytes>, name: &'static str, value: &'static str) -> Self {
+        let mut trailers = HeaderMap::new();
+        trailers.insert(name, HeaderValue::from_static(value));
+        TrailingBody {
+            data: Some(data.into()),
+            trailers: Some(trailers),
+        }
+    }
+}
+
+impl HttpBody for TrailingBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        _: &mut Context,
+    ) -> Poll<Option<Result<Bytes, Infallible>>> {
+        Poll::Ready(self.data.take().map(Ok))
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        _: &mut Context,
+    ) -> Poll<Result<Option<HeaderMap>, Infallible>> {
+        Poll::Ready(Ok(self.trailers.take()))
+    }
+}
+
+// Starts a target answering with a grpc-status trailer, and the trailers it received in the body
+async fn spawn_trailing_target() -> SocketAddr {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|req: Request<Body>| async move {
+                let mut body = req.into_body();
+                while body.data().await.is_some() {}
+                let received = format!("{:?}", body.trailers().await.unwrap());
+                Ok::<_, Infallible>(Response::new(TrailingBody::new(
+                    received,
+                    "grpc-status",
+                    "0",
+                )))
+            });
+            tokio::spawn(Http::new().serve_connection(stream, service));
+        }
+    });
+    addr
+}
+
+// Sends the body over HTTP/2, with its length if given, and returns the response body and trailers
+async fn send_with_trailers(
+    proxy: SocketAddr,
+    body: TrailingBody,
+    length: Option<usize>,
+) -> (String, Option<HeaderMap>) {
+    let client = Client::builder().http2_only(true).build_http();
+    let mut request = Request::post(format!("http://{}/service/Method", proxy))
+        .header("te", "trailers")
+        .body(body)
+        .unwrap();
+    if let Some(length) = length {
+        request
+            .headers_mut()
+            .insert("content-length", HeaderValue::from(length));
+    }
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut body = response.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk.unwrap());
+    }
+    (
+        String::from_utf8(data).unwrap(),
+        body.trailers().await.unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn talks_http2_to_a_cleartext_target() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.upstream_http2 = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let request = Request::post(format!("http://{}/service/Method?x=1", proxy))
+        .header("content-type", "application/grpc")
+        .body(Body::from("payload"))
+        .unwrap();
+    let response = common::send(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "ok");
+
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].parts.version, Version::HTTP_2);
+    assert_eq!(received[0].parts.uri.path(), "/service/Method");
+    assert_eq!(received[0].parts.uri.query(), Some("x=1"));
+    assert_eq!(
+        received[0].parts.uri.authority().map(|a| a.as_str()),
+        Some(target.to_string().as_str())
+    );
+    // The authority pseudo-header takes its place
+    assert_eq!(received[0].header("host"), None);
+    assert_eq!(received[0].header("authorization"), Some("Bearer token"));
+    assert_eq!(received[0].header("content-type"), Some("application/grpc"));
+    assert_eq!(&received[0].body[..], b"payload");
+}
+
+#[tokio::test]
+async fn negotiates_http2_with_a_tls_target() {
+    let target = common::spawn_https_target(false, |req| async move {
+        Response::new(Body::from(format!("{:?}", req.version())))
+    })
+    .await;
+    let mut params = common::params(target);
+    params.target_url = format!("https://localhost:{}", target.port());
+    params.ca_file = Some(common::fixture("ca.pem"));
+    params.upstream_http2 = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "HTTP/2.0");
+}
+
+#[tokio::test]
+async fn talks_http1_to_the_target_by_default() {
+    let (target, received) = common::spawn_recording_target().await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].parts.version, Version::HTTP_11);
+    assert_eq!(
+        received[0].header("host"),
+        Some(target.to_string().as_str())
+    );
+}
+
+#[tokio::test]
+async fn passes_response_trailers_to_http2_clients() {
+    let target = spawn_trailing_target().await;
+    let mut params = common::params(target);
+    params.upstream_http2 = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let (_, trailers) = send_with_trailers(proxy, TrailingBody::new("", "x", "y"), None).await;
+    let trailers = trailers.expect("No trailers in the response");
+    assert_eq!(trailers["grpc-status"], "0");
+}
+
+#[tokio::test]
+async fn passes_request_trailers_to_the_target() {
+    let target = spawn_trailing_target().await;
+    let mut params = common::params(target);
+    params.upstream_http2 = true;
+    // Neither buffering the body for retries nor counting its size may drop the trailers
+    params.max_retries = 1;
+    params.max_body_size = Some(1024);
+    let proxy = common::spawn_proxy(params).await;
+
+    for &length in &[None, Some(7)] {
+        let body = TrailingBody::new("payload", "grpc-timeout", "1S");
+        let (received, _) = send_with_trailers(proxy, body, length).await;
+        assert_eq!(received, r#"Some({"grpc-timeout": "1S"})"#, "{:?}", length);
+    }
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use hyper::StatusCode;
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn serves_clients_in_an_allowed_cidr() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.allow_cidrs = vec!["127.0.0.0/8".parse().unwrap()];
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn refuses_clients_in_a_denied_cidr_without_contacting_the_target() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.allow_cidrs = vec!["127.0.0.0/8".parse().unwrap()];
+    params.deny_cidrs = vec!["127.0.0.1".parse().unwrap()];
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        common::get(proxy, "/").await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert!(received.lock().unwrap().is_empty());
+    assert_eq!(common::command_runs(dir.path()), 0);
+}
+
+#[tokio::test]
+async fn refuses_clients_outside_the_allowlist() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.allow_cidrs = vec!["10.0.0.0/8".parse().unwrap()];
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        common::get(proxy, "/").await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert!(received.lock().unwrap().is_empty());
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use std::net::TcpListener;
+
+use authproxy::proxy::{ListenAddr, OAuthParams, Proxy, ProxyError, ProxyParams, SocksProxy};
+use hyper::StatusCode;
+
+fn listening_on(target_url: String, port: u16) -> ProxyParams {
+    let mut params = ProxyParams::new(
+        target_url,
+        vec![String::from("echo"), String::from("token")],
+    );
+    params.listen_addr = ListenAddr::Tcp {
+        host: String::from("127.0.0.1"),
+        port,
+    };
+    params
+}
+
+#[tokio::test]
+async fn runs_the_proxy_built_without_the_command_line() {
+    let (target, received) = common::spawn_recording_target().await;
+    let proxy = Proxy::bind(listening_on(format!("http://{}", target), 0))
+        .await
+        .unwrap();
+    let addr = proxy.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+    tokio::spawn(proxy.run());
+
+    assert_eq!(common::get(addr, "/").await.status(), StatusCode::OK);
+    assert_eq!(
+        received.lock().unwrap()[0].header("authorization"),
+        Some("Bearer token")
+    );
+}
+
+#[tokio::test]
+async fn fails_with_a_config_error_for_an_invalid_target() {
+    match Proxy::bind(listening_on(String::from("not a url"), 0)).await {
+        Err(err @ ProxyError::Config(_)) => {
+            assert!(err.to_string().starts_with("Invalid proxy configuration: "))
+        }
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started with an invalid target"),
+    }
+}
+
+#[tokio::test]
+async fn fails_with_a_bind_error_for_a_port_in_use() {
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    match Proxy::bind(listening_on(String::from("http://127.0.0.1"), port)).await {
+        Err(ProxyError::Bind(_)) => {}
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Listened on a port in use"),
+    }
+}
+
+#[tokio::test]
+async fn fails_with_a_tls_error_for_a_missing_certificate() {
+    let mut params = listening_on(String::from("http://127.0.0.1"), 0);
+    params.tls_cert = Some("missing-cert.pem".into());
+    params.tls_key = Some("missing-key.pem".into());
+    match Proxy::bind(params).await {
+        Err(ProxyError::Tls(_)) => {}
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Listened without a certificate"),
+    }
+}
+
+#[test]
+fn leaves_the_secrets_out_of_the_debug_output() {
+    let mut params = listening_on(String::from("http://target"), 0);
+    params.admin_token = Some("admin-secret".into());
+    params.client_cert_password = Some("cert-password".into());
+    params.socks_proxy = Some(SocksProxy {
+        addr: String::from("socks:1080"),
+        credentials: Some((String::from("user"), String::from("socks-password"))),
+    });
+    params.oauth = Some(OAuthParams {
+        token_url: String::from("https://auth/token"),
+        client_id: String::from("client"),
+        client_secret: String::from("client-secret"),
+        scope: None,
+    });
+
+    let debug = format!("{:?}", params);
+    for secret in &[
+        "admin-secret",
+        "cert-password",
+        "socks-password",
+        "client-secret",
+    ] {
+        assert!(!debug.contains(secret), "{}", debug);
+    }
+}
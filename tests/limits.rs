@@ -0,0 +1,169 @@
+mod common;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use authproxy::proxy::OverflowMode;
+use futures::{future, stream};
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::time::delay_for;
+
+#[tokio::test]
+async fn limits_the_request_rate_of_a_client() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.rate_limit = Some(2.0);
+    params.rate_burst = Some(2);
+    let proxy = common::spawn_proxy(params).await;
+
+    for _ in 0..2 {
+        assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    }
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "1");
+    assert_eq!(received.lock().unwrap().len(), 2);
+
+    tokio::time::delay_for(Duration::from_millis(600)).await;
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    assert_eq!(received.lock().unwrap().len(), 3);
+}
+
+async fn send_body(max_body_size: u64, content_length: Option<usize>, body: Body) -> StatusCode {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.max_body_size = Some(max_body_size);
+    let proxy = common::spawn_proxy(params).await;
+
+    let mut request = Request::post(format!("http://{}/", proxy));
+    if let Some(content_length) = content_length {
+        request = request.header("content-length", content_length);
+    }
+    let status = common::send(request.body(body).unwrap()).await.status();
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        assert!(received.lock().unwrap().is_empty());
+    }
+    status
+}
+
+fn chunked(chunks: usize) -> Body {
+    let chunks = (0..chunks).map(|_| Ok::<_, io::Error>(Bytes::from(vec![b'a'; 600])));
+    Body::wrap_stream(stream::iter(chunks))
+}
+
+#[tokio::test]
+async fn rejects_a_body_declared_too_large() {
+    let body = Body::from(vec![b'a'; 1200]);
+    assert_eq!(
+        send_body(1000, Some(1200), body).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+}
+
+#[tokio::test]
+async fn rejects_a_streamed_body_once_it_is_too_large() {
+    assert_eq!(
+        send_body(1000, None, chunked(2)).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+}
+
+#[tokio::test]
+async fn forwards_a_body_within_the_limit() {
+    assert_eq!(send_body(1000, None, chunked(1)).await, StatusCode::OK);
+    let body = Body::from(vec![b'a'; 1000]);
+    assert_eq!(send_body(1000, Some(1000), body).await, StatusCode::OK);
+}
+
+// Statuses of the responses to concurrent requests to a slow target, in the order they were sent
+async fn concurrent_statuses(
+    overflow: OverflowMode,
+    max_queued: Option<usize>,
+    requests: usize,
+) -> Vec<StatusCode> {
+    let target = common::spawn_target(|_req: Request<Body>| async {
+        delay_for(Duration::from_millis(300)).await;
+        Response::new(Body::empty())
+    })
+    .await;
+    let mut params = common::params(target);
+    params.max_concurrent_requests = Some(1);
+    params.overflow = overflow;
+    params.max_queued_requests = max_queued;
+    let proxy = common::spawn_proxy(params).await;
+
+    let responses = (0..requests).map(|i| async move {
+        // Keeps the order in which the requests reach the proxy
+        delay_for(Duration::from_millis(50 * i as u64)).await;
+        common::get(proxy, "/").await.status()
+    });
+    future::join_all(responses).await
+}
+
+#[tokio::test]
+async fn rejects_requests_over_the_concurrency_limit() {
+    assert_eq!(
+        concurrent_statuses(OverflowMode::Reject, None, 2).await,
+        vec![StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]
+    );
+}
+
+#[tokio::test]
+async fn queues_requests_over_the_concurrency_limit() {
+    let started_at = Instant::now();
+    assert_eq!(
+        concurrent_statuses(OverflowMode::Queue, None, 3).await,
+        vec![StatusCode::OK; 3]
+    );
+    // Proxied one after the other
+    assert!(started_at.elapsed() >= Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn rejects_requests_over_the_queue_limit() {
+    assert_eq!(
+        concurrent_statuses(OverflowMode::Queue, Some(1), 3).await,
+        vec![
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::SERVICE_UNAVAILABLE
+        ]
+    );
+}
+
+async fn send_header_of_size(max_header_size: usize, size: usize) -> Option<StatusCode> {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.max_header_size = Some(max_header_size);
+    let proxy = common::spawn_proxy(params).await;
+
+    let request = Request::get(format!("http://{}/", proxy))
+        .header("x-large", "a".repeat(size))
+        .body(Body::empty())
+        .unwrap();
+    // The proxy may close the connection before the client reads the response
+    let status = hyper::Client::new().request(request).await.ok()?.status();
+    if status != StatusCode::OK {
+        assert!(received.lock().unwrap().is_empty());
+    }
+    Some(status)
+}
+
+#[tokio::test]
+async fn rejects_requests_with_headers_over_the_limit() {
+    let status = send_header_of_size(8192, 16 * 1024).await;
+    assert!(
+        status.is_none() || status == Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+        "{:?}",
+        status
+    );
+}
+
+#[tokio::test]
+async fn accepts_requests_with_headers_within_the_limit() {
+    assert_eq!(
+        send_header_of_size(32 * 1024, 16 * 1024).await,
+        Some(StatusCode::OK)
+    );
+}
@@ -0,0 +1,180 @@
+mod common;
+
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+
+use authproxy::proxy::{ListenAddr, Proxy, ProxyError};
+use hyper::{Body, Request, StatusCode};
+use tempfile::TempDir;
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serves_requests_over_a_unix_socket() {
+    let dir = TempDir::new().unwrap();
+    let socket_path = dir.path().join("proxy.sock");
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.listen_addr = ListenAddr::Unix(socket_path.clone());
+    let proxy = Proxy::bind(params).await.unwrap();
+    assert_eq!(proxy.local_addr(), None);
+    tokio::spawn(proxy.run());
+
+    let stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let request = Request::get("/over-unix").body(Body::empty()).unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(received.lock().unwrap()[0].parts.uri, "/over-unix");
+}
+
+#[tokio::test]
+async fn serves_requests_with_a_custom_backlog() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.listen_backlog = Some(16);
+    let proxy = common::spawn_proxy(params).await;
+
+    let responses = futures::future::join_all((0..32).map(|_| common::get(proxy, "/"))).await;
+    for response in responses {
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    assert_eq!(received.lock().unwrap().len(), 32);
+}
+
+#[tokio::test]
+async fn serves_https_with_the_given_certificate() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.tls_cert = Some(common::fixture("server.pem"));
+    params.tls_key = Some(common::fixture("server.key"));
+    let proxy = common::spawn_proxy(params).await;
+
+    let uri = format!("https://localhost:{}/over-tls", proxy.port());
+    let response = common::https_client()
+        .get(uri.parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].parts.uri, "/over-tls");
+    assert_eq!(received[0].header("x-forwarded-proto"), Some("https"));
+}
+
+async fn spawn_mtls_proxy() -> SocketAddr {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.tls_cert = Some(common::fixture("server.pem"));
+    params.tls_key = Some(common::fixture("server.key"));
+    params.client_ca = Some(common::fixture("ca.pem"));
+    params.require_client_cert = true;
+    common::spawn_proxy(params).await
+}
+
+#[tokio::test]
+async fn accepts_clients_with_a_trusted_certificate() {
+    let proxy = spawn_mtls_proxy().await;
+
+    let uri = format!("https://localhost:{}/", proxy.port());
+    let response = common::https_client_with_cert()
+        .get(uri.parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn refuses_clients_without_a_certificate() {
+    let proxy = spawn_mtls_proxy().await;
+
+    let uri = format!("https://localhost:{}/", proxy.port());
+    let result = common::https_client().get(uri.parse().unwrap()).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn fails_to_start_with_a_missing_key() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.tls_cert = Some(common::fixture("server.pem"));
+    params.tls_key = Some(common::fixture("missing.key"));
+    match Proxy::bind(params).await {
+        Err(ProxyError::Tls(_)) => {}
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started without a key"),
+    }
+}
+
+#[tokio::test]
+async fn prints_the_port_picked_by_the_os() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut proxy = Command::new(env!("CARGO_BIN_EXE_authproxy"))
+        .args(["--listen-port", "0", &format!("http://{}", target)])
+        .args(["--", "echo", "token"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(proxy.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let addr: SocketAddr = line.trim().parse().unwrap();
+
+    let response = common::get(addr, "/").await;
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+    assert_ne!(addr.port(), 0);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn serves_requests_on_the_socket_passed_by_systemd() {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::os::unix::process::CommandExt;
+
+    let dir = TempDir::new().unwrap();
+    let notify_path = dir.path().join("notify.sock");
+    let notify_socket = UnixDatagram::bind(&notify_path).unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listener_fd = listener.as_raw_fd();
+
+    // The shell execs the proxy, so that LISTEN_PID is the process id of the proxy
+    let script = format!(
+        "LISTEN_PID=$$ exec {} --systemd-socket http://{} -- echo token",
+        env!("CARGO_BIN_EXE_authproxy"),
+        target
+    );
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", &script])
+        .env("LISTEN_FDS", "1")
+        .env("NOTIFY_SOCKET", &notify_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(listener_fd, 3) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut proxy = command.spawn().unwrap();
+    drop(listener);
+
+    let mut notification = [0; 64];
+    let len = notify_socket.recv(&mut notification).unwrap();
+    let response = common::get(addr, "/").await;
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+    assert_eq!(&notification[..len], b"READY=1");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use std::sync::{Mutex, Once};
+
+use authproxy::proxy::AuthLocation;
+use hyper::{Body, Response, StatusCode};
+use log::{LevelFilter, Log, Metadata, Record};
+
+// Keeps the log lines of all the tests in this file, which tell them apart by the values they log
+struct CapturingLogger;
+
+static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static INIT: Once = Once::new();
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let line = format!("{} {}", record.level(), record.args());
+        LINES.lock().unwrap().push(line);
+    }
+
+    fn flush(&self) {}
+}
+
+fn capture_logs() {
+    INIT.call_once(|| {
+        log::set_logger(&CapturingLogger).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+}
+
+fn logged(line: &str) -> bool {
+    LINES.lock().unwrap().iter().any(|logged| logged == line)
+}
+
+fn sh(script: &str) -> Vec<String> {
+    vec![String::from("sh"), String::from("-c"), String::from(script)]
+}
+
+#[tokio::test]
+async fn logs_the_command_stderr_on_failure() {
+    capture_logs();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = sh("echo 'helper: credentials expired' >&2; exit 2");
+    params.error_detail = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = common::body_string(response).await;
+    assert!(
+        body.contains("command exited with exit status: 2"),
+        "{}",
+        body
+    );
+    assert!(logged("ERROR Command stderr: helper: credentials expired"));
+}
+
+#[tokio::test]
+async fn logs_the_command_stderr_on_success_when_asked_to() {
+    capture_logs();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = sh("echo 'helper: using cached credentials' >&2; echo token");
+    params.log_command_stderr = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    assert!(logged(
+        "DEBUG Command stderr: helper: using cached credentials"
+    ));
+}
+
+fn logged_anywhere(text: &str) -> bool {
+    LINES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|logged| logged.contains(text))
+}
+
+async fn use_token(token: &str, log_tokens_unsafe: bool) {
+    capture_logs();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    // Printed in two halves, so that the params logged at startup don't contain the token
+    let (first_half, second_half) = token.split_at(token.len() / 2);
+    params.command = sh(&format!("printf '%s%s' {} {}", first_half, second_half));
+    params.log_tokens_unsafe = log_tokens_unsafe;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    let authorization = format!("Bearer {}", token);
+    assert_eq!(
+        received.lock().unwrap()[0].header("authorization"),
+        Some(authorization.as_str())
+    );
+}
+
+#[tokio::test]
+async fn redacts_the_token_in_the_logs() {
+    let token = "redacted-0123456789abcdef";
+    use_token(token, false).await;
+    assert!(logged_anywhere("Will use token: `Bearer reda… (len 25)`"));
+    assert!(!logged_anywhere(token));
+}
+
+#[tokio::test]
+async fn logs_the_whole_token_when_asked_to() {
+    let token = "unsafe-0123456789abcdef";
+    use_token(token, true).await;
+    assert!(logged_anywhere(&format!(
+        "Will use token: `Bearer {}`",
+        token
+    )));
+}
+
+#[tokio::test]
+async fn redacts_the_token_in_the_logged_redirects() {
+    capture_logs();
+    let token = "redirected-0123456789abcdef";
+    // Sends the client on with the query it got, the token included
+    let target = common::spawn_target(|req| async move {
+        let response = match req.uri().path() {
+            "/start" => Response::builder()
+                .status(StatusCode::FOUND)
+                .header("location", format!("/end?{}", req.uri().query().unwrap())),
+            _ => Response::builder(),
+        };
+        response.body(Body::empty()).unwrap()
+    })
+    .await;
+    let mut params = common::params(target);
+    let (first_half, second_half) = token.split_at(token.len() / 2);
+    params.command = sh(&format!("printf '%s%s' {} {}", first_half, second_half));
+    params.auth_location = AuthLocation::Query;
+    params.follow_redirects = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/start").await.status(), StatusCode::OK);
+    assert!(logged_anywhere(&format!(
+        "Following the redirect to http://{}/end?access_token=redi%E2%80%A6+%28len+27%29",
+        target
+    )));
+    assert!(!logged_anywhere(token));
+}
+
+#[tokio::test]
+async fn redacts_the_token_in_the_logged_cached_responses() {
+    capture_logs();
+    let client_token = "cached-0123456789abcdef";
+    let target = common::spawn_target(|_| async {
+        Response::builder()
+            .header("cache-control", "max-age=60")
+            .body(Body::empty())
+            .unwrap()
+    })
+    .await;
+    let mut params = common::params(target);
+    params.auth_location = AuthLocation::Query;
+    params.cache_responses = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let path = format!("/page?access_token={}", client_token);
+    for _ in 0..2 {
+        assert_eq!(common::get(proxy, &path).await.status(), StatusCode::OK);
+    }
+    let redacted_uri = format!(
+        "http://{}/page?access_token=cach%E2%80%A6+%28len+23%29",
+        target
+    );
+    assert!(logged_anywhere(&format!(
+        "Caching the response for {} for 60 seconds",
+        redacted_uri
+    )));
+    assert!(logged_anywhere(&format!(
+        "Serving the cached response for {}",
+        redacted_uri
+    )));
+    assert!(!logged_anywhere(client_token));
+}
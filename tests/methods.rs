@@ -0,0 +1,80 @@
+mod common;
+
+use hyper::{Body, Method, Request, StatusCode};
+use tempfile::TempDir;
+
+async fn send_method(proxy: std::net::SocketAddr, method: Method) -> hyper::Response<Body> {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://{}/resource", proxy))
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await
+}
+
+#[tokio::test]
+async fn passes_allowed_methods_to_the_target() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.allow_methods = vec![Method::GET, Method::HEAD];
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        send_method(proxy, Method::GET).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        send_method(proxy, Method::HEAD).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(received.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn rejects_methods_outside_the_allowlist_without_running_the_command() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.allow_methods = vec![Method::GET, Method::HEAD];
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = send_method(proxy, Method::POST).await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()["allow"], "GET, HEAD");
+    assert!(received.lock().unwrap().is_empty());
+    assert_eq!(common::command_runs(dir.path()), 0);
+}
+
+#[tokio::test]
+async fn lists_the_methods_left_after_the_blocked_ones() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.block_methods = vec![Method::DELETE, Method::TRACE];
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = send_method(proxy, Method::DELETE).await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(
+        response.headers()["allow"],
+        "GET, HEAD, POST, PUT, CONNECT, OPTIONS, PATCH"
+    );
+    assert_eq!(
+        send_method(proxy, Method::PUT).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn blocked_methods_win_over_allowed_ones() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.allow_methods = vec![Method::GET, Method::POST];
+    params.block_methods = vec![Method::POST];
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = send_method(proxy, Method::POST).await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()["allow"], "GET");
+}
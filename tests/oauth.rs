@@ -0,0 +1,100 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use authproxy::proxy::OAuthParams;
+use hyper::{Body, Request, Response, StatusCode};
+use url::form_urlencoded;
+
+async fn token_endpoint(req: Request<Body>) -> Response<Body> {
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+    let form: Vec<(String, String)> = form_urlencoded::parse(&body).into_owned().collect();
+    let expected = [
+        ("grant_type", "client_credentials"),
+        ("client_id", "proxy"),
+        ("client_secret", "secret"),
+        ("scope", "api"),
+    ];
+    let expected: Vec<(String, String)> = expected
+        .iter()
+        .map(|&(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    if form != expected {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(r#"{"error":"invalid_client"}"#))
+            .unwrap();
+    }
+    Response::new(Body::from(
+        r#"{"access_token":"oauth-token","expires_in":3600}"#,
+    ))
+}
+
+async fn spawn_oauth_proxy(
+    target: SocketAddr,
+    token_endpoint: SocketAddr,
+    secret: &str,
+) -> SocketAddr {
+    let mut params = common::params(target);
+    params.command = Vec::new();
+    params.oauth = Some(OAuthParams {
+        token_url: format!("http://{}/token", token_endpoint),
+        client_id: String::from("proxy"),
+        client_secret: String::from(secret),
+        scope: Some(String::from("api")),
+    });
+    params.error_detail = true;
+    common::spawn_proxy(params).await
+}
+
+#[tokio::test]
+async fn obtains_the_token_from_the_token_endpoint() {
+    let token_endpoint = common::spawn_target(token_endpoint).await;
+    let (target, received) = common::spawn_recording_target().await;
+    let proxy = spawn_oauth_proxy(target, token_endpoint, "secret").await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].header("authorization"),
+        Some("Bearer oauth-token")
+    );
+}
+
+#[tokio::test]
+async fn fails_the_request_when_the_token_endpoint_refuses() {
+    let token_endpoint = common::spawn_target(token_endpoint).await;
+    let (target, received) = common::spawn_recording_target().await;
+    let proxy = spawn_oauth_proxy(target, token_endpoint, "wrong").await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = common::body_string(response).await;
+    assert!(
+        body.contains(
+            r#"Token endpoint responded with 400 Bad Request: {"error":"invalid_client"}"#
+        ),
+        "{}",
+        body
+    );
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn fails_the_request_without_an_access_token() {
+    let token_endpoint =
+        common::spawn_target(|_| async { Response::new(Body::from(r#"{"expires_in":3600}"#)) })
+            .await;
+    let (target, _) = common::spawn_recording_target().await;
+    let proxy = spawn_oauth_proxy(target, token_endpoint, "secret").await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = common::body_string(response).await;
+    assert!(
+        body.contains("Token endpoint response has no access_token"),
+        "{}",
+        body
+    );
+}
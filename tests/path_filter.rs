@@ -0,0 +1,106 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use hyper::StatusCode;
+use tempfile::TempDir;
+
+async fn statuses(proxy: SocketAddr, paths: &[&str]) -> Vec<StatusCode> {
+    let mut statuses = Vec::new();
+    for path in paths {
+        statuses.push(common::get(proxy, path).await.status());
+    }
+    statuses
+}
+
+fn patterns(patterns: &[&str]) -> Vec<authproxy::proxy::PathPattern> {
+    patterns.iter().map(|s| s.parse().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn forwards_only_the_allowed_paths() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.allow_paths = patterns(&["/api", "~^/v[0-9]+/users$"]);
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        statuses(proxy, &["/api", "/api/items", "/v2/users"]).await,
+        vec![StatusCode::OK; 3]
+    );
+    assert_eq!(
+        statuses(proxy, &["/", "/apiary", "/v2/users/1", "/admin"]).await,
+        vec![StatusCode::NOT_FOUND; 4]
+    );
+    let received = received.lock().unwrap();
+    let paths: Vec<_> = received.iter().map(|r| r.parts.uri.path()).collect();
+    assert_eq!(paths, vec!["/api", "/api/items", "/v2/users"]);
+}
+
+#[tokio::test]
+async fn answers_blocked_paths_with_404_without_running_the_command() {
+    let dir = TempDir::new().unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.block_paths = patterns(&["/admin", "~\\.git"]);
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/admin/users").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(common::body_string(response).await, "Not found");
+    assert_eq!(
+        statuses(proxy, &["/repo/.git/config", "/admin"]).await,
+        vec![StatusCode::NOT_FOUND; 2]
+    );
+    assert!(received.lock().unwrap().is_empty());
+    assert_eq!(common::command_runs(dir.path()), 0);
+
+    assert_eq!(
+        statuses(proxy, &["/", "/administrators"]).await,
+        vec![StatusCode::OK; 2]
+    );
+    assert_eq!(received.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn blocks_paths_even_if_they_are_allowed() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.allow_paths = patterns(&["/api"]);
+    params.block_paths = patterns(&["/api/internal", "~/secret$"]);
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        statuses(proxy, &["/api/items", "/api/secrets"]).await,
+        vec![StatusCode::OK; 2]
+    );
+    assert_eq!(
+        statuses(proxy, &["/api/internal/keys", "/api/items/secret", "/web"]).await,
+        vec![StatusCode::NOT_FOUND; 3]
+    );
+    assert_eq!(received.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn blocks_encoded_and_dotted_paths() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.allow_paths = patterns(&["/api"]);
+    params.block_paths = patterns(&["/api/internal"]);
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        statuses(
+            proxy,
+            &[
+                "/api/%69nternal/keys",
+                "/api/items/../internal",
+                "/api/../admin"
+            ]
+        )
+        .await,
+        vec![StatusCode::NOT_FOUND; 3]
+    );
+    assert!(received.lock().unwrap().is_empty());
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+
+use authproxy::proxy::{Proxy, ProxyError};
+use hyper::StatusCode;
+use tempfile::TempDir;
+
+// Run as a separate process, since the signal would reach every proxy in this one
+#[cfg(unix)]
+#[tokio::test]
+async fn writes_the_pid_file_while_running() {
+    let dir = TempDir::new().unwrap();
+    let pid_file = dir.path().join("authproxy.pid");
+    let (target, _) = common::spawn_recording_target().await;
+    let mut proxy = Command::new(env!("CARGO_BIN_EXE_authproxy"))
+        .args(["--listen-port", "0", "--pid-file"])
+        .arg(&pid_file)
+        .arg(format!("http://{}", target))
+        .args(["--", "echo", "token"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(proxy.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    // Once a request is served, the proxy is listening for the signal as well
+    let addr = line.trim().parse().unwrap();
+    assert_eq!(common::get(addr, "/").await.status(), StatusCode::OK);
+
+    let written = fs::read_to_string(&pid_file).unwrap();
+    unsafe {
+        libc::kill(proxy.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let status = proxy.wait().unwrap();
+    assert_eq!(written, format!("{}\n", proxy.id()));
+    assert!(status.success());
+    assert!(!pid_file.exists());
+}
+
+#[tokio::test]
+async fn fails_to_start_when_the_pid_file_cant_be_written() {
+    let dir = TempDir::new().unwrap();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.pid_file = Some(dir.path().join("missing").join("authproxy.pid"));
+    match Proxy::bind(params).await {
+        Err(ProxyError::Config(err)) => {
+            assert!(err.to_string().starts_with("Failed to write PID file "))
+        }
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started without the PID file"),
+    }
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use hyper::{Body, Request, Response};
+
+async fn ok(_req: Request<Body>) -> Response<Body> {
+    Response::new(Body::from("ok"))
+}
+
+async fn connections_for_requests(pool_max_idle_per_host: Option<usize>) -> usize {
+    let (target, connections) = common::spawn_counting_target(ok).await;
+    let mut params = common::params(target);
+    params.pool_max_idle_per_host = pool_max_idle_per_host;
+    let proxy = common::spawn_proxy(params).await;
+
+    for _ in 0..5 {
+        let response = common::get(proxy, "/").await;
+        common::body_string(response).await;
+    }
+    connections.load(Ordering::SeqCst)
+}
+
+#[tokio::test]
+async fn reuses_connections_to_the_target() {
+    assert_eq!(connections_for_requests(None).await, 1);
+}
+
+#[tokio::test]
+async fn keeps_no_idle_connections_when_told_not_to() {
+    assert_eq!(connections_for_requests(Some(0)).await, 5);
+}
+
+#[tokio::test]
+async fn closes_connections_idle_for_longer_than_the_timeout() {
+    let (target, connections) = common::spawn_counting_target(ok).await;
+    let mut params = common::params(target);
+    params.pool_idle_timeout_secs = Some(1);
+    let proxy = common::spawn_proxy(params).await;
+
+    common::body_string(common::get(proxy, "/").await).await;
+    tokio::time::delay_for(std::time::Duration::from_millis(1500)).await;
+    common::body_string(common::get(proxy, "/").await).await;
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+}
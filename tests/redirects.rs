@@ -0,0 +1,107 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use hyper::{Body, Response, StatusCode};
+
+// Redirects /hops/N to /hops/N-1 until /hops/0, which is answered with OK
+async fn spawn_redirecting_target(
+    next_origin: Option<SocketAddr>,
+) -> (SocketAddr, common::ReceivedRequests) {
+    let (final_target, received) = common::spawn_recording_target().await;
+    let target = common::spawn_target(move |req| {
+        let hops: u32 = req
+            .uri()
+            .path()
+            .trim_start_matches("/hops/")
+            .parse()
+            .unwrap_or(0);
+        async move {
+            let location = match (hops, next_origin) {
+                (1, Some(origin)) => format!("http://{}/final", origin),
+                (1, None) => format!("http://{}/final", final_target),
+                (hops, _) => format!("/hops/{}", hops - 1),
+            };
+            Response::builder()
+                .status(StatusCode::FOUND)
+                .header("location", location)
+                .body(Body::empty())
+                .unwrap()
+        }
+    })
+    .await;
+    (target, received)
+}
+
+#[tokio::test]
+async fn passes_redirects_on_by_default() {
+    let (target, received) = spawn_redirecting_target(None).await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    let response = common::get(proxy, "/hops/1").await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn follows_redirects_up_to_the_limit() {
+    let target = common::spawn_target(|req| async move {
+        let hops: u32 = req
+            .uri()
+            .path()
+            .trim_start_matches("/hops/")
+            .parse()
+            .unwrap();
+        let response = match hops {
+            0 => Response::builder(),
+            hops => Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header("location", format!("/hops/{}", hops - 1)),
+        };
+        response.body(Body::from(hops.to_string())).unwrap()
+    })
+    .await;
+    let mut params = common::params(target);
+    params.follow_redirects = true;
+    params.max_redirects = 2;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/hops/2").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "0");
+    let response = common::get(proxy, "/hops/3").await;
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(common::body_string(response).await, "1");
+}
+
+#[tokio::test]
+async fn refuses_to_take_the_token_to_another_origin_by_default() {
+    let (other_origin, received) = common::spawn_recording_target().await;
+    let (target, _) = spawn_redirecting_target(Some(other_origin)).await;
+    let mut params = common::params(target);
+    params.follow_redirects = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/hops/1").await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn follows_redirects_to_another_origin_when_allowed() {
+    let (other_origin, received) = common::spawn_recording_target().await;
+    let (target, _) = spawn_redirecting_target(Some(other_origin)).await;
+    let mut params = common::params(target);
+    params.follow_redirects = true;
+    params.follow_cross_origin = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/hops/2").await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].parts.uri, "/final");
+    assert_eq!(received[0].header("authorization"), Some("Bearer token"));
+    assert_eq!(
+        received[0].header("host"),
+        Some(other_origin.to_string().as_str())
+    );
+}
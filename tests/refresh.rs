@@ -0,0 +1,39 @@
+mod common;
+
+use std::time::Duration;
+
+use tempfile::TempDir;
+use tokio::time::delay_for;
+
+#[tokio::test]
+async fn refreshes_the_token_of_an_idle_proxy() {
+    let dir = TempDir::new().unwrap();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.cache_ttl_secs = 2;
+    params.refresh_ahead = Some(0.5);
+    params.background_refresh = true;
+    common::spawn_proxy(params).await;
+
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(common::command_runs(dir.path()), 1);
+    // Refreshed a second after the first token, well before it expires
+    delay_for(Duration::from_millis(1000)).await;
+    assert_eq!(common::command_runs(dir.path()), 2);
+}
+
+#[tokio::test]
+async fn waits_between_refreshes_of_tokens_expiring_right_away() {
+    let dir = TempDir::new().unwrap();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.cache_ttl_secs = 0;
+    params.refresh_ahead = Some(0.5);
+    params.background_refresh = true;
+    common::spawn_proxy(params).await;
+
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(common::command_runs(dir.path()), 2);
+}
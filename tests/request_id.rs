@@ -0,0 +1,48 @@
+mod common;
+
+use hyper::{Body, Request, StatusCode};
+
+#[tokio::test]
+async fn generates_a_request_id_when_absent() {
+    let (target, received) = common::spawn_recording_target().await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    let first = common::get(proxy, "/").await;
+    let second = common::get(proxy, "/").await;
+    assert_eq!(first.status(), StatusCode::OK);
+    let first_id = first.headers()["x-request-id"].to_str().unwrap();
+    let second_id = second.headers()["x-request-id"].to_str().unwrap();
+    assert_eq!(first_id.len(), 36);
+    assert_ne!(first_id, second_id);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("x-request-id"), Some(first_id));
+    assert_eq!(received[1].header("x-request-id"), Some(second_id));
+}
+
+#[tokio::test]
+async fn preserves_the_request_id_when_present() {
+    let (target, received) = common::spawn_recording_target().await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    let request = Request::get(format!("http://{}/", proxy))
+        .header("x-request-id", "client-id-1")
+        .body(Body::empty())
+        .unwrap();
+    let response = common::send(request).await;
+    assert_eq!(response.headers()["x-request-id"], "client-id-1");
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("x-request-id"), Some("client-id-1"));
+    assert_eq!(received[0].header_values("x-request-id").len(), 1);
+}
+
+#[tokio::test]
+async fn adds_no_request_id_when_turned_off() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.request_id_header = None;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert!(!response.headers().contains_key("x-request-id"));
+    assert_eq!(received.lock().unwrap()[0].header("x-request-id"), None);
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::Value;
+
+// Starts a target that answers every request with a cacheable response and counts them
+async fn spawn_cacheable_target() -> (SocketAddr, Arc<AtomicUsize>) {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let target_hits = hits.clone();
+    let target = common::spawn_target(move |_| {
+        let hit = target_hits.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            Response::builder()
+                .header("cache-control", "max-age=60")
+                .body(Body::from(format!("response {}", hit)))
+                .unwrap()
+        }
+    })
+    .await;
+    (target, hits)
+}
+
+async fn spawn_caching_proxy(target: SocketAddr) -> SocketAddr {
+    let mut params = common::params(target);
+    params.cache_responses = true;
+    common::spawn_proxy(params).await
+}
+
+async fn send(proxy: SocketAddr, method: Method, path: &str) -> Response<Body> {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://{}{}", proxy, path))
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await
+}
+
+#[tokio::test]
+async fn serves_cached_get_responses_within_their_ttl() {
+    let (target, hits) = spawn_cacheable_target().await;
+    let proxy = spawn_caching_proxy(target).await;
+
+    let response = common::get(proxy, "/items").await;
+    assert!(!response.headers().contains_key("age"));
+    assert_eq!(common::body_string(response).await, "response 1");
+    let response = common::get(proxy, "/items").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["age"], "0");
+    assert_eq!(common::body_string(response).await, "response 1");
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    common::get(proxy, "/other").await;
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn never_caches_posts() {
+    let (target, hits) = spawn_cacheable_target().await;
+    let proxy = spawn_caching_proxy(target).await;
+
+    for expected in &["response 1", "response 2"] {
+        let response = send(proxy, Method::POST, "/items").await;
+        assert_eq!(common::body_string(response).await, *expected);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn asks_the_target_when_the_client_asks_for_no_cache() {
+    let (target, hits) = spawn_cacheable_target().await;
+    let proxy = spawn_caching_proxy(target).await;
+
+    common::get(proxy, "/items").await;
+    let request = Request::get(format!("http://{}/items", proxy))
+        .header("cache-control", "no-cache")
+        .body(Body::empty())
+        .unwrap();
+    let response = common::send(request).await;
+    assert_eq!(common::body_string(response).await, "response 2");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn never_serves_one_clients_response_to_another() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let target_hits = hits.clone();
+    let target = common::spawn_target(move |request| {
+        target_hits.fetch_add(1, Ordering::SeqCst);
+        let cookie = request.headers()["cookie"].to_str().unwrap().to_string();
+        async move {
+            Response::builder()
+                .header("cache-control", "max-age=60")
+                .body(Body::from(cookie))
+                .unwrap()
+        }
+    })
+    .await;
+    let proxy = spawn_caching_proxy(target).await;
+
+    for cookie in &["session=alice", "session=bob", "session=alice"] {
+        let request = Request::get(format!("http://{}/account", proxy))
+            .header("cookie", *cookie)
+            .body(Body::empty())
+            .unwrap();
+        let response = common::send(request).await;
+        assert_eq!(common::body_string(response).await, *cookie);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn never_serves_the_debug_echo_from_the_cache() {
+    let (target, hits) = spawn_cacheable_target().await;
+    let mut params = common::params(target);
+    params.cache_responses = true;
+    params.debug_echo_path = Some(String::from("/debug"));
+    params.admin_token = Some("admin-secret".into());
+    let proxy = common::spawn_proxy(params).await;
+
+    common::get(proxy, "/items").await;
+    let request = Request::get(format!("http://{}/debug/items", proxy))
+        .header("authorization", "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let response = common::send(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let echo: Value = serde_json::from_str(&common::body_string(response).await).unwrap();
+    assert_eq!(echo["method"], "GET");
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn serves_cached_responses_while_the_breaker_is_open() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let target_hits = hits.clone();
+    let target = common::spawn_target(move |request| {
+        target_hits.fetch_add(1, Ordering::SeqCst);
+        let status = if request.uri().path() == "/fail" {
+            StatusCode::BAD_GATEWAY
+        } else {
+            StatusCode::OK
+        };
+        async move {
+            Response::builder()
+                .status(status)
+                .header("cache-control", "max-age=60")
+                .body(Body::empty())
+                .unwrap()
+        }
+    })
+    .await;
+    let mut params = common::params(target);
+    params.cache_responses = true;
+    params.breaker_threshold = Some(1);
+    params.breaker_cooldown_secs = 60;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/ok").await.status(), StatusCode::OK);
+    assert_eq!(
+        common::get(proxy, "/fail").await.status(),
+        StatusCode::BAD_GATEWAY
+    );
+    assert_eq!(common::get(proxy, "/ok").await.status(), StatusCode::OK);
+    assert_eq!(
+        common::get(proxy, "/other").await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+// Answers right away with a body that takes longer than the timeout to arrive
+async fn slow_body(_req: Request<Body>) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        tokio::time::delay_for(Duration::from_secs(10)).await;
+        let _ = sender.send_data("late".into()).await;
+    });
+    Response::builder()
+        .header("cache-control", "max-age=60")
+        .body(body)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn applies_the_timeout_to_reading_cacheable_bodies() {
+    let target = common::spawn_target(slow_body).await;
+    let mut params = common::params(target);
+    params.cache_responses = true;
+    params.upstream_timeout_secs = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    let started_at = Instant::now();
+    let response = common::get(proxy, "/items").await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert!(started_at.elapsed() < Duration::from_secs(5));
+}
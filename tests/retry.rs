@@ -0,0 +1,161 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request, Response, StatusCode};
+
+async fn ok(_req: Request<Body>) -> Response<Body> {
+    Response::new(Body::from("ok"))
+}
+
+#[tokio::test]
+async fn retries_until_the_target_answers() {
+    let target = common::spawn_flaky_target(2, ok).await;
+    let mut params = common::params(target);
+    params.max_retries = 2;
+    params.retry_base_delay_ms = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "ok");
+}
+
+#[tokio::test]
+async fn gives_up_after_max_retries() {
+    let target = common::spawn_flaky_target(3, ok).await;
+    let mut params = common::params(target);
+    params.max_retries = 2;
+    params.retry_base_delay_ms = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn does_not_retry_post_by_default() {
+    let target = common::spawn_flaky_target(1, ok).await;
+    let mut params = common::params(target);
+    params.max_retries = 2;
+    params.retry_base_delay_ms = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    let uri = format!("http://{}/", proxy);
+    let response = common::send(Request::post(uri).body(Body::from("body")).unwrap()).await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn does_not_resend_post_the_target_may_have_processed() {
+    let target = common::spawn_flaky_target(1, ok).await;
+    let mut params = common::params(target);
+    params.max_retries = 2;
+    params.retry_base_delay_ms = 1;
+    params.retry_all_methods = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    let uri = format!("http://{}/", proxy);
+    let response = common::send(Request::post(uri).body(Body::from("body")).unwrap()).await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn retries_failed_connects_for_all_methods_when_asked() {
+    // Nothing listens on the port until the target is started after the first attempt
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut params = common::params(addr);
+    params.max_retries = 5;
+    params.retry_base_delay_ms = 50;
+    params.retry_all_methods = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    tokio::spawn(async move {
+        tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+        common::spawn_target_at(addr, ok).await;
+    });
+    let uri = format!("http://{}/", proxy);
+    let response = common::send(Request::post(uri).body(Body::from("body")).unwrap()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+// Asks to try again later the first time, with the Retry-After if given
+async fn spawn_busy_target(retry_after: Option<&'static str>) -> std::net::SocketAddr {
+    let requests = Arc::new(AtomicUsize::new(0));
+    common::spawn_target(move |_req| {
+        let first = requests.fetch_add(1, Ordering::SeqCst) == 0;
+        async move {
+            if !first {
+                return Response::new(Body::from("ok"));
+            }
+            let mut response = Response::builder().status(StatusCode::TOO_MANY_REQUESTS);
+            if let Some(retry_after) = retry_after {
+                response = response.header("retry-after", retry_after);
+            }
+            response.body(Body::from("busy")).unwrap()
+        }
+    })
+    .await
+}
+
+async fn timed_get(proxy: std::net::SocketAddr) -> (StatusCode, Duration) {
+    let started_at = Instant::now();
+    let response = common::get(proxy, "/").await;
+    (response.status(), started_at.elapsed())
+}
+
+#[tokio::test]
+async fn waits_as_long_as_retry_after_asks() {
+    let target = spawn_busy_target(Some("2")).await;
+    let mut params = common::params(target);
+    params.max_retries = 1;
+    params.retry_base_delay_ms = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    let (status, elapsed) = timed_get(proxy).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn waits_no_longer_than_max_retry_wait() {
+    let target = spawn_busy_target(Some("60")).await;
+    let mut params = common::params(target);
+    params.max_retries = 1;
+    params.max_retry_wait_secs = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    let (status, elapsed) = timed_get(proxy).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn backs_off_without_a_valid_retry_after() {
+    for &retry_after in &[None, Some("soon")] {
+        let target = spawn_busy_target(retry_after).await;
+        let mut params = common::params(target);
+        params.max_retries = 1;
+        params.retry_base_delay_ms = 1;
+        let proxy = common::spawn_proxy(params).await;
+
+        let (status, elapsed) = timed_get(proxy).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+}
+
+#[tokio::test]
+async fn passes_the_busy_response_on_without_retries() {
+    let target = spawn_busy_target(Some("0")).await;
+    let proxy = common::spawn_proxy(common::params(target)).await;
+
+    let (status, _) = timed_get(proxy).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use authproxy::proxy::Route;
+use hyper::StatusCode;
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn sends_requests_to_the_route_with_the_longest_prefix() {
+    let (default_target, default_received) = common::spawn_recording_target().await;
+    let (api_target, api_received) = common::spawn_recording_target().await;
+    let (v2_target, v2_received) = common::spawn_recording_target().await;
+    let mut params = common::params(default_target);
+    params.routes = vec![
+        format!("/api=http://{}", api_target).parse().unwrap(),
+        format!("/api/v2=http://{}", v2_target).parse().unwrap(),
+    ];
+    params.strip_route_prefix = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    for path in &["/api/users", "/api/v2/users?page=2", "/other"] {
+        assert_eq!(common::get(proxy, path).await.status(), StatusCode::OK);
+    }
+    assert_eq!(api_received.lock().unwrap()[0].parts.uri, "/users");
+    assert_eq!(v2_received.lock().unwrap()[0].parts.uri, "/users?page=2");
+    assert_eq!(default_received.lock().unwrap()[0].parts.uri, "/other");
+}
+
+#[tokio::test]
+async fn forwards_paths_without_the_prefix_to_strip_unchanged() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.strip_prefix = Some(String::from("/service"));
+    params.add_prefix = Some(String::from("/v2"));
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        common::get(proxy, "/service/foo?a=1").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(common::get(proxy, "/other").await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].parts.uri, "/v2/foo?a=1");
+    assert_eq!(received[1].parts.uri, "/other");
+}
+
+#[tokio::test]
+async fn answers_paths_without_the_prefix_to_strip_with_not_found_when_required() {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.strip_prefix = Some(String::from("/service"));
+    params.require_strip_prefix = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        common::get(proxy, "/other").await.status(),
+        StatusCode::NOT_FOUND
+    );
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn obtains_separately_cached_tokens_for_routes_with_their_own_command() {
+    let api_dir = TempDir::new().unwrap();
+    let default_dir = TempDir::new().unwrap();
+    let (default_target, default_received) = common::spawn_recording_target().await;
+    let (api_target, api_received) = common::spawn_recording_target().await;
+    let (other_target, other_received) = common::spawn_recording_target().await;
+    let mut params = common::params(default_target);
+    params.command = common::counting_command(default_dir.path());
+    let mut api_route: Route = format!("/api=http://{}", api_target).parse().unwrap();
+    api_route.command = Some(vec![
+        String::from("sh"),
+        String::from("-c"),
+        format!(
+            "{} | sed s/token/api/",
+            common::counting_command(api_dir.path())[2]
+        ),
+    ]);
+    params.routes = vec![
+        api_route,
+        format!("/other=http://{}", other_target).parse().unwrap(),
+    ];
+    let proxy = common::spawn_proxy(params).await;
+
+    for path in &["/api/a", "/other/a", "/api/b", "/other/b", "/c"] {
+        assert_eq!(common::get(proxy, path).await.status(), StatusCode::OK);
+    }
+    assert_eq!(common::command_runs(api_dir.path()), 1);
+    assert_eq!(common::command_runs(default_dir.path()), 1);
+    for request in api_received.lock().unwrap().iter() {
+        assert_eq!(request.header("authorization"), Some("Bearer api1"));
+    }
+    // Routes without a command fall back to the global one and share its token
+    for request in other_received.lock().unwrap().iter() {
+        assert_eq!(request.header("authorization"), Some("Bearer token1"));
+    }
+    assert_eq!(
+        default_received.lock().unwrap()[0].header("authorization"),
+        Some("Bearer token1")
+    );
+}
@@ -0,0 +1,35 @@
+// Signals reach every proxy in the process, so this is the only test sending them
+mod common;
+
+use std::time::Duration;
+
+use authproxy::proxy::Proxy;
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+
+#[cfg(unix)]
+#[tokio::test]
+async fn finishes_requests_in_flight_on_sigterm() {
+    let target = common::spawn_target(|_req: Request<Body>| async {
+        delay_for(Duration::from_millis(500)).await;
+        Response::new(Body::from("finished"))
+    })
+    .await;
+    let proxy = Proxy::bind(common::params(target)).await.unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let running = tokio::spawn(proxy.run());
+
+    let in_flight = tokio::spawn(common::get(addr, "/"));
+    delay_for(Duration::from_millis(200)).await;
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGTERM);
+    }
+    delay_for(Duration::from_millis(100)).await;
+
+    assert!(TcpStream::connect(addr).await.is_err());
+    let response = in_flight.await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "finished");
+    running.await.unwrap().unwrap();
+}
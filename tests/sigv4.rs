@@ -0,0 +1,84 @@
+mod common;
+
+use std::io;
+use std::net::SocketAddr;
+
+use authproxy::proxy::SigV4Params;
+use futures::stream;
+use hyper::body::Bytes;
+use hyper::{Body, Request, StatusCode};
+
+fn set_credentials() {
+    std::env::set_var("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "secret");
+    std::env::remove_var("AWS_SESSION_TOKEN");
+}
+
+fn chunked(chunks: usize) -> Body {
+    let chunks = (0..chunks).map(|_| Ok::<_, io::Error>(Bytes::from(vec![b'a'; 600])));
+    Body::wrap_stream(stream::iter(chunks))
+}
+
+async fn spawn_signing_proxy(
+    service: &str,
+    max_body_size: Option<u64>,
+) -> (SocketAddr, SocketAddr, common::ReceivedRequests) {
+    set_credentials();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.sigv4 = Some(SigV4Params {
+        region: String::from("us-east-1"),
+        service: String::from(service),
+    });
+    params.max_body_size = max_body_size;
+    (common::spawn_proxy(params).await, target, received)
+}
+
+#[tokio::test]
+async fn signs_the_requests_instead_of_inserting_a_token() {
+    let (proxy, target, received) = spawn_signing_proxy("execute-api", None).await;
+
+    let request = Request::post(format!("http://{}/items?b=2&a=1", proxy))
+        .body(Body::from("payload"))
+        .unwrap();
+    assert_eq!(common::send(request).await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    let authorization = received[0].header("authorization").unwrap();
+    assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/",));
+    assert!(authorization.contains(
+        "/us-east-1/execute-api/aws4_request, SignedHeaders=host;x-amz-date, Signature="
+    ));
+    assert_eq!(received[0].header("host"), Some(&*target.to_string()));
+    assert_eq!(received[0].header("x-amz-date").map(str::len), Some(16));
+    assert_eq!(received[0].body, "payload");
+}
+
+#[tokio::test]
+async fn rejects_streamed_bodies_too_large_to_sign() {
+    let (proxy, _, received) = spawn_signing_proxy("execute-api", Some(1000)).await;
+
+    let request = Request::post(format!("http://{}/", proxy))
+        .body(chunked(2))
+        .unwrap();
+    assert_eq!(
+        common::send(request).await.status(),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn streams_bodies_to_s3_unsigned() {
+    let (proxy, _, received) = spawn_signing_proxy("s3", None).await;
+
+    let request = Request::put(format!("http://{}/bucket/key", proxy))
+        .body(chunked(2))
+        .unwrap();
+    assert_eq!(common::send(request).await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(
+        received[0].header("x-amz-content-sha256"),
+        Some("UNSIGNED-PAYLOAD")
+    );
+    assert_eq!(received[0].body.len(), 1200);
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use authproxy::proxy::{Proxy, ProxyError, SocksProxy};
+use hyper::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const SOURCE_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+#[tokio::test]
+async fn connects_to_the_target_from_the_source_address() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+    // Answers a single request, with the address it came from
+    let peer = tokio::spawn(async move {
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).await.unwrap();
+            request.push(byte[0]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        peer
+    });
+    let mut params = common::params(target);
+    params.source_address = Some(SOURCE_ADDRESS);
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    assert_eq!(peer.await.unwrap().ip(), SOURCE_ADDRESS);
+}
+
+#[tokio::test]
+async fn fails_to_start_with_an_address_of_another_host() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.source_address = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+
+    match Proxy::bind(params).await {
+        Err(ProxyError::Upstream(err)) => {
+            assert_eq!(err.to_string(), "Can't connect to targets from 192.0.2.1")
+        }
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started with an address of another host"),
+    }
+}
+
+#[tokio::test]
+async fn refuses_a_source_address_with_a_socks_proxy() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.source_address = Some(SOURCE_ADDRESS);
+    params.socks_proxy = Some(SocksProxy {
+        addr: String::from("127.0.0.1:1080"),
+        credentials: None,
+    });
+
+    match Proxy::bind(params).await {
+        Err(ProxyError::Config(err)) => assert_eq!(
+            err.to_string(),
+            "The source address can't be used with a SOCKS proxy"
+        ),
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started connecting through SOCKS from the source address"),
+    }
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use authproxy::proxy::{Proxy, ProxyError};
+use hyper::StatusCode;
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn obtains_the_token_before_listening() {
+    let dir = TempDir::new().unwrap();
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = common::counting_command(dir.path());
+    params.warm_cache = true;
+    let proxy = common::spawn_proxy(params).await;
+    assert_eq!(common::command_runs(dir.path()), 1);
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    assert_eq!(common::command_runs(dir.path()), 1);
+}
+
+#[tokio::test]
+async fn starts_when_warming_up_fails() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("false")];
+    params.warm_cache = true;
+    assert!(Proxy::bind(params).await.is_ok());
+}
+
+#[tokio::test]
+async fn fails_to_start_when_warming_up_fails_fast() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("false")];
+    params.warm_cache = true;
+    params.warm_fail_fast = true;
+    match Proxy::bind(params).await {
+        Err(ProxyError::Command(_)) => {}
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started without a token"),
+    }
+}
+
+#[tokio::test]
+async fn fails_to_start_with_a_missing_command() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("no-such-command")];
+    match Proxy::bind(params).await {
+        Err(ProxyError::Config(err)) => assert_eq!(
+            err.to_string(),
+            "Command `no-such-command` doesn't exist or isn't executable"
+        ),
+        Err(err) => panic!("Failed with another error: {}", err),
+        Ok(_) => panic!("Started with a missing command"),
+    }
+}
+
+#[tokio::test]
+async fn starts_with_a_missing_command_when_not_checking_it() {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![String::from("no-such-command")];
+    params.skip_command_check = true;
+    assert!(Proxy::bind(params).await.is_ok());
+}
+
+#[tokio::test]
+async fn fails_to_start_with_an_invalid_target_url() {
+    let (target, _) = common::spawn_recording_target().await;
+    let cases = [
+        ("http://tar get", "Invalid target URL http://tar get"),
+        ("/api", "The target URL /api must be absolute"),
+        (
+            "localhost:8080",
+            "The target URL localhost:8080 must be absolute",
+        ),
+    ];
+    for &(target_url, message) in &cases {
+        let mut params = common::params(target);
+        params.target_url = String::from(target_url);
+        match Proxy::bind(params).await {
+            Err(ProxyError::Config(err)) => assert_eq!(err.to_string(), message),
+            Err(err) => panic!("Failed with another error: {}", err),
+            Ok(_) => panic!("Started with the target URL {}", target_url),
+        }
+    }
+}
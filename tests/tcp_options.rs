@@ -0,0 +1,121 @@
+// The proxy runs in the test process, so the options are read off its own sockets
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+
+use hyper::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    nodelay: bool,
+    keepalive: bool,
+    keepalive_idle_secs: i32,
+}
+
+fn peer_addr(fd: RawFd) -> Option<SocketAddr> {
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let result =
+        unsafe { libc::getpeername(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    if result != 0 || i32::from(addr.sin_family) != libc::AF_INET {
+        return None;
+    }
+    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+    Some(SocketAddr::from((ip, u16::from_be(addr.sin_port))))
+}
+
+fn int_option(fd: RawFd, level: libc::c_int, name: libc::c_int) -> i32 {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(result, 0);
+    value
+}
+
+// Of the socket in this process connected to the peer
+fn options_of_socket_to(peer: SocketAddr) -> Options {
+    let fd = fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+        .find(|&fd| peer_addr(fd) == Some(peer))
+        .expect("no socket connected to the peer");
+    Options {
+        nodelay: int_option(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY) != 0,
+        keepalive: int_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE) != 0,
+        keepalive_idle_secs: int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+    }
+}
+
+async fn target_connection_options(tcp_nodelay: bool, tcp_keepalive_secs: Option<u64>) -> Options {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.tcp_nodelay = tcp_nodelay;
+    params.tcp_keepalive_secs = tcp_keepalive_secs;
+    let proxy = common::spawn_proxy(params).await;
+
+    // The connection to the target is then kept in the pool
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    options_of_socket_to(target)
+}
+
+#[tokio::test]
+async fn sets_the_options_on_the_connections_to_the_target() {
+    let options = target_connection_options(true, Some(30)).await;
+    let expected = Options {
+        nodelay: true,
+        keepalive: true,
+        keepalive_idle_secs: 30,
+    };
+    assert_eq!(options, expected);
+}
+
+#[tokio::test]
+async fn leaves_the_connections_to_the_target_alone_by_default() {
+    let options = target_connection_options(false, None).await;
+    assert!(!options.nodelay && !options.keepalive, "{:?}", options);
+}
+
+async fn client_connection_options(listen_backlog: Option<u32>) -> Options {
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.tcp_nodelay = true;
+    params.tcp_keepalive_secs = Some(45);
+    params.listen_backlog = listen_backlog;
+    let proxy = common::spawn_proxy(params).await;
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client
+        .write_all(b"GET / HTTP/1.1\r\nhost: proxy\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = [0; 12];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"HTTP/1.1 200");
+    options_of_socket_to(client.local_addr().unwrap())
+}
+
+#[tokio::test]
+async fn sets_the_options_on_the_connections_from_clients() {
+    let expected = Options {
+        nodelay: true,
+        keepalive: true,
+        keepalive_idle_secs: 45,
+    };
+    assert_eq!(client_connection_options(None).await, expected);
+    assert_eq!(client_connection_options(Some(16)).await, expected);
+}
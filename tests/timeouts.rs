@@ -0,0 +1,171 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use authproxy::proxy::{ProxyParams, Route, SocksProxy};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::net::TcpListener;
+
+async fn slow(_req: Request<Body>) -> Response<Body> {
+    tokio::time::delay_for(Duration::from_millis(1500)).await;
+    Response::new(Body::from("slow"))
+}
+
+#[tokio::test]
+async fn answers_with_a_gateway_timeout_when_the_target_is_too_slow() {
+    let target = common::spawn_target(slow).await;
+    let mut params = common::params(target);
+    params.upstream_timeout_secs = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    let started_at = Instant::now();
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert!(started_at.elapsed() < Duration::from_millis(1400));
+}
+
+#[tokio::test]
+async fn waits_for_the_target_without_a_timeout() {
+    let target = common::spawn_target(slow).await;
+    let mut params = common::params(target);
+    params.upstream_timeout_secs = 0;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "slow");
+}
+
+async fn status_of(proxy: std::net::SocketAddr, method: Method, path: &str) -> StatusCode {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://{}{}", proxy, path))
+        .body(Body::empty())
+        .unwrap();
+    common::send(request).await.status()
+}
+
+#[tokio::test]
+async fn applies_the_timeout_of_the_request_method() {
+    let target = common::spawn_target(slow).await;
+    let mut params = common::params(target);
+    params.upstream_timeout_secs = 1;
+    params.method_timeouts = vec![(Method::POST, 5)];
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        status_of(proxy, Method::GET, "/").await,
+        StatusCode::GATEWAY_TIMEOUT
+    );
+    assert_eq!(status_of(proxy, Method::POST, "/").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn applies_the_timeout_of_the_route_over_the_one_of_the_method() {
+    let target = common::spawn_target(slow).await;
+    let mut params = common::params(target);
+    params.upstream_timeout_secs = 1;
+    params.method_timeouts = vec![(Method::POST, 5)];
+    let mut route: Route = format!("/strict=http://{}", target).parse().unwrap();
+    route.timeout_secs = Some(1);
+    params.routes = vec![route];
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(
+        status_of(proxy, Method::POST, "/strict").await,
+        StatusCode::GATEWAY_TIMEOUT
+    );
+    assert_eq!(
+        status_of(proxy, Method::POST, "/other").await,
+        StatusCode::OK
+    );
+}
+
+// Accepts connections but never says anything on them
+async fn spawn_silent_server() -> std::net::SocketAddr {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            connections.push(stream);
+        }
+    });
+    addr
+}
+
+async fn assert_fails_within_the_connect_timeout(params: ProxyParams) {
+    let proxy = common::spawn_proxy(params).await;
+
+    let started_at = Instant::now();
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert!(started_at.elapsed() < Duration::from_millis(1500));
+}
+
+#[tokio::test]
+async fn times_out_the_tls_handshake_with_the_connect_timeout() {
+    let target = spawn_silent_server().await;
+    let mut params = common::params(target);
+    params.target_url = format!("https://localhost:{}", target.port());
+    params.connect_timeout_secs = 1;
+    assert_fails_within_the_connect_timeout(params).await;
+}
+
+#[tokio::test]
+async fn times_out_connecting_through_a_socks_proxy_with_the_connect_timeout() {
+    let socks_proxy = spawn_silent_server().await;
+    let mut params = common::params(socks_proxy);
+    params.socks_proxy = Some(SocksProxy {
+        addr: socks_proxy.to_string(),
+        credentials: None,
+    });
+    params.connect_timeout_secs = 1;
+    assert_fails_within_the_connect_timeout(params).await;
+}
+
+// Answers right away, then sends an event every 400ms for two seconds
+async fn events(_req: Request<Body>) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for i in 0..5 {
+            tokio::time::delay_for(Duration::from_millis(400)).await;
+            if sender
+                .send_data(format!("data: {}\n\n", i).into())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    Response::builder()
+        .header("content-type", "text/event-stream")
+        .body(body)
+        .unwrap()
+}
+
+async fn stream_events(stream_paths: Vec<String>) -> Result<String, hyper::Error> {
+    let target = common::spawn_target(events).await;
+    let mut params = common::params(target);
+    params.upstream_timeout_secs = 1;
+    params.stream_paths = stream_paths;
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/events").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn keeps_streaming_responses_past_the_timeout() {
+    let body = stream_events(vec![String::from("/events")]).await.unwrap();
+    assert_eq!(body.matches("data: ").count(), 5);
+}
+
+#[tokio::test]
+async fn cuts_off_other_responses_at_the_timeout() {
+    assert!(stream_events(Vec::new()).await.is_err());
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use tempfile::TempDir;
+use tokio::time::delay_for;
+
+// Replaces the file with a rename, the way Kubernetes updates projected tokens
+fn replace_token(path: &Path, token: &str) {
+    let new_path = path.with_extension("new");
+    fs::write(&new_path, token).unwrap();
+    fs::rename(new_path, path).unwrap();
+}
+
+async fn received_token(
+    proxy: std::net::SocketAddr,
+    received: &common::ReceivedRequests,
+) -> String {
+    common::get(proxy, "/").await;
+    let received = received.lock().unwrap();
+    received
+        .last()
+        .unwrap()
+        .header("authorization")
+        .unwrap()
+        .to_string()
+}
+
+#[tokio::test]
+async fn reads_the_token_from_the_file_again_once_it_expires() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("token");
+    fs::write(&path, "first\n").unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.token_file = Some(path.clone());
+    params.cache_ttl_secs = 1;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(received_token(proxy, &received).await, "Bearer first");
+    replace_token(&path, "second");
+    assert_eq!(received_token(proxy, &received).await, "Bearer first");
+    delay_for(Duration::from_millis(1100)).await;
+    assert_eq!(received_token(proxy, &received).await, "Bearer second");
+}
+
+#[tokio::test]
+async fn reads_the_watched_token_file_again_once_it_is_replaced() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("token");
+    fs::write(&path, "first").unwrap();
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.token_file = Some(path.clone());
+    params.watch_token_file = true;
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(received_token(proxy, &received).await, "Bearer first");
+    replace_token(&path, "second");
+    // Changes are reported after the watch delay
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(received_token(proxy, &received).await, "Bearer second");
+}
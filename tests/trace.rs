@@ -0,0 +1,68 @@
+mod common;
+
+use std::time::Duration;
+
+use authproxy::proxy::AuthLocation;
+use hyper::{Body, Request, StatusCode};
+use tokio::time::delay_for;
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+async fn forwards_the_trace_headers(otlp_endpoint: Option<String>) {
+    let (target, received) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.otlp_endpoint = otlp_endpoint;
+    let proxy = common::spawn_proxy(params).await;
+
+    let request = Request::get(format!("http://{}/", proxy))
+        .header("traceparent", TRACEPARENT)
+        .header("tracestate", "vendor=value")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(common::send(request).await.status(), StatusCode::OK);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].header("traceparent"), Some(TRACEPARENT));
+    assert_eq!(received[0].header("tracestate"), Some("vendor=value"));
+}
+
+#[tokio::test]
+async fn forwards_the_trace_headers_unchanged() {
+    forwards_the_trace_headers(None).await;
+}
+
+#[tokio::test]
+async fn forwards_the_trace_headers_unchanged_while_exporting_spans() {
+    let (collector, _) = common::spawn_recording_target().await;
+    forwards_the_trace_headers(Some(format!("http://{}", collector))).await;
+}
+
+#[tokio::test]
+async fn redacts_the_token_in_the_traced_url() {
+    let (collector, exported) = common::spawn_recording_target().await;
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.command = vec![
+        String::from("echo"),
+        String::from("traced-0123456789abcdef"),
+    ];
+    params.auth_location = AuthLocation::Query;
+    params.otlp_endpoint = Some(format!("http://{}", collector));
+    let proxy = common::spawn_proxy(params).await;
+
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    // The spans are exported every few seconds
+    for _ in 0..80 {
+        if !exported.lock().unwrap().is_empty() {
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    let exported = exported.lock().unwrap();
+    let spans = String::from_utf8(exported[0].body.to_vec()).unwrap();
+    assert!(
+        spans.contains("/?access_token=trac%E2%80%A6+%28len+23%29"),
+        "{}",
+        spans
+    );
+    assert!(!spans.contains("traced-0123456789abcdef"));
+}
@@ -0,0 +1,199 @@
+mod common;
+
+use std::env;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use authproxy::proxy::SocksProxy;
+use hyper::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+type Credentials = Option<(&'static str, &'static str)>;
+
+async fn read_string(stream: &mut TcpStream) -> io::Result<String> {
+    let len = stream.read_u8().await?;
+    let mut bytes = vec![0; usize::from(len)];
+    stream.read_exact(&mut bytes).await?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Answers the SOCKS5 handshake and connects to the requested address,
+// None when the client didn't authenticate itself
+async fn socks_handshake(
+    stream: &mut TcpStream,
+    credentials: Credentials,
+) -> io::Result<Option<TcpStream>> {
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await?;
+    let mut methods = vec![0; usize::from(greeting[1])];
+    stream.read_exact(&mut methods).await?;
+
+    if let Some((user, pass)) = credentials {
+        stream.write_all(&[5, 2]).await?;
+        let _version = stream.read_u8().await?;
+        let authenticated =
+            read_string(stream).await? == user && read_string(stream).await? == pass;
+        stream
+            .write_all(&[1, if authenticated { 0 } else { 1 }])
+            .await?;
+        if !authenticated {
+            return Ok(None);
+        }
+    } else {
+        stream.write_all(&[5, 0]).await?;
+    }
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        4 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        _ => read_string(stream).await?,
+    };
+    let port = stream.read_u16().await?;
+
+    let target = TcpStream::connect(format!("{}:{}", host, port)).await?;
+    stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(Some(target))
+}
+
+async fn relay(mut client: TcpStream, mut target: TcpStream) {
+    let (mut client_read, mut client_write) = client.split();
+    let (mut target_read, mut target_write) = target.split();
+    let _ = futures::future::try_join(
+        tokio::io::copy(&mut client_read, &mut target_write),
+        tokio::io::copy(&mut target_read, &mut client_write),
+    )
+    .await;
+}
+
+// Starts a SOCKS5 proxy that counts the connections it forwards
+async fn spawn_socks_proxy(credentials: Credentials) -> (SocketAddr, Arc<AtomicUsize>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let forwarded = Arc::new(AtomicUsize::new(0));
+    let forwarded_by_proxy = forwarded.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let forwarded = forwarded_by_proxy.clone();
+            tokio::spawn(async move {
+                if let Ok(Some(target)) = socks_handshake(&mut stream, credentials).await {
+                    forwarded.fetch_add(1, Ordering::SeqCst);
+                    relay(stream, target).await;
+                }
+            });
+        }
+    });
+    (addr, forwarded)
+}
+
+// Answers the CONNECT request and connects to the requested address
+async fn connect_handshake(stream: &mut TcpStream) -> io::Result<TcpStream> {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        request.push(stream.read_u8().await?);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let authority = request
+        .strip_prefix("CONNECT ")
+        .and_then(|request| request.split_whitespace().next())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not a CONNECT request"))?;
+
+    let target = TcpStream::connect(authority).await?;
+    stream
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+    Ok(target)
+}
+
+// Starts an HTTP proxy that counts the connections it tunnels
+async fn spawn_http_proxy() -> (SocketAddr, Arc<AtomicUsize>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let forwarded = Arc::new(AtomicUsize::new(0));
+    let forwarded_by_proxy = forwarded.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let forwarded = forwarded_by_proxy.clone();
+            tokio::spawn(async move {
+                if let Ok(target) = connect_handshake(&mut stream).await {
+                    forwarded.fetch_add(1, Ordering::SeqCst);
+                    relay(stream, target).await;
+                }
+            });
+        }
+    });
+    (addr, forwarded)
+}
+
+async fn get_through_socks(credentials: Credentials, given: Credentials) -> (StatusCode, usize) {
+    let (socks_proxy, forwarded) = spawn_socks_proxy(credentials).await;
+    let (target, _) = common::spawn_recording_target().await;
+    let mut params = common::params(target);
+    params.socks_proxy = Some(SocksProxy {
+        addr: socks_proxy.to_string(),
+        credentials: given.map(|(user, pass)| (user.to_string(), pass.to_string())),
+    });
+    let proxy = common::spawn_proxy(params).await;
+
+    let status = common::get(proxy, "/").await.status();
+    (status, forwarded.load(Ordering::SeqCst))
+}
+
+#[tokio::test]
+async fn connects_through_the_socks_proxy() {
+    assert_eq!(get_through_socks(None, None).await, (StatusCode::OK, 1));
+}
+
+#[tokio::test]
+async fn authenticates_to_the_socks_proxy() {
+    let credentials = Some(("proxy", "secret"));
+    assert_eq!(
+        get_through_socks(credentials, credentials).await,
+        (StatusCode::OK, 1)
+    );
+    assert_eq!(
+        get_through_socks(credentials, Some(("proxy", "wrong"))).await,
+        (StatusCode::BAD_GATEWAY, 0)
+    );
+}
+
+// The only test reading the proxy variables, so setting them doesn't affect the others
+#[tokio::test]
+async fn uses_the_proxies_from_the_environment() {
+    let (http_proxy, forwarded) = spawn_http_proxy().await;
+    for name in &["http_proxy", "HTTP_PROXY"] {
+        env::set_var(name, format!("http://{}", http_proxy));
+    }
+    for name in &["no_proxy", "NO_PROXY"] {
+        env::set_var(name, "localhost");
+    }
+    let (target, received) = common::spawn_recording_target().await;
+
+    let mut params = common::params(target);
+    params.use_system_proxy = true;
+    let proxy = common::spawn_proxy(params).await;
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+    let mut params = common::params(target);
+    params.target_url = format!("http://localhost:{}", target.port());
+    params.use_system_proxy = true;
+    let proxy = common::spawn_proxy(params).await;
+    assert_eq!(common::get(proxy, "/").await.status(), StatusCode::OK);
+    assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    assert_eq!(received.lock().unwrap().len(), 2);
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use authproxy::proxy::{ProxyParams, TlsVersion};
+use hyper::{Body, Request, Response, StatusCode};
+
+async fn ok(_req: Request<Body>) -> Response<Body> {
+    Response::new(Body::from("ok"))
+}
+
+fn https_params(target: SocketAddr) -> ProxyParams {
+    let mut params = common::params(target);
+    params.target_url = format!("https://localhost:{}", target.port());
+    params
+}
+
+#[tokio::test]
+async fn trusts_the_given_ca() {
+    let target = common::spawn_https_target(false, ok).await;
+    let mut params = https_params(target);
+    params.ca_file = Some(common::fixture("ca.pem"));
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(common::body_string(response).await, "ok");
+}
+
+#[tokio::test]
+async fn rejects_a_target_signed_by_an_unknown_ca() {
+    let target = common::spawn_https_target(false, ok).await;
+    let proxy = common::spawn_proxy(https_params(target)).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn fails_to_start_with_a_ca_file_without_certificates() {
+    let target = common::spawn_https_target(false, ok).await;
+    let mut params = https_params(target);
+    params.ca_file = Some(common::fixture("server.key"));
+    assert!(authproxy::proxy::Proxy::bind(params).await.is_err());
+}
+
+#[tokio::test]
+async fn presents_the_client_certificate() {
+    let target = common::spawn_https_target(true, ok).await;
+    let mut params = https_params(target);
+    params.ca_file = Some(common::fixture("ca.pem"));
+    params.client_cert = Some(common::fixture("client.p12"));
+    params.client_cert_password = Some("secret".into());
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn fails_without_the_client_certificate_the_target_requires() {
+    let target = common::spawn_https_target(true, ok).await;
+    let mut params = https_params(target);
+    params.ca_file = Some(common::fixture("ca.pem"));
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn fails_to_start_with_the_wrong_client_certificate_password() {
+    let target = common::spawn_https_target(true, ok).await;
+    let mut params = https_params(target);
+    params.client_cert = Some(common::fixture("client.p12"));
+    params.client_cert_password = Some("wrong".into());
+    match authproxy::proxy::Proxy::bind(params).await {
+        Err(err) => assert!(
+            err.to_string().contains("is the password correct?"),
+            "{}",
+            err
+        ),
+        Ok(_) => panic!("Started with the wrong password"),
+    }
+}
+
+async fn echo_host(req: Request<Body>) -> Response<Body> {
+    Response::new(Body::from(
+        req.headers()["host"].to_str().unwrap().to_string(),
+    ))
+}
+
+#[tokio::test]
+async fn connects_to_the_overridden_address_with_the_real_host_name() {
+    // Not in the certificate, which is only checked against the host name then
+    let target =
+        common::spawn_https_target_at("127.0.0.2:0".parse().unwrap(), false, echo_host).await;
+    let mut params = https_params(target);
+    params.ca_file = Some(common::fixture("ca.pem"));
+    params.resolve = vec![format!("localhost:{}:127.0.0.2", target.port())
+        .parse()
+        .unwrap()];
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        common::body_string(response).await,
+        format!("localhost:{}", target.port())
+    );
+}
+
+#[tokio::test]
+async fn connects_to_an_unresolvable_host_through_its_override() {
+    let target = common::spawn_target(echo_host).await;
+    let mut params = common::params(target);
+    params.target_url = format!("http://api.example.test:{}", target.port());
+    params.resolve = vec![format!("API.example.test:{}:127.0.0.1", target.port())
+        .parse()
+        .unwrap()];
+    let proxy = common::spawn_proxy(params).await;
+
+    let response = common::get(proxy, "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        common::body_string(response).await,
+        format!("api.example.test:{}", target.port())
+    );
+}
+
+async fn status_with_tls_versions(min: Option<TlsVersion>, max: Option<TlsVersion>) -> StatusCode {
+    let target = common::spawn_https_target(false, ok).await;
+    let mut params = https_params(target);
+    params.ca_file = Some(common::fixture("ca.pem"));
+    params.min_tls_version = min;
+    params.max_tls_version = max;
+    let proxy = common::spawn_proxy(params).await;
+
+    common::get(proxy, "/").await.status()
+}
+
+#[tokio::test]
+async fn connects_with_tls_1_3_only() {
+    assert_eq!(
+        status_with_tls_versions(Some(TlsVersion::Tls13), None).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn connects_with_tls_1_2_at_most() {
+    assert_eq!(
+        status_with_tls_versions(None, Some(TlsVersion::Tls12)).await,
+        StatusCode::OK
+    );
+}
+
+async fn status_connecting_to_an_ip(tls_sni: Option<&str>) -> StatusCode {
+    // Not in the certificate, unlike localhost
+    let target = common::spawn_https_target_at("127.0.0.2:0".parse().unwrap(), false, ok).await;
+    let mut params = common::params(target);
+    params.target_url = format!("https://{}", target);
+    params.ca_file = Some(common::fixture("ca.pem"));
+    params.tls_sni = tls_sni.map(String::from);
+    let proxy = common::spawn_proxy(params).await;
+
+    common::get(proxy, "/").await.status()
+}
+
+#[tokio::test]
+async fn verifies_the_certificate_for_the_given_sni() {
+    assert_eq!(
+        status_connecting_to_an_ip(Some("localhost")).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn verifies_the_certificate_for_the_target_address_by_default() {
+    assert_eq!(
+        status_connecting_to_an_ip(None).await,
+        StatusCode::BAD_GATEWAY
+    );
+}